@@ -1,5 +1,6 @@
 
 pub use self::disjoint_sets::DisjointSets;
+pub use self::reed_solomon::ReedSolomon;
 
 mod disjoint_sets;
-
+mod reed_solomon;
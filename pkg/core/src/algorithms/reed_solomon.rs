@@ -0,0 +1,301 @@
+
+/// Irreducible polynomial used to generate GF(2^8) (x^8 + x^4 + x^3 + x^2 + 1)
+const FIELD_POLYNOMIAL: u16 = 0x11d;
+
+/// Arithmetic in the GF(2^8) finite field using log/exp tables
+struct Galois {
+	exp: [u8; 512],
+	log: [u8; 256]
+}
+
+impl Galois {
+	fn new() -> Galois {
+		let mut field = Galois {
+			exp: [0; 512],
+			log: [0; 256]
+		};
+
+		let mut x: u16 = 1;
+		for i in 0..255 {
+			field.exp[i] = x as u8;
+			field.log[x as usize] = i as u8;
+
+			x <<= 1;
+			if x & 0x100 != 0 {
+				x ^= FIELD_POLYNOMIAL;
+			}
+		}
+
+		// Doubled up so that the sum of two logs never needs to be reduced
+		for i in 255..512 {
+			field.exp[i] = field.exp[i - 255];
+		}
+
+		field
+	}
+
+	fn mul(&self, a: u8, b: u8) -> u8 {
+		if a == 0 || b == 0 {
+			return 0;
+		}
+
+		self.exp[(self.log[a as usize] as usize) + (self.log[b as usize] as usize)]
+	}
+
+	fn inv(&self, a: u8) -> u8 {
+		assert!(a != 0);
+		self.exp[255 - (self.log[a as usize] as usize)]
+	}
+
+	fn pow(&self, a: u8, n: usize) -> u8 {
+		if n == 0 {
+			return 1;
+		}
+
+		if a == 0 {
+			return 0;
+		}
+
+		self.exp[((self.log[a as usize] as usize) * n) % 255]
+	}
+
+	/// Gets the product of the given constant with every possible byte (for quickly multiplying whole buffers)
+	fn mul_table(&self, c: u8) -> [u8; 256] {
+		let mut table = [0u8; 256];
+		for i in 0..256 {
+			table[i] = self.mul(c, i as u8);
+		}
+
+		table
+	}
+
+	/// Inverts a square matrix using Gauss-Jordan elimination
+	fn invert(&self, matrix: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+		let n = matrix.len();
+
+		let mut a = matrix.to_vec();
+		let mut out = (0..n).map(|i| {
+			let mut row = vec![0u8; n];
+			row[i] = 1;
+			row
+		}).collect::<Vec<_>>();
+
+		for col in 0..n {
+			let pivot = match (col..n).find(|r| a[*r][col] != 0) {
+				Some(r) => r,
+				None => return None
+			};
+
+			a.swap(col, pivot);
+			out.swap(col, pivot);
+
+			let scale = self.inv(a[col][col]);
+			for j in 0..n {
+				a[col][j] = self.mul(a[col][j], scale);
+				out[col][j] = self.mul(out[col][j], scale);
+			}
+
+			for r in 0..n {
+				let factor = a[r][col];
+				if r == col || factor == 0 {
+					continue;
+				}
+
+				for j in 0..n {
+					let x = self.mul(factor, a[col][j]);
+					let y = self.mul(factor, out[col][j]);
+					a[r][j] ^= x;
+					out[r][j] ^= y;
+				}
+			}
+		}
+
+		Some(out)
+	}
+}
+
+
+/// Systematic Reed-Solomon erasure code over GF(2^8)
+///
+/// The data is split by the caller into 'data_shards' equally sized buffers from which 'parity_shards' more buffers are computed. The original data can be recovered from any 'data_shards' of all of the buffers
+///
+/// NOTE: Every byte offset is coded independently, so any sub-range of the shards can be encoded or reconstructed on its own
+pub struct ReedSolomon {
+	data_shards: usize,
+	parity_shards: usize,
+	field: Galois,
+
+	/// (data_shards + parity_shards) x data_shards matrix which produces every shard from the data shards (the top is the identity)
+	matrix: Vec<Vec<u8>>
+}
+
+impl ReedSolomon {
+
+	pub fn new(data_shards: usize, parity_shards: usize) -> ReedSolomon {
+		assert!(data_shards > 0);
+		assert!(data_shards + parity_shards <= 256);
+
+		let field = Galois::new();
+		let total = data_shards + parity_shards;
+
+		// Any 'data_shards' rows of a Vandermonde matrix are invertible
+		let vandermonde = (0..total).map(|r| {
+			(0..data_shards).map(|c| field.pow(r as u8, c)).collect::<Vec<_>>()
+		}).collect::<Vec<_>>();
+
+		// Multiplying by the inverse of the top square makes the code systematic while keeping the above property
+		let top_inv = field.invert(&vandermonde[0..data_shards]).unwrap();
+
+		let matrix = vandermonde.iter().map(|row| {
+			(0..data_shards).map(|c| {
+				let mut v = 0;
+				for i in 0..data_shards {
+					v ^= field.mul(row[i], top_inv[i][c]);
+				}
+				v
+			}).collect::<Vec<_>>()
+		}).collect::<Vec<_>>();
+
+		ReedSolomon {
+			data_shards,
+			parity_shards,
+			field,
+			matrix
+		}
+	}
+
+	pub fn data_shards(&self) -> usize { self.data_shards }
+
+	pub fn parity_shards(&self) -> usize { self.parity_shards }
+
+	pub fn total_shards(&self) -> usize { self.data_shards + self.parity_shards }
+
+	/// Computes all of the parity shards for the given data shards (which must all be the same length)
+	pub fn encode(&self, data: &[&[u8]]) -> Vec<Vec<u8>> {
+		assert_eq!(data.len(), self.data_shards);
+
+		(self.data_shards..self.total_shards()).map(|r| {
+			self.combine(&self.matrix[r], data)
+		}).collect()
+	}
+
+	/// Fills in all of the missing shards given at least 'data_shards' of them (in the same order as they were created)
+	pub fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<(), &'static str> {
+		if shards.len() != self.total_shards() {
+			return Err("Wrong number of shards given");
+		}
+
+		if shards.iter().all(|s| s.is_some()) {
+			return Ok(());
+		}
+
+		let present = (0..shards.len()).filter(|i| shards[*i].is_some()).take(self.data_shards).collect::<Vec<_>>();
+		if present.len() < self.data_shards {
+			return Err("Not enough shards to reconstruct the data");
+		}
+
+		let len = shards[present[0]].as_ref().unwrap().len();
+		if present.iter().any(|i| shards[*i].as_ref().unwrap().len() != len) {
+			return Err("Shards are not all the same size");
+		}
+
+		let sub = present.iter().map(|i| self.matrix[*i].clone()).collect::<Vec<_>>();
+		let decode = match self.field.invert(&sub) {
+			Some(m) => m,
+			None => return Err("Shards can not be decoded")
+		};
+
+		let data = {
+			let inputs = present.iter().map(|i| &shards[*i].as_ref().unwrap()[..]).collect::<Vec<_>>();
+			(0..self.data_shards).map(|r| {
+				if shards[r].is_some() { None } else { Some(self.combine(&decode[r], &inputs)) }
+			}).collect::<Vec<_>>()
+		};
+
+		for (r, d) in data.into_iter().enumerate() {
+			if d.is_some() {
+				shards[r] = d;
+			}
+		}
+
+		let parity = {
+			let inputs = shards[0..self.data_shards].iter().map(|s| &s.as_ref().unwrap()[..]).collect::<Vec<_>>();
+			(self.data_shards..self.total_shards()).map(|r| {
+				if shards[r].is_some() { None } else { Some(self.combine(&self.matrix[r], &inputs)) }
+			}).collect::<Vec<_>>()
+		};
+
+		for (i, p) in parity.into_iter().enumerate() {
+			if p.is_some() {
+				shards[self.data_shards + i] = p;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Computes the linear combination of the inputs with the given coefficients
+	fn combine(&self, coefficients: &[u8], inputs: &[&[u8]]) -> Vec<u8> {
+		let len = if inputs.len() > 0 { inputs[0].len() } else { 0 };
+		let mut out = vec![0u8; len];
+
+		for (c, input) in coefficients.iter().zip(inputs.iter()) {
+			assert_eq!(input.len(), len);
+
+			if *c == 0 {
+				continue;
+			}
+
+			let table = self.field.mul_table(*c);
+			for (o, x) in out.iter_mut().zip(input.iter()) {
+				*o ^= table[*x as usize];
+			}
+		}
+
+		out
+	}
+
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reed_solomon_reconstructs_from_any_subset() {
+		let rs = ReedSolomon::new(4, 2);
+
+		let data = (0..4).map(|i| {
+			(0..100).map(|j| ((i * 37 + j * 11) % 256) as u8).collect::<Vec<u8>>()
+		}).collect::<Vec<_>>();
+
+		let parity = rs.encode(&data.iter().map(|d| &d[..]).collect::<Vec<_>>());
+		assert_eq!(parity.len(), 2);
+
+		let all = data.iter().chain(parity.iter()).cloned().collect::<Vec<_>>();
+
+		// Every possible pair of lost shards
+		for a in 0..6 {
+			for b in (a + 1)..6 {
+				let mut shards = all.iter().cloned().map(Some).collect::<Vec<_>>();
+				shards[a] = None;
+				shards[b] = None;
+
+				rs.reconstruct(&mut shards).unwrap();
+
+				for i in 0..6 {
+					assert_eq!(shards[i].as_ref().unwrap(), &all[i]);
+				}
+			}
+		}
+
+		// Losing more than the number of parity shards is unrecoverable
+		let mut shards = all.iter().cloned().map(Some).collect::<Vec<_>>();
+		shards[0] = None;
+		shards[1] = None;
+		shards[5] = None;
+		assert!(rs.reconstruct(&mut shards).is_err());
+	}
+
+}
@@ -4,16 +4,18 @@ extern crate haystack;
 extern crate clap;
 extern crate futures;
 extern crate toml;
+extern crate core;
 
 use haystack::directory::Directory;
 use haystack::errors::*;
 use haystack::common::*;
-use clap::{Arg, App, SubCommand};
+use clap::{Arg, App, SubCommand, ArgMatches};
 
 use haystack::client::*;
 use std::fs::File;
 use std::io::{Read};
 use futures::Future;
+use core::FlipSign;
 
 
 
@@ -42,6 +44,44 @@ fn main() -> Result<()> {
 				.value_name("FOLDER")
 				.help("Sets the data directory for store volumes")
 				.takes_value(true))
+			.arg(Arg::with_name("region")
+				.long("region")
+				.value_name("REGION")
+				.help("Sets the region (datacenter) in which this machine is located")
+				.takes_value(true))
+			.arg(Arg::with_name("rack")
+				.long("rack")
+				.value_name("RACK")
+				.help("Sets the rack in which this machine is located (no two replicas of a volume will be placed in the same rack)")
+				.takes_value(true))
+			.subcommand(
+				SubCommand::with_name("repair-index")
+				.about("Rebuilds the index files of volumes from the needles in the volumes (the store must not be running)")
+				.arg(Arg::with_name("folder")
+					.short("f")
+					.long("folder")
+					.value_name("FOLDER")
+					.help("Data directory of the store")
+					.takes_value(true))
+				.arg(Arg::with_name("VOLUME_ID")
+					.help("Volumes to re-index (all volumes in the store if none are given)")
+					.multiple(true)
+					.index(1))
+			)
+			.subcommand(
+				SubCommand::with_name("migrate")
+				.about("Upgrades volumes in an older format so that they can be written to again (the store must not be running)")
+				.arg(Arg::with_name("folder")
+					.short("f")
+					.long("folder")
+					.value_name("FOLDER")
+					.help("Data directory of the store")
+					.takes_value(true))
+				.arg(Arg::with_name("VOLUME_ID")
+					.help("Volumes to migrate (all volumes in the store if none are given)")
+					.multiple(true)
+					.index(1))
+			)
 		)
 		// TODO: Would also be useful to print out a default config file so that it can then be edited nicely
 		.subcommand(
@@ -53,6 +93,42 @@ fn main() -> Result<()> {
 				.value_name("PORT")
 				.help("Sets the listening http port")
 				.takes_value(true))
+			.arg(Arg::with_name("folder")
+				.short("f")
+				.long("folder")
+				.value_name("FOLDER")
+				.help("Sets the data directory for caching entries on disk (if not given, entries will only be cached in memory)")
+				.takes_value(true))
+		)
+		.subcommand(
+			SubCommand::with_name("directory")
+			.about("Starts a node of a raft replicated directory (use with HAYSTACK_DB=raft://... on all other machines)")
+			.arg(Arg::with_name("port")
+				.short("p")
+				.long("port")
+				.value_name("PORT")
+				.help("Sets the listening http port used by other machines to access the directory")
+				.takes_value(true))
+			.arg(Arg::with_name("folder")
+				.short("f")
+				.long("folder")
+				.value_name("FOLDER")
+				.help("Sets the data directory for the raft log of this node")
+				.takes_value(true))
+			.arg(Arg::with_name("bootstrap")
+				.long("bootstrap")
+				.help("Indicates that this should be created as the first node in the directory cluster"))
+			.arg(Arg::with_name("join")
+				.short("j")
+				.long("join")
+				.value_name("RAFT_ADDRESS")
+				.help("Raft address (such as http://127.0.0.1:4001) of an existing directory node to join through")
+				.takes_value(true)
+				.multiple(true))
+		)
+		.subcommand(
+			SubCommand::with_name("pitchfork")
+			.about("Starts the service that health checks all store machines (only one should be running per cluster)")
 		)
 		.subcommand(
 			SubCommand::with_name("client")
@@ -68,16 +144,44 @@ fn main() -> Result<()> {
 					.required(true)
 					.index(2))
 			)
+			.subcommand(
+				SubCommand::with_name("update")
+				.about("Adds or replaces a single alt_key of an existing photo")
+				.arg(Arg::with_name("KEY").required(true).index(1))
+				.arg(Arg::with_name("ALT_KEY")
+					.help("Alternative key integer to use for this upload")
+					.required(true)
+					.index(2))
+				.arg(Arg::with_name("INPUT_FILE")
+					.help("Path to the file to be uploaded")
+					.required(true)
+					.index(3))
+			)
 			.subcommand(
 				SubCommand::with_name("read-url")
 				.arg(Arg::with_name("KEY").required(true).index(1))
 				.arg(Arg::with_name("ALT_KEY").required(true).index(2))
 			)
+			.subcommand(
+				SubCommand::with_name("delete")
+				.about("Deletes a photo along with all of its alt_keys")
+				.arg(Arg::with_name("KEY").required(true).index(1))
+			)
+			.subcommand(
+				SubCommand::with_name("ls")
+				.about("Lists every photo in a logical volume")
+				.arg(Arg::with_name("VOLUME_ID").required(true).index(1))
+			)
+			.subcommand(
+				SubCommand::with_name("stat")
+				.about("Shows the volume, upload time and alt_keys of a photo")
+				.arg(Arg::with_name("KEY").required(true).index(1))
+			)
 		)
 		.get_matches();
 
 
-	let config = if let Some(config_file) = matches.value_of("config") {
+	let mut config = if let Some(config_file) = matches.value_of("config") {
 		let mut file = File::open(config_file).expect("Failed to open the specified config file");
 		let mut contents = String::new();
 		file.read_to_string(&mut contents)?;
@@ -86,6 +190,47 @@ fn main() -> Result<()> {
 		Config::default()
 	};
 
+	// The directory nodes are what every other command connects to, so they must be started without one
+	if let ("directory", Some(m)) = matches.subcommand() {
+		let port = m.value_of("port").unwrap_or("4100").parse::<u16>().expect("Invalid port given");
+		let folder = m.value_of("folder").unwrap_or("/hay-directory");
+		let seeds = m.values_of("join").map(|v| v.map(|s| s.to_string()).collect()).unwrap_or(vec![]);
+		haystack::directory::main::run(folder, port, m.is_present("bootstrap"), seeds)?;
+		return Ok(());
+	}
+
+	// Maintenance of a store's files is done offline, so it doesn't need the directory either
+	if let ("store", Some(m)) = matches.subcommand() {
+		fn volume_ids(m: &ArgMatches) -> Vec<VolumeId> {
+			m.values_of("VOLUME_ID")
+				.map(|v| v.map(|s| s.parse::<VolumeId>().expect("Invalid volume id given")).collect())
+				.unwrap_or(vec![])
+		}
+
+		match m.subcommand() {
+			("repair-index", Some(m)) => {
+				haystack::store::main::repair_index(config, m.value_of("folder").unwrap_or("/hay"), &volume_ids(m))?;
+				return Ok(());
+			},
+			("migrate", Some(m)) => {
+				haystack::store::main::migrate(config, m.value_of("folder").unwrap_or("/hay"), &volume_ids(m))?;
+				return Ok(());
+			},
+			_ => {}
+		};
+	}
+
+	// The location given on the command line takes precedence over the config file
+	if let ("store", Some(m)) = matches.subcommand() {
+		if let Some(r) = m.value_of("region") {
+			config.region = r.to_owned();
+		}
+
+		if let Some(r) = m.value_of("rack") {
+			config.store.rack = r.to_owned();
+		}
+	}
+
 	let dir = Directory::open(config)?;
 
 	match matches.subcommand() {
@@ -96,10 +241,12 @@ fn main() -> Result<()> {
 		},
 		("cache", Some(m)) => {
 			let port = m.value_of("port").unwrap_or("4001").parse::<u16>().expect("Invalid port given");
-			haystack::cache::main::run(dir, port)?;
+			haystack::cache::main::run(dir, port, m.value_of("folder"))?;
 		},
 
-		// TODO: Will also eventually also have the pitch-fork
+		("pitchfork", Some(_)) => {
+			haystack::pitchfork::main::run(dir)?;
+		},
 
 		("client", Some(m)) => {
 
@@ -135,6 +282,33 @@ fn main() -> Result<()> {
 					tokio::run(f);	
 
 				},
+				("update", Some(m)) => {
+					let key = m.value_of("KEY").unwrap().parse::<NeedleKey>().unwrap();
+					let alt_key = m.value_of("ALT_KEY").unwrap().parse::<NeedleAltKey>().unwrap();
+					let filename = m.value_of("INPUT_FILE").unwrap();
+
+					let mut f = File::open(filename)?;
+					let mut data = vec![];
+					f.read_to_end(&mut data)?;
+
+					let chunks = vec![
+						PhotoChunk {
+							alt_key,
+							data: data.into()
+						}
+					];
+
+					let f = c.update_photo(key, chunks)
+					.map_err(|err| {
+						println!("{:?}", err);
+						()
+					}).map(move |_| {
+						println!("Updated photo id: {}", key);
+						()
+					});
+
+					tokio::run(f);
+				},
 				("read-url", Some(m)) => {
 					let key = m.value_of("KEY").unwrap().parse::<NeedleKey>().unwrap();
 					let alt_key = m.value_of("ALT_KEY").unwrap().parse::<NeedleAltKey>().unwrap();
@@ -145,6 +319,46 @@ fn main() -> Result<()> {
 
 					println!("{}", url);
 				},
+				("delete", Some(m)) => {
+					let key = m.value_of("KEY").unwrap().parse::<NeedleKey>().unwrap();
+
+					let f = c.delete_photo(key)
+					.map_err(|err| {
+						println!("{:?}", err);
+						()
+					}).map(move |_| {
+						println!("Deleted photo id: {}", key);
+						()
+					});
+
+					tokio::run(f);
+				},
+				("ls", Some(m)) => {
+					let volume_id = m.value_of("VOLUME_ID").unwrap().parse::<VolumeId>().unwrap();
+
+					for p in c.list_photos(volume_id)? {
+						let alt_keys = p.alt_keys.iter().map(|k| k.flip().to_string()).collect::<Vec<_>>();
+
+						println!(
+							"{}\t{}\t{}\t{}",
+							p.id.flip(), if p.committed { "committed" } else { "pending" }, p.created_at.to_rfc3339(), alt_keys.join(",")
+						);
+					}
+				},
+				("stat", Some(m)) => {
+					let key = m.value_of("KEY").unwrap().parse::<NeedleKey>().unwrap();
+
+					let stat = c.stat_photo(key)?;
+
+					println!("Photo: {}", key);
+					println!("Volume: {}", stat.photo.volume_id.flip());
+					println!("Committed: {}", stat.photo.committed);
+					println!("Created: {}", stat.photo.created_at.to_rfc3339());
+
+					for k in stat.alt_keys {
+						println!("- Alt key {}: {} bytes, {}, uploaded {}", k.alt_key.flip(), k.size, k.content_type, k.created_at.to_rfc3339());
+					}
+				},
 				_ => return Err("Invalid subcommand".into())
 			};
 		},
@@ -0,0 +1,98 @@
+/*
+	Compares the memory usage and speed of the compact NeedleIndex used by stores against a plain HashMap
+
+	Run with: cargo run --release --example needle_index_bench -- [NUM_NEEDLES]
+	(defaults to 100 million needles which needs around 8GB of memory for the HashMap)
+*/
+
+extern crate haystack;
+
+use haystack::common::*;
+use haystack::store::needle::*;
+use haystack::store::needle_index::*;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::Instant;
+
+
+/// Every photo is typically uploaded in a few sizes, each getting its own alt key
+const ALT_KEYS_PER_PHOTO: u64 = 4;
+
+fn needle(i: u64) -> (NeedleKeys, NeedleIndexEntry) {
+	(
+		NeedleKeys { key: i / ALT_KEYS_PER_PHOTO, alt_key: (i % ALT_KEYS_PER_PHOTO) as NeedleAltKey },
+		NeedleIndexEntry {
+			meta: NeedleMeta { flags: 0, size: 1024 + (i % 4096) },
+			block_offset: (1 + i) as BlockOffset
+		}
+	)
+}
+
+fn seconds(start: Instant) -> f64 {
+	let d = start.elapsed();
+	(d.as_secs() as f64) + (d.subsec_nanos() as f64) / 1e9
+}
+
+fn report(name: &str, n: u64, insert_time: f64, lookup_time: f64, memory: usize) {
+	println!("{}", name);
+	println!("  insert: {:.2}s ({:.0} ns/needle)", insert_time, insert_time * 1e9 / (n as f64));
+	println!("  lookup: {:.2}s ({:.0} ns/needle)", lookup_time, lookup_time * 1e9 / (n as f64));
+	println!("  memory: {} MB ({:.1} bytes/needle)", memory / (1024*1024), (memory as f64) / (n as f64));
+}
+
+fn bench_needle_index(n: u64) {
+	let mut index = NeedleIndex::new();
+
+	let start = Instant::now();
+	for i in 0..n {
+		let (keys, entry) = needle(i);
+		index.insert(keys, entry).unwrap();
+	}
+	let insert_time = seconds(start);
+
+	let start = Instant::now();
+	let mut total = 0u64;
+	for i in 0..n {
+		let (keys, _) = needle(i);
+		total += index.get(&keys).unwrap().meta.size;
+	}
+	let lookup_time = seconds(start);
+	assert!(total > 0);
+
+	report("NeedleIndex", n, insert_time, lookup_time, index.memory_size());
+}
+
+fn bench_hashmap(n: u64) {
+	let mut index = HashMap::new();
+
+	let start = Instant::now();
+	for i in 0..n {
+		let (keys, entry) = needle(i);
+		index.insert(keys, entry);
+	}
+	let insert_time = seconds(start);
+
+	let start = Instant::now();
+	let mut total = 0u64;
+	for i in 0..n {
+		let (keys, _) = needle(i);
+		total += index[&keys].meta.size;
+	}
+	let lookup_time = seconds(start);
+	assert!(total > 0);
+
+	// The map stores a key, a value and an 8 byte hash for each bucket
+	let bucket_size = size_of::<NeedleKeys>() + size_of::<NeedleIndexEntry>() + size_of::<u64>();
+	report("HashMap", n, insert_time, lookup_time, index.capacity() * bucket_size);
+}
+
+fn main() {
+	let n = std::env::args().nth(1)
+		.map(|s| s.parse::<u64>().expect("Invalid number of needles"))
+		.unwrap_or(100_000_000);
+
+	println!("Indexing {} needles", n);
+
+	bench_needle_index(n);
+	bench_hashmap(n);
+}
@@ -0,0 +1,427 @@
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::store::needle::*;
+use super::memory::*;
+use core::DirLock;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use bytes::Bytes;
+use crc32c::crc32c_append;
+use hyper::http::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+
+
+const SEGMENT_PREFIX: &str = "segment_";
+
+/// Where a single entry is located in the segment files
+#[derive(Clone)]
+struct DiskEntry {
+	segment_id: u64,
+
+	/// Absolute offset of the start of the needle header in the segment file
+	offset: u64,
+
+	/// Total size of the needle (header, data and footer)
+	size: u64
+}
+
+struct DiskSegment {
+	id: u64,
+	file: File,
+
+	/// Current length of the file (all writes happen at this offset)
+	size: u64
+}
+
+/// A second tier of the cache stored on local disk (ideally an SSD) holding entries that were evicted from memory or were too large to be kept there
+///
+/// Entries are appended as needles (in the same format as in store volumes) to a rotating log of segment files. The data of each needle is the entry metadata followed by the actual photo
+/// Space is reclaimed by deleting the oldest segment along with everything in it, so this behaves like a FIFO cache rather than an LRU one (popular entries will generally be promoted back to memory before they are lost)
+pub struct DiskStore {
+	_lock: DirLock,
+
+	folder: PathBuf,
+
+	/// Maximum number of bytes that all of the segments may take up
+	pub total_space: u64,
+
+	/// Size of the largest entry that we will bother trying to cache
+	max_entry_size: usize,
+
+	/// Size after which we will start writing to a new segment
+	segment_size: u64,
+
+	max_age: Duration,
+
+	/// Number of bytes taken up by all segments (including overwritten entries that haven't been reclaimed yet)
+	pub used_space: u64,
+
+	/// All segments from oldest to newest (the last one is the one being appended to)
+	segments: VecDeque<DiskSegment>,
+
+	index: HashMap<NeedleKeys, DiskEntry>
+}
+
+impl DiskStore {
+
+	/// Opens the cache in the given folder, rebuilding the index from any segments left over from a previous run
+	pub fn open(
+		folder: &Path, total_space: u64, max_entry_size: usize, segment_size: u64, max_age: Duration
+	) -> Result<DiskStore> {
+
+		let lock = DirLock::open(folder)?;
+
+		let mut ids = vec![];
+		for ent in fs::read_dir(folder)? {
+			let name = ent?.file_name();
+			let name = match name.to_str() {
+				Some(s) => s,
+				None => continue
+			};
+
+			if name.starts_with(SEGMENT_PREFIX) {
+				if let Ok(id) = name[SEGMENT_PREFIX.len()..].parse::<u64>() {
+					ids.push(id);
+				}
+			}
+		}
+
+		ids.sort();
+
+		let mut store = DiskStore {
+			_lock: lock,
+			folder: folder.to_owned(),
+			total_space,
+			max_entry_size,
+			segment_size,
+			max_age,
+			used_space: 0,
+			segments: VecDeque::new(),
+			index: HashMap::new()
+		};
+
+		for id in ids {
+			let seg = store.recover_segment(id)?;
+			store.used_space += seg.size;
+			store.segments.push_back(seg);
+		}
+
+		if store.segments.len() == 0 {
+			store.add_segment()?;
+		}
+
+		store.collect()?;
+
+		Ok(store)
+	}
+
+	pub fn lookup(&mut self, keys: &NeedleKeys) -> Cached {
+
+		let e = match self.index.get(keys) {
+			Some(e) => e.clone(),
+			None => return Cached::None
+		};
+
+		let entry = match self.read_entry(keys, &e) {
+			Ok(v) => Arc::new(v),
+			Err(err) => {
+				// Most likely the segment is corrupt, so we'll just pretend that we never had it
+				eprintln!("Failed to read disk cache entry: {:?}", err);
+				self.index.remove(keys);
+				return Cached::None;
+			}
+		};
+
+		let now = SystemTime::now();
+
+		if now.duration_since(entry.inserted_at).unwrap_or(Duration::from_millis(0)).ge(&self.max_age) {
+			self.index.remove(keys);
+			return Cached::Stale(entry);
+		}
+
+		Cached::Valid(entry)
+	}
+
+	pub fn insert(&mut self, keys: NeedleKeys, entry: &MemoryEntry) -> Result<()> {
+
+		self.remove(&keys);
+
+		if entry.data.len() > self.max_entry_size {
+			eprintln!("Not caching entry on disk: too large ({} > {})", entry.data.len(), self.max_entry_size);
+			return Ok(());
+		}
+
+		let mut payload = vec![];
+		write_entry_meta(&mut Cursor::new(&mut payload), entry)?;
+		payload.extend_from_slice(&entry.data);
+
+		let mut buf = NeedleHeader::serialize(entry.cookie.data(), &keys, &NeedleMeta {
+			flags: 0,
+			size: payload.len() as NeedleSize
+		})?;
+
+		let sum = crc32c_append(0, &payload);
+		buf.extend_from_slice(&payload);
+		NeedleFooter::write(&mut buf, sum)?;
+
+		let need_segment = {
+			let seg = self.segments.back().unwrap();
+			seg.size > 0 && seg.size + (buf.len() as u64) > self.segment_size
+		};
+
+		if need_segment {
+			self.add_segment()?;
+		}
+
+		{
+			let seg = self.segments.back_mut().unwrap();
+
+			// NOTE: If this fails part way, then the tail of the segment will be garbage that is skipped when it is recovered
+			seg.file.write_all_at(&buf, seg.size)?;
+
+			self.index.insert(keys, DiskEntry {
+				segment_id: seg.id,
+				offset: seg.size,
+				size: buf.len() as u64
+			});
+
+			seg.size += buf.len() as u64;
+		}
+
+		self.used_space += buf.len() as u64;
+
+		self.collect()
+	}
+
+	/// Forgets about an entry (the space it takes up will be reclaimed once its segment is removed)
+	pub fn remove(&mut self, keys: &NeedleKeys) {
+		self.index.remove(keys);
+	}
+
+	pub fn len(&self) -> usize {
+		self.index.len()
+	}
+
+	fn segment_path(&self, id: u64) -> PathBuf {
+		self.folder.join(String::from(SEGMENT_PREFIX) + &id.to_string())
+	}
+
+	fn add_segment(&mut self) -> Result<()> {
+		let id = match self.segments.back() {
+			Some(s) => s.id + 1,
+			None => 1
+		};
+
+		let file = OpenOptions::new().read(true).write(true).create_new(true).open(self.segment_path(id))?;
+
+		self.segments.push_back(DiskSegment { id, file, size: 0 });
+		Ok(())
+	}
+
+	/// Deletes the oldest segments until we are under our space limit
+	fn collect(&mut self) -> Result<()> {
+
+		// NOTE: The segment currently being written to is never removed
+		while self.used_space > self.total_space && self.segments.len() > 1 {
+			let seg = self.segments.pop_front().unwrap();
+
+			self.index.retain(|_, e| e.segment_id != seg.id);
+			self.used_space -= seg.size;
+
+			let path = self.segment_path(seg.id);
+			drop(seg);
+			fs::remove_file(path)?;
+		}
+
+		Ok(())
+	}
+
+	/// Indexes all of the needles in a segment left over from a previous run
+	/// Anything after the last complete needle is assumed to be from an interrupted write and is truncated
+	fn recover_segment(&mut self, id: u64) -> Result<DiskSegment> {
+
+		let file = OpenOptions::new().read(true).write(true).open(self.segment_path(id))?;
+		let len = file.metadata()?.len();
+
+		let mut off = 0;
+		let mut header_buf = [0u8; NEEDLE_HEADER_SIZE];
+
+		while off + (NEEDLE_HEADER_SIZE as u64) <= len {
+			file.read_exact_at(&mut header_buf, off)?;
+
+			let header = match NeedleHeader::parse(&header_buf) {
+				Ok(h) => h,
+				Err(_) => break
+			};
+
+			let size = header.meta.total_size();
+			if off + size > len {
+				break;
+			}
+
+			self.index.insert(header.keys, DiskEntry {
+				segment_id: id,
+				offset: off,
+				size
+			});
+
+			off += size;
+		}
+
+		if off != len {
+			eprintln!("Truncating disk cache segment {} from {} to {} bytes", id, len, off);
+			file.set_len(off)?;
+		}
+
+		Ok(DiskSegment { id, file, size: off })
+	}
+
+	fn read_entry(&self, keys: &NeedleKeys, e: &DiskEntry) -> Result<MemoryEntry> {
+		let seg = match self.segments.iter().find(|s| s.id == e.segment_id) {
+			Some(s) => s,
+			None => return Err("Missing disk cache segment".into())
+		};
+
+		let mut buf = vec![0u8; e.size as usize];
+		seg.file.read_exact_at(&mut buf, e.offset)?;
+
+		let needle = Needle::from_bytes(Bytes::from(buf))?;
+		needle.check()?;
+
+		if needle.header.keys != *keys {
+			return Err("Disk cache entry has the wrong keys".into());
+		}
+
+		let cookie = needle.header.cookie.clone();
+		let payload = needle.data_bytes();
+
+		let mut c = Cursor::new(&payload[..]);
+		let (inserted_at, store_id, logical_id, headers) = read_entry_meta(&mut c)?;
+		let pos = c.position() as usize;
+
+		Ok(MemoryEntry {
+			inserted_at,
+			cookie,
+			store_id,
+			logical_id,
+			headers,
+			data: payload.slice_from(pos)
+		})
+	}
+
+}
+
+/// Writes everything about an entry aside from the cookie and data
+/// Format: [inserted_at millis][store_id][logical_id][num headers] followed by a [name length][name][value length][value] for each header
+fn write_entry_meta(writer: &mut Write, entry: &MemoryEntry) -> Result<()> {
+	let time = entry.inserted_at.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_millis(0));
+	let millis = time.as_secs() * 1000 + (time.subsec_millis() as u64);
+
+	writer.write_u64::<LittleEndian>(millis)?;
+	writer.write_u32::<LittleEndian>(entry.store_id)?;
+	writer.write_u32::<LittleEndian>(entry.logical_id)?;
+	writer.write_u16::<LittleEndian>(entry.headers.len() as u16)?;
+
+	for (name, value) in entry.headers.iter() {
+		writer.write_u16::<LittleEndian>(name.as_str().len() as u16)?;
+		writer.write_all(name.as_str().as_bytes())?;
+		writer.write_u16::<LittleEndian>(value.as_bytes().len() as u16)?;
+		writer.write_all(value.as_bytes())?;
+	}
+
+	Ok(())
+}
+
+fn read_entry_meta(reader: &mut Read) -> Result<(SystemTime, MachineId, VolumeId, HeaderMap)> {
+	let millis = reader.read_u64::<LittleEndian>()?;
+	let store_id = reader.read_u32::<LittleEndian>()?;
+	let logical_id = reader.read_u32::<LittleEndian>()?;
+	let num_headers = reader.read_u16::<LittleEndian>()?;
+
+	let mut headers = HeaderMap::new();
+
+	for _ in 0..num_headers {
+		let mut name = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+		reader.read_exact(&mut name)?;
+		let mut value = vec![0u8; reader.read_u16::<LittleEndian>()? as usize];
+		reader.read_exact(&mut value)?;
+
+		let name = HeaderName::from_bytes(&name).map_err(|_| Error::from("Invalid cached header name"))?;
+		let value = HeaderValue::from_bytes(&value).map_err(|_| Error::from("Invalid cached header value"))?;
+		headers.append(name, value);
+	}
+
+	Ok((UNIX_EPOCH + Duration::from_millis(millis), store_id, logical_id, headers))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+	use super::super::super::store::api::CookieBuf;
+
+	fn make_entry(data: &'static [u8]) -> MemoryEntry {
+		let mut headers = HeaderMap::new();
+		headers.insert("X-Haystack-Writeable", HeaderValue::from_static("1"));
+
+		MemoryEntry {
+			inserted_at: SystemTime::now(),
+			cookie: CookieBuf::random(),
+			store_id: 2,
+			logical_id: 3,
+			headers,
+			data: Bytes::from(data)
+		}
+	}
+
+	#[test]
+	fn disk_store_recovers_and_evicts() -> Result<()> {
+		let p = Path::new("out/testdiskcache");
+		if p.exists() {
+			fs::remove_dir_all(&p)?;
+		}
+		fs::create_dir_all(&p)?;
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 0 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 0 };
+
+		{
+			// Segments small enough that every entry goes into its own segment
+			let mut store = DiskStore::open(&p, 1024*1024, 1024, 16, Duration::from_secs(60))?;
+			store.insert(keys1.clone(), &make_entry(b"Hello world"))?;
+			store.insert(keys2.clone(), &make_entry(b"Goodbye world"))?;
+			assert_eq!(store.len(), 2);
+		}
+
+		{
+			let mut store = DiskStore::open(&p, 1024*1024, 1024, 16, Duration::from_secs(60))?;
+			assert_eq!(store.len(), 2);
+
+			match store.lookup(&keys1) {
+				Cached::Valid(e) => {
+					assert_eq!(&e.data[..], b"Hello world");
+					assert_eq!(e.logical_id, 3);
+					assert_eq!(e.headers.get("X-Haystack-Writeable").unwrap(), "1");
+				},
+				_ => panic!("Expected entry to be recovered")
+			};
+
+			// Shrinking the space forces the oldest segment to be removed
+			store.total_space = store.used_space - 1;
+			store.collect()?;
+
+			if let Cached::None = store.lookup(&keys1) {} else { panic!("Oldest entry should be evicted") }
+			if let Cached::Valid(_) = store.lookup(&keys2) {} else { panic!("Newest entry should remain") }
+		}
+
+		Ok(())
+	}
+
+}
@@ -3,6 +3,8 @@ use super::super::errors::*;
 use super::super::directory::*;
 use super::super::background_thread::*;
 use super::memory::*;
+use super::disk::*;
+use std::path::Path;
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
 
@@ -11,18 +13,23 @@ pub struct MachineContext {
 	pub id: MachineId,
 	pub inst: Mutex<CacheMachine>,
 	pub config: ConfigRef,
-	pub thread: BackgroundThread
+	pub thread: BackgroundThread,
+
+	/// Size of the largest entry that can be cached in any tier (larger responses are never buffered)
+	pub max_entry_size: usize
 }
 
 impl MachineContext {
 	pub fn from(machine: CacheMachine) -> MachineContext {
 		let config = machine.dir.config.clone();
+		let max_entry_size = machine.max_entry_size();
 
 		MachineContext {
 			id: 0,
 			inst: Mutex::new(machine),
 			config,
-			thread: BackgroundThread::new()
+			thread: BackgroundThread::new(),
+			max_entry_size
 		}
 	}
 }
@@ -36,7 +43,10 @@ pub struct CacheMachine {
 	pub id: MachineId,
 	pub dir: Directory,
 	pub port: u16,
-	pub memory: MemoryStore
+	pub memory: MemoryStore,
+
+	/// Second tier of the cache (only present if the cache was given a folder to store it in)
+	pub disk: Option<DiskStore>
 
 }
 
@@ -45,23 +55,88 @@ pub struct CacheMachine {
 
 impl CacheMachine {
 
-	pub fn load(dir: Directory, port: u16) -> Result<CacheMachine> {
-
-		let mac = dir.db.create_cache_machine("127.0.0.1", port)?;
+	pub fn load(dir: Directory, port: u16, folder: Option<&str>) -> Result<CacheMachine> {
 
 		let memory = MemoryStore::new(
 			dir.config.cache.memory_size, dir.config.cache.max_entry_size,
 			Duration::from_millis(dir.config.cache.max_age)
 		);
 
+		let disk = match folder {
+			Some(f) => Some(DiskStore::open(
+				Path::new(f), dir.config.cache.disk_size, dir.config.cache.disk_max_entry_size,
+				dir.config.cache.disk_segment_size, Duration::from_millis(dir.config.cache.max_age)
+			)?),
+			None => None
+		};
+
+		let mac = dir.db.create_cache_machine("127.0.0.1", port)?;
+
 		Ok(CacheMachine {
 			id: mac.id as MachineId,
 			dir,
 			port,
-			memory
+			memory,
+			disk
 		})
 	}
 
+	pub fn max_entry_size(&self) -> usize {
+		match self.disk {
+			Some(_) => std::cmp::max(self.dir.config.cache.max_entry_size, self.dir.config.cache.disk_max_entry_size),
+			None => self.dir.config.cache.max_entry_size
+		}
+	}
+
+	/// Looks up an entry first in memory and then on disk
+	/// Entries found on disk are moved back into memory as they are likely to be requested again soon
+	pub fn lookup(&mut self, keys: &NeedleKeys) -> Cached {
+
+		match self.memory.lookup(keys) {
+			Cached::None => {},
+			res => return res
+		};
+
+		let res = match self.disk {
+			Some(ref mut disk) => disk.lookup(keys),
+			None => return Cached::None
+		};
+
+		if let Cached::Valid(ref e) = res {
+			if self.memory.fits(e.data.len()) {
+				self.insert(keys.clone(), e.clone());
+			}
+		}
+
+		res
+	}
+
+	/// Caches an entry in memory if it is small enough or otherwise on disk
+	/// Anything evicted from memory to make room for it is moved to disk
+	/// TODO: The disk writes happen while the whole machine is locked
+	pub fn insert(&mut self, keys: NeedleKeys, entry: Arc<MemoryEntry>) {
+
+		let to_disk = if self.memory.fits(entry.data.len()) {
+			if let Some(ref mut disk) = self.disk {
+				disk.remove(&keys);
+			}
+
+			self.memory.insert(keys, entry)
+		}
+		else {
+			self.memory.remove(&keys);
+			vec![(keys, entry)]
+		};
+
+		if let Some(ref mut disk) = self.disk {
+			for (k, e) in to_disk {
+				if let Err(err) = disk.insert(k, &e) {
+					eprintln!("Failed to write to the disk cache: {:?}", err);
+				}
+			}
+		}
+	}
+
 
 	pub fn start(mac_handle_in: &MachineHandle) {
 
@@ -92,9 +167,13 @@ impl CacheMachine {
 		self.dir.db.update_cache_machine_heartbeat(
 			self.id,
 			ready,
+			std::cmp::max(self.dir.config.cache.weight, 1),
 			"127.0.0.1", self.port
 		)?;
 
+		// Keep our copy of the cluster fresh so that requests rarely need to go to the directory
+		self.dir.refresh_cluster()?;
+
 		Ok(())
 	}
 
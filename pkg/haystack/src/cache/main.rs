@@ -20,9 +20,11 @@ fn on_stop(mac_handle: &MachineHandle) {
 }
 
 
-pub fn run(dir: Directory, port: u16) -> Result<()> {
+/// Runs a cache machine
+/// If a folder is given, entries that don't fit in memory will also be cached on disk in that folder
+pub fn run(dir: Directory, port: u16, folder: Option<&str>) -> Result<()> {
 	// TODO: Whenever possible, re-use the ids of previously existing but now dead machines
-	let machine = CacheMachine::load(dir, port)?;
+	let machine = CacheMachine::load(dir, port, folder)?;
 	let mac_ctx = MachineContext::from(machine);
 
 	let mac_handle = Arc::new(mac_ctx);
@@ -126,7 +126,8 @@ impl MemoryStore {
 		}
 	}
 
-	pub fn insert(&mut self, keys: NeedleKeys, entry: Arc<MemoryEntry>) {
+	/// Inserts a new entry, returning all of the older entries that had to be evicted to make room for it
+	pub fn insert(&mut self, keys: NeedleKeys, entry: Arc<MemoryEntry>) -> Vec<(NeedleKeys, Arc<MemoryEntry>)> {
 
 		// Delete any old one
 		self.remove(&keys);
@@ -134,14 +135,14 @@ impl MemoryStore {
 		// Don't try inserting entries that are too large
 		if entry.data.len() > self.max_entry_size {
 			eprintln!("Not caching entry: too large ({} > {})", entry.data.len(), self.max_entry_size);
-			return;
+			return vec![];
 		}
 
 		// Allocate space for it
 		self.used_space += entry.data.len();
 
 		// Make sure we have enough space for it
-		self.collect();
+		let evicted = self.collect();
 		
 		let now = entry.inserted_at.clone();
 
@@ -152,6 +153,8 @@ impl MemoryStore {
 		});
 
 		self.order.insert(now, keys);
+
+		evicted
 	}
 
 	/// Explicit removal of an entry (usually if we the cache is the one that performed the deletion)
@@ -165,9 +168,14 @@ impl MemoryStore {
 		self.index.len()
 	}
 
-	fn collect(&mut self) {
+	/// Whether or not an entry of the given size would be accepted by insert()
+	pub fn fits(&self, size: usize) -> bool {
+		size <= self.max_entry_size
+	}
+
+	fn collect(&mut self) -> Vec<(NeedleKeys, Arc<MemoryEntry>)> {
 		
-		let mut nremoved = 0;
+		let mut evicted = vec![];
 
 		loop {
 			// Get the first item with lowest time
@@ -191,7 +199,7 @@ impl MemoryStore {
 			// NOTE: We will keep stale entries under the assumption that are likely immutable and that on the next wrap of it, it will become 
 			else if /* self.is_stale(&e, &now) || */ self.need_space() {
 				self.delete(&keys, &e);
-				nremoved += 1;
+				evicted.push((keys, e.value));
 			}
 			else {
 				break;
@@ -200,7 +208,9 @@ impl MemoryStore {
 
 		// TODO: Validate that we definately have enough space now
 
-		eprintln!("Removed {} cache keys", nremoved);
+		eprintln!("Removed {} cache keys", evicted.len());
+
+		evicted
 	}
 
 	fn is_stale(&self, e: &MemoryEntryInternal, now: &SystemTime) -> bool {
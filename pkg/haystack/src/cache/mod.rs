@@ -1,5 +1,6 @@
 pub mod api;
 mod memory;
+mod disk;
 mod machine;
 mod routes;
 pub mod main;
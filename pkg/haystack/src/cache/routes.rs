@@ -1,5 +1,6 @@
 use super::api::*;
 use super::super::store::api::*;
+use super::super::store::range::*;
 use super::super::common::*;
 use super::super::http::*;
 use super::super::errors::*;
@@ -16,8 +17,11 @@ use futures::prelude::await;
 use std::time::{SystemTime, Duration};
 use rand::thread_rng;
 use rand::seq::SliceRandom;
-use hyper::header::HeaderMap;
+use hyper::header::{HeaderMap, HeaderValue};
+use futures::future::join_all;
 use bytes::Bytes;
+use byteorder::{LittleEndian, WriteBytesExt};
+use crc32c::crc32c_append;
 
 
 #[async]
@@ -57,6 +61,17 @@ pub fn handle_request(
 pub struct CacheIndexResponse {
 	pub used_space: usize,
 	pub total_space: usize,
+	pub num_entries: usize,
+	pub disk: Option<CacheDiskIndexResponse>,
+
+	/// Parts of the photo hash space for which this cache is the first choice
+	pub ring_ranges: Vec<directory::RingRange>
+}
+
+#[derive(Serialize)]
+pub struct CacheDiskIndexResponse {
+	pub used_space: u64,
+	pub total_space: u64,
 	pub num_entries: usize
 }
 
@@ -64,11 +79,18 @@ pub struct CacheIndexResponse {
 fn index_cache(mac_handle: MachineHandle) -> Result<Response<Body>> {
 	let mac = mac_handle.inst.lock().unwrap();
 
-	// TODO: Would also key good to hashed key-range of this cache
+	let ring_ranges = mac.dir.cluster()?.cache_ring.ranges_for(mac.id);
+
 	Ok(json_response(StatusCode::OK, &CacheIndexResponse {
 		used_space: mac.memory.used_space,
 		total_space: mac.memory.total_space,
-		num_entries: mac.memory.len()
+		num_entries: mac.memory.len(),
+		disk: mac.disk.as_ref().map(|d| CacheDiskIndexResponse {
+			used_space: d.used_space,
+			total_space: d.total_space,
+			num_entries: d.len()
+		}),
+		ring_ranges
 	}))
 }
 
@@ -95,35 +117,6 @@ fn handle_proxy_request(
 
 	let mut store_str = store.to_string();
 
-	// Will get the list of store machine addresses that for for this
-	let get_backend_stores = move |mac: &CacheMachine, volume_id: VolumeId| -> Result<Vec<directory::models::StoreMachine>> {
-		// TODO: Limit the maximum number of 
-
-		let macs = match machine_ids {
-			MachineIds::Data(arr) => {
-				if arr.len() > MAX_MACHINE_LIST_SIZE {
-					vec![]
-				}
-				else {
-					mac.dir.db.read_store_machines(&arr)?
-				}
-			},
-			MachineIds::Unspecified => {
-				mac.dir.db.read_store_machines_for_volume(volume_id)?
-			}
-		};
-
-		let mut arr = macs.into_iter().filter(|m| {
-			m.can_read(&mac.dir.config)
-		}).collect::<Vec<_>>();
-
-		// Randomly choose any of the backends
-		let mut rng = thread_rng();
-		arr.shuffle(&mut rng);
-
-		Ok(arr)
-	};
-
 	match store {
 
 		StorePath::Needle { volume_id, key, alt_key, cookie } => {
@@ -140,7 +133,7 @@ fn handle_proxy_request(
 					
 					let mut mac = mac_handle.inst.lock().unwrap();
 
-					let res = mac.memory.lookup(&keys);
+					let res = mac.lookup(&keys);
 
 					if let Cached::Valid(ref e) = res {
 						if e.logical_id == volume_id {
@@ -150,14 +143,14 @@ fn handle_proxy_request(
 
 					// TODO: Would be great to be able to do this without a lock on mac
 					// This will currently bottle-neck our read-performance as we must hold the lock for this entire time
-					store_macs = get_backend_stores(&mac, volume_id)?;
+					store_macs = get_readable_stores(&mac, &machine_ids, volume_id)?;
 
 					old_entry = if let Cached::Stale(e) = res {
 
 						// A malicious client could send bad cookies and evict entries that are stale from the cache prematurely (because we would end up requesting it with the store via the wrong cookie)
 						// ^ Because we know that the cookie is immutable, we can verify the cookie right here and immediately put the entry back into the cache like nothing ever happened
 						if e.cookie.data() != cookie.data() {
-							mac.memory.insert(keys, e);
+							mac.insert(keys, e);
 							return Ok(bad_request_because("Invalid cookie on stale entry"));
 						}
 
@@ -180,9 +173,12 @@ fn handle_proxy_request(
 						volume_id, key, alt_key, cookie
 					))
 				},
+				// Uploading a new needle to one or more store machines
 				Method::POST => {
-					// TODO: Performing a proxied upload to one or more store machines
-					Ok(bad_request_because("Not implemented"))
+					await!(upload_to_backends(
+						body, mac_handle, machine_ids,
+						volume_id, key, alt_key, cookie
+					))
 				},
 				_ => Ok(bad_request_because("Invalid method"))
 			}
@@ -192,6 +188,165 @@ fn handle_proxy_request(
 }
 
 
+/// Looks up all of the store machines that a proxied request may be forwarded to
+fn lookup_store_machines(
+	mac: &CacheMachine, machine_ids: &MachineIds, volume_id: VolumeId
+) -> Result<Vec<directory::models::StoreMachine>> {
+	Ok(match machine_ids {
+		MachineIds::Data(arr) => {
+			if arr.len() > MAX_MACHINE_LIST_SIZE {
+				vec![]
+			}
+			else {
+				mac.dir.cluster()?.store_machines_with_ids(&arr)
+			}
+		},
+		MachineIds::Unspecified => {
+			mac.dir.read_store_machines_for_volume(volume_id)?
+		}
+	})
+}
+
+/// Will get the list of store machines that we can read this volume from (in a random order)
+fn get_readable_stores(
+	mac: &CacheMachine, machine_ids: &MachineIds, volume_id: VolumeId
+) -> Result<Vec<directory::models::StoreMachine>> {
+	let mut arr = lookup_store_machines(mac, machine_ids, volume_id)?.into_iter().filter(|m| {
+		m.can_read(&mac.dir.config)
+	}).collect::<Vec<_>>();
+
+	// Randomly choose any of the backends (but try the ones in our region first)
+	let mut rng = thread_rng();
+	arr.shuffle(&mut rng);
+	directory::placement::prefer_region(&mut arr, &mac.dir.config.region);
+
+	Ok(arr)
+}
+
+/// Uploads a single needle to every one of the requested stores in parallel
+///
+/// The needle is only put into the cache once every store has acknowledged the write, so a failed upload will never be readable from the cache
+#[async]
+fn upload_to_backends(
+	body: Body, mac_handle: MachineHandle, machine_ids: MachineIds,
+	volume_id: VolumeId, key: NeedleKey, alt_key: NeedleAltKey, cookie: CookieBuf
+) -> Result<Response<Body>> {
+
+	let content_length = match body.content_length() {
+		Some(0) | None => {
+			return Ok(text_response(StatusCode::LENGTH_REQUIRED, "Missing Content-Length"));
+		},
+		Some(n) => n as usize
+	};
+
+	let store_macs = {
+		let mac = mac_handle.inst.lock().unwrap();
+		lookup_store_machines(&mac, &machine_ids, volume_id)?
+	};
+
+	// Every explicitly requested machine must exist
+	if let MachineIds::Data(ref arr) = machine_ids {
+		if store_macs.len() != arr.len() {
+			return Ok(bad_request_because("Invalid list of store machines"));
+		}
+	}
+
+	if store_macs.len() == 0 {
+		return Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "No backend stores to upload to"));
+	}
+
+	// Failing early is better than leaving a photo on only some of its replicas
+	for m in store_macs.iter() {
+		if !m.can_write(&mac_handle.config) {
+			return Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "Some backend stores are not writeable"));
+		}
+	}
+
+	// NOTE: We must buffer the whole body anyway to be able to send it to more than one store
+	let mut buf = Bytes::with_capacity(content_length);
+
+	#[async]
+	for c in body {
+		if buf.len() + c.len() > content_length {
+			return Ok(text_response(StatusCode::BAD_REQUEST, "Request payload bad length"));
+		}
+
+		buf.extend_from_slice(&c);
+	}
+
+	if buf.len() != content_length {
+		return Ok(text_response(StatusCode::BAD_REQUEST, "Request payload bad length"));
+	}
+
+	let store_path = (StorePath::Needle { volume_id, key, alt_key, cookie: cookie.clone() }).to_string();
+
+	let client = hyper::Client::new();
+
+	let uploads = store_macs.iter().map(|store_mac| {
+		let route = format!("{}{}", store_mac.addr(), store_path);
+
+		let req = Request::builder()
+			.method(Method::POST)
+			.uri(&route)
+			.header("Host", Host::Store(store_mac.id as MachineId).to_string())
+			.body(Body::from(buf.clone()))
+			.unwrap();
+
+		client.request(req).then(move |r| -> Result<bool> {
+			match r {
+				Ok(res) => {
+					if !res.status().is_success() {
+						eprintln!("Upload to {} failed with status {}", route, res.status());
+					}
+
+					Ok(res.status().is_success())
+				},
+				Err(e) => {
+					eprintln!("Upload to {} failed with {:?}", route, e);
+					Ok(false)
+				}
+			}
+		})
+	}).collect::<Vec<_>>();
+
+	let acks = await!(join_all(uploads))?;
+
+	if acks.iter().any(|acked| !acked) {
+		return Ok(text_response(StatusCode::BAD_GATEWAY, "Not all backend stores acknowledged the upload"));
+	}
+
+	if buf.len() <= mac_handle.max_entry_size {
+		// Reconstructing the headers that the store would have given us on a read
+		// NOTE: We don't know where the needle was placed in each volume, so there is no ETag and the first revalidation of this entry will need to do a full read from the store
+		let mut headers = HeaderMap::new();
+
+		let mut sum = vec![];
+		sum.write_u32::<LittleEndian>(crc32c_append(0, &buf)).unwrap();
+
+		let content_type = sniff_content_type(&buf);
+
+		headers.insert("X-Haystack-Cookie", HeaderValue::from_str(&cookie.to_string()).unwrap());
+		headers.insert("X-Haystack-Hash", HeaderValue::from_str(&(String::from("crc32c=") + &serialize_urlbase64(&sum))).unwrap());
+		headers.insert("X-Haystack-Writeable", HeaderValue::from_static("1"));
+		headers.insert("Content-Type", HeaderValue::from_str(&content_type).unwrap());
+
+		let entry = Arc::new(MemoryEntry {
+			inserted_at: SystemTime::now(),
+			store_id: store_macs[0].id as MachineId,
+			logical_id: volume_id,
+			cookie,
+			headers,
+			data: buf
+		});
+
+		let mut mac = mac_handle.inst.lock().unwrap();
+		mac.insert(NeedleKeys { key, alt_key }, entry);
+	}
+
+	Ok(text_response(StatusCode::OK, "Needle added!"))
+}
+
+
 #[async]
 fn respond_from_backend(
 	parts: Parts, mac_handle: MachineHandle, store_macs: Vec<directory::models::StoreMachine>, store_path: String, old_entry: Option<Arc<MemoryEntry>>,
@@ -212,6 +367,9 @@ fn respond_from_backend(
 
 		let probably_should_cache = !from_cdn && store_mac.can_write(&mac_handle.config);
 
+		// Whether or not the store will see the client's Range header
+		let forwarded_ranges = old_entry.is_none() && !probably_should_cache;
+
 		let mut req = Request::builder();
 		
 		req.uri(&route);
@@ -233,6 +391,9 @@ fn respond_from_backend(
 			if let Some(v) = parts.headers.get("If-None-Match") {
 				req.header("If-None-Match", v);
 			}
+
+			// Likewise, the store can read just the needed ranges for us
+			forward_range_headers(&parts, &mut req);
 		}
 
 		let res = match await!(client.request(req.body(Body::empty()).unwrap())) {
@@ -255,11 +416,20 @@ fn respond_from_backend(
 			for (name, value) in res.headers().iter() {
 				let norm = name.to_string().to_lowercase();
 
-				if norm.starts_with("x-haystack-") || &norm == "etag" {
+				if norm.starts_with("x-haystack-") || &norm == "etag" || &norm == "content-type" {
 					headers.insert(name, value.clone());
 				} 
 			}
 
+			let is_writeable = if let Some(v) = headers.get("X-Haystack-Writeable") {
+				if v.to_str().unwrap_or("0") == "1" {
+					true
+				}
+				else { false }
+			} else { false };
+
+			let should_cache = !from_cdn && is_writeable;
+
 			let mut buf: Bytes;
 
 			// Regular case, get out the body
@@ -269,6 +439,33 @@ fn respond_from_backend(
 				).to_str().unwrap_or("0").parse::<usize>().unwrap_or(0);
 				// TODO: body.content_length() seems to be private?
 
+				// If it would never end up in the cache, then there is no point in buffering it, so we stream the store's response straight back to the client
+				if !should_cache || content_length > mac_handle.max_entry_size {
+					if forwarded_ranges || !parts.headers.contains_key("Range") {
+						return respond_with_passthrough(parts, cookie, headers, content_length, res.into_body());
+					}
+
+					// We expected to cache the whole needle, but now that we won't, the store should only send the ranges that the client asked for (this is typically the case for large videos)
+					// Dropping the full response closes its connection without reading the rest of the needle
+					drop(res);
+
+					let range_path = (StorePath::Needle { volume_id, key, alt_key, cookie: cookie.clone() }).to_string();
+
+					let mut req = Request::builder();
+					req.uri(format!("{}{}", store_mac.addr(), range_path));
+					req.header("Host", Host::Store(store_mac.id as MachineId).to_string());
+					forward_range_headers(&parts, &mut req);
+
+					// The full path includes the cookie, so the store checks it for us
+					match await!(client.request(req.body(Body::empty()).unwrap())) {
+						Ok(r) => return Ok(relay_store_response(r)),
+						Err(e) => {
+							eprintln!("Backend failed with {:?}", e);
+							continue;
+						}
+					};
+				}
+
 				let body = res.into_body();
 
 				buf = Bytes::with_capacity(content_length as usize);
@@ -317,23 +514,16 @@ fn respond_from_backend(
 
 			let mut mac = mac_handle.inst.lock().unwrap();
 
-
-			let is_writeable = if let Some(v) = entry.headers.get("X-Haystack-Writeable") {
-				if v.to_str().unwrap_or("0") == "1" {
-					true
-				}
-				else { false }
-			} else { false };
-
-			let should_cache = !from_cdn && is_writeable;
-
-			// TODO: In the case of not-caching or the first response, we should be able to just stream back the body before we give the whole thing
 			if should_cache {
-				mac.memory.insert(NeedleKeys { key, alt_key }, entry.clone());
+				mac.insert(NeedleKeys { key, alt_key }, entry.clone());
 			}
 
 			return respond_with_memory_entry(parts, cookie, entry, should_cache);
 		}
+		else if res.status() == StatusCode::PARTIAL_CONTENT || res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+			// Only occurs if we forwarded the client's Range header, so the store has already checked the cookie
+			return Ok(relay_store_response(res));
+		}
 		else {
 			// Otherwise passthrough the successful error response
 			// TODO: Headers as well
@@ -347,6 +537,69 @@ fn respond_from_backend(
 
 }
 
+/// Copies the client's range headers onto a request to a store
+fn forward_range_headers(parts: &Parts, req: &mut hyper::http::request::Builder) {
+	for h in &["Range", "If-Range"] {
+		if let Some(v) = parts.headers.get(*h) {
+			req.header(*h, v);
+		}
+	}
+}
+
+/// Sends back a response from a store to the client exactly as it was received
+fn relay_store_response(res: Response<Body>) -> Response<Body> {
+	let (res_parts, res_body) = res.into_parts();
+
+	let mut res = Response::builder();
+	res.status(res_parts.status);
+	for (h, v) in res_parts.headers.iter() {
+		res.header(h, v.clone());
+	}
+
+	res.body(res_body).unwrap()
+}
+
+/// Forwards a successful response from a store to the client without waiting for the whole body
+fn respond_with_passthrough(
+	parts: Parts, given_cookie: CookieBuf, headers: HeaderMap, content_length: usize, body: Body
+) -> Result<Response<Body>> {
+
+	// The store will not have checked the cookie if we revalidated a stale entry using the privileged path
+	let cookie_matches = match headers.get("X-Haystack-Cookie") {
+		Some(v) => v.to_str().unwrap_or("") == given_cookie.to_string(),
+		None => false
+	};
+
+	if !cookie_matches {
+		return Ok(text_response(StatusCode::FORBIDDEN, "Incorrect cookie"));
+	}
+
+	let mut res = Response::builder();
+
+	for (name, value) in headers.iter() {
+		res.header(name, value.clone());
+	}
+
+	if let Some(v) = parts.headers.get("If-None-Match") {
+		if let Some(v2) = headers.get("ETag") {
+			if let Ok(e) = ETag::from_header(v) {
+				if let Ok(e2) = ETag::from_header(v2) {
+					if e.matches(&e2) {
+						return Ok(res.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
+					}
+				}
+			}
+		}
+	}
+
+	Ok(
+		res
+		.status(StatusCode::OK)
+		.header("Content-Length", content_length.to_string())
+		.body(body).unwrap()
+	)
+}
+
 fn respond_with_memory_entry(
 	parts: Parts, given_cookie: CookieBuf, entry: Arc<MemoryEntry>, will_cache: bool
 ) -> Result<Response<Body>> {
@@ -357,16 +610,21 @@ fn respond_with_memory_entry(
 	}
 
 
-	// TODO: Implement Range, Expires headers (where the expires would be reflective of the internal cache state)
+	// TODO: Implement Expires headers (where the expires would be reflective of the internal cache state)
 
 	let mut res = Response::builder();
 
 	res.status(StatusCode::OK); 
 
+	// The Content-Type is set below as it changes for multi-range responses
 	for (name, value) in entry.headers.iter() {
-		res.header(name, value.clone());
+		if name.as_str() != "content-type" {
+			res.header(name, value.clone());
+		}
 	}
 
+	res.header("Accept-Ranges", "bytes");
+
 	// The Age header will only be on requests that we will actually store in memory
 	if will_cache {
 		let age = SystemTime::now().duration_since(entry.inserted_at)
@@ -388,11 +646,53 @@ fn respond_with_memory_entry(
 		}
 	}
 
+	let content_type = entry.headers.get("Content-Type")
+		.and_then(|v| v.to_str().ok())
+		.unwrap_or("application/octet-stream")
+		.to_owned();
+
+	let total = entry.data.len() as u64;
+
+	let ranges = match entry.headers.get("ETag").map(|v| ETag::from_header(v)) {
+		Some(Ok(etag)) => RangeRequest::from_headers(&parts.headers, total, &etag),
+		_ => RangeRequest::Full
+	};
+
+	match ranges {
+		RangeRequest::Full => {},
+		RangeRequest::Unsatisfiable => return Ok(unsatisfiable_response(total)),
+		RangeRequest::Partial(ranges) => {
+			res.status(StatusCode::PARTIAL_CONTENT);
+
+			// NOTE: Slicing the Bytes does not copy the underlying data
+			if ranges.len() == 1 {
+				let r = &ranges[0];
+				return Ok(
+					res
+					.header("Content-Type", content_type)
+					.header("Content-Range", r.content_range(total))
+					.body(Body::from(entry.data.slice(r.start as usize, r.end as usize))).unwrap()
+				);
+			}
+
+			let (ctype, body) = multipart_body(&ranges, total, &content_type, |r| {
+				Ok(entry.data.slice(r.start as usize, r.end as usize))
+			})?;
+
+			return Ok(
+				res
+				.header("Content-Type", ctype)
+				.body(Body::from(body)).unwrap()
+			);
+		}
+	};
+
 	// TODO: Ensure this is zero copy
 	// We should probably be passing this out
 	Ok(
 		res
 		.status(StatusCode::OK)
+		.header("Content-Type", content_type)
 		.body(Body::from(entry.data.clone())).unwrap()
 	)
 }
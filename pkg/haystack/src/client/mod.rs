@@ -9,6 +9,7 @@ use super::errors::*;
 use super::common::*;
 use super::directory::*;
 use super::paths::*;
+use super::http::sniff_content_type;
 use super::store::api::*;
 use super::cache::api::*;
 use core::FlipSign;
@@ -21,6 +22,9 @@ use std::io::Cursor;
 use futures::prelude::*;
 use futures::prelude::await;
 use futures::Stream;
+use tokio::timer::Delay;
+use std::time::{Duration, Instant};
+use chrono::Utc;
 
 pub struct Client {
 	dir: Arc<Mutex<Directory>>
@@ -32,6 +36,12 @@ pub struct PhotoChunk {
 	pub data: Bytes
 }
 
+/// Everything the directory knows about a single photo
+pub struct PhotoStat {
+	pub photo: models::Photo,
+	pub alt_keys: Vec<models::PhotoAltKey>
+}
+
 impl Client {
 
 	pub fn create(dir: Directory) -> Client {
@@ -51,11 +61,11 @@ impl Client {
 		let dir = self.dir.lock().unwrap();
 
 		let photo = match dir.db.read_photo(keys.key)? {
-			Some(p) => p,
-			None => return Err("No such photo".into())
+			Some(ref p) if p.committed => p.clone(),
+			_ => return Err("No such photo".into())
 		};
 
-		let vol = match dir.db.read_logical_volume(photo.volume_id.flip())? {
+		let vol = match dir.read_logical_volume(photo.volume_id.flip())? {
 			Some(v) => v,
 			None => return Err("Missing the volume".into())
 		};
@@ -80,87 +90,137 @@ impl Client {
 
 
 
+	/// Lists every photo in a logical volume (including ones that are still being uploaded)
+	pub fn list_photos(&self, volume_id: VolumeId) -> Result<Vec<models::Photo>> {
+		let dir = self.dir.lock().unwrap();
+		dir.db.index_photos_for_volume(volume_id)
+	}
+
+	/// Looks up a photo along with the details of each of its alt keys
+	pub fn stat_photo(&self, key: NeedleKey) -> Result<PhotoStat> {
+		let dir = self.dir.lock().unwrap();
+
+		let photo = match dir.db.read_photo(key)? {
+			Some(p) => p,
+			None => return Err("No such photo".into())
+		};
+
+		let alt_keys = dir.db.read_photo_alt_keys(key)?;
+
+		Ok(PhotoStat { photo, alt_keys })
+	}
+
 	/// Creates a new photo containing all of the given chunks
-	/// TODO: On writeability errors, relocate the photo to a new volume that doesn't have the given machines
+	///
+	/// The photo is first created in the directory as uncommitted and is only committed (made readable) once every chunk has been uploaded to every replica. If the upload fails part way, the uncommitted photo will eventually be cleaned up by pitchfork
+	///
+	/// Uploads to each replica are retried a few times with backoff. If some replicas still fail, the photo is moved to another volume that isn't on any of the failed machines and the upload is started over
 	pub fn upload_photo(&self, chunks: Vec<PhotoChunk>) -> impl Future<Item=NeedleKey, Error=Error> {
 		assert!(chunks.len() > 0);
 
-		let dir_handle = self.dir.clone();
+		upload_photo_impl(self.dir.clone(), chunks)
+	}
 
-		fn prepare(dir: &Directory, chunks: Vec<PhotoChunk>) -> Result<(Vec<NeedleChunk>, Vec<models::StoreMachine>)> {
-			
-			let cookie = CookieBuf::random();
+	/// Uploads new alt keys for an existing photo (or replaces existing ones) without needing to re-upload any of the other alt keys
+	///
+	/// If the photo's current volume can't take the new alt keys, then the photo is moved to a new volume along with a copy of all of its other alt keys. The photo keeps being read from the old volume until the move is complete
+	pub fn update_photo(&self, key: NeedleKey, chunks: Vec<PhotoChunk>) -> impl Future<Item=(), Error=Error> {
+		assert!(chunks.len() > 0);
 
-			let vol = dir.choose_logical_volume_for_write()?;
+		update_photo_impl(self.dir.clone(), key, chunks)
+	}
 
-			let p = dir.db.create_photo(&models::NewPhoto {
-				volume_id: vol.id,
-				cookie: cookie.data()
-			})?;
+	/// Deletes a photo along with all of its alt_keys
+	///
+	/// The photo is removed from the directory first such that it immediately stops being readable. If some stores fail to delete their needles, then those needles are left dangling as compaction only reclaims needles that are marked as deleted (they can still be found through the volume list route)
+	pub fn delete_photo(&self, key: NeedleKey) -> impl Future<Item=(), Error=Error> {
 
-			let machines = dir.db.read_store_machines_for_volume(p.volume_id.flip())?;
+		let dir_handle = self.dir.clone();
 
-			if machines.len() == 0 {
-				return Err("Missing any machines to upload to".into());
-			}
+		lazy(move || {
+			let dir = dir_handle.lock().unwrap();
 
-			for m in machines.iter() {
-				if !m.can_write(&dir.config) {
-					return Err("Some machines are not writeable".into());
-				}
-			}
+			let prepare = || -> Result<(VolumeId, Vec<models::StoreMachine>)> {
+				let photo = match dir.db.read_photo(key)? {
+					Some(p) => p,
+					None => return Err("No such photo".into())
+				};
 
-			let needles = chunks.into_iter().map(|c| {
-				NeedleChunk {
-					path: NeedleChunkPath {
-						volume_id: p.volume_id.flip(),
-						key: p.id.flip(),
-						alt_key: c.alt_key,
-						cookie: cookie.clone()
-					},
-					data: c.data.clone()
-				}
-			}).collect::<Vec<_>>();
+				let machines = dir.read_store_machines_for_volume(photo.volume_id.flip())?;
 
-			Ok((needles, machines))
-		};
+				dir.db.delete_photo(&photo)?;
 
-		lazy(move || {
-			let dir = dir_handle.lock().unwrap();
+				Ok((photo.volume_id.flip(), machines))
+			};
 
-			match prepare(&dir, chunks) {
+			match prepare() {
 				Ok(v) => ok(v),
 				Err(e) => err(e)
 			}
 		})
-		.and_then(|(needles, machines)| {
+		.and_then(move |(volume_id, machines)| {
+			let arr = machines.into_iter().map(move |m| {
+				Client::delete_needles(&m, volume_id, key)
+			}).collect::<Vec<_>>();
 
-			// TODO: On failure of a request, retry the request once
-			// TODO: On failure of the retried request, bail out and choose a new volume to contain our photo (basically rerunning most of this upload_photo function)
+			join_all(arr).map(|_| ())
+		})
+	}
 
-			let num = needles.len();
+	/// Deletes all needles for a single photo key from a single store machine
+	pub fn delete_needles(mac: &models::StoreMachine, volume_id: VolumeId, key: NeedleKey)
+		-> impl Future<Item=(), Error=Error> {
 
-			let photo_id = needles[0].path.key;
+		let url = format!(
+			"{}{}",
+			mac.addr(),
+			StorePath::Photo { volume_id, key }.to_string()
+		);
 
-			let arr = machines.into_iter().map(move |m| {
-				let needles = (&needles[..]).to_vec();
-				let m = Arc::new(m);
+		let client = hyper::Client::new();
+		let req = hyper::Request::builder()
+			.uri(url)
+			.method("DELETE")
+			.header("Host", Host::Store(mac.id as MachineId).to_string())
+			.body(hyper::Body::empty())
+			.unwrap();
 
-				//Client::upload_needle_sequential(&m, needles)
-				Client::upload_needle_batch(&m, &needles)
-				.and_then(move |n| {
-					if num != n {
-						return err("Not all chunks uploaded".into());
-					}
+		client.request(req)
+		.map_err(|e| e.into())
+		.and_then(|resp| {
+			// Not finding the photo is fine as it may have never been fully uploaded
+			if !resp.status().is_success() && resp.status() != hyper::StatusCode::NOT_FOUND {
+				return err(format!("Delete failed with code: {}", resp.status()).into());
+			}
 
-					ok(())
-				})
+			ok(())
+		})
+	}
 
-			}).collect::<Vec<_>>();
+	/// Reads the full data of a single needle from a single store machine
+	fn read_needle(mac: &models::StoreMachine, path: &StorePath)
+		-> impl Future<Item=Bytes, Error=Error> {
 
-			join_all(arr).map(move |_| {
-				photo_id
-			})
+		let req = hyper::Request::builder()
+			.uri(format!("{}{}", mac.addr(), path.to_string()))
+			.method("GET")
+			.header("Host", Host::Store(mac.id as MachineId).to_string())
+			.body(hyper::Body::empty())
+			.unwrap();
+
+		hyper::Client::new().request(req)
+		.map_err(|e| e.into())
+		.and_then(|resp| {
+			if resp.status() != hyper::StatusCode::OK {
+				return Either::A(err(format!("Read failed with code: {}", resp.status()).into()));
+			}
+
+			// NOTE: The store aborts the response if the needle doesn't match its checksum
+			Either::B(
+				resp.into_body().concat2()
+				.map_err(|e| e.into())
+				.map(|body| body.into_bytes())
+			)
 		})
 	}
 
@@ -283,3 +343,303 @@ impl Client {
 
 }
 
+
+/// Number of times an upload to a single store will be attempted before giving up on that store
+const UPLOAD_ATTEMPTS: usize = 3;
+
+/// Time in milliseconds to wait before the first retry of an upload to a store (doubled after every retry)
+const UPLOAD_RETRY_DELAY: u64 = 200;
+
+/// Number of times a photo will be moved to a new volume before the upload is considered to have failed
+const MAX_RELOCATIONS: usize = 2;
+
+#[async]
+fn upload_photo_impl(dir_handle: Arc<Mutex<Directory>>, chunks: Vec<PhotoChunk>) -> Result<NeedleKey> {
+
+	let alt_keys = chunks.iter().map(|c| c.alt_key.flip()).collect::<Vec<i32>>();
+
+	let mut photo = {
+		let dir = dir_handle.lock().unwrap();
+		let vol = dir.choose_logical_volume_for_write()?;
+
+		dir.db.create_photo(&models::NewPhoto {
+			volume_id: vol.id,
+			cookie: CookieBuf::random().data(),
+			alt_keys: &alt_keys
+		})?
+	};
+
+	// All machines that failed to take the photo so far
+	let mut blacklist: Vec<MachineId> = vec![];
+
+	let mut num_relocations = 0;
+
+	loop {
+		let failed = await!(upload_to_volume(dir_handle.clone(), photo.clone(), chunks.clone()))?;
+
+		if failed.len() == 0 {
+			break;
+		}
+
+		if num_relocations >= MAX_RELOCATIONS {
+			return Err("Failed to upload photo to all replicas".into());
+		}
+
+		blacklist.extend(failed);
+		num_relocations += 1;
+
+		let old_volume_id = photo.volume_id.flip();
+
+		let old_machines = {
+			let dir = dir_handle.lock().unwrap();
+			let machines = dir.read_store_machines_for_volume(old_volume_id)?;
+			photo = dir.relocate_photo(&photo, &blacklist)?;
+			machines
+		};
+
+		// Best effort cleanup of any needles that did make it onto the old volume (these would otherwise be left dangling)
+		let key = photo.id.flip();
+		let arr = old_machines.iter().map(|m| {
+			Client::delete_needles(m, old_volume_id, key).then(|_| -> FutureResult<(), Error> { ok(()) })
+		}).collect::<Vec<_>>();
+
+		await!(join_all(arr))?;
+	}
+
+	let dir = dir_handle.lock().unwrap();
+
+	dir.db.record_photo_alt_keys(&alt_key_details(&photo, &chunks))?;
+
+	// Fails if the photo was garbage collected because the upload took too long
+	dir.db.commit_photo(&photo)?;
+
+	Ok(photo.id.flip())
+}
+
+#[async]
+fn update_photo_impl(dir_handle: Arc<Mutex<Directory>>, key: NeedleKey, chunks: Vec<PhotoChunk>) -> Result<()> {
+
+	let photo = {
+		let dir = dir_handle.lock().unwrap();
+
+		match dir.db.read_photo(key)? {
+			Some(ref p) if p.committed => p.clone(),
+			_ => return Err("No such photo".into())
+		}
+	};
+
+	let mut alt_keys = photo.alt_keys.iter().map(|k| k.flip()).collect::<Vec<NeedleAltKey>>();
+	for c in chunks.iter() {
+		if !alt_keys.contains(&c.alt_key) {
+			alt_keys.push(c.alt_key);
+		}
+	}
+
+	// Ideally the new alt keys just go into the same volume as all of the existing ones, but that is only possible while it is still a writeable set of replicas
+	let in_place = {
+		let dir = dir_handle.lock().unwrap();
+		match dir.read_logical_volume(photo.volume_id.flip())? {
+			Some(v) => v.write_enabled && !v.is_encoded(),
+			None => return Err("Photo is in an unknown volume".into())
+		}
+	};
+
+	let mut blacklist = vec![];
+
+	if in_place {
+		let failed = await!(upload_to_volume(dir_handle.clone(), photo.clone(), chunks.clone()))?;
+
+		if failed.len() == 0 {
+			let dir = dir_handle.lock().unwrap();
+			dir.db.update_photo_alt_keys(&photo, photo.volume_id.flip(), &alt_keys)?;
+			dir.db.record_photo_alt_keys(&alt_key_details(&photo, &chunks))?;
+			return Ok(());
+		}
+
+		blacklist = failed;
+	}
+
+	// Otherwise the photo must be moved, so every alt key that we aren't replacing must be copied over from the old volume
+	if photo.alt_keys.len() == 0 {
+		return Err("Can not move a photo whose alt keys are unknown".into());
+	}
+
+	let mut all_chunks = chunks.clone();
+	for alt_key in alt_keys.clone() {
+		if chunks.iter().any(|c| c.alt_key == alt_key) {
+			continue;
+		}
+
+		let data = await!(read_alt_key(dir_handle.clone(), photo.clone(), alt_key))?;
+		all_chunks.push(PhotoChunk { alt_key, data });
+	}
+
+	let mut num_relocations = 0;
+
+	loop {
+		let vol = {
+			let dir = dir_handle.lock().unwrap();
+			dir.choose_logical_volume_for_write_avoiding(&blacklist)?
+		};
+
+		let mut moved = photo.clone();
+		moved.volume_id = vol.id;
+
+		let failed = await!(upload_to_volume(dir_handle.clone(), moved, all_chunks.clone()))?;
+
+		if failed.len() == 0 {
+			// Readers only switch over to the new volume once it has every alt key
+			let old_machines = {
+				let dir = dir_handle.lock().unwrap();
+				dir.db.update_photo_alt_keys(&photo, vol.id.flip(), &alt_keys)?;
+				dir.db.record_photo_alt_keys(&alt_key_details(&photo, &chunks))?;
+				dir.read_store_machines_for_volume(photo.volume_id.flip())?
+			};
+
+			// Best effort cleanup of the old copies (these would otherwise be left dangling)
+			let arr = old_machines.iter().map(|m| {
+				Client::delete_needles(m, photo.volume_id.flip(), key).then(|_| -> FutureResult<(), Error> { ok(()) })
+			}).collect::<Vec<_>>();
+
+			await!(join_all(arr))?;
+
+			return Ok(());
+		}
+
+		num_relocations += 1;
+		if num_relocations >= MAX_RELOCATIONS {
+			return Err("Failed to upload photo to all replicas".into());
+		}
+
+		blacklist.extend(failed);
+	}
+}
+
+/// Describes newly uploaded chunks of a photo for the directory
+fn alt_key_details(photo: &models::Photo, chunks: &[PhotoChunk]) -> Vec<models::PhotoAltKey> {
+	let now = Utc::now();
+
+	chunks.iter().map(|c| {
+		models::PhotoAltKey {
+			photo_id: photo.id,
+			alt_key: c.alt_key.flip(),
+			size: c.data.len() as i64,
+			content_type: sniff_content_type(&c.data),
+			created_at: now
+		}
+	}).collect()
+}
+
+/// Uploads chunks of a photo to every replica of the photo's volume
+/// Resolves to the ids of all machines which failed to take every chunk (even after retrying)
+#[async]
+fn upload_to_volume(dir_handle: Arc<Mutex<Directory>>, photo: models::Photo, chunks: Vec<PhotoChunk>) -> Result<Vec<MachineId>> {
+
+	let (machines, writeable) = {
+		let dir = dir_handle.lock().unwrap();
+		let machines = dir.read_store_machines_for_volume(photo.volume_id.flip())?;
+		let writeable = machines.iter().map(|m| m.can_write(&dir.config)).collect::<Vec<_>>();
+		(machines, writeable)
+	};
+
+	if machines.len() == 0 {
+		return Err("Missing any machines to upload to".into());
+	}
+
+	let needles = chunks.iter().map(|c| {
+		NeedleChunk {
+			path: NeedleChunkPath {
+				volume_id: photo.volume_id.flip(),
+				key: photo.id.flip(),
+				alt_key: c.alt_key,
+				cookie: CookieBuf::from(&photo.cookie[..])
+			},
+			data: c.data.clone()
+		}
+	}).collect::<Vec<_>>();
+
+	let arr = machines.into_iter().zip(writeable.into_iter()).map(|(m, can_write)| {
+		let machine_id = m.id.flip();
+
+		// Machines that we already know aren't writeable fail right away instead of waiting for them to reject us
+		let f = if can_write {
+			Either::A(upload_needles_with_retry(m, needles.clone()))
+		} else {
+			Either::B(err::<(), Error>("Machine is not writeable".into()))
+		};
+
+		f.then(move |res| -> FutureResult<_, Error> {
+			if let Err(ref e) = res {
+				eprintln!("Failed to upload to store {}: {:?}", machine_id, e);
+			}
+
+			ok((machine_id, res.is_ok()))
+		})
+	}).collect::<Vec<_>>();
+
+	let failed = await!(join_all(arr))?.into_iter()
+		.filter(|(_, success)| !success)
+		.map(|(id, _)| id)
+		.collect::<Vec<_>>();
+
+	Ok(failed)
+}
+
+/// Reads the data of one alt key of a photo from any readable replica of its volume
+/// For an erasure coded volume, this goes to the stores holding its shards instead (which reconstruct the needle through the same store route)
+#[async]
+fn read_alt_key(dir_handle: Arc<Mutex<Directory>>, photo: models::Photo, alt_key: NeedleAltKey) -> Result<Bytes> {
+
+	let machines = {
+		let dir = dir_handle.lock().unwrap();
+		dir.read_store_machines_for_volume(photo.volume_id.flip())?.into_iter()
+			.filter(|m| m.can_read(&dir.config))
+			.collect::<Vec<_>>()
+	};
+
+	let path = StorePath::Needle {
+		volume_id: photo.volume_id.flip(),
+		key: photo.id.flip(),
+		alt_key,
+		cookie: CookieBuf::from(&photo.cookie[..])
+	};
+
+	for m in machines {
+		match await!(Client::read_needle(&m, &path)) {
+			Ok(data) => return Ok(data),
+			Err(e) => eprintln!("Failed to read alt key {} of photo {} from store {}: {:?}", alt_key, photo.id, m.id, e)
+		}
+	}
+
+	Err("No replica could be read".into())
+}
+
+/// Uploads all of the given chunks to a single store, retrying with exponential backoff until all of them have been written
+#[async]
+fn upload_needles_with_retry(mac: models::StoreMachine, needles: Vec<NeedleChunk>) -> Result<()> {
+
+	let mut delay = UPLOAD_RETRY_DELAY;
+	let mut attempt = 1;
+
+	loop {
+		let e = match await!(Client::upload_needle_batch(&mac, &needles)) {
+			Ok(n) if n == needles.len() => return Ok(()),
+			Ok(_) => Error::from("Not all chunks uploaded"),
+			Err(e) => e
+		};
+
+		if attempt >= UPLOAD_ATTEMPTS {
+			return Err(e);
+		}
+
+		eprintln!("Upload to store {} failed (retrying in {}ms): {:?}", mac.id, delay, e);
+
+		await!(
+			Delay::new(Instant::now() + Duration::from_millis(delay))
+			.map_err(|_| Error::from("Timer failed"))
+		)?;
+
+		delay *= 2;
+		attempt += 1;
+	}
+}
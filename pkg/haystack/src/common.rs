@@ -4,6 +4,10 @@ pub type FormatVersion = u32;
 
 pub const CURRENT_FORMAT_VERSION: FormatVersion = 1;
 
+/// Oldest format version of volumes that can still be read
+/// Volumes between this and the current version are served read-only until they are migrated (see 'hay store migrate')
+pub const OLDEST_FORMAT_VERSION: FormatVersion = 1;
+
 /// Uniquely identifies this complete set of machines
 pub type ClusterId = u64;
 
@@ -27,7 +31,7 @@ pub type NeedleSize = u64;
 pub type Cookie = [u8; 16];
 
 
-#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct NeedleKeys {
 	pub key: NeedleKey,
 	pub alt_key: NeedleAltKey
@@ -51,6 +55,9 @@ pub struct StoreConfig {
 	/// Maximum size of each volume on a store machine
 	pub allocation_size: u64,
 
+	/// Largest needle that will be accepted by an upload or volume import (the whole needle is held in memory until it is written)
+	pub max_upload_size: u64,
+
 	/// How many multiples of the allocation size less than the total store space to leave empty
 	/// This space will ensure that we don't risk overprovisioning space and that we have working area to perform online compactions on the same machine
 	pub allocation_reserved: usize,
@@ -59,10 +66,26 @@ pub struct StoreConfig {
 	/// TODO: Probably good to validate this against the fs2::allocation_granularity for a volume
 	pub preallocate_size: u64,
 
+	/// Fraction of a volume's used space that must be reclaimable before the volume will be compacted
+	pub compaction_threshold: f64,
+
+	/// Maximum number of bytes of needles that will be copied in one go while a compaction holds the lock on a volume
+	pub compaction_step_size: u64,
+
+	/// Maximum number of bytes per second of needles that will be read while checking volumes for corruption in the background (0 to disable scrubbing)
+	pub scrub_rate: u64,
+
+	/// Time in milliseconds after a volume has been fully scrubbed before it will be scrubbed again
+	pub scrub_interval: u64,
+
 	pub heartbeat_interval: u64,
 
 	/// Must get a heartbeat with-in this amount of time to be considering alive and well
-	pub heartbeat_timeout: u64
+	pub heartbeat_timeout: u64,
+
+	/// Name of the rack in which this store machine is located
+	/// Replicas of a volume will never be placed on two machines in the same rack (if left empty, the machine is assumed to be in a rack of its own)
+	pub rack: String
 }
 
 impl Default for StoreConfig {
@@ -71,11 +94,17 @@ impl Default for StoreConfig {
 			num_replicas: 3,
 			block_size: 64,
 			allocation_size: 100*1024*1024, // 100MB for testing
+			max_upload_size: 10*1024*1024, // 10MB
 			allocation_reserved: 2,
 			preallocate_size: 1*1024*1024, // 1MB for testing
 			space: 1024*1024*1024, // 1GB
+			compaction_threshold: 0.25,
+			compaction_step_size: 1*1024*1024, // 1MB
+			scrub_rate: 4*1024*1024, // 4MB/s
+			scrub_interval: 7*24*60*60*1000, // Every week
 			heartbeat_interval: 10000, // Heartbeat send every 10 seconds
-			heartbeat_timeout: 30000
+			heartbeat_timeout: 30000,
+			rack: String::new()
 		}
 	}
 }
@@ -85,15 +114,108 @@ impl Default for StoreConfig {
 pub struct CacheConfig {
 	pub memory_size: usize,
 	pub max_age: u64,
-	pub max_entry_size: usize
+	pub max_entry_size: usize,
+
+	/// Total number of bytes that the on-disk tier of the cache may use (only used if the cache is given a folder)
+	pub disk_size: u64,
+
+	/// Size of the largest entry that will be stored in the on-disk tier
+	pub disk_max_entry_size: usize,
+
+	/// Size of each log file in the on-disk tier (space is reclaimed one segment at a time)
+	pub disk_segment_size: u64,
+
+	/// Relative share of photos that this cache machine should be responsible for
+	pub weight: u32,
+
+	/// Number of points each cache gets on the hash ring per unit of weight (must be the same on all machines)
+	pub ring_virtual_nodes: usize,
+
+	/// Number of caches that each photo is spread across (useful for spreading load when some photos are very popular)
+	pub ring_replicas: usize
 }
 
 impl Default for CacheConfig {
 	fn default() -> Self {
 		CacheConfig {
-			memory_size: 100*1024, // 100Mb of in-memory caching
+			memory_size: 100*1024*1024, // 100Mb of in-memory caching
 			max_age: 60*60*1000, // 1 hour before the cache must be invalidated
-			max_entry_size: 10*1024
+			max_entry_size: 1024*1024,
+			disk_size: 10*1024*1024*1024, // 10Gb
+			disk_max_entry_size: 16*1024*1024,
+			disk_segment_size: 256*1024*1024,
+			weight: 1,
+			ring_virtual_nodes: 64,
+			ring_replicas: 1
+		}
+	}
+}
+
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PitchforkConfig {
+	/// Time in milliseconds between successive health checks of the whole cluster
+	pub interval: u64,
+
+	/// Number of random photos to read from each volume on a store during each health check
+	pub probe_count: usize,
+
+	/// Alt key used when reading random photos
+	/// The directory doesn't track which alt keys exist for each photo, so this should be one that all photos are uploaded with
+	pub probe_alt_key: NeedleAltKey,
+
+	/// Maximum amount of time in milliseconds to wait for a store to respond to a single probe
+	pub probe_timeout: u64,
+
+	/// If a store hasn't sent a heartbeat in this many milliseconds, then we assume that it is gone for good and all of its volumes will be re-replicated onto other stores
+	pub replica_timeout: u64,
+
+	/// Whether or not fully written read-only volumes should be erasure coded to save space (instead of being kept as full replicas forever)
+	pub encode: bool,
+
+	/// Number of data shards that each coded volume is split into
+	pub encode_data_shards: usize,
+
+	/// Number of parity shards computed for each coded volume (this many stores can be lost without losing any data)
+	pub encode_parity_shards: usize,
+
+	/// Time in milliseconds that a client has to upload and commit a new photo before it is considered abandoned and deleted
+	pub upload_timeout: u64,
+
+	/// Maximum amount of time in milliseconds that copying a whole volume onto a new replica may take before it is given up on
+	pub replicate_timeout: u64
+}
+
+impl Default for PitchforkConfig {
+	fn default() -> Self {
+		PitchforkConfig {
+			interval: 30000, // Check every 30 seconds
+			probe_count: 2,
+			probe_alt_key: 1,
+			probe_timeout: 5000,
+			replica_timeout: 60*60*1000, // 1 hour
+			encode: false,
+			encode_data_shards: 10,
+			encode_parity_shards: 4,
+			upload_timeout: 60*60*1000, // 1 hour
+			replicate_timeout: 60*60*1000 // 1 hour
+		}
+	}
+}
+
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DirectoryConfig {
+	/// Time in milliseconds for which each machine can use its in-memory copy of the cluster's machines and volumes before it must be re-read from the directory
+	pub cluster_max_age: u64
+}
+
+impl Default for DirectoryConfig {
+	fn default() -> Self {
+		DirectoryConfig {
+			cluster_max_age: 10000 // Same as the heartbeat interval
 		}
 	}
 }
@@ -103,11 +225,18 @@ impl Default for CacheConfig {
 #[serde(default)]
 pub struct Config {
 
+	/// Name of the region (datacenter) in which this machine is located
+	/// Stores will try to keep a replica of every volume in another region and reads will prefer stores in the same region
+	pub region: String,
+
 	pub store: StoreConfig,
 
-	pub cache: CacheConfig
+	pub cache: CacheConfig,
+
+	pub pitchfork: PitchforkConfig,
 
-	// TODO: Probably also move the directory config into here as well
+	// TODO: Probably also move the directory connection config into here as well
+	pub directory: DirectoryConfig
 
 }
 
@@ -0,0 +1,81 @@
+use super::super::errors::*;
+use super::super::common::*;
+use super::models::*;
+use super::store::DirectoryStore;
+use super::ring::HashRing;
+use core::FlipSign;
+use std::time::{Duration, Instant};
+
+
+/// An in-memory copy of all of the machine and volume tables in the directory
+///
+/// These tables are small and change slowly, so each machine keeps one of these around to avoid hitting the directory on every request
+/// NOTE: This may be a little out of date, so anything that must be exact (like allocating new volumes) should still go to the directory
+pub struct ClusterSnapshot {
+	pub store_machines: Vec<StoreMachine>,
+	pub cache_machines: Vec<CacheMachine>,
+	pub logical_volumes: Vec<LogicalVolume>,
+	pub physical_volumes: Vec<PhysicalVolume>,
+	pub volume_shards: Vec<VolumeShard>,
+
+	/// Ring of all caches that were readable when this snapshot was made
+	pub cache_ring: HashRing,
+
+	/// When this snapshot was read from the directory
+	pub fetched_at: Instant
+}
+
+impl ClusterSnapshot {
+
+	pub fn fetch(db: &DirectoryStore, config: &Config) -> Result<ClusterSnapshot> {
+		let cache_machines = db.index_cache_machines()?;
+
+		let readable = cache_machines.iter().filter(|m| m.can_read(config)).cloned().collect::<Vec<_>>();
+		let cache_ring = HashRing::build(&readable, config.cache.ring_virtual_nodes);
+
+		Ok(ClusterSnapshot {
+			store_machines: db.index_store_machines()?,
+			cache_machines,
+			logical_volumes: db.index_logical_volumes()?,
+			physical_volumes: db.index_physical_volumes()?,
+			volume_shards: db.index_volume_shards()?,
+			cache_ring,
+			fetched_at: Instant::now()
+		})
+	}
+
+	pub fn is_expired(&self, max_age: u64) -> bool {
+		self.fetched_at.elapsed() >= Duration::from_millis(max_age)
+	}
+
+	pub fn logical_volume(&self, id: VolumeId) -> Option<&LogicalVolume> {
+		self.logical_volumes.iter().find(|v| v.id == id.flip())
+	}
+
+	/// Equivalent to DirectoryStore::read_store_machines
+	pub fn store_machines_with_ids(&self, ids: &[MachineId]) -> Vec<StoreMachine> {
+		self.store_machines.iter()
+			.filter(|m| ids.contains(&m.id.flip()))
+			.cloned()
+			.collect()
+	}
+
+	/// Equivalent to DirectoryStore::read_store_machines_for_volume
+	pub fn store_machines_for_volume(&self, vol: VolumeId) -> Vec<StoreMachine> {
+		self.physical_volumes.iter()
+			.filter(|p| p.logical_id == vol.flip())
+			.filter_map(|p| self.store_machines.iter().find(|m| m.id == p.machine_id))
+			.cloned()
+			.collect()
+	}
+
+	/// Gets every store machine holding a shard of an erasure coded volume along with the index of the shard it holds
+	pub fn shard_machines_for_volume(&self, vol: VolumeId) -> Vec<(usize, StoreMachine)> {
+		self.volume_shards.iter()
+			.filter(|s| s.logical_id == vol.flip())
+			.filter_map(|s| {
+				self.store_machines.iter().find(|m| m.id == s.machine_id).map(|m| (s.shard_index as usize, m.clone()))
+			})
+			.collect()
+	}
+}
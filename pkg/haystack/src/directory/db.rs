@@ -4,13 +4,12 @@ use super::super::errors::*;
 use super::super::common::*;
 use super::models::*;
 use super::schema;
+use super::store::DirectoryStore;
 use core::FlipSign;
-use std::env;
-use dotenv::dotenv;
-use chrono::{Utc};
+use chrono::{DateTime, Utc};
 
 
-/// Simple wrapper for performing 
+/// Directory store backed by a PostgreSQL database
 pub struct DB {
 	conn: PgConnection
 }
@@ -20,14 +19,17 @@ use diesel::sql_types::{Integer};
 
 impl DB {
 
-	pub fn connect() -> DB {
+	pub fn connect(database_url: &str) -> DB {
 		DB {
-			conn: establish_connection()
+			conn: establish_connection(database_url)
 		}
 	}
 
+}
+
+impl DirectoryStore for DB {
 
-	pub fn get_param(&self, key: i32) -> Result<Option<Vec<u8>>> {
+	fn get_param(&self, key: i32) -> Result<Option<Vec<u8>>> {
 		use super::schema::params::dsl::*;
 
 		let res = params
@@ -41,7 +43,7 @@ impl DB {
 	}
 
 	/// Creates a new parameter. Errors out if it already exists
-	pub fn create_param(&self, key: i32, value: Vec<u8>) -> Result<()> {
+	fn create_param(&self, key: i32, value: Vec<u8>) -> Result<()> {
 		// Generally I would like a wrapper that makes a
 
 
@@ -63,7 +65,7 @@ impl DB {
 		Ok(())
 	}
 
-	pub fn create_logical_volume(&self, vol: &NewLogicalVolume) -> Result<LogicalVolume> {
+	fn create_logical_volume(&self, vol: &NewLogicalVolume) -> Result<LogicalVolume> {
 		let v = diesel::insert_into(schema::logical_volumes::table)
 			.values(vol)
 			.get_result::<LogicalVolume>(&self.conn)?;
@@ -71,18 +73,18 @@ impl DB {
 		Ok(v)
 	}
 
-	pub fn index_logical_volumes(&self) -> Result<Vec<LogicalVolume>> {
+	fn index_logical_volumes(&self) -> Result<Vec<LogicalVolume>> {
 		use super::schema::logical_volumes::dsl::*;
 		Ok(logical_volumes.get_results::<LogicalVolume>(&self.conn)?)
 	}
 
-	pub fn read_logical_volume(&self, id_value: VolumeId) -> Result<Option<LogicalVolume>> {
+	fn read_logical_volume(&self, id_value: VolumeId) -> Result<Option<LogicalVolume>> {
 		use super::schema::logical_volumes::dsl::*;
 		Ok(logical_volumes.filter(id.eq(id_value.flip())).first::<LogicalVolume>(&self.conn).optional()?)
 	}
 
 	/// Find all logical volumes associated with a single machine
-	pub fn read_logical_volumes_for_store_machine(&self, id_value: MachineId) -> Result<Vec<LogicalVolume>> {
+	fn read_logical_volumes_for_store_machine(&self, id_value: MachineId) -> Result<Vec<LogicalVolume>> {
 
 		use super::schema::logical_volumes::dsl::*;
 		use super::schema::physical_volumes::dsl::*;
@@ -97,7 +99,7 @@ impl DB {
 	}
 
 	// TODO: We do want to be able to update many logical volumes all at once for 
-	pub fn update_logical_volume_writeable(&self, id_value: VolumeId, is: bool) -> Result<()> {
+	fn update_logical_volume_writeable(&self, id_value: VolumeId, is: bool) -> Result<()> {
 		use super::schema::logical_volumes::dsl::*;
 
 		expect_changed(
@@ -110,20 +112,115 @@ impl DB {
 		)
 	}
 
-	pub fn create_photo(&self, new_photo: &NewPhoto) -> Result<Photo> {
+	fn create_photo(&self, new_photo: &NewPhoto) -> Result<Photo> {
 		Ok(diesel::insert_into(schema::photos::table)
 			.values(new_photo)
 			.get_result::<Photo>(&self.conn)?)
 	}
 
-	pub fn read_photo(&self, id_value: NeedleKey) -> Result<Option<Photo>> {
+	fn read_photo(&self, id_value: NeedleKey) -> Result<Option<Photo>> {
 		use super::schema::photos::dsl::*;
 		Ok(photos.filter(id.eq(id_value.flip())).first::<Photo>(&self.conn).optional()?)
 	}
 
+	/// Picks a random sample of up to 'limit' photos stored in the given logical volume
+	fn read_random_photos_for_volume(&self, vol: VolumeId, limit: usize) -> Result<Vec<Photo>> {
+		use super::schema::photos::dsl::*;
+
+		Ok(photos
+			.filter(volume_id.eq(vol.flip()))
+			.filter(committed.eq(true))
+			.order(sql::<Integer>("RANDOM()"))
+			.limit(limit as i64)
+			.get_results::<Photo>(&self.conn)?)
+	}
+
+	fn index_photos_for_volume(&self, vol: VolumeId) -> Result<Vec<Photo>> {
+		use super::schema::photos::dsl::*;
+
+		Ok(photos
+			.filter(volume_id.eq(vol.flip()))
+			.order(id.asc())
+			.get_results::<Photo>(&self.conn)?)
+	}
+
+	/// Records details about some alt keys of photos (replacing anything previously recorded for the same alt keys)
+	fn record_photo_alt_keys(&self, new_alt_keys: &[PhotoAltKey]) -> Result<()> {
+		use super::schema::photo_alt_keys::dsl::*;
+
+		self.conn.transaction::<_, Error, _>(|| {
+			for k in new_alt_keys {
+				diesel::delete(
+					photo_alt_keys
+					.filter(photo_id.eq(k.photo_id))
+					.filter(alt_key.eq(k.alt_key))
+				)
+				.execute(&self.conn)?;
+			}
+
+			expect_changed(
+				diesel::insert_into(photo_alt_keys)
+					.values(new_alt_keys)
+					.execute(&self.conn)
+					.map(|n| { if n == new_alt_keys.len() { 1 } else { 0 } })?
+			)
+		})
+	}
+
+	fn read_photo_alt_keys(&self, id: NeedleKey) -> Result<Vec<PhotoAltKey>> {
+		use super::schema::photo_alt_keys::dsl::*;
+
+		Ok(photo_alt_keys
+			.filter(photo_id.eq(id.flip()))
+			.order(alt_key.asc())
+			.get_results::<PhotoAltKey>(&self.conn)?)
+	}
+
+	/// Makes an uncommitted photo visible to readers
+	/// Will succeed only if the photo is still uncommitted and hasn't changed volumes since last time we checked
+	fn commit_photo(&self, photo: &Photo) -> Result<()> {
+		use super::schema::photos::dsl::*;
+
+		expect_changed(
+			diesel::update(
+				photos
+				.filter(id.eq(photo.id))
+				.filter(volume_id.eq(photo.volume_id))
+				.filter(committed.eq(false))
+			)
+			.set(committed.eq(true))
+			.execute(&self.conn)?
+		)
+	}
+
+	fn read_uncommitted_photos(&self, before: DateTime<Utc>, limit: usize) -> Result<Vec<Photo>> {
+		use super::schema::photos::dsl::*;
+
+		Ok(photos
+			.filter(committed.eq(false))
+			.filter(created_at.lt(before))
+			.limit(limit as i64)
+			.get_results::<Photo>(&self.conn)?)
+	}
+
+	/// Deletes a photo only if it still hasn't been committed
+	fn delete_uncommitted_photo(&self, photo: &Photo) -> Result<()> {
+		use super::schema::photos::dsl::*;
+
+		expect_changed(
+			diesel::delete(
+				photos
+				.filter(id.eq(photo.id))
+				.filter(volume_id.eq(photo.volume_id))
+				.filter(committed.eq(false))
+			)
+			.execute(&self.conn)?
+		)
+	}
+
 	/// Performs a test-and-set on the volume_id of a photo
 	/// Will succeed only if operation ended up changing the volume_id
-	pub fn update_photo_volume_id(&self, photo: &Photo, new_volume_id: VolumeId) -> Result<()> {
+	fn update_photo_volume_id(&self, photo: &Photo, new_volume_id: VolumeId) -> Result<()> {
 		use super::schema::photos::dsl::*;
 
 		expect_changed(
@@ -137,9 +234,27 @@ impl DB {
 		)
 	}
 
+	/// Moves a photo while replacing its alt keys (performs a test-and-set on the volume_id of the photo)
+	fn update_photo_alt_keys(&self, photo: &Photo, new_volume_id: VolumeId, new_alt_keys: &[NeedleAltKey]) -> Result<()> {
+		use super::schema::photos::dsl::*;
+
+		expect_changed(
+			diesel::update(
+				photos
+				.filter(id.eq(photo.id))
+				.filter(volume_id.eq(photo.volume_id))
+			)
+			.set((
+				volume_id.eq(new_volume_id.flip()),
+				alt_keys.eq(new_alt_keys.iter().map(|k| k.flip()).collect::<Vec<i32>>())
+			))
+			.execute(&self.conn)?
+		)
+	}
+
 	/// Deletes a photo
 	/// Will succeed if and only if the logical volume hasn't changed since last time we checked
-	pub fn delete_photo(&self, photo: &Photo) -> Result<()> {
+	fn delete_photo(&self, photo: &Photo) -> Result<()> {
 		use super::schema::photos::dsl::*;
 
 		expect_changed(
@@ -154,7 +269,7 @@ impl DB {
 
 	// NOTE: We would like to atomically increment the size of allocated space as well as adding the volume
 	// TODO: Should we also simultaenously change the allocation amounts
-	pub fn create_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()> {
+	fn create_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()> {
 		expect_changed(
 			diesel::insert_into(schema::physical_volumes::table)
 				.values(&PhysicalVolume {
@@ -165,7 +280,7 @@ impl DB {
 		)
 	}
 
-	pub fn create_physical_volumes(&self, logical_id: VolumeId, machine_ids: &[MachineId]) -> Result<()> {
+	fn create_physical_volumes(&self, logical_id: VolumeId, machine_ids: &[MachineId]) -> Result<()> {
 		expect_changed(
 			diesel::insert_into(schema::physical_volumes::table)
 				.values(&machine_ids.iter().map(|m| {
@@ -179,9 +294,68 @@ impl DB {
 		)
 	}
 
-	// TODO: Eventually may need to be able to delete physical_volume mappings if we decide that a machine is completely dead and not recoverable
+	fn index_physical_volumes(&self) -> Result<Vec<PhysicalVolume>> {
+		use super::schema::physical_volumes::dsl::*;
+		Ok(physical_volumes.get_results::<PhysicalVolume>(&self.conn)?)
+	}
+
+	/// Removes a single replica from a logical volume (used when a machine is completely dead and not recoverable)
+	fn delete_physical_volume(&self, logical_id_value: VolumeId, machine_id_value: MachineId) -> Result<()> {
+		use super::schema::physical_volumes::dsl::*;
+
+		expect_changed(
+			diesel::delete(
+				physical_volumes
+				.filter(logical_id.eq(logical_id_value.flip()))
+				.filter(machine_id.eq(machine_id_value.flip()))
+			)
+			.execute(&self.conn)?
+		)
+	}
+
+	fn create_volume_shards(&self, logical_id_value: VolumeId, data_shards_value: usize, parity_shards_value: usize, machine_ids: &[MachineId]) -> Result<()> {
+		use super::schema::logical_volumes::dsl::*;
 
-	pub fn create_cache_machine(&self, addr_ip: &str, addr_port: u16) -> Result<CacheMachine> {
+		if machine_ids.len() != data_shards_value + parity_shards_value {
+			return Err("Need exactly one machine per shard".into());
+		}
+
+		self.conn.transaction::<_, Error, _>(|| {
+			// Only a volume that isn't already encoded may be changed
+			expect_changed(
+				diesel::update(
+					logical_volumes
+					.filter(id.eq(logical_id_value.flip()))
+					.filter(data_shards.eq(0))
+				)
+				.set((
+					data_shards.eq(data_shards_value as i32),
+					parity_shards.eq(parity_shards_value as i32)
+				))
+				.execute(&self.conn)?
+			)?;
+
+			expect_changed(
+				diesel::insert_into(schema::volume_shards::table)
+					.values(&machine_ids.iter().enumerate().map(|(i, m)| {
+						VolumeShard {
+							logical_id: logical_id_value.flip(),
+							shard_index: i as i32,
+							machine_id: m.flip()
+						}
+					}).collect::<Vec<_>>())
+					.execute(&self.conn)
+					.map(|n| { if n == machine_ids.len() { 1 } else { 0 } })?
+			)
+		})
+	}
+
+	fn index_volume_shards(&self) -> Result<Vec<VolumeShard>> {
+		use super::schema::volume_shards::dsl::*;
+		Ok(volume_shards.get_results::<VolumeShard>(&self.conn)?)
+	}
+
+	fn create_cache_machine(&self, addr_ip: &str, addr_port: u16) -> Result<CacheMachine> {
 		let new_machine = NewCacheMachine {
 			addr_ip,
 			addr_port: addr_port.flip(),
@@ -195,12 +369,12 @@ impl DB {
 		Ok(m)
 	}
 
-	pub fn index_cache_machines(&self) -> Result<Vec<CacheMachine>> {
+	fn index_cache_machines(&self) -> Result<Vec<CacheMachine>> {
 		use super::schema::cache_machines::dsl::*;
 		Ok(cache_machines.get_results::<CacheMachine>(&self.conn)?)
 	}
 
-	pub fn read_cache_machine(&self, id_value: MachineId) -> Result<Option<CacheMachine>> {
+	fn read_cache_machine(&self, id_value: MachineId) -> Result<Option<CacheMachine>> {
 		use super::schema::cache_machines::dsl::*;
 
 		Ok(cache_machines
@@ -208,9 +382,10 @@ impl DB {
 			.first::<CacheMachine>(&self.conn).optional()?)
 	}
 
-	pub fn update_cache_machine_heartbeat(&self,
+	fn update_cache_machine_heartbeat(&self,
 		id_value: MachineId,
 		ready_value: bool,
+		weight_value: u32,
 		addr_ip_value: &str, addr_port_value: u16
 	) -> Result<()> {
 		use super::schema::cache_machines::dsl::*;
@@ -221,6 +396,7 @@ impl DB {
 			)
 			.set((
 				ready.eq(ready_value),
+				weight.eq(weight_value.flip()),
 				addr_ip.eq(addr_ip_value),
 				addr_port.eq(addr_port_value.flip()),
 				last_heartbeat.eq( Utc::now() )
@@ -230,7 +406,7 @@ impl DB {
 	}
 
 
-	pub fn create_store_machine(&self, addr_ip: &str, addr_port: u16) -> Result<StoreMachine> {
+	fn create_store_machine(&self, addr_ip: &str, addr_port: u16) -> Result<StoreMachine> {
 		
 		let new_machine = NewStoreMachine {
 			addr_ip,
@@ -244,12 +420,12 @@ impl DB {
 		Ok(m)
 	}
 
-	pub fn index_store_machines(&self) -> Result<Vec<StoreMachine>> {
+	fn index_store_machines(&self) -> Result<Vec<StoreMachine>> {
 		use super::schema::store_machines::dsl::*;
 		Ok(store_machines.get_results::<StoreMachine>(&self.conn)?)
 	}
 
-	pub fn read_store_machine(&self, id_value: MachineId) -> Result<Option<StoreMachine>> {
+	fn read_store_machine(&self, id_value: MachineId) -> Result<Option<StoreMachine>> {
 		use super::schema::store_machines::dsl::*;
 
 		Ok(store_machines
@@ -257,22 +433,21 @@ impl DB {
 			.first::<StoreMachine>(&self.conn).optional()?)
 	}
 
-	pub fn read_store_machines(&self, id_values: &[MachineId]) -> Result<Vec<StoreMachine>> {
+	fn read_store_machines(&self, id_values: &[MachineId]) -> Result<Vec<StoreMachine>> {
 		use super::schema::store_machines::dsl::*;
 
 		if id_values.len() == 0 {
 			return Ok(vec![]);
 		}
 
-		let mut q = store_machines.filter(id.eq(id_values[0].flip()));
-		for id_value in &id_values[1..] {
-			q.or_filter(id.eq(id_value.flip()));
-		}
+		let ids = id_values.iter().map(|i| i.flip()).collect::<Vec<i32>>();
 
-		Ok(q.get_results::<StoreMachine>(&self.conn)?)
+		Ok(store_machines
+			.filter(id.eq_any(ids))
+			.get_results::<StoreMachine>(&self.conn)?)
 	}
 
-	pub fn read_store_machines_for_volume(&self, vol: VolumeId) -> Result<Vec<StoreMachine>> {
+	fn read_store_machines_for_volume(&self, vol: VolumeId) -> Result<Vec<StoreMachine>> {
 		use super::schema::store_machines::dsl::*;
 		use super::schema::physical_volumes::dsl::*;
 
@@ -285,11 +460,12 @@ impl DB {
 		)
 	}
 
-	pub fn update_store_machine_heartbeat(&self,
+	fn update_store_machine_heartbeat(&self,
 		id_value: MachineId,
 		ready_value: bool,
 		addr_ip_value: &str, addr_port_value: u16,
-		allocated_space_value: u64, total_space_value: u64, write_enabled_value: bool
+		allocated_space_value: u64, total_space_value: u64, write_enabled_value: bool,
+		corrupt_needles_value: u64
 	) -> Result<()> {
 		use super::schema::store_machines::dsl::*;
 
@@ -304,14 +480,15 @@ impl DB {
 				last_heartbeat.eq( Utc::now() ),
 				allocated_space.eq(allocated_space_value.flip()),
 				total_space.eq(total_space_value.flip()),
-				write_enabled.eq(write_enabled_value)
+				write_enabled.eq(write_enabled_value),
+				corrupt_needles.eq(corrupt_needles_value.flip())
 			))
 			.execute(&self.conn)?
 		)
 	}
 
 
-	pub fn update_store_machine_health(&self, id_value: MachineId, alive_value: bool, healthy_value: bool) -> Result<()> {
+	fn update_store_machine_health(&self, id_value: MachineId, alive_value: bool, healthy_value: bool) -> Result<()> {
 		use super::schema::store_machines::dsl::*;
 
 		expect_changed(
@@ -326,6 +503,21 @@ impl DB {
 		)
 	}
 
+	fn update_store_machine_location(&self, id_value: MachineId, region_value: &str, rack_value: &str) -> Result<()> {
+		use super::schema::store_machines::dsl::*;
+
+		expect_changed(
+			diesel::update(
+				store_machines.filter(id.eq(id_value.flip()))
+			)
+			.set((
+				region.eq(region_value),
+				rack.eq(rack_value)
+			))
+			.execute(&self.conn)?
+		)
+	}
+
 	
 }
 
@@ -338,9 +530,6 @@ fn expect_changed(n: usize) -> Result<()> {
 	}
 }
 
-fn establish_connection() -> PgConnection {
-	dotenv().ok();
-
-	let database_url = env::var("HAYSTACK_DB").expect("HAYSTACK_DB must be set");
-	PgConnection::establish(&database_url).expect(&format!("Error connecting to {}", database_url))
+fn establish_connection(database_url: &str) -> PgConnection {
+	PgConnection::establish(database_url).expect(&format!("Error connecting to {}", database_url))
 }
\ No newline at end of file
@@ -0,0 +1,195 @@
+use super::super::errors::*;
+use super::ops::*;
+use super::tables::DirectoryTables;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use fs2::FileExt;
+
+
+/// Directory store that runs entirely inside of the current process without needing a database server
+///
+/// If given a path, all state is persisted to that single file. Every operation locks the file and reloads it, so many processes (or many instances in the same process) can safely share one file
+/// Otherwise it only lives in memory and can be shared by cloning it (mainly for running a whole cluster in a single test)
+#[derive(Clone)]
+pub struct EmbeddedStore {
+	path: Option<PathBuf>,
+	state: Arc<Mutex<DirectoryTables>>
+}
+
+impl EmbeddedStore {
+
+	/// Opens the store persisted at the given path (creating it if it doesn't exist yet)
+	pub fn open(path: &Path) -> Result<EmbeddedStore> {
+		let s = EmbeddedStore {
+			path: Some(path.to_owned()),
+			state: Arc::new(Mutex::new(DirectoryTables::default()))
+		};
+
+		// Fail early if the file is not readable
+		s.transaction(|_| Ok(()))?;
+
+		Ok(s)
+	}
+
+	pub fn in_memory() -> EmbeddedStore {
+		EmbeddedStore {
+			path: None,
+			state: Arc::new(Mutex::new(DirectoryTables::default()))
+		}
+	}
+
+	/// Runs a single operation against the latest state of the store
+	/// Any changes made will be persisted if the operation succeeds
+	fn transaction<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&mut DirectoryTables) -> Result<T> {
+		let mut state = self.state.lock().unwrap();
+
+		let path = match self.path {
+			Some(ref p) => p,
+			None => return f(&mut state)
+		};
+
+		// A separate lock file is used as the main file gets atomically replaced on every write
+		let lock_file = OpenOptions::new().create(true).write(true).open(path.with_extension("lock"))?;
+		lock_file.lock_exclusive()?;
+
+		let mut data = vec![];
+		if path.exists() {
+			fs::File::open(path)?.read_to_end(&mut data)?;
+		}
+
+		*state = if data.len() > 0 {
+			serde_json::from_slice(&data)?
+		} else {
+			DirectoryTables::default()
+		};
+
+		let res = f(&mut state)?;
+
+		let new_data = serde_json::to_vec(&*state)?;
+		if new_data != data {
+			let tmp_path = path.with_extension("tmp");
+			{
+				let mut tmp = fs::File::create(&tmp_path)?;
+				tmp.write_all(&new_data)?;
+				tmp.sync_all()?;
+			}
+
+			fs::rename(&tmp_path, path)?;
+		}
+
+		lock_file.unlock()?;
+
+		Ok(res)
+	}
+}
+
+impl DirectoryExecutor for EmbeddedStore {
+	fn execute(&self, op: DirectoryOp) -> Result<DirectoryResult> {
+		self.transaction(|t| t.apply(op))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::super::common::*;
+	use super::super::models::*;
+	use super::super::store::DirectoryStore;
+	use core::FlipSign;
+	use chrono::{Utc, Duration};
+
+	#[test]
+	fn embedded_store_persists() -> Result<()> {
+		let p = Path::new("out/testdirectory");
+		if p.exists() {
+			fs::remove_file(&p)?;
+		}
+
+		let cookie: Cookie = [7u8; 16];
+
+		let photo = {
+			let db = EmbeddedStore::open(&p)?;
+
+			let m = db.create_store_machine("127.0.0.1", 4000)?;
+			let v = db.create_logical_volume(&NewLogicalVolume { hash_key: 12 })?;
+			db.create_physical_volumes(v.id.flip(), &[m.id.flip()])?;
+
+			// Duplicate replicas are rejected without partially applying
+			assert!(db.create_physical_volumes(v.id.flip(), &[m.id.flip()]).is_err());
+
+			db.create_photo(&NewPhoto { volume_id: v.id, cookie: &cookie, alt_keys: &[1, 2] })?
+		};
+
+		// A second instance should see everything done by the first
+		let db = EmbeddedStore::open(&p)?;
+
+		let p2 = db.read_photo(photo.id.flip())?.unwrap();
+		assert_eq!(p2.volume_id, photo.volume_id);
+		assert_eq!(&p2.cookie[..], &cookie[..]);
+		assert_eq!(p2.alt_keys, vec![1, 2]);
+
+		let macs = db.read_store_machines_for_volume(photo.volume_id.flip())?;
+		assert_eq!(macs.len(), 1);
+		assert_eq!(db.index_physical_volumes()?.len(), 1);
+
+		db.update_photo_alt_keys(&p2, p2.volume_id.flip(), &[1, 2, 3])?;
+		let p2 = db.read_photo(photo.id.flip())?.unwrap();
+		assert_eq!(p2.alt_keys, vec![1, 2, 3]);
+
+		db.delete_photo(&p2)?;
+		assert!(db.read_photo(photo.id.flip())?.is_none());
+
+		Ok(())
+	}
+
+	#[test]
+	fn embedded_store_commits_photos() -> Result<()> {
+		let p = Path::new("out/testdirectory_commit");
+		if p.exists() {
+			fs::remove_file(&p)?;
+		}
+
+		let db = EmbeddedStore::open(&p)?;
+
+		let v = db.create_logical_volume(&NewLogicalVolume { hash_key: 12 })?;
+		let photo = db.create_photo(&NewPhoto { volume_id: v.id, cookie: &[7u8; 16], alt_keys: &[] })?;
+		assert!(!photo.committed);
+
+		// Uncommitted photos are never probed
+		assert_eq!(db.read_random_photos_for_volume(v.id.flip(), 10)?.len(), 0);
+
+		let later = Utc::now() + Duration::seconds(1);
+		assert_eq!(db.read_uncommitted_photos(photo.created_at, 10)?.len(), 0);
+		assert_eq!(db.read_uncommitted_photos(later, 10)?.len(), 1);
+
+		db.commit_photo(&photo)?;
+		assert!(db.read_photo(photo.id.flip())?.unwrap().committed);
+
+		// Committing twice or garbage collecting a committed photo should both fail
+		assert!(db.commit_photo(&photo).is_err());
+		assert!(db.delete_uncommitted_photo(&photo).is_err());
+		assert_eq!(db.read_uncommitted_photos(later, 10)?.len(), 0);
+		assert_eq!(db.read_random_photos_for_volume(v.id.flip(), 10)?.len(), 1);
+		assert_eq!(db.index_photos_for_volume(v.id.flip())?.len(), 1);
+
+		let details = |alt_key: i32, size: i64| PhotoAltKey {
+			photo_id: photo.id, alt_key, size, content_type: "image/png".into(), created_at: Utc::now()
+		};
+
+		// Recording an alt key again replaces what was there before
+		db.record_photo_alt_keys(&[details(2, 10), details(1, 20)])?;
+		db.record_photo_alt_keys(&[details(2, 30)])?;
+
+		let alt_keys = db.read_photo_alt_keys(photo.id.flip())?;
+		assert_eq!(alt_keys.iter().map(|k| (k.alt_key, k.size)).collect::<Vec<_>>(), vec![(1, 20), (2, 30)]);
+
+		db.delete_photo(&photo)?;
+		assert_eq!(db.read_photo_alt_keys(photo.id.flip())?.len(), 0);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,113 @@
+use super::super::errors::*;
+use super::super::http::*;
+use super::ops::*;
+use super::replicated::DirectoryStateMachine;
+use raft::node::{Node, NodeConfig};
+use raft::server::ExecuteError;
+use raft::rpc::marshal;
+use core::fs::DirLock;
+use hyper::{Body, Response, Method, StatusCode};
+use hyper::http::request::Parts;
+use futures::prelude::*;
+use futures::prelude::await;
+use std::path::Path;
+use std::sync::Arc;
+
+
+pub struct DirectoryNodeContext {
+	pub node: Arc<Node<DirectoryOutcome>>,
+	pub state_machine: Arc<DirectoryStateMachine>
+}
+
+pub type DirectoryNodeHandle = Arc<DirectoryNodeContext>;
+
+fn on_start(_: &DirectoryNodeHandle) {}
+
+fn on_stop(_: &DirectoryNodeHandle) {}
+
+
+/// Runs a single member of a raft replicated directory
+///
+/// 'folder' holds the raft log and metadata for this node. The first node in the cluster must be started with 'bootstrap' and all others will join the cluster by contacting the 'seeds' (raft rpc addresses of existing nodes)
+/// Other machines will talk to this node through the http api on the given port
+pub fn run(folder: &str, port: u16, bootstrap: bool, seeds: Vec<String>) -> Result<()> {
+
+	let lock = DirLock::open(Path::new(folder))?;
+
+	// The tables are restored from their last snapshot and raft replays the rest of the log on top of them
+	let (state_machine, last_applied) = DirectoryStateMachine::open(Path::new(folder))?;
+	let state_machine = Arc::new(state_machine);
+
+	// The raft node runs in the background on its own runtime
+	let mut runtime = tokio::runtime::Runtime::new()?;
+
+	let node = runtime.block_on(Node::start(NodeConfig {
+		dir: lock,
+		bootstrap,
+		seed_list: seeds,
+		state_machine: state_machine.clone(),
+		last_applied
+	}))?;
+
+	println!("Starting directory node {}", node.id);
+
+	let handle = Arc::new(DirectoryNodeContext {
+		node,
+		state_machine
+	});
+
+	start_http_server(
+		port,
+		&handle,
+		&handle_request,
+		&on_start,
+		&on_stop
+	);
+
+	Ok(())
+}
+
+/// Executes a single JSON encoded DirectoryOp given in a 'POST /' request
+///
+/// Responds with a JSON DirectoryOutcome if the operation was executed, or a 503 if this node is not the leader (in which case the client should try another node)
+#[async]
+fn handle_request(
+	parts: Parts, body: Body, handle: DirectoryNodeHandle
+) -> Result<Response<Body>> {
+
+	if parts.method != Method::POST || parts.uri.path() != "/" {
+		return Ok(bad_request_because("Only POST / is supported"));
+	}
+
+	let data = await!(body.concat2())?;
+
+	let op: DirectoryOp = match serde_json::from_slice(&data) {
+		Ok(v) => v,
+		Err(_) => return Ok(bad_request_because("Invalid directory operation"))
+	};
+
+	if op.is_read() {
+		// Reads go through the leader so that they never miss a committed write
+		return match await!(handle.node.server.linearizable_read()) {
+			Ok(_) => Ok(json_response(StatusCode::OK, &handle.state_machine.read(op))),
+			Err(ExecuteError::Propose(_)) => {
+				Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "Not the leader"))
+			},
+			Err(ExecuteError::NoResult) => {
+				Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "Could not confirm leadership"))
+			}
+		};
+	}
+
+	let cmd = marshal(&op)?;
+
+	match await!(handle.node.server.execute(cmd)) {
+		Ok(out) => Ok(json_response(StatusCode::OK, &out)),
+		Err(ExecuteError::Propose(_)) => {
+			Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "Not the leader"))
+		},
+		Err(ExecuteError::NoResult) => {
+			Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, "Operation may not have been applied"))
+		}
+	}
+}
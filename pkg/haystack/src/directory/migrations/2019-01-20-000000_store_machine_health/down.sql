@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE store_machines ALTER COLUMN alive SET DEFAULT FALSE;
+ALTER TABLE store_machines ALTER COLUMN healthy SET DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- Store machines are assumed to be healthy until pitch-fork finds otherwise
+
+ALTER TABLE store_machines ALTER COLUMN alive SET DEFAULT TRUE;
+ALTER TABLE store_machines ALTER COLUMN healthy SET DEFAULT TRUE;
+
+UPDATE store_machines SET alive = TRUE, healthy = TRUE;
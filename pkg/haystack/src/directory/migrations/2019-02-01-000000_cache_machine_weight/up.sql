@@ -0,0 +1,3 @@
+-- Relative share of the photo hash space that each cache will be responsible for
+
+ALTER TABLE cache_machines ADD COLUMN weight INT NOT NULL DEFAULT 1 CHECK (weight > 0);
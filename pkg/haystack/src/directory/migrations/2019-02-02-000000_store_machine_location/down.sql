@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE store_machines DROP COLUMN region;
+ALTER TABLE store_machines DROP COLUMN rack;
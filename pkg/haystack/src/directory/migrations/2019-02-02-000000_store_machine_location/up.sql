@@ -0,0 +1,4 @@
+-- Location of each store machine used to spread the replicas of a volume across failure domains
+
+ALTER TABLE store_machines ADD COLUMN region TEXT NOT NULL DEFAULT '';
+ALTER TABLE store_machines ADD COLUMN rack TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+DROP TABLE volume_shards;
+
+ALTER TABLE logical_volumes DROP COLUMN data_shards;
+ALTER TABLE logical_volumes DROP COLUMN parity_shards;
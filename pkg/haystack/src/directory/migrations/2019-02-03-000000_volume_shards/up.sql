@@ -0,0 +1,12 @@
+-- Erasure coding of sealed volumes into shards spread across store machines
+-- A volume with data_shards = 0 is not encoded and is stored as full replicas in physical_volumes
+
+ALTER TABLE logical_volumes ADD COLUMN data_shards INT NOT NULL DEFAULT 0;
+ALTER TABLE logical_volumes ADD COLUMN parity_shards INT NOT NULL DEFAULT 0;
+
+CREATE TABLE volume_shards (
+	logical_id INT NOT NULL REFERENCES logical_volumes (id),
+	shard_index INT NOT NULL,
+	machine_id INT NOT NULL REFERENCES store_machines (id),
+	PRIMARY KEY (logical_id, shard_index)
+);
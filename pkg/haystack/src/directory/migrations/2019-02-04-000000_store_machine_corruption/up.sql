@@ -0,0 +1,3 @@
+-- Number of needles that a store has found to be corrupt while scrubbing its volumes (reported with every heartbeat)
+
+ALTER TABLE store_machines ADD COLUMN corrupt_needles BIGINT NOT NULL DEFAULT 0;
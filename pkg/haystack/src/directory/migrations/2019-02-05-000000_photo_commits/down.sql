@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+
+DROP INDEX photos_uncommitted_idx;
+ALTER TABLE photos DROP COLUMN created_at;
+ALTER TABLE photos DROP COLUMN committed;
@@ -0,0 +1,8 @@
+-- Photos are created as pending and only become readable once all of their needles have been uploaded to every replica
+-- (all existing photos are assumed to have been fully uploaded)
+
+ALTER TABLE photos ADD COLUMN committed BOOL NOT NULL DEFAULT TRUE;
+ALTER TABLE photos ALTER COLUMN committed SET DEFAULT FALSE;
+
+ALTER TABLE photos ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+CREATE INDEX photos_uncommitted_idx ON photos (created_at) WHERE NOT committed;
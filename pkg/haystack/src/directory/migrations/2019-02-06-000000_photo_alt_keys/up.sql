@@ -0,0 +1,4 @@
+-- Every alt key that has been uploaded for each photo (so that they can all be carried over when a photo moves to another volume)
+-- This is left empty for photos uploaded before alt keys were tracked
+
+ALTER TABLE photos ADD COLUMN alt_keys INT[] NOT NULL DEFAULT '{}';
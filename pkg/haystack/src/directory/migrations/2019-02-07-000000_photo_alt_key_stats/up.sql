@@ -0,0 +1,10 @@
+-- Details about each alt key uploaded for a photo (mainly for auditing what is stored in the cluster)
+
+CREATE TABLE photo_alt_keys (
+	photo_id BIGINT NOT NULL REFERENCES photos(id) ON DELETE CASCADE,
+	alt_key INT NOT NULL,
+	size BIGINT NOT NULL,
+	content_type TEXT NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	PRIMARY KEY (photo_id, alt_key)
+);
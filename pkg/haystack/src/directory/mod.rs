@@ -1,7 +1,16 @@
 
 pub mod models;
 pub mod schema;
+mod store;
+mod ops;
+mod tables;
 mod db;
+mod embedded;
+mod replicated;
+mod cluster;
+mod ring;
+pub mod placement;
+pub mod main;
 
 use super::common::*;
 use super::errors::*;
@@ -11,10 +20,18 @@ use rand::prelude::*;
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use core::FlipSign;
 use self::db::DB;
+pub use self::store::DirectoryStore;
+pub use self::embedded::EmbeddedStore;
+pub use self::replicated::RaftStore;
+pub use self::cluster::ClusterSnapshot;
+pub use self::ring::{HashRing, RingRange};
 use std::hash::Hasher;
 use rand::thread_rng;
 use rand::seq::SliceRandom;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::env;
+use std::path::Path;
+use dotenv::dotenv;
 
 
 pub struct Directory {
@@ -24,7 +41,11 @@ pub struct Directory {
 	pub config: ConfigRef,
 
 	// TODO: Eventually we'd like to make sure that this can become private
-	pub db: DB
+	/// NOTE: Changes to machines or volumes should go through the methods on Directory so that our copy of the cluster gets invalidated
+	pub db: Box<DirectoryStore + Send>,
+
+	/// Last copy of the machine/volume tables read from the db (or None if it needs to be re-read)
+	cluster: Mutex<Option<Arc<ClusterSnapshot>>>
 
 }
 
@@ -43,21 +64,44 @@ pub struct Directory {
 	- DeletePhoto(key, [alt_key])
 		-> Drops the 
 
-	- CreatePhoto(key, quantity, sizes)
-		-> Returns a cookie, logical_id and a list of stores
-		-> Also returns whether or not this change needs to be commited
+	- CreatePhoto(volume, cookie)
+		-> Creates an uncommitted photo (see DirectoryStore::create_photo)
+		-> The client then uploads every alt key to every replica of the volume
 
-	- CommitPhoto(key, cookie)
-		-> Issue being that if it isn't uplaoded in time, then the old image will be totally dead
+	- CommitPhoto(photo)
+		-> Makes the photo readable once all replicas have acknowledged every alt key
+		-> If it isn't committed in time (see PitchforkConfig::upload_timeout), then pitchfork deletes the photo and any needles that made it to the stores
 
 */
 
 impl Directory {
 
 	/// Connects to the backing database and initializes the cluster if needed
+	/// 
+	/// The database is chosen by the HAYSTACK_DB environment variable:
+	/// - 'postgres://...' connects to a PostgreSQL server
+	/// - 'file:PATH' uses an embedded directory stored in the single file at PATH
+	/// - 'raft://HOST:PORT,HOST:PORT,...' uses a quorum of directory nodes started with 'hay directory'
 	pub fn open(config: Config) -> Result<Directory> {
+		dotenv().ok();
+
+		let url = env::var("HAYSTACK_DB").map_err(|_| "HAYSTACK_DB must be set")?;
+
+		let db: Box<DirectoryStore + Send> = if url.starts_with("file:") {
+			Box::new(EmbeddedStore::open(Path::new(&url["file:".len()..]))?)
+		} else if url.starts_with("raft://") {
+			let addrs = url["raft://".len()..].split(',').collect::<Vec<_>>();
+			Box::new(RaftStore::connect(&addrs)?)
+		} else {
+			Box::new(DB::connect(&url))
+		};
+
+		Directory::from_store(config, db)
+	}
 
-		let db = DB::connect();
+	/// Creates a directory on top of an already opened store and initializes the cluster if needed
+	/// (mainly for running many machines in one process with a shared EmbeddedStore)
+	pub fn from_store(config: Config, db: Box<DirectoryStore + Send>) -> Result<Directory> {
 
 		let cluster_id = match db.get_param(ParamKey::ClusterId as i32)? {
 			Some(p) => (&p[..]).read_u64::<LittleEndian>()?,
@@ -77,14 +121,110 @@ impl Directory {
 		Ok(Directory {
 			db,
 			config: Arc::new(config),
-			cluster_id
+			cluster_id,
+			cluster: Mutex::new(None)
 		})		
 	}
 
+	/// Gets an in-memory copy of all the machines and volumes in the cluster
+	/// This will only go to the db if the last copy has been invalidated or is too old
+	pub fn cluster(&self) -> Result<Arc<ClusterSnapshot>> {
+		{
+			let c = self.cluster.lock().unwrap();
+			if let Some(ref snap) = *c {
+				if !snap.is_expired(self.config.directory.cluster_max_age) {
+					return Ok(snap.clone());
+				}
+			}
+		}
+
+		self.refresh_cluster()
+	}
+
+	/// Re-reads the in-memory copy of the cluster from the db (mainly to be periodically called by heartbeat threads)
+	pub fn refresh_cluster(&self) -> Result<Arc<ClusterSnapshot>> {
+		let snap = Arc::new(ClusterSnapshot::fetch(&*self.db, &self.config)?);
+		*self.cluster.lock().unwrap() = Some(snap.clone());
+		Ok(snap)
+	}
+
+	/// Should be called after making any changes to machines or volumes through the db so that we don't keep using the old values
+	pub fn invalidate_cluster(&self) {
+		*self.cluster.lock().unwrap() = None;
+	}
+
 	pub fn create_logical_volume(&self) -> Result<LogicalVolume> {
-		self.db.create_logical_volume(&NewLogicalVolume {
+		let v = self.db.create_logical_volume(&NewLogicalVolume {
 			hash_key: rand::thread_rng().next_u64().flip()
-		})
+		})?;
+
+		self.invalidate_cluster();
+		Ok(v)
+	}
+
+	/// Changes whether or not new photos can be written to a logical volume
+	pub fn update_logical_volume_writeable(&self, id: VolumeId, is: bool) -> Result<()> {
+		self.db.update_logical_volume_writeable(id, is)?;
+		self.invalidate_cluster();
+		Ok(())
+	}
+
+	/// Adds replicas of a logical volume on all of the given machines
+	pub fn create_physical_volumes(&self, logical_id: VolumeId, machine_ids: &[MachineId]) -> Result<()> {
+		self.db.create_physical_volumes(logical_id, machine_ids)?;
+		self.invalidate_cluster();
+		Ok(())
+	}
+
+	/// Removes the replica of a logical volume on a single machine
+	pub fn delete_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()> {
+		self.db.delete_physical_volume(logical_id, machine_id)?;
+		self.invalidate_cluster();
+		Ok(())
+	}
+
+	/// Records that a logical volume was erasure coded (see DirectoryStore::create_volume_shards)
+	pub fn create_volume_shards(&self, logical_id: VolumeId, data_shards: usize, parity_shards: usize, machine_ids: &[MachineId]) -> Result<()> {
+		self.db.create_volume_shards(logical_id, data_shards, parity_shards, machine_ids)?;
+		self.invalidate_cluster();
+		Ok(())
+	}
+
+	/// Records the outcome of pitchfork checking up on a store machine
+	pub fn update_store_machine_health(&self, id: MachineId, alive: bool, healthy: bool) -> Result<()> {
+		self.db.update_store_machine_health(id, alive, healthy)?;
+		self.invalidate_cluster();
+		Ok(())
+	}
+
+	/// Looks up a logical volume first in our in-memory copy of the cluster
+	/// (falling back to the db in case it was created after our copy was made)
+	pub fn read_logical_volume(&self, id: VolumeId) -> Result<Option<LogicalVolume>> {
+		if let Some(v) = self.cluster()?.logical_volume(id) {
+			return Ok(Some(v.clone()));
+		}
+
+		self.db.read_logical_volume(id)
+	}
+
+	/// Gets all store machines that have a replica of the given volume using our in-memory copy of the cluster
+	/// (falling back to the db in case the volume was created after our copy was made)
+	///
+	/// For an erasure coded volume, this is instead every machine holding one of its shards (any of which can serve reads)
+	pub fn read_store_machines_for_volume(&self, vol: VolumeId) -> Result<Vec<StoreMachine>> {
+		let cluster = self.cluster()?;
+
+		let macs = cluster.store_machines_for_volume(vol);
+		if macs.len() > 0 {
+			return Ok(macs);
+		}
+
+		let shards = cluster.shard_machines_for_volume(vol);
+		if shards.len() > 0 {
+			return Ok(shards.into_iter().map(|(_, m)| m).collect());
+		}
+
+		self.db.read_store_machines_for_volume(vol)
 	}
 
 
@@ -92,10 +232,16 @@ impl Directory {
 	/// NOTE: We currently assume that all photos are small enough to fit into a volume such that it will get marked as read-only before any serious overflows start occuring
 	/// If there is a failure during uploading, then it should retry with a new volume
 	pub fn choose_logical_volume_for_write(&self) -> Result<LogicalVolume> {
-		let volumes = self.db.index_logical_volumes()?;
+		self.choose_logical_volume_for_write_avoiding(&[])
+	}
 
-		let avail_vols: Vec<&LogicalVolume> = volumes.iter().filter(|v| {
-			v.write_enabled == true
+	/// Same as choose_logical_volume_for_write but skips any volume with a replica on one of the given machines
+	pub fn choose_logical_volume_for_write_avoiding(&self, blacklist: &[MachineId]) -> Result<LogicalVolume> {
+		let cluster = self.cluster()?;
+
+		let avail_vols: Vec<&LogicalVolume> = cluster.logical_volumes.iter().filter(|v| {
+			v.write_enabled == true &&
+			!cluster.store_machines_for_volume(v.id.flip()).iter().any(|m| blacklist.contains(&m.id.flip()))
 		}).collect();
 
 		if avail_vols.len() == 0 {
@@ -108,45 +254,44 @@ impl Directory {
 		Ok((*vol).clone())
 	}
 
-	/// For a photo, given its volume, picks a cache to use to 
-	/// TODO: Eventually this needs to be able to pick between multiples caches per bucket in order to support redundancy in ranges
+	/// For a photo, given its volume, picks a cache to use to read it
+	/// If the cache ring is replicated, this will spread reads of the same photo across all of its replicas
 	pub fn choose_cache(&self, photo: &Photo, vol: &LogicalVolume) -> Result<CacheMachine> {
+		let caches = self.choose_caches(photo, vol)?;
+
+		let mut rng = thread_rng();
+		Ok(caches.choose(&mut rng).unwrap().clone())
+	}
+
+	/// Gets all of the caches responsible for a photo (in order of preference)
+	pub fn choose_caches(&self, photo: &Photo, vol: &LogicalVolume) -> Result<Vec<CacheMachine>> {
 		if photo.volume_id != vol.id {
 			return Err("Wrong volume given".into())
 		}
-		
-		let mut caches = self.db.index_cache_machines()?.into_iter().filter(|m| {
-			m.can_read(&self.config)
-		}).collect::<Vec<_>>();
 
-		if caches.len() == 0 {
-			return Err("Not enough available caches/store".into());
-		}
+		let cluster = self.cluster()?;
 
-		// To pick the cache server, we use a simple Distributed Hash Table approach with a random key per volume
+		// The position on the ring is hashed with a random key per volume
 		let mut hasher = siphasher::sip::SipHasher::new_with_keys(vol.hash_key.flip(), 0);
 		hasher.write_u64(photo.id.flip());
 		let hash = hasher.finish();
-		let bucket_size = std::u64::MAX / (caches.len() as u64);
-		let mut cache_idx = (hash / bucket_size) as usize; // XXX: Assumes usize is >= u64
 
-		// Handle the case of hitting the max integer value
-		if cache_idx >= caches.len() {
-			cache_idx = caches.len() - 1;
-		}
-
-		// Pick from a sorted list to ensure stability
-		caches.sort_by(|a, b| { a.id.cmp(&b.id) });
+		let caches = cluster.cache_ring.lookup(hash, self.config.cache.ring_replicas).into_iter()
+			.filter_map(|id| cluster.cache_machines.iter().find(|m| m.id.flip() == id))
+			.cloned()
+			.collect::<Vec<_>>();
 
-		let cache = &caches[cache_idx];
+		if caches.len() == 0 {
+			return Err("Not enough available caches/store".into());
+		}
 
-		Ok((*cache).clone())
+		Ok(caches)
 	}
 
 	/// Picks a load balanced store machine from which to read the given photo (to be used only when the cache misses)
 	pub fn choose_store(&self, photo: &Photo) -> Result<StoreMachine> {
 
-		let stores = self.db.read_store_machines_for_volume(photo.volume_id.flip())?
+		let mut stores = self.read_store_machines_for_volume(photo.volume_id.flip())?
 		.into_iter().filter(|m| {
 			m.can_read(&self.config)
 		}).collect::<Vec<_>>();
@@ -155,6 +300,11 @@ impl Directory {
 			return Err("Not enough available caches/store".into());
 		}
 
+		// Only consider stores in our own region if there are any
+		if stores.iter().any(|m| m.region == self.config.region) {
+			stores.retain(|m| m.region == self.config.region);
+		}
+
 		// Random load balancing
 		let mut rng = thread_rng();
 		let store = stores.choose(&mut rng).unwrap();
@@ -162,27 +312,21 @@ impl Directory {
 		Ok((*store).clone())
 	}
 
-	/// Assign the photo to a new logical volume ideally with a blacklist of machines that we no longer want to use
-	/// 
-	/// TODO: For efficiency, if uploading successfully reaches some machines, we should prefer to reuse those machines for the next attemp 
-	pub fn relocate_photo(blacklist: &Vec<MachineId>) {
+	/// Assign the photo to a new logical volume that has no replicas on any of the machines in the blacklist
+	/// Returns the photo as it now is in the directory (this will fail if the photo was concurrently moved or deleted)
+	///
+	/// NOTE: Needles already uploaded to the old volume are not touched, so the caller is responsible for deleting them
+	/// TODO: For efficiency, if uploading successfully reaches some machines, we should prefer to reuse those machines for the next attempt
+	pub fn relocate_photo(&self, photo: &Photo, blacklist: &[MachineId]) -> Result<Photo> {
+		let vol = self.choose_logical_volume_for_write_avoiding(blacklist)?;
 
+		self.db.update_photo_volume_id(photo, vol.id.flip())?;
 
-		// XXX: For updating an existing image, we do need to first retrieve a volume and cookie assignment and then upload, and then commit it 
-
-		// Uncommited ones are considered abandoned
+		let mut p = photo.clone();
+		p.volume_id = vol.id;
+		Ok(p)
 	}
 
-	// Uploading:
-	// - Get the Photo object
-	// - Get urls to all stores for the associated logical volume
-	// - Perform a POST request to all of them
-	// - Retry once on individual failures
-	// - On failure, attempt to reassign to a new volume
-
-	// I
-	
-
 }
 
 fn generate_cluster_id() -> ClusterId {
@@ -1,7 +1,7 @@
 use diesel::*;
 use super::schema::*;
 use super::super::common::Config;
-use chrono::{DateTime, Utc, Duration};
+use chrono::{DateTime, Utc, Duration, TimeZone};
 
 pub enum ParamKey {
 	ClusterId = 1
@@ -16,7 +16,7 @@ pub struct Param {
 }
 
 
-#[derive(Queryable, Identifiable, AsChangeset, Clone)]
+#[derive(Queryable, Identifiable, AsChangeset, Clone, Serialize, Deserialize)]
 #[table_name = "store_machines"]
 pub struct StoreMachine {
 	pub id: i32,
@@ -39,15 +39,26 @@ pub struct StoreMachine {
 
 	/// Set to true if the machine is accepting new writes (for existing volumes)
 	/// NOTE: This says nothing about new-allocations right now
-	pub write_enabled: bool
+	pub write_enabled: bool,
+
+	/// Name of the region (datacenter) in which this machine is located
+	#[serde(default)]
+	pub region: String,
+
+	/// Name of the rack within the region (empty if unknown)
+	#[serde(default)]
+	pub rack: String,
+
+	/// Number of needles on this machine that failed integrity checks and still need to be repaired
+	/// Updated periodically by the store
+	#[serde(default)]
+	pub corrupt_needles: i64
 }
 
 impl StoreMachine {
 
-	/// Check whether or not we are allowed to read from this machine
-	pub fn can_read(&self, config: &Config) -> bool {
-		// TODO: Eventually also account for the external health checks by pitch-fork
-
+	/// Check whether or not the machine itself claims to be up (based only on its own heartbeats)
+	pub fn is_ready(&self, config: &Config) -> bool {
 		if !self.ready {
 			return false;
 		}
@@ -64,6 +75,12 @@ impl StoreMachine {
 		true
 	}
 
+	/// Check whether or not we are allowed to read from this machine
+	/// In addition to heartbeating, the machine must not have failed any of the external health checks by pitch-fork
+	pub fn can_read(&self, config: &Config) -> bool {
+		self.is_ready(config) && self.alive && self.healthy
+	}
+
 	/// Check whether or not we are allocated to write new needles to any writeable volume on this machine
 	pub fn can_write(&self, config: &Config) -> bool {
 		self.write_enabled && self.can_read(config)
@@ -78,6 +95,16 @@ impl StoreMachine {
 	pub fn addr(&self) -> String {
 		String::from("http://") + &self.addr_ip + ":" + &self.addr_port.to_string()
 	}
+
+	/// Whether or not a single failure (of a rack) could take out both of these machines
+	/// Machines with no known rack are only considered to be in the same domain as themselves
+	pub fn same_failure_domain(&self, other: &StoreMachine) -> bool {
+		if self.rack.len() == 0 || other.rack.len() == 0 {
+			return self.id == other.id;
+		}
+
+		self.region == other.region && self.rack == other.rack
+	}
 }
 
 
@@ -90,7 +117,7 @@ pub struct NewStoreMachine<'a> {
 }
 
 /// NOTE: These will be ephemeral and will only exist while they need to 
-#[derive(Queryable, Identifiable, Clone)]
+#[derive(Queryable, Identifiable, Clone, Serialize, Deserialize)]
 #[table_name = "cache_machines"]
 pub struct CacheMachine {
 	pub id: i32,
@@ -100,9 +127,15 @@ pub struct CacheMachine {
 	pub ready: bool,
 	pub alive: bool,
 	pub healthy: bool,
-	pub hostname: String // TODO: Do we still want to use this?
+	pub hostname: String, // TODO: Do we still want to use this?
+
+	/// Relative amount of the hash space that this cache should be responsible for
+	#[serde(default = "default_cache_weight")]
+	pub weight: i32
 }
 
+fn default_cache_weight() -> i32 { 1 }
+
 impl CacheMachine {
 
 	// Basically the same as the StoreMachine one
@@ -140,12 +173,26 @@ pub struct NewCacheMachine<'a> {
 // 
 
 
-#[derive(Queryable, Identifiable, AsChangeset, Clone)]
+#[derive(Queryable, Identifiable, AsChangeset, Clone, Serialize, Deserialize)]
 #[table_name = "logical_volumes"]
 pub struct LogicalVolume {
 	pub id: i32,
 	pub write_enabled: bool,
-	pub hash_key: i64
+	pub hash_key: i64,
+
+	/// Number of data shards that the volume was erasure coded into (or 0 if it is still stored as full replicas)
+	#[serde(default)]
+	pub data_shards: i32,
+
+	#[serde(default)]
+	pub parity_shards: i32
+}
+
+impl LogicalVolume {
+	/// Whether or not this volume is stored as shards in volume_shards rather than as replicas in physical_volumes
+	pub fn is_encoded(&self) -> bool {
+		self.data_shards > 0
+	}
 }
 
 #[derive(Insertable)]
@@ -155,7 +202,7 @@ pub struct NewLogicalVolume {
 }
 
 
-#[derive(Queryable, Insertable)]
+#[derive(Queryable, Insertable, Clone, Serialize, Deserialize)]
 #[table_name = "physical_volumes"]
 pub struct PhysicalVolume {
 	pub logical_id: i32,
@@ -163,19 +210,62 @@ pub struct PhysicalVolume {
 }
 
 
-#[derive(Queryable, Identifiable, AsChangeset)]
+/// Placement of a single shard of an erasure coded logical volume
+#[derive(Queryable, Insertable, Clone, Serialize, Deserialize)]
+#[table_name = "volume_shards"]
+pub struct VolumeShard {
+	pub logical_id: i32,
+	pub shard_index: i32,
+	pub machine_id: i32
+}
+
+
+#[derive(Queryable, Identifiable, AsChangeset, Clone, Serialize, Deserialize)]
 #[table_name = "photos"]
 pub struct Photo {
 	pub id: i64,
 	pub volume_id: i32,
-	pub cookie: Vec<u8>
+	pub cookie: Vec<u8>,
+
+	/// Photos start out uncommitted and can't be read until every alt key has been uploaded to every replica
+	#[serde(default = "default_photo_committed")]
+	pub committed: bool,
+
+	/// Used to find uploads that were abandoned before they were committed
+	#[serde(default = "default_photo_created_at")]
+	pub created_at: DateTime<Utc>,
+
+	/// Every alt key uploaded for this photo (empty if the photo was uploaded before these were tracked)
+	#[serde(default)]
+	pub alt_keys: Vec<i32>
+}
+
+fn default_photo_committed() -> bool { true }
+
+fn default_photo_created_at() -> DateTime<Utc> { Utc.timestamp(0, 0) }
+
+/// Details about a single uploaded alt key of a photo
+#[derive(Queryable, Insertable, Clone, Serialize, Deserialize)]
+#[table_name = "photo_alt_keys"]
+pub struct PhotoAltKey {
+	pub photo_id: i64,
+	pub alt_key: i32,
+
+	/// Number of bytes of data in the needle
+	pub size: i64,
+
+	/// MIME type as sniffed from the data when it was uploaded
+	pub content_type: String,
+
+	pub created_at: DateTime<Utc>
 }
 
 #[derive(Insertable)]
 #[table_name = "photos"]
 pub struct NewPhoto<'a> {
 	pub volume_id: i32,
-	pub cookie: &'a [u8]
+	pub cookie: &'a [u8],
+	pub alt_keys: &'a [i32]
 }
 
 
@@ -0,0 +1,332 @@
+use super::super::errors::*;
+use super::super::common::*;
+use super::models::*;
+use super::store::DirectoryStore;
+use chrono::{DateTime, Utc};
+
+
+/// A single DirectoryStore operation in a form that can be sent over the network or appended to a log
+///
+/// NOTE: Anything non-deterministic (like the current time) is decided by whoever creates the operation so that applying the same operation on many replicas will always produce the same state
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DirectoryOp {
+	GetParam { key: i32 },
+	CreateParam { key: i32, value: Vec<u8> },
+
+	CreateLogicalVolume { hash_key: i64 },
+	IndexLogicalVolumes,
+	ReadLogicalVolume { id: VolumeId },
+	ReadLogicalVolumesForStoreMachine { id: MachineId },
+	UpdateLogicalVolumeWriteable { id: VolumeId, is: bool },
+
+	CreatePhoto {
+		volume_id: i32, cookie: Vec<u8>, time: DateTime<Utc>,
+		#[serde(default)]
+		alt_keys: Vec<i32>
+	},
+	ReadPhoto { id: NeedleKey },
+	ReadRandomPhotosForVolume { vol: VolumeId, limit: usize },
+	IndexPhotosForVolume { vol: VolumeId },
+	RecordPhotoAltKeys { alt_keys: Vec<PhotoAltKey> },
+	ReadPhotoAltKeys { id: NeedleKey },
+	CommitPhoto { id: i64, volume_id: i32 },
+	ReadUncommittedPhotos { before: DateTime<Utc>, limit: usize },
+	DeleteUncommittedPhoto { id: i64, volume_id: i32 },
+	UpdatePhotoVolumeId { id: i64, volume_id: i32, new_volume_id: VolumeId },
+	UpdatePhotoAltKeys { id: i64, volume_id: i32, new_volume_id: VolumeId, alt_keys: Vec<NeedleAltKey> },
+	DeletePhoto { id: i64, volume_id: i32 },
+
+	CreatePhysicalVolumes { logical_id: VolumeId, machine_ids: Vec<MachineId> },
+	IndexPhysicalVolumes,
+	DeletePhysicalVolume { logical_id: VolumeId, machine_id: MachineId },
+
+	CreateVolumeShards { logical_id: VolumeId, data_shards: usize, parity_shards: usize, machine_ids: Vec<MachineId> },
+	IndexVolumeShards,
+
+	CreateCacheMachine { addr_ip: String, addr_port: u16, time: DateTime<Utc> },
+	IndexCacheMachines,
+	ReadCacheMachine { id: MachineId },
+	UpdateCacheMachineHeartbeat {
+		id: MachineId, ready: bool, addr_ip: String, addr_port: u16, time: DateTime<Utc>,
+		#[serde(default)]
+		weight: u32
+	},
+
+	CreateStoreMachine { addr_ip: String, addr_port: u16, time: DateTime<Utc> },
+	IndexStoreMachines,
+	ReadStoreMachine { id: MachineId },
+	ReadStoreMachines { ids: Vec<MachineId> },
+	ReadStoreMachinesForVolume { vol: VolumeId },
+	UpdateStoreMachineHeartbeat {
+		id: MachineId, ready: bool, addr_ip: String, addr_port: u16,
+		allocated_space: u64, total_space: u64, write_enabled: bool,
+		time: DateTime<Utc>,
+		#[serde(default)]
+		corrupt_needles: u64
+	},
+	UpdateStoreMachineHealth { id: MachineId, alive: bool, healthy: bool },
+	UpdateStoreMachineLocation { id: MachineId, region: String, rack: String }
+}
+
+impl DirectoryOp {
+	/// Whether or not this operation will never change any state
+	pub fn is_read(&self) -> bool {
+		match self {
+			DirectoryOp::GetParam { .. } |
+			DirectoryOp::IndexLogicalVolumes |
+			DirectoryOp::ReadLogicalVolume { .. } |
+			DirectoryOp::ReadLogicalVolumesForStoreMachine { .. } |
+			DirectoryOp::ReadPhoto { .. } |
+			DirectoryOp::ReadRandomPhotosForVolume { .. } |
+			DirectoryOp::IndexPhotosForVolume { .. } |
+			DirectoryOp::ReadPhotoAltKeys { .. } |
+			DirectoryOp::ReadUncommittedPhotos { .. } |
+			DirectoryOp::IndexPhysicalVolumes |
+			DirectoryOp::IndexVolumeShards |
+			DirectoryOp::IndexCacheMachines |
+			DirectoryOp::ReadCacheMachine { .. } |
+			DirectoryOp::IndexStoreMachines |
+			DirectoryOp::ReadStoreMachine { .. } |
+			DirectoryOp::ReadStoreMachines { .. } |
+			DirectoryOp::ReadStoreMachinesForVolume { .. } => true,
+			_ => false
+		}
+	}
+}
+
+/// The output of successfully applying a DirectoryOp
+#[derive(Serialize, Deserialize)]
+pub enum DirectoryResult {
+	Empty,
+	Param(Option<Vec<u8>>),
+	LogicalVolume(LogicalVolume),
+	LogicalVolumeOption(Option<LogicalVolume>),
+	LogicalVolumes(Vec<LogicalVolume>),
+	Photo(Photo),
+	PhotoOption(Option<Photo>),
+	Photos(Vec<Photo>),
+	PhotoAltKeys(Vec<PhotoAltKey>),
+	PhysicalVolumes(Vec<PhysicalVolume>),
+	VolumeShards(Vec<VolumeShard>),
+	CacheMachine(CacheMachine),
+	CacheMachineOption(Option<CacheMachine>),
+	CacheMachines(Vec<CacheMachine>),
+	StoreMachine(StoreMachine),
+	StoreMachineOption(Option<StoreMachine>),
+	StoreMachines(Vec<StoreMachine>)
+}
+
+/// Result of an operation as it is passed between machines (errors are only kept as their message)
+pub type DirectoryOutcome = std::result::Result<DirectoryResult, String>;
+
+/// A directory store that works by executing DirectoryOps (implementing this gives a full DirectoryStore)
+pub trait DirectoryExecutor {
+	fn execute(&self, op: DirectoryOp) -> Result<DirectoryResult>;
+}
+
+macro_rules! expect_result {
+	($res:expr, $variant:ident) => {
+		match $res {
+			DirectoryResult::$variant(v) => Ok(v),
+			_ => Err("Unexpected directory result type".into())
+		}
+	};
+}
+
+macro_rules! expect_empty {
+	($res:expr) => {
+		match $res {
+			DirectoryResult::Empty => Ok(()),
+			_ => Err("Unexpected directory result type".into())
+		}
+	};
+}
+
+impl<T: DirectoryExecutor> DirectoryStore for T {
+
+	fn get_param(&self, key: i32) -> Result<Option<Vec<u8>>> {
+		expect_result!(self.execute(DirectoryOp::GetParam { key })?, Param)
+	}
+
+	fn create_param(&self, key: i32, value: Vec<u8>) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::CreateParam { key, value })?)
+	}
+
+	fn create_logical_volume(&self, vol: &NewLogicalVolume) -> Result<LogicalVolume> {
+		expect_result!(self.execute(DirectoryOp::CreateLogicalVolume { hash_key: vol.hash_key })?, LogicalVolume)
+	}
+
+	fn index_logical_volumes(&self) -> Result<Vec<LogicalVolume>> {
+		expect_result!(self.execute(DirectoryOp::IndexLogicalVolumes)?, LogicalVolumes)
+	}
+
+	fn read_logical_volume(&self, id: VolumeId) -> Result<Option<LogicalVolume>> {
+		expect_result!(self.execute(DirectoryOp::ReadLogicalVolume { id })?, LogicalVolumeOption)
+	}
+
+	fn read_logical_volumes_for_store_machine(&self, id: MachineId) -> Result<Vec<LogicalVolume>> {
+		expect_result!(self.execute(DirectoryOp::ReadLogicalVolumesForStoreMachine { id })?, LogicalVolumes)
+	}
+
+	fn update_logical_volume_writeable(&self, id: VolumeId, is: bool) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdateLogicalVolumeWriteable { id, is })?)
+	}
+
+	fn create_photo(&self, new_photo: &NewPhoto) -> Result<Photo> {
+		expect_result!(self.execute(DirectoryOp::CreatePhoto {
+			volume_id: new_photo.volume_id,
+			cookie: new_photo.cookie.to_vec(),
+			time: Utc::now(),
+			alt_keys: new_photo.alt_keys.to_vec()
+		})?, Photo)
+	}
+
+	fn read_photo(&self, id: NeedleKey) -> Result<Option<Photo>> {
+		expect_result!(self.execute(DirectoryOp::ReadPhoto { id })?, PhotoOption)
+	}
+
+	fn read_random_photos_for_volume(&self, vol: VolumeId, limit: usize) -> Result<Vec<Photo>> {
+		expect_result!(self.execute(DirectoryOp::ReadRandomPhotosForVolume { vol, limit })?, Photos)
+	}
+
+	fn index_photos_for_volume(&self, vol: VolumeId) -> Result<Vec<Photo>> {
+		expect_result!(self.execute(DirectoryOp::IndexPhotosForVolume { vol })?, Photos)
+	}
+
+	fn record_photo_alt_keys(&self, alt_keys: &[PhotoAltKey]) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::RecordPhotoAltKeys { alt_keys: alt_keys.to_vec() })?)
+	}
+
+	fn read_photo_alt_keys(&self, id: NeedleKey) -> Result<Vec<PhotoAltKey>> {
+		expect_result!(self.execute(DirectoryOp::ReadPhotoAltKeys { id })?, PhotoAltKeys)
+	}
+
+	fn commit_photo(&self, photo: &Photo) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::CommitPhoto { id: photo.id, volume_id: photo.volume_id })?)
+	}
+
+	fn read_uncommitted_photos(&self, before: DateTime<Utc>, limit: usize) -> Result<Vec<Photo>> {
+		expect_result!(self.execute(DirectoryOp::ReadUncommittedPhotos { before, limit })?, Photos)
+	}
+
+	fn delete_uncommitted_photo(&self, photo: &Photo) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::DeleteUncommittedPhoto { id: photo.id, volume_id: photo.volume_id })?)
+	}
+
+	fn update_photo_volume_id(&self, photo: &Photo, new_volume_id: VolumeId) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdatePhotoVolumeId {
+			id: photo.id, volume_id: photo.volume_id, new_volume_id
+		})?)
+	}
+
+	fn update_photo_alt_keys(&self, photo: &Photo, new_volume_id: VolumeId, alt_keys: &[NeedleAltKey]) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdatePhotoAltKeys {
+			id: photo.id, volume_id: photo.volume_id, new_volume_id, alt_keys: alt_keys.to_vec()
+		})?)
+	}
+
+	fn delete_photo(&self, photo: &Photo) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::DeletePhoto { id: photo.id, volume_id: photo.volume_id })?)
+	}
+
+	fn create_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()> {
+		self.create_physical_volumes(logical_id, &[machine_id])
+	}
+
+	fn create_physical_volumes(&self, logical_id: VolumeId, machine_ids: &[MachineId]) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::CreatePhysicalVolumes {
+			logical_id, machine_ids: machine_ids.to_vec()
+		})?)
+	}
+
+	fn index_physical_volumes(&self) -> Result<Vec<PhysicalVolume>> {
+		expect_result!(self.execute(DirectoryOp::IndexPhysicalVolumes)?, PhysicalVolumes)
+	}
+
+	fn delete_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::DeletePhysicalVolume { logical_id, machine_id })?)
+	}
+
+	fn create_volume_shards(&self, logical_id: VolumeId, data_shards: usize, parity_shards: usize, machine_ids: &[MachineId]) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::CreateVolumeShards {
+			logical_id, data_shards, parity_shards, machine_ids: machine_ids.to_vec()
+		})?)
+	}
+
+	fn index_volume_shards(&self) -> Result<Vec<VolumeShard>> {
+		expect_result!(self.execute(DirectoryOp::IndexVolumeShards)?, VolumeShards)
+	}
+
+	fn create_cache_machine(&self, addr_ip: &str, addr_port: u16) -> Result<CacheMachine> {
+		expect_result!(self.execute(DirectoryOp::CreateCacheMachine {
+			addr_ip: addr_ip.to_owned(), addr_port, time: Utc::now()
+		})?, CacheMachine)
+	}
+
+	fn index_cache_machines(&self) -> Result<Vec<CacheMachine>> {
+		expect_result!(self.execute(DirectoryOp::IndexCacheMachines)?, CacheMachines)
+	}
+
+	fn read_cache_machine(&self, id: MachineId) -> Result<Option<CacheMachine>> {
+		expect_result!(self.execute(DirectoryOp::ReadCacheMachine { id })?, CacheMachineOption)
+	}
+
+	fn update_cache_machine_heartbeat(&self,
+		id: MachineId,
+		ready: bool,
+		weight: u32,
+		addr_ip: &str, addr_port: u16
+	) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdateCacheMachineHeartbeat {
+			id, ready, addr_ip: addr_ip.to_owned(), addr_port, time: Utc::now(), weight
+		})?)
+	}
+
+	fn create_store_machine(&self, addr_ip: &str, addr_port: u16) -> Result<StoreMachine> {
+		expect_result!(self.execute(DirectoryOp::CreateStoreMachine {
+			addr_ip: addr_ip.to_owned(), addr_port, time: Utc::now()
+		})?, StoreMachine)
+	}
+
+	fn index_store_machines(&self) -> Result<Vec<StoreMachine>> {
+		expect_result!(self.execute(DirectoryOp::IndexStoreMachines)?, StoreMachines)
+	}
+
+	fn read_store_machine(&self, id: MachineId) -> Result<Option<StoreMachine>> {
+		expect_result!(self.execute(DirectoryOp::ReadStoreMachine { id })?, StoreMachineOption)
+	}
+
+	fn read_store_machines(&self, ids: &[MachineId]) -> Result<Vec<StoreMachine>> {
+		expect_result!(self.execute(DirectoryOp::ReadStoreMachines { ids: ids.to_vec() })?, StoreMachines)
+	}
+
+	fn read_store_machines_for_volume(&self, vol: VolumeId) -> Result<Vec<StoreMachine>> {
+		expect_result!(self.execute(DirectoryOp::ReadStoreMachinesForVolume { vol })?, StoreMachines)
+	}
+
+	fn update_store_machine_heartbeat(&self,
+		id: MachineId,
+		ready: bool,
+		addr_ip: &str, addr_port: u16,
+		allocated_space: u64, total_space: u64, write_enabled: bool,
+		corrupt_needles: u64
+	) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdateStoreMachineHeartbeat {
+			id, ready, addr_ip: addr_ip.to_owned(), addr_port,
+			allocated_space, total_space, write_enabled,
+			time: Utc::now(),
+			corrupt_needles
+		})?)
+	}
+
+	fn update_store_machine_health(&self, id: MachineId, alive: bool, healthy: bool) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdateStoreMachineHealth { id, alive, healthy })?)
+	}
+
+	fn update_store_machine_location(&self, id: MachineId, region: &str, rack: &str) -> Result<()> {
+		expect_empty!(self.execute(DirectoryOp::UpdateStoreMachineLocation {
+			id, region: region.to_owned(), rack: rack.to_owned()
+		})?)
+	}
+
+}
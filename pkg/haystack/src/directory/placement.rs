@@ -0,0 +1,223 @@
+use super::super::errors::*;
+use super::models::StoreMachine;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+
+
+/// Chooses 'n' machines from the candidates to hold replicas of a volume alongside the 'local' machine
+///
+/// Every chosen machine will be in a different failure domain than the local machine and all other chosen machines. Whenever possible, at least one of them will also be in a different region than the local machine so that the volume survives the loss of a whole region
+pub fn choose_replicas(local: &StoreMachine, candidates: Vec<StoreMachine>, n: usize) -> Result<Vec<StoreMachine>> {
+
+	let mut candidates = candidates;
+
+	// TODO: Possibly more useful to spread across less-allocated machines first (with randomness still though)
+	let mut rng = thread_rng();
+	candidates.shuffle(&mut rng);
+
+	let mut chosen: Vec<StoreMachine> = vec![];
+
+	if n == 0 {
+		return Ok(chosen);
+	}
+
+	if let Some(i) = candidates.iter().position(|m| m.region != local.region) {
+		chosen.push(candidates.remove(i));
+	}
+
+	for m in candidates {
+		if chosen.len() >= n {
+			break;
+		}
+
+		if m.same_failure_domain(local) || chosen.iter().any(|c| m.same_failure_domain(c)) {
+			continue;
+		}
+
+		chosen.push(m);
+	}
+
+	if chosen.len() < n {
+		return Err("Not enough failure domains available to place all replicas".into());
+	}
+
+	Ok(chosen)
+}
+
+/// Chooses a machine from the candidates to hold one more replica of a volume which already has replicas on the 'existing' machines
+///
+/// Same as with choose_replicas, the new machine is never in the failure domain of an existing replica, and if all existing replicas are in one region, a machine in another region is preferred
+pub fn choose_replacement(existing: &[StoreMachine], candidates: Vec<StoreMachine>) -> Result<StoreMachine> {
+
+	let mut candidates = candidates.into_iter().filter(|m| {
+		!existing.iter().any(|e| e.id == m.id || m.same_failure_domain(e))
+	}).collect::<Vec<_>>();
+
+	let mut rng = thread_rng();
+	candidates.shuffle(&mut rng);
+
+	if existing.len() > 0 && existing.iter().all(|e| e.region == existing[0].region) {
+		if let Some(i) = candidates.iter().position(|m| m.region != existing[0].region) {
+			return Ok(candidates.remove(i));
+		}
+	}
+
+	candidates.pop().ok_or_else(|| "No failure domain available for another replica".into())
+}
+
+/// Chooses 'n' distinct machines from the candidates to each hold one shard of an erasure coded volume
+///
+/// There are usually more shards than failure domains, so machines are taken from each failure domain in turn to spread the shards as evenly as possible
+pub fn choose_shards(candidates: Vec<StoreMachine>, n: usize) -> Result<Vec<StoreMachine>> {
+
+	if candidates.len() < n {
+		return Err("Not enough machines available to place all shards".into());
+	}
+
+	let mut candidates = candidates;
+
+	let mut rng = thread_rng();
+	candidates.shuffle(&mut rng);
+
+	let mut domains: Vec<Vec<StoreMachine>> = vec![];
+	for m in candidates {
+		match domains.iter().position(|d| d[0].same_failure_domain(&m)) {
+			Some(i) => domains[i].push(m),
+			None => domains.push(vec![m])
+		};
+	}
+
+	let mut chosen = vec![];
+	while chosen.len() < n {
+		for d in domains.iter_mut() {
+			if chosen.len() >= n {
+				break;
+			}
+
+			if let Some(m) = d.pop() {
+				chosen.push(m);
+			}
+		}
+	}
+
+	Ok(chosen)
+}
+
+/// Orders machines such that those in the given region come first (otherwise keeping the existing order)
+pub fn prefer_region(machines: &mut Vec<StoreMachine>, region: &str) {
+	machines.sort_by_key(|m| m.region != region);
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+
+	fn make_store(id: i32, region: &str, rack: &str) -> StoreMachine {
+		StoreMachine {
+			id,
+			addr_ip: String::from("127.0.0.1"),
+			addr_port: 4000,
+			last_heartbeat: Utc::now(),
+			ready: true,
+			alive: true,
+			healthy: true,
+			allocated_space: 0,
+			total_space: 0,
+			write_enabled: true,
+			region: region.to_owned(),
+			rack: rack.to_owned(),
+			corrupt_needles: 0
+		}
+	}
+
+	#[test]
+	fn choose_replicas_distinct_domains() -> Result<()> {
+		let local = make_store(1, "us", "a");
+
+		let candidates = vec![
+			make_store(2, "us", "a"),
+			make_store(3, "us", "a"),
+			make_store(4, "us", "b"),
+			make_store(5, "eu", "a"),
+			make_store(6, "eu", "a")
+		];
+
+		for _ in 0..10 {
+			let reps = choose_replicas(&local, candidates.clone(), 2)?;
+			assert_eq!(reps.len(), 2);
+
+			// Always one remote replica and nothing on the same rack as us
+			assert!(reps.iter().any(|m| m.region == "eu"));
+			assert!(reps.iter().all(|m| !m.same_failure_domain(&local)));
+			assert!(!reps[0].same_failure_domain(&reps[1]));
+		}
+
+		// Only three racks exist other than our own
+		assert!(choose_replicas(&local, candidates.clone(), 3).is_err());
+
+		// Machines with no known rack can always be used
+		let unknown = vec![make_store(2, "", ""), make_store(3, "", "")];
+		assert_eq!(choose_replicas(&make_store(1, "", ""), unknown, 2)?.len(), 2);
+
+		Ok(())
+	}
+
+	#[test]
+	fn choose_replacement_avoids_existing_domains() -> Result<()> {
+		let existing = vec![make_store(1, "us", "a"), make_store(2, "us", "b")];
+
+		let candidates = vec![
+			make_store(3, "us", "a"),
+			make_store(4, "us", "b"),
+			make_store(5, "us", "c"),
+			make_store(6, "eu", "a")
+		];
+
+		// The other region is preferred while all replicas are in one region
+		for _ in 0..10 {
+			assert_eq!(choose_replacement(&existing, candidates.clone())?.id, 6);
+		}
+
+		let candidates = vec![make_store(3, "us", "a"), make_store(5, "us", "c")];
+		assert_eq!(choose_replacement(&existing, candidates)?.id, 5);
+
+		let candidates = vec![make_store(3, "us", "a"), make_store(4, "us", "b")];
+		assert!(choose_replacement(&existing, candidates).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn choose_shards_spreads_domains() -> Result<()> {
+		let candidates = vec![
+			make_store(1, "us", "a"),
+			make_store(2, "us", "a"),
+			make_store(3, "us", "a"),
+			make_store(4, "us", "b"),
+			make_store(5, "us", "b"),
+			make_store(6, "eu", "a")
+		];
+
+		for _ in 0..10 {
+			let shards = choose_shards(candidates.clone(), 4)?;
+			assert_eq!(shards.len(), 4);
+
+			// Every rack gets a shard before any rack gets a third one
+			assert!(shards.iter().any(|m| m.id == 6));
+			assert!(shards.iter().filter(|m| m.rack == "b").count() >= 1);
+
+			let mut ids = shards.iter().map(|m| m.id).collect::<Vec<_>>();
+			ids.sort();
+			ids.dedup();
+			assert_eq!(ids.len(), 4);
+		}
+
+		assert_eq!(choose_shards(candidates.clone(), 6)?.len(), 6);
+		assert!(choose_shards(candidates.clone(), 7).is_err());
+
+		Ok(())
+	}
+
+}
@@ -0,0 +1,320 @@
+use super::super::errors::*;
+use super::ops::*;
+use super::tables::DirectoryTables;
+use raft::protos::LogIndex;
+use raft::rpc::{marshal, unmarshal};
+use raft::state_machine::{StateMachine, StateMachineSnapshot};
+use raft::atomic::BlobFile;
+use bytes::Bytes;
+use hyper::{Request, Body, Method, StatusCode};
+use futures::prelude::*;
+use futures::future::lazy;
+use futures::sync::oneshot;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::Path;
+
+
+/// Number of log entries applied between persisted snapshots of the tables
+/// Once a snapshot is persisted, raft is free to discard all log entries before it
+const SNAPSHOT_INTERVAL: LogIndex = 1000;
+
+/// Format of the file holding the latest snapshot of the tables
+#[derive(Serialize, Deserialize)]
+struct DirectorySnapshot {
+	/// Index of the last log entry applied to the tables
+	last_applied: LogIndex,
+
+	/// The serialized DirectoryTables (also what is sent to other nodes by raft)
+	data: Vec<u8>
+}
+
+/// The directory tables as a raft state machine
+/// Every directory node holds one of these and applies the same log of mutating DirectoryOps to it
+pub struct DirectoryStateMachine {
+	tables: Mutex<DirectoryTables>,
+
+	/// Where snapshots are persisted (None if the tables are only ever kept in memory)
+	snapshot_file: Option<BlobFile>,
+
+	/// Index and serialized tables of the latest persisted snapshot
+	last_snapshot: Mutex<Option<(LogIndex, Bytes)>>
+}
+
+impl DirectoryStateMachine {
+	pub fn new() -> DirectoryStateMachine {
+		DirectoryStateMachine {
+			tables: Mutex::new(DirectoryTables::default()),
+			snapshot_file: None,
+			last_snapshot: Mutex::new(None)
+		}
+	}
+
+	/// Opens the tables snapshotted in the given folder (or empty tables if nothing has been snapshotted there yet)
+	/// Also returns the index of the last log entry applied to them
+	pub fn open(folder: &Path) -> Result<(DirectoryStateMachine, LogIndex)> {
+		let builder = BlobFile::builder(&folder.join("tables"))?;
+
+		let (file, tables, last_snapshot) = if builder.exists() {
+			let (file, data) = builder.open()?;
+			let snapshot: DirectorySnapshot = unmarshal(&data)?;
+			let tables: DirectoryTables = unmarshal(&snapshot.data)?;
+			(file, tables, Some((snapshot.last_applied, Bytes::from(snapshot.data))))
+		}
+		else {
+			let tables = DirectoryTables::default();
+			let file = builder.create(&marshal(DirectorySnapshot {
+				last_applied: 0,
+				data: marshal(&tables)?
+			})?)?;
+
+			(file, tables, None)
+		};
+
+		let last_applied = last_snapshot.as_ref().map(|s| s.0).unwrap_or(0);
+
+		Ok((DirectoryStateMachine {
+			tables: Mutex::new(tables),
+			snapshot_file: Some(file),
+			last_snapshot: Mutex::new(last_snapshot)
+		}, last_applied))
+	}
+
+	/// Persists a new snapshot of the tables if enough entries have been applied since the last one
+	fn snapshot_if_needed(&self, index: LogIndex, tables: &DirectoryTables) -> raft::errors::Result<()> {
+		let file = match self.snapshot_file {
+			Some(ref f) => f,
+			None => return Ok(())
+		};
+
+		let mut last_snapshot = self.last_snapshot.lock().unwrap();
+
+		let last_index = last_snapshot.as_ref().map(|s| s.0).unwrap_or(0);
+		if index < last_index + SNAPSHOT_INTERVAL {
+			return Ok(());
+		}
+
+		let data = marshal(tables)?;
+		file.store(&marshal(DirectorySnapshot { last_applied: index, data: data.clone() })?)?;
+		*last_snapshot = Some((index, Bytes::from(data)));
+
+		Ok(())
+	}
+
+	/// Performs a read-only operation directly against the local copy of the tables
+	/// NOTE: This may not reflect the latest committed changes unless a linearizable read was first done through the raft server
+	pub fn read(&self, op: DirectoryOp) -> DirectoryOutcome {
+		if !op.is_read() {
+			return Err("Not a read operation".into());
+		}
+
+		let mut tables = self.tables.lock().unwrap();
+		tables.apply(op).map_err(|e| e.to_string())
+	}
+}
+
+impl StateMachine<DirectoryOutcome> for DirectoryStateMachine {
+
+	fn apply(&self, index: LogIndex, op: &[u8]) -> raft::errors::Result<DirectoryOutcome> {
+		let op: DirectoryOp = unmarshal(op)?;
+
+		// NOTE: Failing operations (like a test-and-set that didn't match) are deterministic and don't change the tables, so they are returned as a regular outcome instead of halting the log
+		let mut tables = self.tables.lock().unwrap();
+		let out = tables.apply(op).map_err(|e| e.to_string());
+
+		// Without a snapshot the log just keeps growing, so this doesn't need to stop the log from being applied
+		if let Err(e) = self.snapshot_if_needed(index, &tables) {
+			eprintln!("Failed to snapshot the directory tables: {}", e);
+		}
+
+		Ok(out)
+	}
+
+	fn snapshot(&self) -> Option<StateMachineSnapshot> {
+		self.last_snapshot.lock().unwrap().as_ref().map(|s| {
+			StateMachineSnapshot {
+				last_applied: s.0,
+				data: s.1.clone()
+			}
+		})
+	}
+
+	fn restore(&self, last_applied: LogIndex, data: Bytes) -> raft::errors::Result<()> {
+		let tables: DirectoryTables = unmarshal(&data)?;
+
+		// Must be persisted before raft resets its log to start after this snapshot
+		if let Some(ref file) = self.snapshot_file {
+			let mut last_snapshot = self.last_snapshot.lock().unwrap();
+			file.store(&marshal(DirectorySnapshot { last_applied, data: data.to_vec() })?)?;
+			*last_snapshot = Some((last_applied, data));
+		}
+
+		*self.tables.lock().unwrap() = tables;
+		Ok(())
+	}
+}
+
+
+/// Directory store which forwards every operation to a quorum of directory nodes (started with 'hay directory')
+pub struct RaftStore {
+	/// Base http urls of every directory node we know about
+	addrs: Vec<String>,
+
+	/// Index into addrs of the last node able to execute an operation (most likely the current leader)
+	last_good: AtomicUsize,
+
+	runtime: tokio::runtime::Runtime
+}
+
+impl RaftStore {
+
+	/// Creates a store given the 'host:port' addresses of the http api of each directory node
+	pub fn connect(addrs: &[&str]) -> Result<RaftStore> {
+		if addrs.len() == 0 {
+			return Err("No directory nodes given".into());
+		}
+
+		Ok(RaftStore {
+			addrs: addrs.iter().map(|a| format!("http://{}", a)).collect(),
+			last_good: AtomicUsize::new(0),
+			runtime: tokio::runtime::Runtime::new()?
+		})
+	}
+
+	/// Sends a single encoded operation to one node and blocks until it responds
+	/// NOTE: This may be called from inside other runtimes, so the request is run on our own runtime rather than with block_on
+	fn request(&self, addr: &str, body: Vec<u8>) -> Result<(StatusCode, Bytes)> {
+		let req = Request::builder()
+			.method(Method::POST)
+			.uri(format!("{}/", addr))
+			.header("Content-Type", "application/json; charset=utf-8")
+			.body(Body::from(body))
+			.unwrap();
+
+		let (tx, rx) = oneshot::channel();
+
+		self.runtime.executor().spawn(lazy(move || {
+			hyper::Client::new().request(req)
+			.and_then(|res| {
+				let status = res.status();
+				res.into_body().concat2().map(move |b| (status, b.into_bytes()))
+			})
+			.then(move |r| {
+				let _ = tx.send(r);
+				Ok(())
+			})
+		}));
+
+		let r = rx.wait().map_err(|_| Error::from("Directory request was cancelled"))?;
+		Ok(r?)
+	}
+}
+
+impl DirectoryExecutor for RaftStore {
+	fn execute(&self, op: DirectoryOp) -> Result<DirectoryResult> {
+		let body = serde_json::to_vec(&op)?;
+
+		let n = self.addrs.len();
+		let start = self.last_good.load(Ordering::Relaxed);
+
+		for i in 0..n {
+			let idx = (start + i) % n;
+
+			let (status, data) = match self.request(&self.addrs[idx], body.clone()) {
+				Ok(v) => v,
+				Err(e) => {
+					eprintln!("Directory node {} failed with {:?}", self.addrs[idx], e);
+					continue;
+				}
+			};
+
+			if status == StatusCode::OK {
+				self.last_good.store(idx, Ordering::Relaxed);
+
+				let out: DirectoryOutcome = serde_json::from_slice(&data)?;
+				return out.map_err(|e| e.into());
+			}
+			// Not the leader, so it is safe to try someone else
+			else if status == StatusCode::SERVICE_UNAVAILABLE {
+				continue;
+			}
+			// Otherwise we don't know if the operation was applied, so it is not safe to retry it
+			else {
+				return Err(format!("Directory node failed with status {}", status).into());
+			}
+		}
+
+		Err("No directory node was able to execute the operation".into())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::models::*;
+
+	#[test]
+	fn directory_state_machine_apply() -> Result<()> {
+		let sm = DirectoryStateMachine::new();
+
+		let op = marshal(&DirectoryOp::CreateLogicalVolume { hash_key: 5 })?;
+		match sm.apply(1, &op)? {
+			Ok(DirectoryResult::LogicalVolume(LogicalVolume { id: 1, .. })) => {},
+			_ => panic!("Expected a new volume")
+		};
+
+		// Failures are returned without breaking the log
+		let op = marshal(&DirectoryOp::UpdateLogicalVolumeWriteable { id: 2, is: true })?;
+		assert!(sm.apply(2, &op)?.is_err());
+
+		match sm.read(DirectoryOp::IndexLogicalVolumes) {
+			Ok(DirectoryResult::LogicalVolumes(ref v)) if v.len() == 1 => {},
+			_ => panic!("Expected one volume")
+		};
+
+		Ok(())
+	}
+
+	#[test]
+	fn directory_state_machine_snapshot() -> Result<()> {
+		let folder = Path::new("out/testdirsnapshot");
+		if folder.exists() {
+			std::fs::remove_dir_all(folder)?;
+		}
+		std::fs::create_dir_all(folder)?;
+
+		{
+			let (sm, last_applied) = DirectoryStateMachine::open(folder)?;
+			assert_eq!(last_applied, 0);
+
+			let op = marshal(&DirectoryOp::CreateLogicalVolume { hash_key: 5 })?;
+			sm.apply(1, &op)?;
+			assert!(sm.snapshot().is_none());
+
+			let op = marshal(&DirectoryOp::CreateLogicalVolume { hash_key: 6 })?;
+			sm.apply(SNAPSHOT_INTERVAL, &op)?;
+			assert_eq!(sm.snapshot().map(|s| s.last_applied), Some(SNAPSHOT_INTERVAL));
+		}
+
+		// Reopening restores the snapshotted tables
+		let (sm, last_applied) = DirectoryStateMachine::open(folder)?;
+		assert_eq!(last_applied, SNAPSHOT_INTERVAL);
+
+		match sm.read(DirectoryOp::IndexLogicalVolumes) {
+			Ok(DirectoryResult::LogicalVolumes(ref v)) if v.len() == 2 => {},
+			_ => panic!("Expected two volumes")
+		};
+
+		// Snapshots can be installed on other nodes
+		let other = DirectoryStateMachine::new();
+		other.restore(SNAPSHOT_INTERVAL, sm.snapshot().unwrap().data)?;
+
+		match other.read(DirectoryOp::IndexLogicalVolumes) {
+			Ok(DirectoryResult::LogicalVolumes(ref v)) if v.len() == 2 => {},
+			_ => panic!("Expected two volumes")
+		};
+
+		Ok(())
+	}
+}
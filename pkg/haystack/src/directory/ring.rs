@@ -0,0 +1,214 @@
+use super::super::common::*;
+use super::models::CacheMachine;
+use core::FlipSign;
+use std::hash::Hasher;
+
+
+/// A single virtual node on the ring
+#[derive(Clone, Serialize)]
+pub struct RingPoint {
+	pub position: u64,
+	pub machine_id: MachineId
+}
+
+/// An inclusive span of the hash space that is owned by a single machine
+#[derive(Clone, Serialize)]
+pub struct RingRange {
+	pub start: u64,
+	pub end: u64,
+	pub machine_id: MachineId
+}
+
+/// Consistent hashing ring used to decide which caches are responsible for which photos
+///
+/// Every cache is placed at many pseudo-random points on the ring (more points for higher weights) and owns all hashes between the previous point and each of its own points. This way adding or removing a single cache only moves the photos that it owns (or will own) rather than reshuffling everything
+pub struct HashRing {
+	/// Every virtual node sorted by position
+	points: Vec<RingPoint>
+}
+
+impl HashRing {
+
+	/// Creates a ring containing the given machines
+	/// NOTE: All machines must use the same number of 'virtual_nodes' in order to agree on the layout of the ring
+	pub fn build(machines: &[CacheMachine], virtual_nodes: usize) -> HashRing {
+		let mut points = vec![];
+
+		for m in machines {
+			let n = virtual_nodes * (std::cmp::max(m.weight, 1) as usize);
+
+			for i in 0..n {
+				let mut hasher = siphasher::sip::SipHasher::new_with_keys(0, 0);
+				hasher.write_u32(m.id.flip());
+				hasher.write_u64(i as u64);
+
+				points.push(RingPoint {
+					position: hasher.finish(),
+					machine_id: m.id.flip()
+				});
+			}
+		}
+
+		// Ties are broken by machine id so that all machines build an identical ring
+		points.sort_by(|a, b| (a.position, a.machine_id).cmp(&(b.position, b.machine_id)));
+
+		HashRing { points }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.points.len() == 0
+	}
+
+	/// Gets up to 'n' distinct machines responsible for the given hash in order of preference
+	/// The first machine is the owner of the hash and the rest are the next distinct machines found going clockwise around the ring
+	pub fn lookup(&self, hash: u64, n: usize) -> Vec<MachineId> {
+		let mut out = vec![];
+
+		if self.is_empty() {
+			return out;
+		}
+
+		// First point at or after the hash (wrapping around to the start)
+		let start = match self.points.binary_search_by(|p| p.position.cmp(&hash)) {
+			Ok(i) => i,
+			Err(i) => i
+		};
+
+		for i in 0..self.points.len() {
+			if out.len() >= n {
+				break;
+			}
+
+			let id = self.points[(start + i) % self.points.len()].machine_id;
+			if !out.contains(&id) {
+				out.push(id);
+			}
+		}
+
+		out
+	}
+
+	/// Gets all ranges of the hash space owned by the given machine (as the first choice for those hashes)
+	pub fn ranges_for(&self, machine_id: MachineId) -> Vec<RingRange> {
+		self.ranges().into_iter().filter(|r| r.machine_id == machine_id).collect()
+	}
+
+	/// Splits the entire hash space into ranges by owner
+	/// Adjacent ranges belonging to the same machine are merged together
+	pub fn ranges(&self) -> Vec<RingRange> {
+		let mut out: Vec<RingRange> = vec![];
+
+		let last = match self.points.last() {
+			Some(p) => p.clone(),
+			None => return out
+		};
+
+		let mut start = 0;
+
+		for p in self.points.iter() {
+			// Multiple points at the same position will own nothing but the first one
+			if p.position < start {
+				continue;
+			}
+
+			HashRing::push_range(&mut out, RingRange { start, end: p.position, machine_id: p.machine_id });
+
+			if p.position == std::u64::MAX {
+				return out;
+			}
+
+			start = p.position + 1;
+		}
+
+		// Everything after the last point wraps around to the owner of the first point
+		HashRing::push_range(&mut out, RingRange { start: last.position + 1, end: std::u64::MAX, machine_id: self.points[0].machine_id });
+
+		out
+	}
+
+	fn push_range(out: &mut Vec<RingRange>, range: RingRange) {
+		if let Some(r) = out.last_mut() {
+			if r.machine_id == range.machine_id && r.end + 1 == range.start {
+				r.end = range.end;
+				return;
+			}
+		}
+
+		out.push(range);
+	}
+
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+
+	fn make_cache(id: i32, weight: i32) -> CacheMachine {
+		CacheMachine {
+			id,
+			addr_ip: String::from("127.0.0.1"),
+			addr_port: 4001,
+			last_heartbeat: Utc::now(),
+			ready: true,
+			alive: true,
+			healthy: true,
+			hostname: String::new(),
+			weight
+		}
+	}
+
+	fn hashes() -> Vec<u64> {
+		(0..1000u64).map(|i| {
+			let mut hasher = siphasher::sip::SipHasher::new_with_keys(1, 2);
+			hasher.write_u64(i);
+			hasher.finish()
+		}).collect()
+	}
+
+	#[test]
+	fn hash_ring_stable_when_adding_machines() {
+		let caches = vec![make_cache(1, 1), make_cache(2, 1), make_cache(3, 2)];
+
+		let ring1 = HashRing::build(&caches[0..2], 64);
+		let ring2 = HashRing::build(&caches, 64);
+
+		let mut nmoved = 0;
+		let mut ntaken = 0;
+
+		for h in hashes() {
+			let a = ring1.lookup(h, 1)[0];
+			let b = ring2.lookup(h, 1)[0];
+
+			if a != b {
+				nmoved += 1;
+
+				// Only the new machine should take over anything
+				assert_eq!(b, 3);
+			}
+
+			if b == 3 {
+				ntaken += 1;
+			}
+		}
+
+		assert_eq!(nmoved, ntaken);
+
+		// With double the weight, the new machine should get around half of the hashes
+		assert!(ntaken > 350 && ntaken < 650);
+
+		// Replicas are always distinct machines
+		let reps = ring2.lookup(12345, 5);
+		assert_eq!(reps.len(), 3);
+
+		// The ranges cover the entire hash space
+		let ranges = ring2.ranges();
+		assert_eq!(ranges[0].start, 0);
+		assert_eq!(ranges[ranges.len() - 1].end, std::u64::MAX);
+		for i in 1..ranges.len() {
+			assert_eq!(ranges[i - 1].end + 1, ranges[i].start);
+		}
+	}
+
+}
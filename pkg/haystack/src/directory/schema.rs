@@ -8,6 +8,7 @@ table! {
         alive -> Bool,
         healthy -> Bool,
         hostname -> Text,
+        weight -> Int4,
     }
 }
 
@@ -16,6 +17,8 @@ table! {
         id -> Int4,
         write_enabled -> Bool,
         hash_key -> Int8,
+        data_shards -> Int4,
+        parity_shards -> Int4,
     }
 }
 
@@ -31,6 +34,19 @@ table! {
         id -> Int8,
         volume_id -> Int4,
         cookie -> Bytea,
+        committed -> Bool,
+        created_at -> Timestamptz,
+        alt_keys -> Array<Int4>,
+    }
+}
+
+table! {
+    photo_alt_keys (photo_id, alt_key) {
+        photo_id -> Int8,
+        alt_key -> Int4,
+        size -> Int8,
+        content_type -> Text,
+        created_at -> Timestamptz,
     }
 }
 
@@ -53,18 +69,34 @@ table! {
         allocated_space -> Int8,
         total_space -> Int8,
         write_enabled -> Bool,
+        region -> Text,
+        rack -> Text,
+        corrupt_needles -> Int8,
+    }
+}
+
+table! {
+    volume_shards (logical_id, shard_index) {
+        logical_id -> Int4,
+        shard_index -> Int4,
+        machine_id -> Int4,
     }
 }
 
+joinable!(photo_alt_keys -> photos (photo_id));
 joinable!(photos -> logical_volumes (volume_id));
 joinable!(physical_volumes -> logical_volumes (logical_id));
 joinable!(physical_volumes -> store_machines (machine_id));
+joinable!(volume_shards -> logical_volumes (logical_id));
+joinable!(volume_shards -> store_machines (machine_id));
 
 allow_tables_to_appear_in_same_query!(
     cache_machines,
     logical_volumes,
     params,
+    photo_alt_keys,
     photos,
     physical_volumes,
     store_machines,
+    volume_shards,
 );
@@ -0,0 +1,125 @@
+use super::super::errors::*;
+use super::super::common::*;
+use super::models::*;
+use chrono::{DateTime, Utc};
+
+
+/// All of the persistent state operations needed by the directory
+/// This allows the directory to be backed by a real database server or embedded directly into the running process
+///
+/// NOTE: All ids are in the same signed form as they are stored in the models unless they are taken as a VolumeId/MachineId/NeedleKey
+pub trait DirectoryStore {
+
+	fn get_param(&self, key: i32) -> Result<Option<Vec<u8>>>;
+
+	/// Creates a new parameter. Errors out if it already exists
+	fn create_param(&self, key: i32, value: Vec<u8>) -> Result<()>;
+
+
+	fn create_logical_volume(&self, vol: &NewLogicalVolume) -> Result<LogicalVolume>;
+
+	fn index_logical_volumes(&self) -> Result<Vec<LogicalVolume>>;
+
+	fn read_logical_volume(&self, id: VolumeId) -> Result<Option<LogicalVolume>>;
+
+	/// Find all logical volumes associated with a single machine
+	fn read_logical_volumes_for_store_machine(&self, id: MachineId) -> Result<Vec<LogicalVolume>>;
+
+	fn update_logical_volume_writeable(&self, id: VolumeId, is: bool) -> Result<()>;
+
+
+	/// Creates a new uncommitted photo (see commit_photo)
+	fn create_photo(&self, new_photo: &NewPhoto) -> Result<Photo>;
+
+	fn read_photo(&self, id: NeedleKey) -> Result<Option<Photo>>;
+
+	/// Picks a random sample of up to 'limit' committed photos stored in the given logical volume
+	fn read_random_photos_for_volume(&self, vol: VolumeId, limit: usize) -> Result<Vec<Photo>>;
+
+	/// Lists every photo in the given logical volume (including uncommitted ones) in order of id
+	fn index_photos_for_volume(&self, vol: VolumeId) -> Result<Vec<Photo>>;
+
+	/// Records details about some alt keys of photos (replacing anything previously recorded for the same alt keys)
+	fn record_photo_alt_keys(&self, alt_keys: &[PhotoAltKey]) -> Result<()>;
+
+	/// Gets the details of every alt key recorded for a photo in order of alt key
+	fn read_photo_alt_keys(&self, id: NeedleKey) -> Result<Vec<PhotoAltKey>>;
+
+	/// Makes an uncommitted photo visible to readers
+	/// Will succeed only if the photo is still uncommitted and hasn't changed volumes since last time we checked
+	fn commit_photo(&self, photo: &Photo) -> Result<()>;
+
+	/// Finds up to 'limit' photos that were created before the given time but never committed
+	fn read_uncommitted_photos(&self, before: DateTime<Utc>, limit: usize) -> Result<Vec<Photo>>;
+
+	/// Deletes a photo only if it still hasn't been committed
+	/// Will succeed if and only if the photo is still uncommitted and in the same volume
+	fn delete_uncommitted_photo(&self, photo: &Photo) -> Result<()>;
+
+	/// Performs a test-and-set on the volume_id of a photo
+	/// Will succeed only if operation ended up changing the volume_id
+	fn update_photo_volume_id(&self, photo: &Photo, new_volume_id: VolumeId) -> Result<()>;
+
+	/// Moves a photo to a (possibly the same) logical volume while replacing the set of alt keys that it has
+	/// Performs a test-and-set on the volume_id of the photo, so this will only succeed if the photo hasn't been moved since last time we checked
+	fn update_photo_alt_keys(&self, photo: &Photo, new_volume_id: VolumeId, alt_keys: &[NeedleAltKey]) -> Result<()>;
+
+	/// Deletes a photo
+	/// Will succeed if and only if the logical volume hasn't changed since last time we checked
+	fn delete_photo(&self, photo: &Photo) -> Result<()>;
+
+
+	fn create_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()>;
+
+	/// Adds many replicas to a logical volume all at once (either all or none of them will be added)
+	fn create_physical_volumes(&self, logical_id: VolumeId, machine_ids: &[MachineId]) -> Result<()>;
+
+	fn index_physical_volumes(&self) -> Result<Vec<PhysicalVolume>>;
+
+	/// Removes a single replica from a logical volume (used when a machine is completely dead and not recoverable)
+	fn delete_physical_volume(&self, logical_id: VolumeId, machine_id: MachineId) -> Result<()>;
+
+
+	/// Records that a logical volume was erasure coded with shard i being placed on machine_ids[i] (either all or none of this will be recorded)
+	fn create_volume_shards(&self, logical_id: VolumeId, data_shards: usize, parity_shards: usize, machine_ids: &[MachineId]) -> Result<()>;
+
+	fn index_volume_shards(&self) -> Result<Vec<VolumeShard>>;
+
+
+	fn create_cache_machine(&self, addr_ip: &str, addr_port: u16) -> Result<CacheMachine>;
+
+	fn index_cache_machines(&self) -> Result<Vec<CacheMachine>>;
+
+	fn read_cache_machine(&self, id: MachineId) -> Result<Option<CacheMachine>>;
+
+	fn update_cache_machine_heartbeat(&self,
+		id: MachineId,
+		ready: bool,
+		weight: u32,
+		addr_ip: &str, addr_port: u16
+	) -> Result<()>;
+
+
+	fn create_store_machine(&self, addr_ip: &str, addr_port: u16) -> Result<StoreMachine>;
+
+	fn index_store_machines(&self) -> Result<Vec<StoreMachine>>;
+
+	fn read_store_machine(&self, id: MachineId) -> Result<Option<StoreMachine>>;
+
+	fn read_store_machines(&self, ids: &[MachineId]) -> Result<Vec<StoreMachine>>;
+
+	fn read_store_machines_for_volume(&self, vol: VolumeId) -> Result<Vec<StoreMachine>>;
+
+	fn update_store_machine_heartbeat(&self,
+		id: MachineId,
+		ready: bool,
+		addr_ip: &str, addr_port: u16,
+		allocated_space: u64, total_space: u64, write_enabled: bool,
+		corrupt_needles: u64
+	) -> Result<()>;
+
+	fn update_store_machine_health(&self, id: MachineId, alive: bool, healthy: bool) -> Result<()>;
+
+	fn update_store_machine_location(&self, id: MachineId, region: &str, rack: &str) -> Result<()>;
+
+}
@@ -0,0 +1,426 @@
+use super::super::errors::*;
+use super::super::common::*;
+use super::models::*;
+use super::ops::*;
+use core::FlipSign;
+use std::collections::BTreeMap;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+
+
+/// Everything in the directory as a set of in-memory tables mirroring the postgres schema
+/// Used by every directory store that doesn't have a real database behind it
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct DirectoryTables {
+	params: BTreeMap<i32, Vec<u8>>,
+	store_machines: BTreeMap<i32, StoreMachine>,
+	cache_machines: BTreeMap<i32, CacheMachine>,
+	logical_volumes: BTreeMap<i32, LogicalVolume>,
+	physical_volumes: Vec<PhysicalVolume>,
+	volume_shards: Vec<VolumeShard>,
+	photos: BTreeMap<i64, Photo>,
+
+	/// Details of every alt key of each photo (sorted by alt key)
+	photo_alt_keys: BTreeMap<i64, Vec<PhotoAltKey>>,
+
+	/// Last id handed out for each table with an auto-incrementing id (equivalent to the SERIAL sequences in postgres)
+	last_ids: TableSequences
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct TableSequences {
+	store_machine: i32,
+	cache_machine: i32,
+	logical_volume: i32,
+	photo: i64
+}
+
+impl DirectoryTables {
+
+	/// Performs a single operation on the tables
+	///
+	/// NOTE: Every operation checks that it will succeed before modifying anything, so the tables are left untouched if an error is returned
+	pub fn apply(&mut self, op: DirectoryOp) -> Result<DirectoryResult> {
+		Ok(match op {
+			DirectoryOp::GetParam { key } => {
+				DirectoryResult::Param(self.params.get(&key).cloned())
+			},
+			DirectoryOp::CreateParam { key, value } => {
+				if self.params.contains_key(&key) {
+					return Err("Failed to insert new param".into());
+				}
+
+				self.params.insert(key, value);
+				DirectoryResult::Empty
+			},
+
+			DirectoryOp::CreateLogicalVolume { hash_key } => {
+				self.last_ids.logical_volume += 1;
+
+				let v = LogicalVolume {
+					id: self.last_ids.logical_volume,
+					write_enabled: false,
+					hash_key,
+					data_shards: 0,
+					parity_shards: 0
+				};
+
+				self.logical_volumes.insert(v.id, v.clone());
+				DirectoryResult::LogicalVolume(v)
+			},
+			DirectoryOp::IndexLogicalVolumes => {
+				DirectoryResult::LogicalVolumes(self.logical_volumes.values().cloned().collect())
+			},
+			DirectoryOp::ReadLogicalVolume { id } => {
+				DirectoryResult::LogicalVolumeOption(self.logical_volumes.get(&id.flip()).cloned())
+			},
+			DirectoryOp::ReadLogicalVolumesForStoreMachine { id } => {
+				DirectoryResult::LogicalVolumes(
+					self.physical_volumes.iter()
+					.filter(|p| p.machine_id == id.flip())
+					.filter_map(|p| self.logical_volumes.get(&p.logical_id).cloned())
+					.collect()
+				)
+			},
+			DirectoryOp::UpdateLogicalVolumeWriteable { id, is } => {
+				match self.logical_volumes.get_mut(&id.flip()) {
+					Some(v) => v.write_enabled = is,
+					None => return Err("Nothing modified".into())
+				};
+
+				DirectoryResult::Empty
+			},
+
+			DirectoryOp::CreatePhoto { volume_id, cookie, time, alt_keys } => {
+				if !self.logical_volumes.contains_key(&volume_id) {
+					return Err("Photo volume does not exist".into());
+				}
+
+				if cookie.len() != std::mem::size_of::<Cookie>() {
+					return Err("Invalid photo cookie".into());
+				}
+
+				self.last_ids.photo += 1;
+
+				let p = Photo {
+					id: self.last_ids.photo,
+					volume_id,
+					cookie,
+					committed: false,
+					created_at: time,
+					alt_keys
+				};
+
+				self.photos.insert(p.id, p.clone());
+				DirectoryResult::Photo(p)
+			},
+			DirectoryOp::ReadPhoto { id } => {
+				DirectoryResult::PhotoOption(self.photos.get(&id.flip()).cloned())
+			},
+			DirectoryOp::ReadRandomPhotosForVolume { vol, limit } => {
+				let mut arr = self.photos.values()
+					.filter(|p| p.volume_id == vol.flip() && p.committed)
+					.cloned()
+					.collect::<Vec<_>>();
+
+				arr.shuffle(&mut thread_rng());
+				arr.truncate(limit);
+				DirectoryResult::Photos(arr)
+			},
+			DirectoryOp::IndexPhotosForVolume { vol } => {
+				DirectoryResult::Photos(
+					self.photos.values()
+					.filter(|p| p.volume_id == vol.flip())
+					.cloned()
+					.collect()
+				)
+			},
+			DirectoryOp::RecordPhotoAltKeys { alt_keys } => {
+				if alt_keys.iter().any(|k| !self.photos.contains_key(&k.photo_id)) {
+					return Err("Photo does not exist".into());
+				}
+
+				for k in alt_keys {
+					let arr = self.photo_alt_keys.entry(k.photo_id).or_insert(vec![]);
+					arr.retain(|other| other.alt_key != k.alt_key);
+					arr.push(k);
+					arr.sort_by_key(|other| other.alt_key);
+				}
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::ReadPhotoAltKeys { id } => {
+				DirectoryResult::PhotoAltKeys(self.photo_alt_keys.get(&id.flip()).cloned().unwrap_or(vec![]))
+			},
+			DirectoryOp::CommitPhoto { id, volume_id } => {
+				match self.photos.get_mut(&id) {
+					Some(ref mut p) if p.volume_id == volume_id && !p.committed => {
+						p.committed = true;
+					},
+					_ => return Err("Nothing modified".into())
+				};
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::ReadUncommittedPhotos { before, limit } => {
+				DirectoryResult::Photos(
+					self.photos.values()
+					.filter(|p| !p.committed && p.created_at < before)
+					.take(limit)
+					.cloned()
+					.collect()
+				)
+			},
+			DirectoryOp::DeleteUncommittedPhoto { id, volume_id } => {
+				let matches = match self.photos.get(&id) {
+					Some(p) => p.volume_id == volume_id && !p.committed,
+					None => false
+				};
+
+				if !matches {
+					return Err("Nothing modified".into());
+				}
+
+				self.photos.remove(&id);
+				self.photo_alt_keys.remove(&id);
+				DirectoryResult::Empty
+			},
+			DirectoryOp::UpdatePhotoVolumeId { id, volume_id, new_volume_id } => {
+				match self.photos.get_mut(&id) {
+					Some(ref mut p) if p.volume_id == volume_id => {
+						p.volume_id = new_volume_id.flip();
+					},
+					_ => return Err("Nothing modified".into())
+				};
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::UpdatePhotoAltKeys { id, volume_id, new_volume_id, alt_keys } => {
+				match self.photos.get_mut(&id) {
+					Some(ref mut p) if p.volume_id == volume_id => {
+						p.volume_id = new_volume_id.flip();
+						p.alt_keys = alt_keys.into_iter().map(|k| k.flip()).collect();
+					},
+					_ => return Err("Nothing modified".into())
+				};
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::DeletePhoto { id, volume_id } => {
+				let matches = match self.photos.get(&id) {
+					Some(p) => p.volume_id == volume_id,
+					None => false
+				};
+
+				if !matches {
+					return Err("Nothing modified".into());
+				}
+
+				self.photos.remove(&id);
+				self.photo_alt_keys.remove(&id);
+				DirectoryResult::Empty
+			},
+
+			DirectoryOp::CreatePhysicalVolumes { logical_id, machine_ids } => {
+				let lid = logical_id.flip();
+
+				if !self.logical_volumes.contains_key(&lid) {
+					return Err("Logical volume does not exist".into());
+				}
+
+				for (i, m) in machine_ids.iter().enumerate() {
+					let mid = m.flip();
+					if !self.store_machines.contains_key(&mid) || self.has_physical_volume(lid, mid) || machine_ids[0..i].contains(m) {
+						return Err("Nothing modified".into());
+					}
+				}
+
+				for m in machine_ids {
+					self.physical_volumes.push(PhysicalVolume {
+						logical_id: lid,
+						machine_id: m.flip()
+					});
+				}
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::IndexPhysicalVolumes => {
+				DirectoryResult::PhysicalVolumes(self.physical_volumes.clone())
+			},
+			DirectoryOp::DeletePhysicalVolume { logical_id, machine_id } => {
+				let idx = self.physical_volumes.iter().position(|p| {
+					p.logical_id == logical_id.flip() && p.machine_id == machine_id.flip()
+				});
+
+				match idx {
+					Some(i) => { self.physical_volumes.remove(i); },
+					None => return Err("Nothing modified".into())
+				};
+
+				DirectoryResult::Empty
+			},
+
+			DirectoryOp::CreateVolumeShards { logical_id, data_shards, parity_shards, machine_ids } => {
+				let lid = logical_id.flip();
+
+				match self.logical_volumes.get(&lid) {
+					Some(v) if !v.is_encoded() => {},
+					_ => return Err("Nothing modified".into())
+				};
+
+				if data_shards == 0 || machine_ids.len() != data_shards + parity_shards {
+					return Err("Need exactly one machine per shard".into());
+				}
+
+				if machine_ids.iter().any(|m| !self.store_machines.contains_key(&m.flip())) {
+					return Err("Nothing modified".into());
+				}
+
+				for (i, m) in machine_ids.iter().enumerate() {
+					self.volume_shards.push(VolumeShard {
+						logical_id: lid,
+						shard_index: i as i32,
+						machine_id: m.flip()
+					});
+				}
+
+				let v = self.logical_volumes.get_mut(&lid).unwrap();
+				v.data_shards = data_shards as i32;
+				v.parity_shards = parity_shards as i32;
+
+				DirectoryResult::Empty
+			},
+			DirectoryOp::IndexVolumeShards => {
+				DirectoryResult::VolumeShards(self.volume_shards.clone())
+			},
+
+			DirectoryOp::CreateCacheMachine { addr_ip, addr_port, time } => {
+				self.last_ids.cache_machine += 1;
+
+				let m = CacheMachine {
+					id: self.last_ids.cache_machine,
+					addr_ip,
+					addr_port: addr_port.flip(),
+					last_heartbeat: time,
+					ready: false,
+					alive: false,
+					healthy: false,
+					hostname: String::new(),
+					weight: 1
+				};
+
+				self.cache_machines.insert(m.id, m.clone());
+				DirectoryResult::CacheMachine(m)
+			},
+			DirectoryOp::IndexCacheMachines => {
+				DirectoryResult::CacheMachines(self.cache_machines.values().cloned().collect())
+			},
+			DirectoryOp::ReadCacheMachine { id } => {
+				DirectoryResult::CacheMachineOption(self.cache_machines.get(&id.flip()).cloned())
+			},
+			DirectoryOp::UpdateCacheMachineHeartbeat { id, ready, addr_ip, addr_port, time, weight } => {
+				let m = match self.cache_machines.get_mut(&id.flip()) {
+					Some(m) => m,
+					None => return Err("Nothing modified".into())
+				};
+
+				m.ready = ready;
+				m.addr_ip = addr_ip;
+				m.addr_port = addr_port.flip();
+				m.last_heartbeat = time;
+				m.weight = std::cmp::max(weight, 1).flip();
+				DirectoryResult::Empty
+			},
+
+			DirectoryOp::CreateStoreMachine { addr_ip, addr_port, time } => {
+				self.last_ids.store_machine += 1;
+
+				// NOTE: Same defaults as in the postgres schema
+				let m = StoreMachine {
+					id: self.last_ids.store_machine,
+					addr_ip,
+					addr_port: addr_port.flip(),
+					last_heartbeat: time,
+					ready: false,
+					alive: true,
+					healthy: true,
+					allocated_space: 0,
+					total_space: 0,
+					write_enabled: false,
+					region: String::new(),
+					rack: String::new(),
+					corrupt_needles: 0
+				};
+
+				self.store_machines.insert(m.id, m.clone());
+				DirectoryResult::StoreMachine(m)
+			},
+			DirectoryOp::IndexStoreMachines => {
+				DirectoryResult::StoreMachines(self.store_machines.values().cloned().collect())
+			},
+			DirectoryOp::ReadStoreMachine { id } => {
+				DirectoryResult::StoreMachineOption(self.store_machines.get(&id.flip()).cloned())
+			},
+			DirectoryOp::ReadStoreMachines { ids } => {
+				DirectoryResult::StoreMachines(
+					self.store_machines.values()
+					.filter(|m| ids.contains(&m.id.flip()))
+					.cloned()
+					.collect()
+				)
+			},
+			DirectoryOp::ReadStoreMachinesForVolume { vol } => {
+				DirectoryResult::StoreMachines(
+					self.physical_volumes.iter()
+					.filter(|p| p.logical_id == vol.flip())
+					.filter_map(|p| self.store_machines.get(&p.machine_id).cloned())
+					.collect()
+				)
+			},
+			DirectoryOp::UpdateStoreMachineHeartbeat {
+				id, ready, addr_ip, addr_port, allocated_space, total_space, write_enabled, time, corrupt_needles
+			} => {
+				let m = match self.store_machines.get_mut(&id.flip()) {
+					Some(m) => m,
+					None => return Err("Nothing modified".into())
+				};
+
+				m.ready = ready;
+				m.addr_ip = addr_ip;
+				m.addr_port = addr_port.flip();
+				m.last_heartbeat = time;
+				m.allocated_space = allocated_space.flip();
+				m.total_space = total_space.flip();
+				m.write_enabled = write_enabled;
+				m.corrupt_needles = corrupt_needles.flip();
+				DirectoryResult::Empty
+			},
+			DirectoryOp::UpdateStoreMachineHealth { id, alive, healthy } => {
+				let m = match self.store_machines.get_mut(&id.flip()) {
+					Some(m) => m,
+					None => return Err("Nothing modified".into())
+				};
+
+				m.alive = alive;
+				m.healthy = healthy;
+				DirectoryResult::Empty
+			},
+			DirectoryOp::UpdateStoreMachineLocation { id, region, rack } => {
+				let m = match self.store_machines.get_mut(&id.flip()) {
+					Some(m) => m,
+					None => return Err("Nothing modified".into())
+				};
+
+				m.region = region;
+				m.rack = rack;
+				DirectoryResult::Empty
+			}
+		})
+	}
+
+	fn has_physical_volume(&self, logical_id: i32, machine_id: i32) -> bool {
+		self.physical_volumes.iter().any(|p| p.logical_id == logical_id && p.machine_id == machine_id)
+	}
+}
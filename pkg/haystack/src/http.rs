@@ -5,6 +5,7 @@ use hyper::service::service_fn;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use super::errors::Error;
+use mime_sniffer::MimeTypeSniffer;
 
 pub fn bad_request() -> Response<Body> {
 	Response::builder().status(StatusCode::BAD_REQUEST).body(Body::empty()).unwrap()
@@ -35,6 +36,13 @@ pub fn text_response(code: StatusCode, text: &'static str) -> Response<Body> {
 		.unwrap()
 }
 
+/// Guesses the MIME type of some data from its first few bytes
+pub fn sniff_content_type(data: &[u8]) -> String {
+	let magic = data[0..std::cmp::min(8, data.len())].to_vec();
+	let mime = if magic.len() > 4 { magic.sniff_mime_type() } else { None };
+	mime.unwrap_or("application/octet-stream").to_owned()
+}
+
 /// Wraps a regular async request in a wrapper that logs out errors and nicely responds to clients on errors
 /// NOTE: The error type doesn't really matter as we never resolve to a error, just as long as it is sendable across threads, hyper won't complain
 pub fn handle_request_guard<F, P, I>(
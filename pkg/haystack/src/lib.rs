@@ -25,6 +25,7 @@ extern crate hyper;
 extern crate ctrlc;
 extern crate siphasher;
 extern crate tokio;
+extern crate raft;
 
 
 pub mod errors {
@@ -33,6 +34,8 @@ pub mod errors {
 			Io(::std::io::Error);
 			Db(diesel::result::Error);
 			HTTP(hyper::Error);
+			Json(serde_json::Error);
+			Raft(raft::errors::Error);
 		}
 
 		errors {
@@ -64,5 +67,6 @@ mod paths;
 pub mod store;
 pub mod directory;
 pub mod cache;
+pub mod pitchfork;
 pub mod client;
 
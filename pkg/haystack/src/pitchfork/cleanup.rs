@@ -0,0 +1,52 @@
+use super::super::errors::*;
+use super::super::directory::Directory;
+use super::super::client::Client;
+use core::FlipSign;
+use futures::future::*;
+use tokio::runtime::Runtime;
+use chrono::{Utc, Duration};
+
+
+/// Maximum number of abandoned photos cleaned up in each pass
+const MAX_PHOTOS_PER_PASS: usize = 1000;
+
+
+/// Deletes photos whose uploads were started but never committed within the upload timeout
+///
+/// Each photo is removed from the directory before its needles are deleted so that a late commit by the uploading client can never make a half deleted photo readable. Deletes are best effort: if some stores fail to delete their needles, then those needles are left dangling as compaction only reclaims needles that are marked as deleted (they can still be found through the volume list route)
+pub fn cleanup_uncommitted_photos(dir: &Directory, runtime: &mut Runtime) -> Result<()> {
+
+	let config = dir.config.clone();
+
+	let before = Utc::now() - Duration::milliseconds(config.pitchfork.upload_timeout as i64);
+
+	for photo in dir.db.read_uncommitted_photos(before, MAX_PHOTOS_PER_PASS)? {
+		let key = photo.id.flip();
+		let volume_id = photo.volume_id.flip();
+
+		// Fails if the upload was committed since we listed it
+		if dir.db.delete_uncommitted_photo(&photo).is_err() {
+			continue;
+		}
+
+		println!("- Photo {}: removing abandoned upload from volume {}", key, volume_id);
+
+		let machines = dir.read_store_machines_for_volume(volume_id)?;
+
+		// A failure on one store should not stop the deletes on the others
+		let arr = machines.iter().map(|m| {
+			let machine_id = m.id.flip();
+			Client::delete_needles(m, volume_id, key).then(move |res| -> FutureResult<(), Error> {
+				if let Err(e) = res {
+					eprintln!("Photo {}: failed to delete needles from Store {}: {:?}", key, machine_id, e);
+				}
+
+				ok(())
+			})
+		}).collect::<Vec<_>>();
+
+		runtime.block_on(join_all(arr))?;
+	}
+
+	Ok(())
+}
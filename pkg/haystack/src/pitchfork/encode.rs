@@ -0,0 +1,218 @@
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::paths::*;
+use super::super::directory::{Directory, placement};
+use super::super::directory::models;
+use super::super::store::api::*;
+use super::health::{store_get, with_timeout};
+use core::FlipSign;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use hyper::{Body, StatusCode};
+use futures::prelude::*;
+use futures::future::*;
+use tokio::runtime::Runtime;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+
+
+/// Erasure codes sealed volumes so that they no longer need to be kept around as full replicas
+///
+/// - Only read-only volumes that have all of their replicas on readable stores and which their stores report as full are considered
+/// - One of the replicas encodes the volume and sends each shard to a different store
+/// - Once every shard is in place, the shards are recorded in the directory and only then are the replicas dropped
+///
+/// NOTE: At most one volume is encoded per pass as this moves a whole volume worth of data around the cluster
+pub fn encode_volumes(dir: &Mutex<Directory>, runtime: &mut Runtime) -> Result<()> {
+
+	// The directory is only locked while encoding so that other tasks can use it while we wait to delete the replicas
+	let (config, encoded) = {
+		let dir = dir.lock().unwrap();
+		(dir.config.clone(), encode_next_volume(&dir, runtime)?)
+	};
+
+	let (volume_id, replicas) = match encoded {
+		Some(v) => v,
+		None => return Ok(())
+	};
+
+	// Other machines may still be reading from the replicas until their copy of the cluster expires
+	std::thread::sleep(Duration::from_millis(config.directory.cluster_max_age));
+
+	// The replicas are no longer referenced by the directory, so failing to delete them only leaves behind some stray files
+	let deletes = replicas.iter().map(|mac| {
+		let id = mac.id.flip();
+		delete_volume(mac, volume_id).then(move |res| -> FutureResult<(), Error> {
+			if let Err(e) = res {
+				eprintln!("Volume {}: failed to delete replica on store {}: {:?}", volume_id, id, e);
+			}
+
+			ok(())
+		})
+	}).collect::<Vec<_>>();
+
+	runtime.block_on(join_all(deletes))?;
+
+	Ok(())
+}
+
+/// Encodes the first volume that is ready to be encoded and records its shards in the directory
+/// Returns the id of the volume along with the stores holding its now unused replicas
+fn encode_next_volume(dir: &Directory, runtime: &mut Runtime) -> Result<Option<(VolumeId, Vec<models::StoreMachine>)>> {
+
+	let config = dir.config.clone();
+
+	if !config.pitchfork.encode {
+		return Ok(None);
+	}
+
+	let data_shards = config.pitchfork.encode_data_shards;
+	let parity_shards = config.pitchfork.encode_parity_shards;
+
+	let machines = dir.db.index_store_machines()?;
+	let machines_by_id = machines.iter().map(|m| (m.id.flip(), m)).collect::<HashMap<MachineId, _>>();
+
+	let mut volume_machines: HashMap<VolumeId, Vec<MachineId>> = HashMap::new();
+	for p in dir.db.index_physical_volumes()? {
+		volume_machines.entry(p.logical_id.flip()).or_insert(vec![]).push(p.machine_id.flip());
+	}
+
+	for v in dir.db.index_logical_volumes()? {
+		if v.write_enabled || v.is_encoded() {
+			continue;
+		}
+
+		let volume_id = v.id.flip();
+		let replicas = volume_machines.get(&volume_id).cloned().unwrap_or(vec![]);
+
+		// Anything missing replicas must first be repaired so that we encode from a known good copy
+		if replicas.len() < config.store.num_replicas || !replicas.iter().all(|id| machines_by_id[id].can_read(&config)) {
+			continue;
+		}
+
+		let source = machines_by_id[replicas.choose(&mut thread_rng()).unwrap()];
+
+		let timeout = Duration::from_millis(config.pitchfork.probe_timeout);
+		let info = match runtime.block_on(read_volume(source, volume_id, timeout)) {
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Volume {}: failed to read from store {}: {:?}", volume_id, source.id, e);
+				continue;
+			}
+		};
+
+		// Volumes that still have space may be made writeable again
+		if info.writeable || info.compaction.is_some() {
+			continue;
+		}
+
+		let candidates = machines.iter().filter(|m| m.can_allocate(&config)).cloned().collect::<Vec<_>>();
+		let targets = match placement::choose_shards(candidates, data_shards + parity_shards) {
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Volume {}: can not place shards: {:?}", volume_id, e);
+				return Ok(None);
+			}
+		};
+
+		let target_ids = targets.iter().map(|m| m.id.flip()).collect::<Vec<MachineId>>();
+
+		println!("- Volume {}: encoding into {}+{} shards on store {}", volume_id, data_shards, parity_shards, source.id);
+
+		let req = StoreEncodeRequest {
+			data_shards,
+			parity_shards,
+			machine_ids: target_ids.clone()
+		};
+
+		let res = match runtime.block_on(encode_volume(source, volume_id, &req)) {
+			Ok(r) => r,
+			Err(e) => {
+				eprintln!("Volume {}: encoding failed: {:?}", volume_id, e);
+				return Ok(None);
+			}
+		};
+
+		dir.create_volume_shards(volume_id, data_shards, parity_shards, &target_ids)?;
+
+		for id in replicas.iter() {
+			dir.delete_physical_volume(volume_id, *id)?;
+		}
+
+		println!("- Volume {}: encoded {} needles ({} bytes per shard)", volume_id, res.num_needles, res.shard_size);
+
+		let stores = replicas.iter().map(|id| machines_by_id[id].clone()).collect::<Vec<_>>();
+		return Ok(Some((volume_id, stores)));
+	}
+
+	Ok(None)
+}
+
+fn read_volume(
+	mac: &models::StoreMachine, volume_id: VolumeId, timeout: Duration
+) -> impl Future<Item=StoreReadVolumeBody, Error=Error> {
+
+	with_timeout(store_get(&mac.addr(), mac.id.flip(), StorePath::Volume { volume_id }), timeout)
+	.and_then(|(status, body)| {
+		if status != StatusCode::OK {
+			return err(format!("Volume read failed with code: {}", status).into());
+		}
+
+		match serde_json::from_slice::<StoreReadVolumeBody>(&body) {
+			Ok(v) => ok(v),
+			Err(_) => err("Invalid json response received".into())
+		}
+	})
+}
+
+fn encode_volume(
+	mac: &models::StoreMachine, volume_id: VolumeId, req: &StoreEncodeRequest
+) -> impl Future<Item=StoreEncodeResponse, Error=Error> {
+
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", mac.addr(), StorePath::VolumeEncode { volume_id }.to_string()))
+		.method("POST")
+		.header("Host", Host::Store(mac.id.flip()).to_string())
+		.header("Content-Type", "application/json")
+		.body(Body::from(serde_json::to_string(req).unwrap()))
+		.unwrap();
+
+	hyper::Client::new().request(req)
+	.map_err(|e| e.into())
+	.and_then(|resp| {
+		let status = resp.status();
+
+		resp.into_body().concat2()
+		.map_err(|e| e.into())
+		.and_then(move |body| {
+			if status != StatusCode::OK {
+				return err(format!("Encode failed with code: {} ({})", status, String::from_utf8_lossy(&body)).into());
+			}
+
+			match serde_json::from_slice::<StoreEncodeResponse>(&body) {
+				Ok(res) => ok(res),
+				Err(_) => err("Invalid json response received".into())
+			}
+		})
+	})
+}
+
+fn delete_volume(mac: &models::StoreMachine, volume_id: VolumeId) -> impl Future<Item=(), Error=Error> {
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", mac.addr(), StorePath::Volume { volume_id }.to_string()))
+		.method("DELETE")
+		.header("Host", Host::Store(mac.id.flip()).to_string())
+		.body(Body::empty())
+		.unwrap();
+
+	hyper::Client::new().request(req)
+	.map_err(|e| e.into())
+	.and_then(|resp| {
+		if !resp.status().is_success() && resp.status() != StatusCode::NOT_FOUND {
+			return err(format!("Delete failed with code: {}", resp.status()).into());
+		}
+
+		ok(())
+	})
+}
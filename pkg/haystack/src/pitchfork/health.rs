@@ -0,0 +1,248 @@
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::paths::*;
+use super::super::directory::Directory;
+use super::super::directory::models;
+use super::super::store::api::*;
+use core::FlipSign;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use hyper::{Body, StatusCode};
+use futures::prelude::*;
+use futures::future::*;
+use tokio::runtime::Runtime;
+use tokio::timer::Timeout;
+
+
+/// Outcome of checking a single store machine
+struct ProbeResult {
+	machine_id: MachineId,
+
+	/// Whether or not the machine responded to us at all
+	alive: bool,
+
+	/// Whether or not every check performed against the machine succeeded
+	healthy: bool,
+
+	/// All volumes which the machine reported as having space for more writes
+	writeable_volumes: Vec<VolumeId>
+}
+
+impl ProbeResult {
+	fn dead(machine_id: MachineId) -> ProbeResult {
+		ProbeResult {
+			machine_id,
+			alive: false,
+			healthy: false,
+			writeable_volumes: vec![]
+		}
+	}
+}
+
+
+/// Performs one complete pass of health checking over all store machines and logical volumes in the cluster
+///
+/// - Every ready store is probed by listing its volumes and reading random needles from each volume
+/// - Health changes are recorded in the directory and all volumes on unhealthy machines are made read-only
+/// - Writeable volumes that are missing replicas are made read-only
+/// - Read-only volumes whose replicas are all healthy and have space left are made writeable again
+pub fn check_cluster(dir: &Directory, runtime: &mut Runtime) -> Result<()> {
+
+	let config = dir.config.clone();
+
+	let machines = dir.db.index_store_machines()?;
+	let volumes = dir.db.index_logical_volumes()?;
+
+	let mut machine_volumes: HashMap<MachineId, Vec<VolumeId>> = HashMap::new();
+	let mut volume_machines: HashMap<VolumeId, Vec<MachineId>> = HashMap::new();
+	for p in dir.db.index_physical_volumes()? {
+		machine_volumes.entry(p.machine_id.flip()).or_insert(vec![]).push(p.logical_id.flip());
+		volume_machines.entry(p.logical_id.flip()).or_insert(vec![]).push(p.machine_id.flip());
+	}
+
+	let mut results = vec![];
+	let mut probes = vec![];
+
+	for m in machines.iter() {
+		let machine_id = m.id.flip();
+
+		// Machines that are not heartbeating are dead regardless of whether or not they would respond to us
+		if !m.is_ready(&config) {
+			results.push(ProbeResult::dead(machine_id));
+			continue;
+		}
+
+		let mut samples = vec![];
+		for volume_id in machine_volumes.get(&machine_id).cloned().unwrap_or(vec![]) {
+			let photos = dir.db.read_random_photos_for_volume(volume_id, config.pitchfork.probe_count)?;
+			samples.push((volume_id, photos.into_iter().map(|p| p.id.flip()).collect::<Vec<_>>()));
+		}
+
+		probes.push(probe_store(m, samples, &config));
+	}
+
+	results.extend(runtime.block_on(join_all(probes))?);
+
+
+	let machines_by_id = machines.iter().map(|m| (m.id.flip(), m)).collect::<HashMap<MachineId, _>>();
+
+	let mut healthy_machines = HashSet::new();
+	let mut writeable_replicas = HashSet::new();
+
+	for r in results {
+		let m = machines_by_id[&r.machine_id];
+
+		if m.alive != r.alive || m.healthy != r.healthy {
+			println!("- Store {}: alive: {}, healthy: {}", r.machine_id, r.alive, r.healthy);
+			dir.update_store_machine_health(r.machine_id, r.alive, r.healthy)?;
+		}
+
+		if r.healthy {
+			healthy_machines.insert(r.machine_id);
+		}
+
+		for volume_id in r.writeable_volumes {
+			writeable_replicas.insert((volume_id, r.machine_id));
+		}
+	}
+
+	for v in volumes {
+		let volume_id = v.id.flip();
+		let replicas = volume_machines.get(&volume_id).cloned().unwrap_or(vec![]);
+
+		let complete = replicas.len() >= config.store.num_replicas;
+		let healthy = replicas.iter().all(|id| healthy_machines.contains(id));
+
+		if v.write_enabled {
+			if !complete {
+				println!("- Volume {}: missing replicas ({} of {})", volume_id, replicas.len(), config.store.num_replicas);
+				dir.update_logical_volume_writeable(volume_id, false)?;
+			}
+			else if !healthy {
+				println!("- Volume {}: has unhealthy replicas", volume_id);
+				dir.update_logical_volume_writeable(volume_id, false)?;
+			}
+		}
+		else if complete && healthy {
+			let has_space = replicas.iter().all(|id| {
+				machines_by_id[id].write_enabled && writeable_replicas.contains(&(volume_id, *id))
+			});
+
+			if has_space {
+				println!("- Volume {}: re-enabling writes", volume_id);
+				dir.update_logical_volume_writeable(volume_id, true)?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks that a store responds, that it has all the volumes the directory expects it to have, and that it can read the given sample of photos from those volumes
+fn probe_store(
+	mac: &models::StoreMachine, samples: Vec<(VolumeId, Vec<NeedleKey>)>, config: &Config
+) -> impl Future<Item=ProbeResult, Error=Error> {
+
+	let machine_id = mac.id.flip();
+	let addr = mac.addr();
+	let alt_key = config.pitchfork.probe_alt_key;
+	let timeout = Duration::from_millis(config.pitchfork.probe_timeout);
+
+	with_timeout(store_get(&addr, machine_id, StorePath::Index), timeout)
+	.and_then(|(status, body)| {
+		if status != StatusCode::OK {
+			return err(format!("Volume listing failed with code: {}", status).into());
+		}
+
+		match serde_json::from_slice::<Vec<StoreReadVolumeBody>>(&body) {
+			Ok(v) => ok(v),
+			Err(_) => err("Invalid json response received".into())
+		}
+	})
+	.then(move |res| {
+		let listed = match res {
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Store {} failed to respond: {:?}", machine_id, e);
+				return Either::A(ok(ProbeResult::dead(machine_id)));
+			}
+		};
+
+		let mut healthy = true;
+
+		for (volume_id, _) in samples.iter() {
+			if !listed.iter().any(|v| v.id == *volume_id) {
+				eprintln!("Store {} is missing volume {}", machine_id, volume_id);
+				healthy = false;
+			}
+		}
+
+		let writeable_volumes = listed.iter().filter(|v| v.writeable).map(|v| v.id).collect::<Vec<_>>();
+
+		let mut reads = vec![];
+		for (volume_id, keys) in samples {
+			for key in keys {
+				let path = StorePath::Partial { volume_id, key, alt_key };
+
+				reads.push(
+					with_timeout(store_get(&addr, machine_id, path), timeout)
+					.then(move |res| -> FutureResult<bool, Error> {
+						// NOTE: Not finding the needle is fine as we don't know for sure that the photo has the alt key we probed with
+						let ok_read = match res {
+							Ok((status, _)) => status == StatusCode::OK || status == StatusCode::NOT_FOUND,
+							Err(_) => false
+						};
+
+						if !ok_read {
+							eprintln!("Store {} failed to read needle {}/{}/{}", machine_id, volume_id, key, alt_key);
+						}
+
+						ok(ok_read)
+					})
+				);
+			}
+		}
+
+		Either::B(join_all(reads).map(move |reads| {
+			ProbeResult {
+				machine_id,
+				alive: true,
+				healthy: healthy && reads.into_iter().all(|r| r),
+				writeable_volumes
+			}
+		}))
+	})
+}
+
+/// Performs a GET request to a store and reads back the entire response body
+pub fn store_get(addr: &str, machine_id: MachineId, path: StorePath) -> impl Future<Item=(StatusCode, bytes::Bytes), Error=Error> {
+	let client = hyper::Client::new();
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", addr, path.to_string()))
+		.method("GET")
+		.header("Host", Host::Store(machine_id).to_string())
+		.body(Body::empty())
+		.unwrap();
+
+	client.request(req)
+	.and_then(|resp| {
+		let status = resp.status();
+
+		// The whole body must be read such that we notice failures in the middle of it
+		resp.into_body().concat2().map(move |body| (status, body.into_bytes()))
+	})
+	.map_err(|e| e.into())
+}
+
+pub fn with_timeout<F>(f: F, timeout: Duration) -> impl Future<Item=F::Item, Error=Error>
+	where F: Future<Error=Error> {
+
+	Timeout::new(f, timeout).map_err(|e| {
+		if e.is_elapsed() {
+			"Store request timed out".into()
+		}
+		else {
+			e.into_inner().unwrap_or_else(|| "Timer failed".into())
+		}
+	})
+}
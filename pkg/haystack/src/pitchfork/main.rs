@@ -0,0 +1,78 @@
+use super::super::directory::Directory;
+use super::super::errors::*;
+use super::super::background_thread::*;
+use super::health;
+use super::repair;
+use super::encode;
+use super::cleanup;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+
+
+pub struct PitchforkContext {
+	pub dir: Mutex<Directory>,
+	pub thread: BackgroundThread
+}
+
+pub type PitchforkHandle = Arc<PitchforkContext>;
+
+
+pub fn run(dir: Directory) -> Result<()> {
+
+	println!("Starting Pitchfork");
+
+	let interval = dir.config.pitchfork.interval;
+
+	let handle = Arc::new(PitchforkContext {
+		dir: Mutex::new(dir),
+		thread: BackgroundThread::new()
+	});
+
+	let thread_handle = handle.clone();
+	handle.thread.start(move || {
+		let mut runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
+
+		while thread_handle.thread.is_running() {
+			{
+				let dir = thread_handle.dir.lock().unwrap();
+
+				if let Err(e) = health::check_cluster(&dir, &mut runtime) {
+					eprintln!("Health check failed: {:?}", e);
+				}
+
+				if let Err(e) = repair::repair_volumes(&dir, &mut runtime) {
+					eprintln!("Volume repair failed: {:?}", e);
+				}
+			}
+
+			// This locks the directory itself as it may need to wait a while after encoding a volume
+			if let Err(e) = encode::encode_volumes(&thread_handle.dir, &mut runtime) {
+				eprintln!("Volume encoding failed: {:?}", e);
+			}
+
+			{
+				let dir = thread_handle.dir.lock().unwrap();
+
+				if let Err(e) = cleanup::cleanup_uncommitted_photos(&dir, &mut runtime) {
+					eprintln!("Photo cleanup failed: {:?}", e);
+				}
+			}
+
+			thread_handle.thread.wait(interval);
+		}
+	});
+
+	// Block until we are asked to shutdown
+	let (tx, rx) = channel::<()>();
+	ctrlc::set_handler(move || {
+		let _ = tx.send(());
+	}).expect("Error setting Ctrl-C handler");
+
+	rx.recv().ok();
+
+	handle.thread.stop();
+
+	println!("Shutdown!");
+
+	Ok(())
+}
@@ -1,3 +1,10 @@
+mod health;
+mod repair;
+mod encode;
+mod cleanup;
+pub mod main;
+
+
 
 
 /*
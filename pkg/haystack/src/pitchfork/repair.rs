@@ -0,0 +1,178 @@
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::paths::*;
+use super::super::directory::Directory;
+use super::super::directory::models;
+use super::super::directory::placement;
+use super::super::store::api::*;
+use super::health::with_timeout;
+use core::FlipSign;
+use std::collections::HashMap;
+use hyper::{Body, StatusCode};
+use futures::prelude::*;
+use futures::future::*;
+use tokio::runtime::Runtime;
+use chrono::{Utc, Duration};
+use std::time::Duration as StdDuration;
+use rand::thread_rng;
+use rand::seq::SliceRandom;
+
+
+/// Restores the replication factor of all logical volumes that have lost replicas
+///
+/// - Replicas on stores that haven't been heard from in a long time are removed from the directory
+/// - Each read-only volume with fewer than num_replicas replicas is copied in full from a readable replica onto a new store with space for it (which is outside of the failure domains of the remaining replicas)
+/// - Only once the new store has received and verified every needle is it registered as a replica
+pub fn repair_volumes(dir: &Directory, runtime: &mut Runtime) -> Result<()> {
+
+	let config = dir.config.clone();
+
+	let machines = dir.db.index_store_machines()?;
+	let machines_by_id = machines.iter().map(|m| (m.id.flip(), m)).collect::<HashMap<MachineId, _>>();
+
+	let mut volume_machines: HashMap<VolumeId, Vec<MachineId>> = HashMap::new();
+	for p in dir.db.index_physical_volumes()? {
+		volume_machines.entry(p.logical_id.flip()).or_insert(vec![]).push(p.machine_id.flip());
+	}
+
+	for v in dir.db.index_logical_volumes()? {
+		let volume_id = v.id.flip();
+
+		// Coded volumes are protected by their parity shards instead of by replicas
+		if v.is_encoded() {
+			continue;
+		}
+
+		let mut replicas = volume_machines.get(&volume_id).cloned().unwrap_or(vec![]);
+
+		let readable = replicas.iter().filter(|id| machines_by_id[id].can_read(&config)).cloned().collect::<Vec<_>>();
+
+		// Nothing we can do without at least one good copy of the volume
+		if readable.len() == 0 {
+			continue;
+		}
+
+		// Forget about replicas on machines which are gone for good (we never drop a readable replica)
+		let mut dead = vec![];
+		for id in replicas.iter() {
+			if is_gone(machines_by_id[id], &config) {
+				println!("- Volume {}: dropping replica on dead store {}", volume_id, id);
+				dir.delete_physical_volume(volume_id, *id)?;
+				dead.push(*id);
+			}
+		}
+
+		replicas.retain(|id| !dead.contains(id));
+
+		// Writes must be stopped (normally by the health check) before we can take a consistent copy of the volume
+		if replicas.len() >= config.store.num_replicas || v.write_enabled {
+			continue;
+		}
+
+		let mut rng = thread_rng();
+
+		let source = machines_by_id[readable.choose(&mut rng).unwrap()];
+
+		let existing = replicas.iter().map(|id| machines_by_id[id].clone()).collect::<Vec<_>>();
+
+		let candidates = machines.iter().filter(|m| {
+			m.can_allocate(&config) && !replicas.contains(&m.id.flip())
+		}).cloned().collect::<Vec<_>>();
+
+		// The new replica must not share a failure domain with any replica that is still around
+		let target = match placement::choose_replacement(&existing, candidates) {
+			Ok(m) => m,
+			Err(_) => {
+				eprintln!("Volume {}: no stores available to hold a new replica", volume_id);
+				continue;
+			}
+		};
+
+		println!("- Volume {}: replicating from store {} to store {}", volume_id, source.id, target.id);
+
+		// A hung transfer would otherwise stall every later pass of pitchfork
+		let timeout = StdDuration::from_millis(config.pitchfork.replicate_timeout);
+
+		let num = match runtime.block_on(with_timeout(replicate_volume(source, &target, volume_id), timeout)) {
+			Ok(n) => n,
+			Err(e) => {
+				eprintln!("Volume {}: replication failed: {:?}", volume_id, e);
+				continue;
+			}
+		};
+
+		dir.create_physical_volumes(volume_id, &[target.id.flip()])?;
+
+		println!("- Volume {}: replicated {} needles", volume_id, num);
+	}
+
+	Ok(())
+}
+
+/// Whether or not a machine has been out of contact long enough that we should no longer wait for it to come back
+fn is_gone(mac: &models::StoreMachine, config: &Config) -> bool {
+	if mac.is_ready(config) {
+		return false;
+	}
+
+	let now = Utc::now();
+	now.ge(&mac.last_heartbeat) &&
+		(now - mac.last_heartbeat).ge(&Duration::milliseconds(config.pitchfork.replica_timeout as i64))
+}
+
+/// Streams all needles in a volume from one store to another and resolves to the number of needles copied
+fn replicate_volume(
+	source: &models::StoreMachine, target: &models::StoreMachine, volume_id: VolumeId
+) -> impl Future<Item=usize, Error=Error> {
+
+	let path = StorePath::VolumeNeedles { volume_id }.to_string();
+
+	let client = hyper::Client::new();
+
+	let export_req = hyper::Request::builder()
+		.uri(format!("{}{}", source.addr(), path))
+		.method("GET")
+		.header("Host", Host::Store(source.id.flip()).to_string())
+		.body(Body::empty())
+		.unwrap();
+
+	let import_url = format!("{}{}", target.addr(), path);
+	let import_host = Host::Store(target.id.flip()).to_string();
+
+	client.request(export_req)
+	.map_err(|e| e.into())
+	.and_then(move |resp| {
+		if resp.status() != StatusCode::OK {
+			return Either::A(err(format!("Export failed with code: {}", resp.status()).into()));
+		}
+
+		// Passing the export body straight through means that the source will abort the transfer if it finds corruption in the middle of the volume
+		let import_req = hyper::Request::builder()
+			.uri(import_url)
+			.method("PUT")
+			.header("Host", import_host)
+			.body(Body::wrap_stream(resp.into_body()))
+			.unwrap();
+
+		Either::B(
+			hyper::Client::new().request(import_req)
+			.map_err(|e| e.into())
+		)
+	})
+	.and_then(|resp| {
+		let status = resp.status();
+
+		resp.into_body().concat2()
+		.map_err(|e| e.into())
+		.and_then(move |body| {
+			if status != StatusCode::OK {
+				return err(format!("Import failed with code: {} ({})", status, String::from_utf8_lossy(&body)).into());
+			}
+
+			match serde_json::from_slice::<StoreImportResponse>(&body) {
+				Ok(res) => ok(res.num_imported),
+				Err(_) => err("Invalid json response received".into())
+			}
+		})
+	})
+}
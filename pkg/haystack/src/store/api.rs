@@ -207,6 +207,44 @@ pub enum StorePath {
 		volume_id: VolumeId
 	},
 
+	/// '/<volume_id>/needles'
+	/// Raw data of every needle in a volume (used for replicating whole volumes between stores)
+	VolumeNeedles {
+		volume_id: VolumeId
+	},
+
+	/// '/<volume_id>/list' or '/<volume_id>/list/<after>'
+	/// One page of the index entries of the needles in a volume starting at the given position in its index file
+	VolumeList {
+		volume_id: VolumeId,
+		position: u64
+	},
+
+	/// '/<volume_id>/corrupt'
+	/// Keys of all needles in the volume that were found to be corrupt by scrubbing
+	VolumeCorrupt {
+		volume_id: VolumeId
+	},
+
+	/// '/<volume_id>/repair'
+	/// Replaces all needles in the volume found to be corrupt with good copies from other replicas
+	VolumeRepair {
+		volume_id: VolumeId
+	},
+
+	/// '/<volume_id>/encode'
+	/// Erasure codes the volume into shards distributed to other stores
+	VolumeEncode {
+		volume_id: VolumeId
+	},
+
+	/// '/<volume_id>/shards/<index>'
+	/// Raw data of a single shard of an erasure coded volume
+	VolumeShard {
+		volume_id: VolumeId,
+		index: usize
+	},
+
 	/// '/<volume_id>/<key>'
 	Photo {
 		volume_id: VolumeId,
@@ -220,6 +258,14 @@ pub enum StorePath {
 		alt_key: NeedleAltKey
 	},
 
+	/// '/<volume_id>/<key>/<alt_key>/repair'
+	/// Replaces a single needle with a good copy from another replica (if it is corrupt)
+	NeedleRepair {
+		volume_id: VolumeId,
+		key: NeedleKey,
+		alt_key: NeedleAltKey
+	},
+
 	/// '/<volume_id>/<key>/<alt_key>/<cookie>'
 	Needle {
 		volume_id: VolumeId,
@@ -246,6 +292,50 @@ impl StorePath {
 			});
 		}
 
+		if segs.len() == 2 && &segs[1] == "needles" {
+			return Ok(StorePath::VolumeNeedles {
+				volume_id
+			});
+		}
+
+		if segs.len() == 2 && &segs[1] == "list" {
+			return Ok(StorePath::VolumeList {
+				volume_id, position: 0
+			});
+		}
+
+		if segs.len() == 3 && &segs[1] == "list" {
+			return match segs[2].parse::<u64>() {
+				Ok(position) => Ok(StorePath::VolumeList { volume_id, position }),
+				Err(_) => Err("Invalid list position")
+			};
+		}
+
+		if segs.len() == 2 && &segs[1] == "corrupt" {
+			return Ok(StorePath::VolumeCorrupt {
+				volume_id
+			});
+		}
+
+		if segs.len() == 2 && &segs[1] == "repair" {
+			return Ok(StorePath::VolumeRepair {
+				volume_id
+			});
+		}
+
+		if segs.len() == 2 && &segs[1] == "encode" {
+			return Ok(StorePath::VolumeEncode {
+				volume_id
+			});
+		}
+
+		if segs.len() == 3 && &segs[1] == "shards" {
+			return match segs[2].parse::<usize>() {
+				Ok(index) => Ok(StorePath::VolumeShard { volume_id, index }),
+				Err(_) => Err("Invalid shard index")
+			};
+		}
+
 		let key = match segs[1].parse::<NeedleKey>() {
 			Ok(v) => v,
 			Err(_) => return Err("Invalid needle key")
@@ -268,6 +358,12 @@ impl StorePath {
 			});
 		}
 
+		if segs.len() == 4 && &segs[3] == "repair" {
+			return Ok(StorePath::NeedleRepair {
+				volume_id, key, alt_key
+			});
+		}
+
 		let cookie = match segs[3].parse::<CookieBuf>() {
 			Ok(v) => v,
 			Err(_) => return Err("Invalid cookie")
@@ -287,10 +383,24 @@ impl StorePath {
 			StorePath::Index => "/".into(),
 			StorePath::Volume { volume_id } =>
 				format!("/{}", volume_id),
+			StorePath::VolumeNeedles { volume_id } =>
+				format!("/{}/needles", volume_id),
+			StorePath::VolumeList { volume_id, position } =>
+				format!("/{}/list/{}", volume_id, position),
+			StorePath::VolumeCorrupt { volume_id } =>
+				format!("/{}/corrupt", volume_id),
+			StorePath::VolumeRepair { volume_id } =>
+				format!("/{}/repair", volume_id),
+			StorePath::VolumeEncode { volume_id } =>
+				format!("/{}/encode", volume_id),
+			StorePath::VolumeShard { volume_id, index } =>
+				format!("/{}/shards/{}", volume_id, index),
 			StorePath::Photo { volume_id, key } =>
 				format!("/{}/{}", volume_id, key),
 			StorePath::Partial { volume_id, key, alt_key } => 
 				format!("/{}/{}/{}", volume_id, key, alt_key),
+			StorePath::NeedleRepair { volume_id, key, alt_key } =>
+				format!("/{}/{}/{}/repair", volume_id, key, alt_key),
 			StorePath::Needle { volume_id, key, alt_key, cookie } => 
 				format!("/{}/{}/{}/{}", volume_id, key, alt_key, cookie.to_string()) 
 		}
@@ -298,6 +408,57 @@ impl StorePath {
 }
 
 
+/// Snapshot of the progress of an active compaction (reported in the store's volume listings)
+#[derive(Serialize, Deserialize)]
+pub struct VolumeCompactionProgress {
+	/// Number of bytes of the original volume that have been processed so far
+	pub processed: u64,
+
+	/// Total number of bytes of the original volume that need to be processed
+	pub total: u64,
+
+	/// Size of the new packed volume so far
+	pub packed: u64
+}
+
+/// Describes a single physical volume on a store (returned by the index and volume routes)
+#[derive(Serialize, Deserialize)]
+pub struct StoreReadVolumeBody {
+	pub id: VolumeId,
+	pub num_needles: usize,
+	pub used_space: u64,
+	pub allocated_space: u64,
+
+	/// Whether or not the volume has enough space left to continue accepting uploads
+	pub writeable: bool,
+
+	pub compaction_pending: u64,
+	pub compaction: Option<VolumeCompactionProgress>,
+
+	/// Number of needles that were found to be corrupt the last time that they were scrubbed
+	#[serde(default)]
+	pub corrupt_needles: usize
+}
+
+/// A single needle as listed by the volume list route
+#[derive(Serialize, Deserialize)]
+pub struct StoreNeedleListEntry {
+	pub key: NeedleKey,
+	pub alt_key: NeedleAltKey,
+	pub size: NeedleSize,
+	pub flags: u8,
+	pub block_offset: BlockOffset
+}
+
+/// One page of needles in a volume (in the order that they were recorded in its index file)
+#[derive(Serialize, Deserialize)]
+pub struct StoreNeedleListResponse {
+	pub needles: Vec<StoreNeedleListEntry>,
+
+	/// Position at which the next page starts (or None if this was the last page)
+	pub next: Option<u64>
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StoreError {
 	pub code: u16,
@@ -310,6 +471,38 @@ pub struct StoreWriteBatchResponse {
 	pub error: Option<StoreError> // If present than this error occured while writing further chunks beyond those counted in num_written
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct StoreImportResponse {
+	pub num_imported: usize
+}
+
+/// Result of repairing all of the corrupt needles in a volume
+#[derive(Serialize, Deserialize)]
+pub struct StoreRepairResponse {
+	pub num_repaired: usize,
+
+	/// Needles for which no other replica had a good copy (these are still corrupt)
+	pub failed: Vec<NeedleKeys>
+}
+
+/// Body of a request to erasure code a volume
+#[derive(Serialize, Deserialize)]
+pub struct StoreEncodeRequest {
+	pub data_shards: usize,
+	pub parity_shards: usize,
+
+	/// Store that will receive each shard (in order of shard index)
+	pub machine_ids: Vec<MachineId>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoreEncodeResponse {
+	pub num_needles: usize,
+
+	/// Size of the data in each shard
+	pub shard_size: u64
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -1,4 +1,5 @@
 use std::io;
+use std::fs;
 use std::io::{Write, Read, Seek};
 use std::fs::{File, OpenOptions};
 use std::io::{Cursor, SeekFrom};
@@ -8,13 +9,15 @@ use super::super::errors::*;
 use super::super::paths::Host;
 use super::api::*;
 use super::volume::{PhysicalVolume};
+use super::shard::VolumeShard;
 use core::DirLock;
 use std::path::{Path, PathBuf};
 use super::super::directory::Directory;
+use super::super::directory::placement;
 use core::FlipSign;
 use std::sync::{Arc,Mutex,RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
-use rand::seq::SliceRandom;
+use std::time::{Duration, Instant};
 use rand::Rng;
 use super::machine_index::*;
 use super::super::background_thread::*;
@@ -30,6 +33,9 @@ pub struct MachineContext {
 	pub dir: Mutex<Directory>,
 	pub thread: BackgroundThread,
 
+	/// Thread that slowly checks every needle on this machine for corruption
+	pub scrubber: BackgroundThread,
+
 	/// Caches whether or not the store should qualify as 'writeable'. This is updated in the background thread that does heartbeats
 	pub writeable: AtomicBool
 }
@@ -44,6 +50,7 @@ impl MachineContext {
 			config: dir.config.clone(),
 			dir: Mutex::new(dir),
 			thread: BackgroundThread::new(),
+			scrubber: BackgroundThread::new(),
 			writeable: AtomicBool::new(writeable)
 		}
 	}
@@ -60,12 +67,21 @@ impl MachineContext {
 pub type MachineHandle = Arc<MachineContext>;
 
 
+/// Gets the path of the file for a volume in a store's folder
+fn volume_path(folder: &str, volume_id: VolumeId) -> PathBuf {
+	Path::new(folder).join(String::from("haystack_") + &volume_id.to_string())
+}
+
+
 /// Encapsulates the broad configuration and current state of a single store machine
 pub struct StoreMachine {
 
 	/// All volumes 
 	pub volumes: HashMap<VolumeId, Arc<Mutex<PhysicalVolume>>>,
 
+	/// Shards of erasure coded volumes (at most one per volume)
+	pub shards: HashMap<VolumeId, Arc<VolumeShard>>,
+
 	_lock: DirLock,
 	
 	config: ConfigRef,
@@ -107,13 +123,18 @@ impl StoreMachine {
 			return Err("Connected to a different cluster".into());
 		}
 
+		// The location may have changed since the last time this machine was started
+		dir.db.update_store_machine_location(idx.machine_id, &dir.config.region, &dir.config.store.rack)?;
+		dir.invalidate_cluster();
+
 		let mut machine = StoreMachine {
 			_lock: lock,
 			folder: String::from(folder),
 			config: dir.config.clone(),
 			index: idx,
 			port,
-			volumes: HashMap::new()
+			volumes: HashMap::new(),
+			shards: HashMap::new()
 		};
 
 		let vol_ids = machine.index.read_all()?;
@@ -121,15 +142,153 @@ impl StoreMachine {
 			machine.open_volume(id, false)?;
 		}
 
+		machine.open_shards()?;
+
 		Ok(machine)
 	}
 
+	/// Rebuilds the index files of volumes in a store's folder from the needles in the volumes themselves (or of all volumes if no ids are given)
+	/// NOTE: The store must not be running while this is done
+	pub fn repair_indexes(config: ConfigRef, folder: &str, volume_ids: &[VolumeId]) -> Result<()> {
+		StoreMachine::for_each_offline_volume(folder, volume_ids, &mut |id: VolumeId, path: &Path| {
+			let vol = PhysicalVolume::open_rebuilding_index(config.clone(), path)?;
+			println!("- Volume {} re-indexed with {} needles", id, vol.num_needles());
+			vol.close()
+		})
+	}
+
+	/// Upgrades volumes in a store's folder that are in an older format version (or all volumes if no ids are given)
+	/// NOTE: The store must not be running while this is done
+	pub fn migrate_volumes(config: ConfigRef, folder: &str, volume_ids: &[VolumeId]) -> Result<()> {
+		StoreMachine::for_each_offline_volume(folder, volume_ids, &mut |id: VolumeId, path: &Path| {
+			let mut vol = PhysicalVolume::open(config.clone(), path)?;
+
+			if !vol.needs_migration() {
+				println!("- Volume {} is already in the current format", id);
+			}
+
+			vol.migrate()?;
+			vol.close()
+		})
+	}
+
+	/// Locks a store's folder and runs the given function on each of the requested volumes in it (or all volumes if no ids are given)
+	fn for_each_offline_volume(
+		folder: &str, volume_ids: &[VolumeId], f: &mut FnMut(VolumeId, &Path) -> Result<()>
+	) -> Result<()> {
+		let path = Path::new(folder);
+
+		let volumes_path = path.join(String::from("volumes"));
+		if !volumes_path.exists() {
+			return Err("Folder does not contain a store".into());
+		}
+
+		let _lock = DirLock::open(path)?;
+
+		let mut idx = StoreMachineIndex::open(&volumes_path)?;
+
+		for id in idx.read_all()? {
+			if volume_ids.len() > 0 && !volume_ids.contains(&id) {
+				continue;
+			}
+
+			let vol_path = volume_path(folder, id);
+
+			PhysicalVolume::recover_compaction(&vol_path)?;
+
+			if !vol_path.exists() {
+				continue;
+			}
+
+			f(id, &vol_path)?;
+		}
+
+		Ok(())
+	}
+
 	pub fn id(&self) -> MachineId {
 		self.index.machine_id
 	}
 
 	fn get_volume_path(&self, volume_id: VolumeId) -> PathBuf {
-		Path::new(&self.folder).join(String::from("haystack_") + &volume_id.to_string())
+		volume_path(&self.folder, volume_id)
+	}
+
+	fn get_shard_path(&self, volume_id: VolumeId) -> PathBuf {
+		Path::new(&self.folder).join(format!("haystack_{}.shard", volume_id))
+	}
+
+	/// Path at which a shard file can be written before it is complete and added to the machine
+	pub fn get_shard_temp_path(&self, volume_id: VolumeId, shard_index: usize) -> PathBuf {
+		Path::new(&self.folder).join(format!("haystack_{}.shard{}.tmp", volume_id, shard_index))
+	}
+
+	/// Shard files aren't tracked in the machine index, so we just find all of them in the folder
+	fn open_shards(&mut self) -> Result<()> {
+		for entry in fs::read_dir(&self.folder)? {
+			let name = entry?.file_name().into_string().unwrap_or(String::new());
+
+			if !name.starts_with("haystack_") || !name.ends_with(".shard") {
+				continue;
+			}
+
+			let volume_id = match name["haystack_".len()..(name.len() - ".shard".len())].parse::<VolumeId>() {
+				Ok(id) => id,
+				Err(_) => continue
+			};
+
+			let shard = VolumeShard::open(&self.get_shard_path(volume_id))?;
+
+			if shard.header.volume_id != volume_id || shard.header.cluster_id != self.index.cluster_id {
+				return Err("Opened shard does not belong to this store".into());
+			}
+
+			self.shards.insert(volume_id, Arc::new(shard));
+		}
+
+		Ok(())
+	}
+
+	/// Moves a fully written shard file into place and starts using it
+	pub fn add_shard(&mut self, volume_id: VolumeId, shard_index: usize, path: &Path) -> Result<()> {
+		if self.shards.contains_key(&volume_id) {
+			return Err("Already holding a shard of this volume".into());
+		}
+
+		let shard = VolumeShard::open(path)?;
+
+		if shard.header.volume_id != volume_id || shard.header.shard_index != shard_index || shard.header.cluster_id != self.index.cluster_id {
+			return Err("Shard file does not match the expected volume".into());
+		}
+
+		fs::rename(path, self.get_shard_path(volume_id))?;
+		File::open(&self.folder)?.sync_all()?;
+
+		self.shards.insert(volume_id, Arc::new(shard));
+
+		Ok(())
+	}
+
+	/// Permanently deletes a whole volume from this machine (once the directory no longer references it)
+	pub fn remove_volume(&mut self, volume_id: VolumeId) -> Result<()> {
+		let vol = match self.volumes.remove(&volume_id) {
+			Some(v) => v,
+			None => return Err("Volume not found".into())
+		};
+
+		// Streams from in-flight reads have their own file handles, so only other holders of the volume itself would be an issue
+		let vol = match Arc::try_unwrap(vol) {
+			Ok(v) => v.into_inner().unwrap(),
+			Err(v) => {
+				self.volumes.insert(volume_id, v);
+				return Err("Volume is still in use".into());
+			}
+		};
+
+		self.index.remove_volume_id(volume_id)?;
+		vol.remove()?;
+
+		Ok(())
 	}
 
 	fn open_volume(&mut self, volume_id: VolumeId, expect_empty: bool) -> Result<()> {
@@ -140,6 +299,8 @@ impl StoreMachine {
 
 		let path = self.get_volume_path(volume_id);
 
+		PhysicalVolume::recover_compaction(&path)?;
+
 		let vol = if path.exists() {
 			PhysicalVolume::open(self.config.clone(), &path)?
 		} else {
@@ -185,23 +346,29 @@ impl StoreMachine {
 			vol_stats.insert(*id, StoreMachineVolumeStats {
 				used_space: v.used_space(),
 				allocated_space: v.superblock.allocated_space,
+				compaction_pending: v.compaction_pending(),
+				corrupt_needles: v.corrupt_needles().len(),
 				can_write: v.can_write(),
 				can_write_soft: v.can_write_soft()
 			});
 		}
 
+		let shard_space = self.shards.values().map(|s| s.used_space()).sum();
+
 		let total_space = self.config.store.space -
 			(self.config.store.allocation_size * (self.config.store.allocation_reserved as u64));
 
 		StoreMachineStats {
 			config: self.config.clone(),
 			volumes: vol_stats,
+			shard_space,
 			total_space
 		}
 	}
 
 	pub fn start(mac_handle_in: &MachineHandle) {
-		// TODO: Another duty of this thread will be to start and run compactions (as it has to do the updating of compactions anyway)
+
+		StoreMachine::start_scrubber(mac_handle_in);
 	
 		let mac_handle = mac_handle_in.clone();
 		mac_handle_in.thread.start(move || {
@@ -260,8 +427,21 @@ impl StoreMachine {
 				}
 
 
+				let compacting = match StoreMachine::run_compaction(&mac_handle) {
+					Ok(v) => v,
+					Err(e) => {
+						println!("{:?}", e);
+						false
+					}
+				};
+
 				let mut time = mac_handle.config.store.heartbeat_interval;
 
+				// Immediately come back to continue an unfinished compaction
+				if compacting {
+					time = 0;
+				}
+
 				// Because many machines can be pending allocation all at once, we will randomly sleep some fraction of the heartbeat before trying to create a volume in order to avoid many machines trying to 
 				if pending_alloc {
 					// NOTE: .gen() should return a positive float from 0-1
@@ -279,6 +459,138 @@ impl StoreMachine {
 		});
 	}
 
+	/// Starts the thread that checks the integrity of every volume on this machine at a limited rate
+	fn start_scrubber(mac_handle_in: &MachineHandle) {
+		let mac_handle = mac_handle_in.clone();
+		mac_handle_in.scrubber.start(move || {
+			while mac_handle.scrubber.is_running() {
+				let time = match StoreMachine::run_scrub(&mac_handle) {
+					Ok(t) => t,
+					Err(e) => {
+						println!("{:?}", e);
+						mac_handle.config.store.heartbeat_interval
+					}
+				};
+
+				mac_handle.scrubber.wait(time);
+			}
+		});
+	}
+
+	/// Performs a single step of scrubbing (starting to scrub the next volume due for it if needed)
+	/// Returns how long to wait in milliseconds before the next step to keep to the configured scrub rate
+	fn run_scrub(mac_handle: &MachineHandle) -> Result<u64> {
+
+		let rate = mac_handle.config.store.scrub_rate;
+		let idle = mac_handle.config.store.heartbeat_interval;
+
+		if rate == 0 {
+			return Ok(idle);
+		}
+
+		let vol_handle = {
+			let mac = mac_handle.inst.read().unwrap();
+
+			let mut active = None;
+			let mut candidate = None;
+			for (_, v) in mac.volumes.iter() {
+				let vol = v.lock().unwrap();
+				if vol.is_scrubbing() {
+					active = Some(v.clone());
+					break;
+				}
+				else if candidate.is_none() && vol.should_scrub(mac_handle.config.store.scrub_interval) {
+					candidate = Some(v.clone());
+				}
+			}
+
+			match active {
+				Some(v) => v,
+				None => match candidate {
+					Some(v) => {
+						v.lock().unwrap().begin_scrub();
+						v
+					},
+					None => return Ok(idle)
+				}
+			}
+		};
+
+		let start = Instant::now();
+
+		let (volume_id, nchecked, done, ncorrupt) = {
+			let mut vol = vol_handle.lock().unwrap();
+			let n = vol.step_scrub(mac_handle.config.store.compaction_step_size)?;
+			(vol.superblock.volume_id, n, !vol.is_scrubbing(), vol.corrupt_needles().len())
+		};
+
+		if done {
+			println!("- Volume {} scrubbed on Store {} ({} corrupt needles)", volume_id, mac_handle.id, ncorrupt);
+
+			// Lets the directory find out about any corruption as soon as possible
+			if ncorrupt > 0 {
+				mac_handle.thread.notify();
+			}
+		}
+
+		// Waiting for however long reading that many bytes should have taken at our rate
+		let target = nchecked * 1000 / rate;
+		let elapsed = (start.elapsed().as_secs() * 1000) + (start.elapsed().subsec_millis() as u64);
+
+		Ok(if target > elapsed { target - elapsed } else { 0 })
+	}
+
+	/// Continues compacting the volume currently being compacted, or starts compacting the next volume with enough reclaimable space
+	/// To keep heartbeats flowing, this will only run for up to half of a heartbeat interval and returns whether or not a compaction is still incomplete
+	fn run_compaction(mac_handle: &MachineHandle) -> Result<bool> {
+
+		let vol_handle = {
+			let mac = mac_handle.inst.read().unwrap();
+
+			let mut active = None;
+			let mut candidate = None;
+			for (_, v) in mac.volumes.iter() {
+				let vol = v.lock().unwrap();
+				if vol.is_compacting() {
+					active = Some(v.clone());
+					break;
+				}
+				else if candidate.is_none() && vol.should_compact() {
+					candidate = Some(v.clone());
+				}
+			}
+
+			match active {
+				Some(v) => v,
+				None => match candidate {
+					Some(v) => {
+						let mut vol = v.lock().unwrap();
+						println!("- Compacting volume {} on Store {}", vol.superblock.volume_id, mac_handle.id);
+						vol.begin_compaction()?;
+						drop(vol);
+						v
+					},
+					None => return Ok(false)
+				}
+			}
+		};
+
+		let step_size = mac_handle.config.store.compaction_step_size;
+		let deadline = Instant::now() + Duration::from_millis(mac_handle.config.store.heartbeat_interval / 2);
+
+		while mac_handle.thread.is_running() && Instant::now() < deadline {
+			// NOTE: The lock is only held for a single step so that requests to the volume can make progress in between steps
+			let done = vol_handle.lock().unwrap().step_compaction(step_size)?;
+			if done {
+				// Our space usage has changed, so the directory should find out about it
+				mac_handle.thread.notify();
+				return Ok(false);
+			}
+		}
+
+		Ok(true)
+	}
+
 	// For shutting down inside of the thread (should consume the thread's mac_handle)
 	fn shutdown(mac_handle: MachineHandle) -> Result<()> {
 
@@ -289,7 +601,7 @@ impl StoreMachine {
 		// (On restart it will be pitch-fork's responsibility to bring them back up)
 		let vols = dir.db.read_logical_volumes_for_store_machine(mac_handle.id)?;
 		for v in vols {
-			dir.db.update_logical_volume_writeable(v.id as VolumeId, false)?;
+			dir.update_logical_volume_writeable(v.id as VolumeId, false)?;
 		}
 
 		// Perform final heartbeart to take this node off of the ready list
@@ -311,7 +623,8 @@ impl StoreMachine {
 			"127.0.0.1", port,
 			stats.allocated_space(),
 			stats.total_space,
-			writeable
+			writeable,
+			stats.corrupt_needles()
 		)?;
 
 		Ok(())
@@ -335,7 +648,7 @@ impl StoreMachine {
 			let writeable = s.can_write_soft;
 
 			if !writeable && v.write_enabled {
-				dir.db.update_logical_volume_writeable(v.id as VolumeId, false)?;
+				dir.update_logical_volume_writeable(v.id as VolumeId, false)?;
 			}
 		}
 
@@ -347,7 +660,10 @@ impl StoreMachine {
 
 		let num_replicas = mac_handle.config.store.num_replicas;
 
-		let (mut macs, vol) = {
+		// NOTE: We assume that NUM_REPLICAS is always at least 1
+		let n_other = if num_replicas > 1 { num_replicas - 1 } else { 0 };
+
+		let (macs, vol) = {
 			let dir = mac_handle.dir.lock().unwrap();
 
 			let all_macs = match dir.db.index_store_machines() {
@@ -355,13 +671,20 @@ impl StoreMachine {
 				Err(e) => return Either::A(err(e))
 			};
 
-			let mut macs = all_macs.into_iter().filter(|m| {
+			let local = match all_macs.iter().find(|m| (m.id as MachineId) == mac_handle.id) {
+				Some(m) => m.clone(),
+				None => return Either::A(err("Missing our own machine in the directory".into()))
+			};
+
+			let candidates = all_macs.into_iter().filter(|m| {
 				m.can_allocate(&dir.config) && (m.id as MachineId) != mac_handle.id
 			}).collect::<Vec<_>>();
 
-			if macs.len() < num_replicas - 1 {
-				return Either::A(err("Not enough replicas available to allocate new volume".into()));
-			}
+			// Random choice of which machines to choose as replicas (each in a separate failure domain)
+			let macs = match placement::choose_replicas(&local, candidates, n_other) {
+				Ok(v) => v,
+				Err(e) => return Either::A(err(e))
+			};
 
 			let vol = match dir.create_logical_volume() { Ok(v) => v, Err(e) => return Either::A(err(e)) };
 			
@@ -370,17 +693,9 @@ impl StoreMachine {
 
 		let vol_id = vol.id.flip();
 
-		let mut rng = rand::thread_rng();
-
-		// Random choice of which machines to choose as replicas
-		// TODO: Possibly more useful to spear across less-allocated machines first (with randomness still though)
-		macs.shuffle(&mut rng);
-
 		// TODO: Should retry once for each machine
 		// Also, if a machine fails, then we can proceed up to the next available machine (next in our random sequence)
 		// Basically using the vector as a stream and take until we get some number of successes (but all in parallel)
-		// NOTE: We assume that NUM_REPLICAS is always at least 1
-		let n_other = if num_replicas > 1 { num_replicas - 1 } else { 0 };
 
 		// Fanning out and making requests to all machines we need
 		let arr = macs[0..n_other].iter().map(move |m| {
@@ -420,10 +735,10 @@ impl StoreMachine {
 			}));
 
 			// Create all physical mappings
-			if let Err(e) = dir.db.create_physical_volumes(vol_id, &all_ids) { return err(e); }
+			if let Err(e) = dir.create_physical_volumes(vol_id, &all_ids) { return err(e); }
 
 			// Mark as writeable
-			if let Err(e) = dir.db.update_logical_volume_writeable(vol_id, true) { return err(e); }
+			if let Err(e) = dir.update_logical_volume_writeable(vol_id, true) { return err(e); }
 
 			ok(())
 		}))
@@ -439,6 +754,12 @@ pub struct StoreMachineVolumeStats {
 	/// Total amount of space on disk commited towards this volume but not necessarily fully used
 	pub allocated_space: u64,
 
+	/// Amount of space in the volume that could be reclaimed by a compaction
+	pub compaction_pending: u64,
+
+	/// Number of needles that were found to be corrupt by scrubbing
+	pub corrupt_needles: usize,
+
 	pub can_write: bool,
 	pub can_write_soft: bool
 }
@@ -452,7 +773,10 @@ pub struct StoreMachineStats {
 	/// The total amount of space that we are allowed to allocate towards primary data files
 	pub total_space: u64,
 
-	pub volumes: HashMap<VolumeId, StoreMachineVolumeStats>
+	pub volumes: HashMap<VolumeId, StoreMachineVolumeStats>,
+
+	/// Space used by all shards of erasure coded volumes on this machine
+	pub shard_space: u64
 }
 
 impl StoreMachineStats {
@@ -465,8 +789,12 @@ impl StoreMachineStats {
 		sum
 	}
 
+	pub fn corrupt_needles(&self) -> u64 {
+		self.volumes.values().map(|v| v.corrupt_needles as u64).sum()
+	}
+
 	pub fn allocated_space(&self) -> u64 {
-		let mut sum = 0;
+		let mut sum = self.shard_space;
 		for (_, v) in self.volumes.iter() {
 			sum += v.allocated_space
 		}
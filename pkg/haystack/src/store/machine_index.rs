@@ -122,6 +122,23 @@ impl StoreMachineIndex {
 		Ok(())
 	}
 
+	/// Removes all references to the given volume id by rewriting the list of ids
+	/// NOTE: Not atomic, so a crash in the middle of this may leave the volumes after the removed one untracked (their files will be left behind as strays)
+	pub fn remove_volume_id(&mut self, id: VolumeId) -> Result<()> {
+		let ids = self.read_all()?.into_iter().filter(|v| *v != id).collect::<Vec<_>>();
+
+		let mut buf = vec![];
+		for v in ids {
+			buf.write_u32::<LittleEndian>(v)?;
+		}
+
+		self.file.set_len(VOLUMES_HEADER_SIZE as u64)?;
+		self.file.seek(SeekFrom::End(0))?;
+		self.file.write_all(&buf)?;
+		self.file.sync_data()?;
+		Ok(())
+	}
+
 }
 
 
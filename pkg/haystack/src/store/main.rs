@@ -1,4 +1,5 @@
 use super::super::directory::Directory;
+use super::super::common::*;
 use super::machine::*;
 use super::super::errors::*;
 use super::super::http::*;
@@ -12,6 +13,7 @@ fn on_start(mac_handle: &MachineHandle) {
 }
 
 fn on_stop(mac_handle: &MachineHandle) {
+	mac_handle.scrubber.stop();
 	mac_handle.thread.stop();
 
 	// Wait for a small amount of time after we've been marked as not-ready in case stray requests are still pending
@@ -48,4 +50,14 @@ pub fn run(dir: Directory, port: u16, folder: &str) -> Result<()> {
 	}
 
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// Rebuilds the index files of the volumes in a store's folder while the store is offline
+pub fn repair_index(config: Config, folder: &str, volume_ids: &[VolumeId]) -> Result<()> {
+	StoreMachine::repair_indexes(Arc::new(config), folder, volume_ids)
+}
+
+/// Upgrades the volumes in a store's folder to the current format version while the store is offline
+pub fn migrate(config: Config, folder: &str, volume_ids: &[VolumeId]) -> Result<()> {
+	StoreMachine::migrate_volumes(Arc::new(config), folder, volume_ids)
+}
@@ -3,9 +3,15 @@ mod stream;
 mod superblock;
 mod machine_index;
 mod machine;
-mod needle;
+pub mod needle;
 mod volume_index;
+pub mod needle_index;
 mod volume;
+pub mod shard;
 mod route_write;
+mod route_replicate;
+mod route_shard;
+mod route_repair;
+pub mod range;
 mod routes;
 pub mod main;
@@ -4,6 +4,9 @@ use super::api::CookieBuf;
 use core::block_size_remainder;
 use std::io::Cursor;
 use std::io::{Write, Read};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use futures::{Async, Poll};
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 use bytes::Bytes;
 use crc32c::crc32c_append;
@@ -26,7 +29,23 @@ pub const NEEDLE_FOOTER_SIZE: usize =
 
 
 
-const FLAG_DELETED: u8 = 1;
+pub const FLAG_DELETED: u8 = 1;
+
+
+/// Layout of all needles written by this version of the store
+pub const CURRENT_NEEDLE_LAYOUT: FormatVersion = 1;
+
+/// Gets the version of the layout of needles (header, data and footer) in volumes of the given format version
+/// Returns None for unknown formats
+///
+/// Volumes whose needles are laid out the same as in the current format can be migrated by just rewriting their superblock, while all others must be rewritten through a compaction
+/// NOTE: All formats so far share the same layout. When it changes, the readers below must keep a way to parse the old layout
+pub fn needle_layout_version(format_version: FormatVersion) -> Option<FormatVersion> {
+	match format_version {
+		1 => Some(1),
+		_ => None
+	}
+}
 
 
 #[derive(Clone)]
@@ -54,7 +73,8 @@ impl NeedleMeta {
 	}
 }
 
-// Basically all of this can be 
+/// In memory, these are packed much more tightly by the NeedleIndex
+#[derive(Clone)]
 pub struct NeedleIndexEntry {
 	pub meta: NeedleMeta,
 
@@ -111,9 +131,22 @@ pub struct NeedleHeader {
 impl NeedleHeader {
 
 	pub fn read(reader: &mut Read) -> Result<NeedleHeader> {
+		NeedleHeader::read_layout(reader, CURRENT_NEEDLE_LAYOUT)
+	}
+
+	/// Same as read() but for a needle in the given layout (see needle_layout_version())
+	pub fn read_layout(reader: &mut Read, layout: FormatVersion) -> Result<NeedleHeader> {
 		let mut buf = [0u8; NEEDLE_HEADER_SIZE];
 		reader.read_exact(&mut buf)?;
-		NeedleHeader::parse(&buf)
+		NeedleHeader::parse_layout(&buf, layout)
+	}
+
+	/// Same as parse() but for a needle in the given layout
+	pub fn parse_layout(header: &[u8; NEEDLE_HEADER_SIZE], layout: FormatVersion) -> Result<NeedleHeader> {
+		match layout {
+			1 => NeedleHeader::parse(header),
+			_ => Err("Unknown needle layout version".into())
+		}
 	}
 
 	pub fn parse(header: &[u8; NEEDLE_HEADER_SIZE]) -> Result<NeedleHeader> {
@@ -176,6 +209,15 @@ impl NeedleFooter {
 		Ok(())
 	}
 
+	/// Validates a footer and gets the checksum stored in it
+	pub fn parse(footer: &[u8; NEEDLE_FOOTER_SIZE]) -> Result<u32> {
+		if &footer[0..FOOTER_MAGIC_SIZE] != FOOTER_MAGIC.as_bytes() {
+			return Err("Needle footer bad magic".into());
+		}
+
+		Ok((&footer[FOOTER_MAGIC_SIZE..]).read_u32::<LittleEndian>()?)
+	}
+
 }
 
 
@@ -190,14 +232,43 @@ impl Needle {
 
 	/// Reads a single needle at the current position in one read given known metadata for it
 	pub fn read_oneshot(reader: &mut Read, meta: &NeedleMeta) -> Result<Needle> {
+		Needle::read_oneshot_layout(reader, meta, CURRENT_NEEDLE_LAYOUT)
+	}
+
+	/// Same as read_oneshot() but for a needle in the given layout
+	/// The returned needle is always in the current layout
+	pub fn read_oneshot_layout(reader: &mut Read, meta: &NeedleMeta, layout: FormatVersion) -> Result<Needle> {
+		if layout != 1 {
+			return Err("Unknown needle layout version".into());
+		}
 
 		let mut buf = Vec::new();
 		buf.resize(meta.total_size() as usize, 0u8); // TODO: Use an unsafe resize without filling
 
 		reader.read_exact(&mut buf)?;
 
+		let needle = Needle::from_bytes(buf.into())?;
+
+		// Validate that the metadata we were given is actually correct
+		if needle.header.meta.size != meta.size {
+			return Err("Inconsistently".into());
+		}
+
+		Ok(needle)
+	}
+
+	/// Parses a single needle from a buffer containing exactly its header, data and footer (without any padding)
+	pub fn from_bytes(buf: Bytes) -> Result<Needle> {
+		if buf.len() < NEEDLE_HEADER_SIZE + NEEDLE_FOOTER_SIZE {
+			return Err("Needle buffer too small".into());
+		}
+
 		let header = NeedleHeader::parse(array_ref!(&buf, 0, NEEDLE_HEADER_SIZE))?;
-		
+
+		if (buf.len() as u64) != header.meta.total_size() {
+			return Err("Needle buffer size does not match its header".into());
+		}
+
 		let magic_start = NEEDLE_HEADER_SIZE + (header.meta.size as usize);
 		if &buf[magic_start..(magic_start + FOOTER_MAGIC.len())] != FOOTER_MAGIC.as_bytes() {
 			// Generally this means that it is legitamitely corrupt
@@ -205,17 +276,17 @@ impl Needle {
 			return Err("Needle footer bad magic".into());
 		}
 
-		// Validate that the metadata we were given is actually correct
-		if header.meta.size != meta.size {
-			return Err("Inconsistently".into());
-		}
-
 		Ok(Needle {
 			header,
-			buf: buf.into()
+			buf
 		})
 	}
 
+	/// Gets the raw header, data and footer of this needle as they are stored on disk (without padding)
+	pub fn into_bytes(self) -> Bytes {
+		self.buf
+	}
+
 
 
 
@@ -253,4 +324,145 @@ impl Needle {
 		Ok(())
 	}
 
-}
\ No newline at end of file
+}
+
+
+/// Size of the chunks in which needle data is streamed out of a volume
+const STREAM_CHUNK_SIZE: usize = 64*1024;
+
+/// Incrementally reads the data of a single needle from a volume file while verifying its checksum
+///
+/// NOTE: This holds its own handle to the volume file and only performs positional reads, so the volume can continue to be used (and even compacted) while a needle is being streamed
+pub struct NeedleStream {
+	pub header: NeedleHeader,
+	pub block_offset: BlockOffset,
+
+	file: File,
+
+	/// Absolute offset in the file to the first byte of data
+	data_offset: u64,
+
+	/// Absolute offset in the file to the next byte of data that will be read
+	offset: u64,
+
+	/// Number of bytes of data not yet read
+	remaining: u64,
+
+	/// Checksum stored in the needle's footer
+	checksum: u32,
+
+	/// Checksum of all data read so far
+	sum: u32,
+
+	/// Whether or not the checksum will be checked once all the data has been read (only possible if reading all of the data)
+	verify: bool
+}
+
+impl NeedleStream {
+
+	/// Reads the header and footer of the needle starting at the given offset in a volume whose needles are in the given layout
+	pub fn open(file: File, offset: u64, block_offset: BlockOffset, layout: FormatVersion) -> Result<NeedleStream> {
+		let mut header_buf = [0u8; NEEDLE_HEADER_SIZE];
+		file.read_exact_at(&mut header_buf, offset)?;
+		let header = NeedleHeader::parse_layout(&header_buf, layout)?;
+
+		let data_offset = offset + (NEEDLE_HEADER_SIZE as u64);
+
+		let mut footer_buf = [0u8; NEEDLE_FOOTER_SIZE];
+		file.read_exact_at(&mut footer_buf, data_offset + header.meta.size)?;
+		let checksum = NeedleFooter::parse(&footer_buf)?;
+
+		let remaining = header.meta.size;
+
+		Ok(NeedleStream {
+			header,
+			block_offset,
+			file,
+			data_offset,
+			offset: data_offset,
+			remaining,
+			checksum,
+			sum: 0,
+			verify: true
+		})
+	}
+
+	/// Size of the needle's data
+	pub fn size(&self) -> NeedleSize {
+		self.header.meta.size
+	}
+
+	/// Gets the checksum of the data as stored in the footer (in the same format as Needle::crc32c)
+	pub fn crc32c(&self) -> Vec<u8> {
+		let mut buf = vec![];
+		buf.write_u32::<LittleEndian>(self.checksum).unwrap();
+		buf
+	}
+
+	/// Reads up to the first 'max' bytes of data without advancing the stream (and without any integrity checking)
+	pub fn prefix(&self, max: usize) -> Result<Vec<u8>> {
+		let mut buf = Vec::new();
+		buf.resize(std::cmp::min(max as u64, self.header.meta.size) as usize, 0);
+		self.file.read_exact_at(&mut buf, self.data_offset)?;
+		Ok(buf)
+	}
+
+	/// Reads the bytes in [start, end) of the data without advancing the stream (and without any integrity checking)
+	pub fn read_range(&self, start: u64, end: u64) -> Result<Bytes> {
+		if start > end || end > self.header.meta.size {
+			return Err("Range out of bounds of needle".into());
+		}
+
+		let mut buf = Vec::new();
+		buf.resize((end - start) as usize, 0);
+		self.file.read_exact_at(&mut buf, self.data_offset + start)?;
+		Ok(buf.into())
+	}
+
+	/// Limits the stream to only produce the bytes in [start, end) of the data
+	/// NOTE: Because only part of the data will be read, the checksum will not be verified
+	pub fn restrict(&mut self, start: u64, end: u64) -> Result<()> {
+		if start > end || end > self.header.meta.size {
+			return Err("Range out of bounds of needle".into());
+		}
+
+		self.offset = self.data_offset + start;
+		self.remaining = end - start;
+		self.verify = false;
+		Ok(())
+	}
+
+	/// Reads the next chunk of data
+	///
+	/// The final chunk will only be returned once the checksum of the whole needle has been verified, so an error is returned instead if the needle is corrupt
+	pub fn next_chunk(&mut self) -> Result<Option<Bytes>> {
+		if self.remaining == 0 {
+			return Ok(None);
+		}
+
+		let n = std::cmp::min(STREAM_CHUNK_SIZE as u64, self.remaining) as usize;
+
+		let mut buf = Vec::new();
+		buf.resize(n, 0);
+		self.file.read_exact_at(&mut buf, self.offset)?;
+
+		self.sum = crc32c_append(self.sum, &buf);
+		self.offset += n as u64;
+		self.remaining -= n as u64;
+
+		if self.remaining == 0 && self.verify && self.sum != self.checksum {
+			return Err("Needle data does not match checksum".into());
+		}
+
+		Ok(Some(buf.into()))
+	}
+}
+
+impl futures::Stream for NeedleStream {
+	type Item = Bytes;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+		Ok(Async::Ready(self.next_chunk()?))
+	}
+}
@@ -0,0 +1,278 @@
+/*
+	Compact in-memory index of every needle in a physical volume
+
+	Like in the Haystack paper, a store needs to keep the location of every needle in memory so that a read only ever needs a single disk operation. With a HashMap<NeedleKeys, NeedleIndexEntry>, each needle costs around 48 bytes (plus the slack of the map), so instead every needle is packed into a single 21 byte slot of an open addressing hash table
+
+	Slots are placed using Robin Hood linear probing, which keeps probe sequences short even when the table is very full. Needles are never removed from the index (deletions are just a flag), so there is no need for tombstones
+*/
+
+use super::super::common::*;
+use super::super::errors::*;
+use super::needle::*;
+use std::mem::size_of;
+
+
+/// Largest needle that can be represented in the index
+pub const MAX_INDEXED_NEEDLE_SIZE: NeedleSize = ::std::u32::MAX as NeedleSize;
+
+/// The table grows once more than this fraction of slots are used
+const MAX_LOAD_FACTOR: f64 = 0.9;
+
+const MIN_CAPACITY: usize = 16;
+
+
+#[repr(packed)]
+#[derive(Clone, Copy)]
+struct Slot {
+	key: NeedleKey,
+	alt_key: NeedleAltKey,
+
+	/// Zero if the slot is empty (no needle can start at offset zero as that is where the volume's superblock is)
+	block_offset: BlockOffset,
+
+	size: u32,
+	flags: u8
+}
+
+const EMPTY_SLOT: Slot = Slot { key: 0, alt_key: 0, block_offset: 0, size: 0, flags: 0 };
+
+impl Slot {
+	fn is_empty(&self) -> bool {
+		self.block_offset == 0
+	}
+
+	fn keys(&self) -> NeedleKeys {
+		NeedleKeys { key: self.key, alt_key: self.alt_key }
+	}
+
+	fn entry(&self) -> NeedleIndexEntry {
+		NeedleIndexEntry {
+			meta: NeedleMeta { flags: self.flags, size: self.size as NeedleSize },
+			block_offset: self.block_offset
+		}
+	}
+}
+
+/// Mixes both keys into a well distributed hash (photo keys are typically sequential)
+fn hash_keys(key: NeedleKey, alt_key: NeedleAltKey) -> u64 {
+	let mut h = key ^ ((alt_key as u64).rotate_left(32));
+	h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+	h ^ (h >> 31)
+}
+
+
+pub struct NeedleIndex {
+	slots: Vec<Slot>,
+
+	/// Number of non-empty slots
+	len: usize
+}
+
+impl NeedleIndex {
+
+	pub fn new() -> NeedleIndex {
+		NeedleIndex::with_capacity(0)
+	}
+
+	/// Creates an index that can hold at least the given number of needles before it needs to grow
+	pub fn with_capacity(n: usize) -> NeedleIndex {
+		let mut cap = MIN_CAPACITY;
+		while (cap as f64) * MAX_LOAD_FACTOR < (n as f64) {
+			cap *= 2;
+		}
+
+		NeedleIndex {
+			slots: vec![EMPTY_SLOT; cap],
+			len: 0
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn clear(&mut self) {
+		*self = NeedleIndex::new();
+	}
+
+	/// Number of bytes of memory used by the index
+	pub fn memory_size(&self) -> usize {
+		self.slots.len() * size_of::<Slot>()
+	}
+
+	/// Gets the ideal position of some keys in the table
+	fn home(&self, key: NeedleKey, alt_key: NeedleAltKey) -> usize {
+		(hash_keys(key, alt_key) as usize) & (self.slots.len() - 1)
+	}
+
+	/// How far a slot at the given position is from its ideal position
+	fn distance(&self, slot: &Slot, pos: usize) -> usize {
+		pos.wrapping_sub(self.home(slot.key, slot.alt_key)) & (self.slots.len() - 1)
+	}
+
+	fn find(&self, keys: &NeedleKeys) -> Option<usize> {
+		let mask = self.slots.len() - 1;
+		let mut pos = self.home(keys.key, keys.alt_key);
+		let mut dist = 0;
+
+		loop {
+			let slot = &self.slots[pos];
+
+			// With Robin Hood probing, we would have already been placed before any slot that is closer to its home than we are
+			if slot.is_empty() || self.distance(slot, pos) < dist {
+				return None;
+			}
+
+			if slot.key == keys.key && slot.alt_key == keys.alt_key {
+				return Some(pos);
+			}
+
+			pos = (pos + 1) & mask;
+			dist += 1;
+		}
+	}
+
+	pub fn get(&self, keys: &NeedleKeys) -> Option<NeedleIndexEntry> {
+		self.find(keys).map(|pos| self.slots[pos].entry())
+	}
+
+	/// Updates just the flags of an existing needle
+	/// Returns false if the needle isn't in the index
+	pub fn set_flags(&mut self, keys: &NeedleKeys, flags: u8) -> bool {
+		match self.find(keys) {
+			Some(pos) => {
+				self.slots[pos].flags = flags;
+				true
+			},
+			None => false
+		}
+	}
+
+	/// Adds a needle to the index (replacing any existing entry with the same keys)
+	pub fn insert(&mut self, keys: NeedleKeys, entry: NeedleIndexEntry) -> Result<()> {
+		if entry.meta.size > MAX_INDEXED_NEEDLE_SIZE {
+			return Err("Needle is too large to be indexed".into());
+		}
+
+		if entry.block_offset == 0 {
+			return Err("Needle can not start at the beginning of the volume".into());
+		}
+
+		if let Some(pos) = self.find(&keys) {
+			self.slots[pos] = Slot {
+				key: keys.key,
+				alt_key: keys.alt_key,
+				block_offset: entry.block_offset,
+				size: entry.meta.size as u32,
+				flags: entry.meta.flags
+			};
+
+			return Ok(());
+		}
+
+		if ((self.len + 1) as f64) > (self.slots.len() as f64) * MAX_LOAD_FACTOR {
+			self.grow();
+		}
+
+		self.place(Slot {
+			key: keys.key,
+			alt_key: keys.alt_key,
+			block_offset: entry.block_offset,
+			size: entry.meta.size as u32,
+			flags: entry.meta.flags
+		});
+
+		self.len += 1;
+
+		Ok(())
+	}
+
+	/// Puts a slot for keys that are not yet in the table into the table
+	fn place(&mut self, mut slot: Slot) {
+		let mask = self.slots.len() - 1;
+		let mut pos = self.home(slot.key, slot.alt_key);
+		let mut dist = 0;
+
+		loop {
+			if self.slots[pos].is_empty() {
+				self.slots[pos] = slot;
+				return;
+			}
+
+			// Take the place of any slot that is closer to its home than we are and keep going with that one instead
+			let other_dist = self.distance(&self.slots[pos], pos);
+			if other_dist < dist {
+				::std::mem::swap(&mut slot, &mut self.slots[pos]);
+				dist = other_dist;
+			}
+
+			pos = (pos + 1) & mask;
+			dist += 1;
+		}
+	}
+
+	fn grow(&mut self) {
+		let old_slots = ::std::mem::replace(&mut self.slots, vec![EMPTY_SLOT; 0]);
+		self.slots = vec![EMPTY_SLOT; old_slots.len() * 2];
+
+		for slot in old_slots.into_iter() {
+			if !slot.is_empty() {
+				self.place(slot);
+			}
+		}
+	}
+
+	/// Iterates over every needle in the index (in no particular order)
+	pub fn iter<'a>(&'a self) -> impl Iterator<Item=(NeedleKeys, NeedleIndexEntry)> + 'a {
+		self.slots.iter()
+			.filter(|s| !s.is_empty())
+			.map(|s| (s.keys(), s.entry()))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+	use std::collections::HashMap;
+
+	#[test]
+	fn needle_index_matches_hashmap() {
+		let mut index = NeedleIndex::new();
+		let mut map = HashMap::new();
+
+		for i in 0..10000u64 {
+			let keys = NeedleKeys { key: i / 3, alt_key: (i % 3) as NeedleAltKey };
+			let entry = NeedleIndexEntry {
+				meta: NeedleMeta { flags: 0, size: i * 7 },
+				block_offset: (i + 1) as BlockOffset
+			};
+
+			map.insert(keys.clone(), entry.block_offset);
+			index.insert(keys, entry).unwrap();
+		}
+
+		// Overwriting should not add any new entries
+		let keys = NeedleKeys { key: 5, alt_key: 1 };
+		index.insert(keys.clone(), NeedleIndexEntry {
+			meta: NeedleMeta { flags: 0, size: 1 },
+			block_offset: 20000
+		}).unwrap();
+		map.insert(keys.clone(), 20000);
+
+		assert!(index.set_flags(&keys, FLAG_DELETED));
+		assert!(index.get(&keys).unwrap().meta.deleted());
+
+		assert_eq!(index.len(), map.len());
+		assert_eq!(index.iter().count(), map.len());
+
+		for (k, off) in map.iter() {
+			assert_eq!(index.get(k).unwrap().block_offset, *off);
+		}
+
+		assert!(index.get(&NeedleKeys { key: 100000, alt_key: 0 }).is_none());
+		assert!(!index.set_flags(&NeedleKeys { key: 100000, alt_key: 0 }, 0));
+	}
+}
@@ -0,0 +1,222 @@
+use super::super::errors::*;
+use super::api::ETag;
+use hyper::{Body, Response, StatusCode};
+use hyper::header::HeaderMap;
+use bytes::{Bytes, BytesMut, BufMut};
+use rand::RngCore;
+
+/// To avoid clients making us do a lot of small reads for a single request, we will ignore requests for more ranges than this and just return the whole thing
+const MAX_NUM_RANGES: usize = 16;
+
+/// A span of bytes in some resource
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByteRange {
+	/// Offset of the first byte in the range
+	pub start: u64,
+
+	/// Offset one past the last byte in the range
+	pub end: u64
+}
+
+impl ByteRange {
+	pub fn len(&self) -> u64 {
+		self.end - self.start
+	}
+
+	/// Formats the value of the Content-Range header for this range of a resource of the given total size
+	pub fn content_range(&self, total: u64) -> String {
+		format!("bytes {}-{}/{}", self.start, self.end - 1, total)
+	}
+}
+
+/// What the client wants out of a resource based on the Range related headers in a request
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+	/// The client wants the whole thing (either no ranges were requested or we have decided to ignore them)
+	Full,
+
+	/// Only the given ranges should be returned (in the order they were requested)
+	Partial(Vec<ByteRange>),
+
+	/// None of the requested ranges overlap with the resource
+	Unsatisfiable
+}
+
+impl RangeRequest {
+
+	/// Determines which parts of a resource of size 'total' should be returned given the request headers
+	/// 'etag' is the current ETag of the resource which is used to validate any If-Range header given
+	pub fn from_headers(headers: &HeaderMap, total: u64, etag: &ETag) -> RangeRequest {
+
+		let range = match headers.get("Range") {
+			Some(v) => match v.to_str() {
+				Ok(s) => s,
+				Err(_) => return RangeRequest::Full
+			},
+			None => return RangeRequest::Full
+		};
+
+		// If the resource was changed since the client got the rest of it, we must give them the whole thing again
+		// NOTE: We never give out a Last-Modified, so an If-Range with a date can't ever match
+		if let Some(v) = headers.get("If-Range") {
+			match ETag::from_header(v) {
+				Ok(e) => {
+					if !e.matches(etag) {
+						return RangeRequest::Full;
+					}
+				},
+				Err(_) => return RangeRequest::Full
+			};
+		}
+
+		RangeRequest::parse(range, total)
+	}
+
+	/// Parses the value of a 'Range: bytes=...' header
+	/// NOTE: Per the spec, a header that we can't understand is ignored rather than being an error
+	pub fn parse(s: &str, total: u64) -> RangeRequest {
+
+		let specs = match s.trim().splitn(2, '=').collect::<Vec<_>>()[..] {
+			[unit, specs] if unit.trim() == "bytes" => specs,
+			_ => return RangeRequest::Full
+		};
+
+		let mut ranges = vec![];
+
+		for spec in specs.split(',') {
+			let spec = spec.trim();
+			if spec.len() == 0 {
+				continue;
+			}
+
+			let (first, last) = match spec.splitn(2, '-').collect::<Vec<_>>()[..] {
+				[first, last] => (first.trim(), last.trim()),
+				_ => return RangeRequest::Full
+			};
+
+			let r = if first.len() == 0 {
+				// Suffix range of the form '-N' for the last N bytes
+				let n = match last.parse::<u64>() {
+					Ok(n) => n,
+					Err(_) => return RangeRequest::Full
+				};
+
+				if n == 0 {
+					continue;
+				}
+
+				ByteRange { start: total - std::cmp::min(n, total), end: total }
+			}
+			else {
+				let start = match first.parse::<u64>() {
+					Ok(n) => n,
+					Err(_) => return RangeRequest::Full
+				};
+
+				let end = if last.len() == 0 {
+					total
+				} else {
+					match last.parse::<u64>() {
+						Ok(n) if n >= start => std::cmp::min(n.saturating_add(1), total),
+						_ => return RangeRequest::Full
+					}
+				};
+
+				ByteRange { start, end }
+			};
+
+			// Ranges starting past the end of the resource are skipped
+			if r.start >= total || r.len() == 0 {
+				continue;
+			}
+
+			ranges.push(r);
+		}
+
+		if ranges.len() > MAX_NUM_RANGES {
+			return RangeRequest::Full;
+		}
+
+		if ranges.len() == 0 {
+			return RangeRequest::Unsatisfiable;
+		}
+
+		RangeRequest::Partial(ranges)
+	}
+}
+
+/// The response given when none of the requested ranges could be served
+pub fn unsatisfiable_response(total: u64) -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::RANGE_NOT_SATISFIABLE)
+		.header("Content-Range", format!("bytes */{}", total))
+		.body(Body::empty())
+		.unwrap()
+}
+
+/// Builds a 'multipart/byteranges' body out of multiple ranges of a resource
+/// 'read' should produce the data for each range
+/// Returns the value of the Content-Type header that should be used along with the body
+pub fn multipart_body<F>(
+	ranges: &[ByteRange], total: u64, content_type: &str, mut read: F
+) -> Result<(String, Bytes)>
+	where F: FnMut(&ByteRange) -> Result<Bytes> {
+
+	let mut boundary_buf = [0u8; 16];
+	rand::thread_rng().fill_bytes(&mut boundary_buf);
+	let boundary = boundary_buf.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+	let mut body = BytesMut::new();
+
+	for r in ranges {
+		let data = read(r)?;
+
+		let part_header = format!(
+			"\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+			boundary, content_type, r.content_range(total)
+		);
+
+		body.reserve(part_header.len() + data.len());
+		body.put_slice(part_header.as_bytes());
+		body.put_slice(&data);
+	}
+
+	let end = format!("\r\n--{}--\r\n", boundary);
+	body.reserve(end.len());
+	body.put_slice(end.as_bytes());
+
+	Ok((format!("multipart/byteranges; boundary={}", boundary), body.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn range_parse() {
+		assert_eq!(RangeRequest::parse("bytes=0-99", 1000),
+			RangeRequest::Partial(vec![ ByteRange { start: 0, end: 100 } ]));
+
+		assert_eq!(RangeRequest::parse("bytes=900-, -50", 1000),
+			RangeRequest::Partial(vec![
+				ByteRange { start: 900, end: 1000 },
+				ByteRange { start: 950, end: 1000 }
+			]));
+
+		// Clamped to the end of the resource
+		assert_eq!(RangeRequest::parse("bytes=10-5000", 1000),
+			RangeRequest::Partial(vec![ ByteRange { start: 10, end: 1000 } ]));
+
+		// The largest possible last byte position must not overflow
+		assert_eq!(RangeRequest::parse("bytes=0-18446744073709551615", 1000),
+			RangeRequest::Partial(vec![ ByteRange { start: 0, end: 1000 } ]));
+
+		assert_eq!(RangeRequest::parse("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+		assert_eq!(RangeRequest::parse("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+
+		// Syntactically invalid headers are ignored
+		assert_eq!(RangeRequest::parse("bytes=5-1", 1000), RangeRequest::Full);
+		assert_eq!(RangeRequest::parse("items=0-1", 1000), RangeRequest::Full);
+		assert_eq!(RangeRequest::parse("bytes=a-b", 1000), RangeRequest::Full);
+	}
+}
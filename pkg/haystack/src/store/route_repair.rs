@@ -0,0 +1,212 @@
+/*
+	This file contains the routes for repairing needles that were found to be corrupt (usually by scrubbing)
+	A good copy of each needle is fetched from another replica of the same logical volume, checked against its checksum and then appended to the local volume as the new authoritative copy (the old copy is reclaimed by the next compaction)
+*/
+
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::http::*;
+use super::super::paths::*;
+use super::super::directory::models;
+use super::machine::*;
+use super::volume::*;
+use super::api::*;
+use super::routes::get_volume_handle;
+use core::FlipSign;
+use hyper::{Body, Response, StatusCode};
+use futures::prelude::*;
+use futures::prelude::await;
+use futures::future::*;
+use std::sync::{Arc, Mutex};
+use byteorder::{WriteBytesExt, LittleEndian};
+use crc32c::crc32c_append;
+use bytes::Bytes;
+
+
+/// Repairs a single needle if it is currently corrupt
+#[async]
+pub fn repair_needle(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId, key: NeedleKey, alt_key: NeedleAltKey
+) -> Result<Response<Body>> {
+
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let keys = NeedleKeys { key, alt_key };
+
+	// Checked again right now in case it was repaired or corrupted since the last scrub
+	match vol_handle.lock().unwrap().check_needle(&keys) {
+		Some(true) => {},
+		Some(false) => return Ok(text_response(StatusCode::OK, "Needle is not corrupt")),
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Needle not found"))
+	};
+
+	let repaired = await!(repair_keys(mac_handle.clone(), vol_handle, volume_id, keys))?;
+
+	// So that the directory hears about the new number of corrupt needles
+	mac_handle.thread.notify();
+
+	if !repaired {
+		return Ok(text_response(StatusCode::BAD_GATEWAY, "No other replica has a good copy of the needle"));
+	}
+
+	Ok(text_response(StatusCode::OK, "Needle repaired!"))
+}
+
+/// Repairs every needle in a volume that was found to be corrupt by the last scrub
+/// NOTE: Needles are repaired one at a time to avoid flooding the other replicas
+#[async]
+pub fn repair_volume(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId
+) -> Result<Response<Body>> {
+
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let corrupt = vol_handle.lock().unwrap().corrupt_needles();
+
+	let mut num_repaired = 0;
+	let mut failed = vec![];
+
+	for keys in corrupt {
+		let repaired = match await!(repair_keys(mac_handle.clone(), vol_handle.clone(), volume_id, keys.clone())) {
+			Ok(v) => v,
+			Err(e) => {
+				eprintln!("Failed to repair needle {}/{} of volume {}: {:?}", keys.key, keys.alt_key, volume_id, e);
+				false
+			}
+		};
+
+		if repaired {
+			num_repaired += 1;
+		}
+		else {
+			failed.push(keys);
+		}
+	}
+
+	mac_handle.thread.notify();
+
+	Ok(json_response(StatusCode::OK, &StoreRepairResponse { num_repaired, failed }))
+}
+
+/// Replaces a single needle with the first good copy that can be found on another replica
+/// Returns false if none of the other replicas had a good copy
+#[async]
+fn repair_keys(
+	mac_handle: MachineHandle,
+	vol_handle: Arc<Mutex<PhysicalVolume>>,
+	volume_id: VolumeId,
+	keys: NeedleKeys
+) -> Result<bool> {
+
+	// Remembering which copy is corrupt, so that we don't clobber a newer upload that happens while we are fetching
+	let block_offset = match vol_handle.lock().unwrap().peek_needle_block_offset(&keys) {
+		Some(v) => v,
+		None => return Ok(true)
+	};
+
+	for m in replica_machines(&mac_handle, volume_id)? {
+		let (cookie, data) = match await!(fetch_needle(&m, volume_id, &keys)) {
+			Ok(Some(v)) => v,
+			Ok(None) => continue,
+			Err(e) => {
+				eprintln!("Failed to fetch needle {}/{} of volume {} from store {}: {:?}", keys.key, keys.alt_key, volume_id, m.id, e);
+				continue;
+			}
+		};
+
+		let mut vol = vol_handle.lock().unwrap();
+		vol.repair_needle(&keys, block_offset, cookie, &data)?;
+		vol.flush()?;
+
+		return Ok(true);
+	}
+
+	Ok(false)
+}
+
+/// Gets all other readable stores holding a full copy of a volume
+fn replica_machines(mac_handle: &MachineHandle, volume_id: VolumeId) -> Result<Vec<models::StoreMachine>> {
+	let dir = mac_handle.dir.lock().unwrap();
+
+	Ok(dir.cluster()?.store_machines_for_volume(volume_id).into_iter()
+		.filter(|m| m.id.flip() != mac_handle.id && m.can_read(&dir.config))
+		.collect())
+}
+
+/// Reads a needle from another store along with its cookie
+/// Resolves to None if the store doesn't have the needle and fails if the data doesn't match the checksum given by the store
+fn fetch_needle(
+	mac: &models::StoreMachine, volume_id: VolumeId, keys: &NeedleKeys
+) -> impl Future<Item=Option<(CookieBuf, Bytes)>, Error=Error> {
+
+	let path = StorePath::Partial { volume_id, key: keys.key, alt_key: keys.alt_key };
+
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", mac.addr(), path.to_string()))
+		.method("GET")
+		.header("Host", Host::Store(mac.id.flip()).to_string())
+		.body(Body::empty())
+		.unwrap();
+
+	hyper::Client::new().request(req)
+	.map_err(|e| e.into())
+	.and_then(|resp| {
+		if resp.status() == StatusCode::NOT_FOUND {
+			return Either::A(ok(None));
+		}
+
+		if resp.status() != StatusCode::OK {
+			return Either::A(err(format!("Needle read failed with code: {}", resp.status()).into()));
+		}
+
+		let parsed = {
+			let header = |name: &str| -> Result<String> {
+				match resp.headers().get(name).map(|v| v.to_str()) {
+					Some(Ok(s)) => Ok(s.to_owned()),
+					_ => Err(format!("Missing {} header", name).into())
+				}
+			};
+
+			header("X-Haystack-Cookie").and_then(|cookie| {
+				let cookie = cookie.parse::<CookieBuf>().map_err(|_| Error::from("Invalid cookie"))?;
+
+				let hash = header("X-Haystack-Hash")?;
+				if !hash.starts_with("crc32c=") {
+					return Err("Unsupported hash".into());
+				}
+
+				let sum = parse_urlbase64(&hash["crc32c=".len()..]).map_err(|_| Error::from("Invalid hash"))?;
+
+				Ok((cookie, sum))
+			})
+		};
+
+		let (cookie, sum) = match parsed {
+			Ok(v) => v,
+			Err(e) => return Either::A(err(e))
+		};
+
+		Either::B(
+			resp.into_body().concat2()
+			.map_err(|e| e.into())
+			.and_then(move |body| {
+				let mut actual = vec![];
+				actual.write_u32::<LittleEndian>(crc32c_append(0, &body)).unwrap();
+
+				if actual != sum {
+					return err("Needle data does not match checksum".into());
+				}
+
+				ok(Some((cookie, body.into_bytes())))
+			})
+		)
+	})
+}
@@ -0,0 +1,240 @@
+/*
+	This file contains the routes for copying entire volumes between stores
+	A volume is exported as the concatenation of every live needle exactly as it is stored on disk (header, data and footer without padding), so the receiving store can verify every checksum before accepting it
+*/
+
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::http::*;
+use super::machine::*;
+use super::volume::*;
+use super::needle::*;
+use super::stream::SingleStream;
+use super::api::*;
+use hyper::{Body, Response, StatusCode};
+use futures::prelude::*;
+use futures::future::*;
+use futures::stream;
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use arrayref::*;
+
+
+/// Streams back all needles that have not been deleted in the given volume
+/// NOTE: Each needle is checked for integrity before it is sent and the response will be aborted if any of them are corrupt
+pub fn export_volume(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId
+) -> Result<Response<Body>> {
+
+	let vol_handle = {
+		let mac = mac_handle.inst.read().unwrap();
+
+		match mac.volumes.get(&volume_id) {
+			Some(v) => v.clone(),
+			None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found")),
+		}
+	};
+
+	let all_keys = vol_handle.lock().unwrap().live_needle_keys();
+
+	let strm = stream::iter_ok::<_, Error>(all_keys)
+	.and_then(move |keys| -> Result<Option<Bytes>> {
+		// NOTE: We only lock the volume for one needle at a time so that other requests can make progress while we are exporting
+		let mut vol = vol_handle.lock().unwrap();
+
+		// Needles deleted since we started are simply skipped
+		let n = match vol.read_needle(&keys)? {
+			Some(n) => n.needle,
+			None => return Ok(None)
+		};
+
+		n.check()?;
+
+		Ok(Some(n.into_bytes()))
+	})
+	.filter_map(|data| data)
+	.map(|data| hyper::Chunk::from(data))
+	.map_err(move |e| {
+		eprintln!("Failed to export volume {}: {:?}", volume_id, e);
+		std::io::Error::new(std::io::ErrorKind::Other, "Volume export failed")
+	});
+
+	Ok(Response::builder()
+		.status(StatusCode::OK)
+		.header("Content-Type", "application/octet-stream")
+		.body(Body::wrap_stream(strm))
+		.unwrap())
+}
+
+
+// Internal state of the volume importer
+struct ImportState {
+	vol_handle: Arc<Mutex<PhysicalVolume>>,
+
+	/// Largest needle that we are willing to buffer
+	max_size: NeedleSize,
+
+	/// Header of the needle currently being received
+	header: Option<NeedleHeader>,
+
+	/// All bytes received so far for the current needle
+	buf: Vec<u8>,
+
+	num_imported: usize
+}
+
+/// Fills a new volume with all of the needles in the request body (as produced by export_volume on another store)
+/// If the import fails part way through, then the volume is deleted again so that the import can be safely retried
+pub fn import_volume(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId,
+	body: Body
+) -> impl Future<Item=Response<Body>, Error=Error> {
+
+	fn get_volume(mac_handle: &MachineHandle, volume_id: VolumeId) -> Result<Arc<Mutex<PhysicalVolume>>> {
+		let mut mac = mac_handle.inst.write().unwrap();
+
+		// Importing on top of existing needles would leave us with a mix of two volumes
+		if mac.volumes.contains_key(&volume_id) {
+			return Err(ErrorKind::API(409, "Volume already exists").into());
+		}
+
+		if !mac.stats().can_allocate() {
+			return Err(ErrorKind::API(400, "Can not currently allocate volumes").into());
+		}
+
+		mac.create_volume(volume_id)?;
+
+		println!("- Volume {} created on Store {} for import", volume_id, mac_handle.id);
+		mac_handle.thread.notify();
+
+		Ok(mac.volumes.get(&volume_id).unwrap().clone())
+	}
+
+	// Consumes as many bytes as are needed to complete the current needle, returning the rest
+	fn take_needle(state: &mut ImportState, data: Bytes) -> Result<Bytes> {
+
+		let needed = match state.header {
+			Some(ref h) => h.meta.total_size() as usize,
+			None => NEEDLE_HEADER_SIZE
+		};
+
+		let ntake = std::cmp::min(needed - state.buf.len(), data.len());
+		state.buf.extend_from_slice(&data[0..ntake]);
+
+		if state.buf.len() == needed {
+			if state.header.is_none() {
+				let header = NeedleHeader::parse(array_ref!(&state.buf, 0, NEEDLE_HEADER_SIZE))
+					.map_err(|_| Error::from(ErrorKind::API(400, "Invalid needle header")))?;
+
+				// Otherwise a bad header could make us buffer an arbitrary amount of data
+				if header.meta.size > state.max_size {
+					return Err(ErrorKind::API(400, "Needle is too large").into());
+				}
+
+				state.header = Some(header);
+			}
+			else {
+				let buf = std::mem::replace(&mut state.buf, vec![]);
+				state.header = None;
+
+				append_needle(state, Bytes::from(buf))?;
+			}
+		}
+
+		Ok(data.slice_from(ntake))
+	}
+
+	fn append_needle(state: &mut ImportState, buf: Bytes) -> Result<()> {
+		let needle = Needle::from_bytes(buf)
+			.map_err(|_| Error::from(ErrorKind::API(400, "Invalid needle")))?;
+
+		if needle.check().is_err() {
+			return Err(ErrorKind::API(400, "Needle failed integrity check").into());
+		}
+
+		let mut vol = state.vol_handle.lock().unwrap();
+
+		if !vol.can_write() {
+			return Err(ErrorKind::API(400, "Volume is out of space and not writeable").into());
+		}
+
+		let mut strm = SingleStream::from(needle.data());
+		vol.append_needle(
+			needle.header.keys.clone(), needle.header.cookie.clone(), needle.header.meta.clone(), &mut strm
+		)?;
+
+		state.num_imported += 1;
+
+		Ok(())
+	}
+
+	let vol_handle = match get_volume(&mac_handle, volume_id) {
+		Ok(v) => v,
+		Err(Error(ErrorKind::API(code, msg), _)) => {
+			return Either::A(ok(text_response(StatusCode::from_u16(code).unwrap_or(StatusCode::BAD_REQUEST), msg)));
+		},
+		Err(e) => return Either::A(err(e))
+	};
+
+	let state_handle = Arc::new(Mutex::new(ImportState {
+		vol_handle,
+		max_size: mac_handle.config.store.max_upload_size,
+		header: None,
+		buf: vec![],
+		num_imported: 0
+	}));
+
+	Either::B(body
+	.map_err(|e| e.into())
+	.fold(state_handle.clone(), |state_handle, c| {
+		{
+			let mut state = state_handle.lock().unwrap();
+			let mut data = c.into_bytes();
+
+			while data.len() > 0 {
+				data = match take_needle(&mut state, data) {
+					Ok(d) => d,
+					Err(e) => return err(e)
+				};
+			}
+		}
+
+		ok(state_handle)
+	})
+	.and_then(|state_handle| {
+		let state = state_handle.lock().unwrap();
+
+		if state.buf.len() > 0 {
+			return Err(ErrorKind::API(400, "Received a partial needle").into());
+		}
+
+		state.vol_handle.lock().unwrap().flush()?;
+
+		Ok(state.num_imported)
+	})
+	.then(move |res| {
+		if res.is_err() {
+			let mut mac = mac_handle.inst.write().unwrap();
+
+			match mac.remove_volume(volume_id) {
+				Ok(_) => println!("- Volume {} deleted from Store {} after a failed import", volume_id, mac_handle.id),
+				Err(e) => eprintln!("Failed to delete volume {} after a failed import: {:?}", volume_id, e)
+			}
+
+			mac_handle.thread.notify();
+		}
+
+		match res {
+			Ok(num_imported) => {
+				println!("- Imported {} needles into volume {}", num_imported, volume_id);
+				ok(json_response(StatusCode::OK, &StoreImportResponse { num_imported }))
+			},
+			Err(Error(ErrorKind::API(code, msg), _)) => {
+				ok(text_response(StatusCode::from_u16(code).unwrap_or(StatusCode::BAD_REQUEST), msg))
+			},
+			Err(e) => err(e)
+		}
+	}))
+}
@@ -0,0 +1,542 @@
+/*
+	This file contains the routes for erasure coded volumes
+	A sealed volume is encoded by one of its replicas into shards which are sent off to other stores. After that, any store holding one of the shards can serve reads from the volume by fetching the needed pieces of the data shards from the other stores (reconstructing them from the rest of the shards if some of them are unavailable)
+*/
+
+use super::super::common::*;
+use super::super::errors::*;
+use super::super::http::*;
+use super::super::paths::*;
+use super::super::directory::models;
+use super::machine::*;
+use super::shard::*;
+use super::needle::*;
+use super::range::*;
+use super::api::*;
+use core::algorithms::ReedSolomon;
+use core::FlipSign;
+use hyper::{Body, Response, StatusCode};
+use hyper::header::HeaderMap;
+use hyper::http::request::Parts;
+use futures::prelude::*;
+use futures::prelude::await;
+use futures::future::*;
+use futures::{Async, Poll};
+use futures::sync::oneshot;
+use std::io::Write;
+use std::fs;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use bytes::Bytes;
+
+
+/// Size of the chunks in which shard files are streamed out
+const SHARD_CHUNK_SIZE: u64 = 64*1024;
+
+
+/// Whether or not reads from a volume on this machine must go through its shard (we hold a shard but not a full copy of the volume)
+pub fn is_coded(mac_handle: &MachineHandle, volume_id: VolumeId) -> bool {
+	let mac = mac_handle.inst.read().unwrap();
+	!mac.volumes.contains_key(&volume_id) && mac.shards.contains_key(&volume_id)
+}
+
+/// Encodes a volume on this machine and sends each of its shards to the requested stores
+/// NOTE: The volume should already be read-only everywhere, as it is only locked briefly for each needle that is read while it is being encoded
+#[async]
+pub fn encode_volume(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId,
+	body: Body
+) -> Result<Response<Body>> {
+
+	let body = await!(body.concat2())?;
+
+	let req = match serde_json::from_slice::<StoreEncodeRequest>(&body) {
+		Ok(v) => v,
+		Err(_) => return Ok(bad_request_because("Invalid encode request"))
+	};
+
+	let total = req.data_shards + req.parity_shards;
+	if req.data_shards == 0 || total > 256 || req.machine_ids.len() != total {
+		return Ok(bad_request_because("Invalid number of shards"));
+	}
+
+	let (vol_handle, paths) = {
+		let mac = mac_handle.inst.read().unwrap();
+
+		let v = match mac.volumes.get(&volume_id) {
+			Some(v) => v.clone(),
+			None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+		};
+
+		(v, (0..total).map(|i| mac.get_shard_temp_path(volume_id, i)).collect::<Vec<_>>())
+	};
+
+	// Figuring out where every shard is going before doing any work
+	let machines = {
+		let dir = mac_handle.dir.lock().unwrap();
+		let cluster = dir.cluster()?;
+
+		let mut arr = vec![];
+		for id in req.machine_ids.iter() {
+			match cluster.store_machines.iter().find(|m| m.id.flip() == *id) {
+				Some(m) => arr.push(m.clone()),
+				None => return Ok(bad_request_because("Unknown store machine"))
+			}
+		}
+
+		arr
+	};
+
+	// Leftovers from a previously failed attempt
+	remove_files(&paths);
+
+	// Encoding reads and writes the entire volume, so it is done on its own thread rather than stalling every other request being served by this one
+	let (tx, rx) = oneshot::channel();
+	{
+		let paths = paths.clone();
+		let (data_shards, parity_shards) = (req.data_shards, req.parity_shards);
+		thread::spawn(move || {
+			let _ = tx.send(VolumeShard::encode(&vol_handle, data_shards, parity_shards, &paths));
+		});
+	}
+
+	let header = match await!(rx) {
+		Ok(r) => r,
+		Err(_) => Err("Volume encoding thread stopped unexpectedly".into())
+	};
+
+	let header = match header {
+		Ok(h) => h,
+		Err(e) => {
+			remove_files(&paths);
+			return Err(e);
+		}
+	};
+
+	println!("- Volume {} encoded into {}+{} shards on Store {}", volume_id, req.data_shards, req.parity_shards, mac_handle.id);
+
+	let mut uploads = vec![];
+	for (i, m) in machines.iter().enumerate() {
+		// Our own shard just needs to be moved into place
+		if m.id.flip() == mac_handle.id {
+			if let Err(e) = mac_handle.inst.write().unwrap().add_shard(volume_id, i, &paths[i]) {
+				remove_files(&paths);
+				return Err(e);
+			}
+
+			continue;
+		}
+
+		uploads.push(upload_shard(m, volume_id, i, &paths[i]));
+	}
+
+	let res = await!(join_all(uploads));
+
+	remove_files(&paths);
+	res?;
+
+	Ok(json_response(StatusCode::OK, &StoreEncodeResponse {
+		num_needles: header.num_needles as usize,
+		shard_size: header.shard_size
+	}))
+}
+
+/// Sends a complete shard file to the store that should hold it
+fn upload_shard(
+	mac: &models::StoreMachine, volume_id: VolumeId, index: usize, path: &Path
+) -> impl Future<Item=(), Error=Error> {
+
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) => return Either::A(err(e.into()))
+	};
+
+	let size = match file.metadata() {
+		Ok(m) => m.len(),
+		Err(e) => return Either::A(err(e.into()))
+	};
+
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", mac.addr(), StorePath::VolumeShard { volume_id, index }.to_string()))
+		.method("PUT")
+		.header("Host", Host::Store(mac.id.flip()).to_string())
+		.header("Content-Length", size.to_string())
+		.body(Body::wrap_stream(FileChunks { file, offset: 0, end: size }))
+		.unwrap();
+
+	let mac_id = mac.id;
+
+	Either::B(
+		hyper::Client::new().request(req)
+		.map_err(|e| e.into())
+		.and_then(move |resp| {
+			if !resp.status().is_success() {
+				return err(format!("Failed to upload shard {} to store {} (code: {})", index, mac_id, resp.status()).into());
+			}
+
+			ok(())
+		})
+	)
+}
+
+/// Receives a shard of a volume from the store that encoded it
+#[async]
+pub fn import_shard(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId, index: usize,
+	body: Body
+) -> Result<Response<Body>> {
+
+	let path = {
+		let mac = mac_handle.inst.read().unwrap();
+
+		if let Some(s) = mac.shards.get(&volume_id) {
+			if s.header.shard_index == index {
+				return Ok(text_response(StatusCode::OK, "Shard already exists"));
+			}
+
+			return Ok(text_response(StatusCode::CONFLICT, "Already holding a different shard of this volume"));
+		}
+
+		if !mac.stats().can_allocate() {
+			return Ok(text_response(StatusCode::BAD_REQUEST, "Can not currently allocate volumes"));
+		}
+
+		mac.get_shard_temp_path(volume_id, index)
+	};
+
+	let mut file = File::create(&path)?;
+
+	#[async]
+	for c in body {
+		file.write_all(&c)?;
+	}
+
+	file.sync_all()?;
+	drop(file);
+
+	// The whole file is verified before it is used
+	let res = mac_handle.inst.write().unwrap().add_shard(volume_id, index, &path);
+	if let Err(e) = res {
+		eprintln!("Rejected shard {} of volume {}: {:?}", index, volume_id, e);
+		remove_files(&[path]);
+		return Ok(bad_request_because("Invalid shard file"));
+	}
+
+	println!("- Shard {} of volume {} added to Store {}", index, volume_id, mac_handle.id);
+	mac_handle.thread.notify();
+
+	Ok(text_response(StatusCode::CREATED, "Shard added!"))
+}
+
+/// Streams out the raw data of one of our shards (or a single range of it given a Range header)
+pub fn read_shard(
+	parts: &Parts,
+	mac_handle: MachineHandle,
+	volume_id: VolumeId, index: usize
+) -> Result<Response<Body>> {
+
+	let shard = {
+		let mac = mac_handle.inst.read().unwrap();
+
+		match mac.shards.get(&volume_id) {
+			Some(s) if s.header.shard_index == index => s.clone(),
+			_ => return Ok(text_response(StatusCode::NOT_FOUND, "Shard not found"))
+		}
+	};
+
+	let size = shard.header.shard_size;
+
+	let ranges = match parts.headers.get("Range").and_then(|v| v.to_str().ok()) {
+		Some(s) => RangeRequest::parse(s, size),
+		None => RangeRequest::Full
+	};
+
+	let range = match ranges {
+		RangeRequest::Full => None,
+		RangeRequest::Unsatisfiable => return Ok(unsatisfiable_response(size)),
+		RangeRequest::Partial(ref r) if r.len() == 1 => Some(r[0].clone()),
+		RangeRequest::Partial(_) => return Ok(bad_request_because("Only one range of a shard can be read at a time"))
+	};
+
+	let (file, data_offset) = shard.data_file()?;
+
+	let mut res = Response::builder();
+	res.header("Content-Type", "application/octet-stream");
+	res.header("Accept-Ranges", "bytes");
+
+	let (start, end) = match range {
+		Some(r) => {
+			res.status(StatusCode::PARTIAL_CONTENT);
+			res.header("Content-Range", r.content_range(size));
+			(r.start, r.end)
+		},
+		None => {
+			res.status(StatusCode::OK);
+			(0, size)
+		}
+	};
+
+	res.header("Content-Length", (end - start).to_string());
+
+	Ok(res.body(Body::wrap_stream(FileChunks {
+		file,
+		offset: data_offset + start,
+		end: data_offset + end
+	})).unwrap())
+}
+
+/// Reads a needle from a volume of which we only hold a shard
+///
+/// The needle is put back together from the data shards holding it and any data shard that can't be read is reconstructed from the other shards
+/// NOTE: The whole needle is read into memory as it must be verified before any of it is sent (so Range requests are ignored). Coded volumes are cold, so this should be rare
+#[async]
+pub fn read_coded_photo(
+	headers: HeaderMap,
+	mac_handle: MachineHandle,
+	volume_id: VolumeId, key: NeedleKey, alt_key: NeedleAltKey,
+	given_cookie: Option<CookieBuf>
+) -> Result<Response<Body>> {
+
+	let shard = match mac_handle.inst.read().unwrap().shards.get(&volume_id).cloned() {
+		Some(s) => s,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let keys = NeedleKeys { key, alt_key };
+
+	let (position, entry) = match shard.lookup(&keys) {
+		Some((i, e)) => (i as BlockOffset, e.clone()),
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Needle not found"))
+	};
+
+	let given_etag = match headers.get("If-None-Match") {
+		Some(v) => match ETag::from_header(v) {
+			Ok(e) => Some(e),
+			Err(_) => return Ok(bad_request())
+		},
+		None => None
+	};
+
+	// Coded volumes never change, so the position of the needle is all that the cache needs to revalidate it
+	if given_cookie.is_none() {
+		if let Some(ref e) = given_etag {
+			if e.partial_matches(mac_handle.id, volume_id, position) {
+				return Ok(Response::builder()
+					.status(StatusCode::NOT_MODIFIED)
+					.header("ETag", e.to_string())
+					.header("X-Haystack-Writeable", "0")
+					.body(Body::empty()).unwrap());
+			}
+		}
+	}
+
+	let holders = shard_holders(&mac_handle, volume_id)?;
+
+	let buf = await!(read_stream_range(
+		shard.clone(), holders, entry.offset, entry.offset + entry.meta.total_size()
+	))?;
+
+	let needle = Needle::from_bytes(buf)?;
+	needle.check()?;
+
+	if needle.header.keys != keys {
+		return Err("Coded read produced the wrong needle".into());
+	}
+
+	if let Some(c) = given_cookie {
+		if c.data() != needle.header.cookie.data() {
+			return Ok(text_response(StatusCode::FORBIDDEN, "Incorrect cookie"));
+		}
+	}
+
+	let etag = ETag {
+		store_id: mac_handle.id, volume_id, block_offset: position, checksum: Bytes::from(needle.crc32c())
+	};
+
+	let mut res = Response::builder();
+
+	res
+	.header("ETag", etag.to_string())
+	.header("X-Haystack-Cookie", needle.header.cookie.to_string())
+	.header("X-Haystack-Hash", String::from("crc32c=") + &serialize_urlbase64(needle.crc32c()))
+	.header("X-Haystack-Writeable", "0");
+
+	if let Some(e) = given_etag {
+		if etag.matches(&e) {
+			return Ok(res.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
+		}
+	}
+
+	let content_type = sniff_content_type(needle.data());
+
+	let data = needle.data_bytes();
+
+	Ok(res
+		.status(StatusCode::OK)
+		.header("Content-Type", content_type.as_str())
+		.header("Content-Length", data.len().to_string())
+		.body(Body::from(data))
+		.unwrap())
+}
+
+/// Finds the readable store holding each shard of a volume (indexed by shard index)
+fn shard_holders(mac_handle: &MachineHandle, volume_id: VolumeId) -> Result<Vec<Option<models::StoreMachine>>> {
+	let dir = mac_handle.dir.lock().unwrap();
+
+	let mut holders = vec![];
+	for (i, m) in dir.cluster()?.shard_machines_for_volume(volume_id) {
+		if !m.can_read(&dir.config) {
+			continue;
+		}
+
+		if holders.len() <= i {
+			holders.resize(i + 1, None);
+		}
+
+		holders[i] = Some(m);
+	}
+
+	Ok(holders)
+}
+
+/// Reads the bytes in [start, end) of the stream of needles that was encoded into the shards
+#[async]
+fn read_stream_range(
+	shard: Arc<VolumeShard>, holders: Vec<Option<models::StoreMachine>>, start: u64, end: u64
+) -> Result<Bytes> {
+
+	let mut out = Vec::new();
+	out.reserve((end - start) as usize);
+
+	for piece in shard.header.pieces(start, end) {
+		let data = match await!(read_shard_piece(&shard, &holders, piece.shard_index, piece.start, piece.end)) {
+			Ok(d) => d,
+			Err(e) => {
+				eprintln!("Failed to read shard {} of volume {}: {:?}", piece.shard_index, shard.header.volume_id, e);
+				await!(reconstruct_shard_piece(shard.clone(), holders.clone(), piece.shard_index, piece.start, piece.end))?
+			}
+		};
+
+		out.extend_from_slice(&data);
+	}
+
+	Ok(Bytes::from(out))
+}
+
+/// Reads the bytes in [start, end) of a single shard either locally or from the store holding it
+fn read_shard_piece(
+	shard: &VolumeShard, holders: &[Option<models::StoreMachine>], index: usize, start: u64, end: u64
+) -> impl Future<Item=Bytes, Error=Error> {
+
+	if index == shard.header.shard_index {
+		return Either::A(result(shard.read_data(start, end)));
+	}
+
+	match holders.get(index) {
+		Some(Some(m)) => Either::B(Either::A(fetch_shard_range(m, shard.header.volume_id, index, start, end))),
+		_ => Either::B(Either::B(err("No readable store holds this shard".into())))
+	}
+}
+
+/// Recomputes the bytes in [start, end) of a shard from the same range of the other shards
+/// NOTE: The range is requested from all other shards at once and whichever of them respond are used (rather than waiting to find out which ones fail)
+#[async]
+fn reconstruct_shard_piece(
+	shard: Arc<VolumeShard>, holders: Vec<Option<models::StoreMachine>>, index: usize, start: u64, end: u64
+) -> Result<Bytes> {
+
+	let reads = (0..shard.header.total_shards()).map(|i| {
+		let f = if i == index {
+			Either::A(err("Missing shard".into()))
+		} else {
+			Either::B(read_shard_piece(&shard, &holders, i, start, end))
+		};
+
+		f.then(|r: Result<Bytes>| -> Result<Option<Vec<u8>>> { Ok(r.ok().map(|b| b.to_vec())) })
+	}).collect::<Vec<_>>();
+
+	let mut pieces = await!(join_all(reads))?;
+
+	let rs = ReedSolomon::new(shard.header.data_shards, shard.header.parity_shards);
+	rs.reconstruct(&mut pieces).map_err(|e| Error::from(e))?;
+
+	Ok(Bytes::from(pieces[index].take().unwrap()))
+}
+
+/// Requests the bytes in [start, end) of a shard from another store
+fn fetch_shard_range(
+	mac: &models::StoreMachine, volume_id: VolumeId, index: usize, start: u64, end: u64
+) -> impl Future<Item=Bytes, Error=Error> {
+
+	let req = hyper::Request::builder()
+		.uri(format!("{}{}", mac.addr(), StorePath::VolumeShard { volume_id, index }.to_string()))
+		.method("GET")
+		.header("Host", Host::Store(mac.id.flip()).to_string())
+		.header("Range", format!("bytes={}-{}", start, end - 1))
+		.body(Body::empty())
+		.unwrap();
+
+	let len = end - start;
+
+	hyper::Client::new().request(req)
+	.map_err(|e| e.into())
+	.and_then(move |resp| {
+		let status = resp.status();
+
+		resp.into_body().concat2()
+		.map_err(|e| e.into())
+		.and_then(move |body| {
+			if status != StatusCode::PARTIAL_CONTENT || (body.len() as u64) != len {
+				return err(format!("Shard read failed with code: {}", status).into());
+			}
+
+			ok(body.into_bytes())
+		})
+	})
+}
+
+fn remove_files(paths: &[PathBuf]) {
+	for p in paths {
+		if p.exists() {
+			if let Err(e) = fs::remove_file(p) {
+				eprintln!("Failed to remove {:?}: {:?}", p, e);
+			}
+		}
+	}
+}
+
+
+/// Streams out a span of a file in chunks using positional reads
+struct FileChunks {
+	file: File,
+
+	/// Absolute offset in the file of the next byte to send
+	offset: u64,
+
+	/// Absolute offset in the file one past the last byte to send
+	end: u64
+}
+
+impl futures::Stream for FileChunks {
+	type Item = hyper::Chunk;
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> Poll<Option<hyper::Chunk>, std::io::Error> {
+		if self.offset >= self.end {
+			return Ok(Async::Ready(None));
+		}
+
+		let n = std::cmp::min(SHARD_CHUNK_SIZE, self.end - self.offset) as usize;
+
+		let mut buf = vec![0u8; n];
+		self.file.read_exact_at(&mut buf, self.offset)?;
+		self.offset += n as u64;
+
+		Ok(Async::Ready(Some(hyper::Chunk::from(buf))))
+	}
+}
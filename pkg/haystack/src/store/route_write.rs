@@ -26,6 +26,10 @@ pub fn write_single(
 	body: Body
 ) -> Result<Response<Body>> {
 
+	if content_length > mac_handle.config.store.max_upload_size {
+		return Ok(text_response(StatusCode::BAD_REQUEST, "Needle is too large"));
+	}
+
 	let mut chunks = vec![];
 	let mut nread = 0;
 
@@ -140,9 +144,14 @@ pub fn write_batch(
 
 		// Check if we are done building the header
 		if state.header_buf.len() == NEEDLE_CHUNK_HEADER_SIZE {
-			state.header = Some(
-				NeedleChunk::read_header(&mut std::io::Cursor::new(&state.header_buf))?
-			);
+			let header = NeedleChunk::read_header(&mut std::io::Cursor::new(&state.header_buf))?;
+
+			// All of the chunks of a needle are held in memory until the whole needle is received
+			if header.1 > state.mac_handle.config.store.max_upload_size {
+				return Err(ErrorKind::API(400, "Needle is too large").into());
+			}
+
+			state.header = Some(header);
 
 			state.header_buf.clear();
 			state.nread = 0;
@@ -5,14 +5,19 @@ use super::super::http::*;
 use super::api::*;
 use super::machine::*;
 use super::volume::*;
+use super::range::*;
 use hyper::{Body, Response, Method, StatusCode};
 use hyper::http::request::Parts;
 use hyper::body::Payload;
-use mime_sniffer::MimeTypeSniffer;
 use futures::prelude::*;
 use futures::prelude::await;
 use futures::future::*;
 use futures::Stream;
+use std::sync::{Arc, Mutex};
+
+
+/// Maximum number of needles returned in each page by the volume list route
+const NEEDLE_LIST_PAGE_SIZE: usize = 10000;
 
 
 #[async]
@@ -54,23 +59,85 @@ pub fn handle_request(
 			match parts.method {
 				Method::GET => read_volume(mac_handle, volume_id),
 				Method::POST => create_volume(mac_handle, volume_id),
+				Method::DELETE => delete_volume(mac_handle, volume_id),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeList { volume_id, position } => {
+			match parts.method {
+				Method::GET => list_needles(mac_handle, volume_id, position),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeCorrupt { volume_id } => {
+			match parts.method {
+				Method::GET => read_corrupt_needles(mac_handle, volume_id),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeRepair { volume_id } => {
+			match parts.method {
+				Method::POST => await!(super::route_repair::repair_volume(mac_handle, volume_id)),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeEncode { volume_id } => {
+			match parts.method {
+				Method::POST => await!(super::route_shard::encode_volume(mac_handle, volume_id, body)),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeShard { volume_id, index } => {
+			match parts.method {
+				Method::GET => super::route_shard::read_shard(&parts, mac_handle, volume_id, index),
+				Method::PUT => await!(super::route_shard::import_shard(mac_handle, volume_id, index, body)),
+				_ => Ok(invalid_method())
+			}
+		},
+
+		StorePath::VolumeNeedles { volume_id } => {
+			match parts.method {
+				Method::GET => super::route_replicate::export_volume(mac_handle, volume_id),
+				Method::PUT => await!(super::route_replicate::import_volume(mac_handle, volume_id, body)),
 				_ => Ok(invalid_method())
 			}
 		},
 
 		StorePath::Photo { volume_id, key } => {
-			Ok(bad_request())
+			match parts.method {
+				Method::DELETE => delete_photo_all(mac_handle, volume_id, key),
+				_ => Ok(invalid_method())
+			}
 		},
 
 		StorePath::Partial { volume_id, key, alt_key } => {
 			match parts.method {
+				Method::GET if super::route_shard::is_coded(&mac_handle, volume_id) => {
+					await!(super::route_shard::read_coded_photo(parts.headers.clone(), mac_handle, volume_id, key, alt_key, None))
+				},
 				Method::GET => read_photo(&parts, mac_handle, volume_id, key, alt_key, None),
+				Method::DELETE => delete_photo(mac_handle, volume_id, key, alt_key),
 				_ => return Ok(invalid_method())
 			}
 		},
 
+		StorePath::NeedleRepair { volume_id, key, alt_key } => {
+			match parts.method {
+				Method::POST => await!(super::route_repair::repair_needle(mac_handle, volume_id, key, alt_key)),
+				_ => Ok(invalid_method())
+			}
+		},
+
 		StorePath::Needle { volume_id, key, alt_key, cookie } => {
 			match parts.method {
+				Method::GET if super::route_shard::is_coded(&mac_handle, volume_id) => {
+					await!(super::route_shard::read_coded_photo(parts.headers.clone(), mac_handle, volume_id, key, alt_key, Some(cookie)))
+				},
 				Method::GET => read_photo(&parts, mac_handle, volume_id, key, alt_key, Some(cookie)),
 				Method::POST => {
 					
@@ -91,20 +158,18 @@ pub fn handle_request(
 	}
 }
 
-#[derive(Serialize)]
-struct StoreReadVolumeBody {
-	id: VolumeId,
-	num_needles: usize,
-	used_space: u64
-}
-
 // TODO: 'std::convert::From<&PhysicalVolume> for'
 impl StoreReadVolumeBody {
 	fn from(vol: &PhysicalVolume) -> StoreReadVolumeBody {
 		StoreReadVolumeBody {
 			id: vol.superblock.volume_id,
 			num_needles: vol.num_needles(),
-			used_space: vol.used_space()
+			used_space: vol.used_space(),
+			allocated_space: vol.superblock.allocated_space,
+			writeable: vol.can_write_soft(),
+			compaction_pending: vol.compaction_pending(),
+			compaction: vol.compaction_progress(),
+			corrupt_needles: vol.corrupt_needles().len()
 		}
 	}
 }
@@ -165,6 +230,72 @@ fn create_volume(
 	Ok(text_response(StatusCode::CREATED, "Volume created!"))
 }
 
+/// Lists the keys of all needles in a volume that failed their integrity checks
+fn read_corrupt_needles(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId
+) -> Result<Response<Body>> {
+
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let keys = vol_handle.lock().unwrap().corrupt_needles();
+
+	Ok(json_response(StatusCode::OK, &keys))
+}
+
+/// Lists one page of the needles in a volume by reading a chunk of its index file
+fn list_needles(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId,
+	position: u64
+) -> Result<Response<Body>> {
+
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let (entries, next) = vol_handle.lock().unwrap().list_needles(position, NEEDLE_LIST_PAGE_SIZE)?;
+
+	let needles = entries.into_iter().map(|(k, e)| {
+		StoreNeedleListEntry {
+			key: k.key,
+			alt_key: k.alt_key,
+			size: e.meta.size,
+			flags: e.meta.flags,
+			block_offset: e.block_offset
+		}
+	}).collect();
+
+	Ok(json_response(StatusCode::OK, &StoreNeedleListResponse { needles, next }))
+}
+
+/// Permanently deletes a volume (it should already have been removed from the directory)
+fn delete_volume(
+	mac_handle: MachineHandle,
+	volume_id: VolumeId
+) -> Result<Response<Body>> {
+
+	let mut mac = mac_handle.inst.write().unwrap();
+
+	if !mac.volumes.contains_key(&volume_id) {
+		return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"));
+	}
+
+	if let Err(e) = mac.remove_volume(volume_id) {
+		eprintln!("Failed to delete volume {}: {:?}", volume_id, e);
+		return Ok(text_response(StatusCode::CONFLICT, "Volume could not be deleted right now"));
+	}
+
+	println!("- Volume {} deleted from Store {}", volume_id, mac_handle.id);
+	mac_handle.thread.notify();
+
+	Ok(text_response(StatusCode::OK, "Volume deleted!"))
+}
+
 
 
 
@@ -225,10 +356,8 @@ fn read_photo(
 
 
 	// TODO: I do want to be able to support exporting legit errors
-	let r = vol.read_needle(&NeedleKeys { key, alt_key })?;
-
-	let (n, offset) = match r {
-		Some(n) => (n.needle, n.block_offset),
+	let mut strm = match vol.open_needle(&NeedleKeys { key, alt_key })? {
+		Some(s) => s,
 		None => {
 			return Ok(
 				text_response(StatusCode::NOT_FOUND, "Needle not found")
@@ -236,13 +365,11 @@ fn read_photo(
 		}
 	};
 
-	// Integrity check
-	if let Err(_) = n.check() {
-		return Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, "Integrity check failed"));
-	}
+	// The stream has its own handle to the file, so the volume does not need to stay locked while the data is sent
+	drop(vol);
 
 	if let Some(c) = given_cookie {
-		if c.data() != n.header.cookie.data() {
+		if c.data() != strm.header.cookie.data() {
 			return Ok(text_response(StatusCode::FORBIDDEN, "Incorrect cookie"));
 		}
 	}
@@ -255,15 +382,15 @@ fn read_photo(
 
 	// Now producing the response
 
-	let cookie = n.header.cookie.to_string();
+	let cookie = strm.header.cookie.to_string();
 
-	let sum = serialize_urlbase64(n.crc32c());
+	let sum = serialize_urlbase64(&strm.crc32c());
 
 	let mut res = Response::builder();
 	
 	// The etag is mainly designed to make hits to the same machine very efficient and hits to other machines at least able to notice after a disk read
 	let etag = ETag {
-		store_id: mac_id, volume_id, block_offset: offset, checksum: bytes::Bytes::from(n.crc32c())
+		store_id: mac_id, volume_id, block_offset: strm.block_offset, checksum: bytes::Bytes::from(strm.crc32c())
 	};	
 
 	res
@@ -281,33 +408,87 @@ fn read_photo(
 	// Sniffing the Content-Type from the first few bytes of the file
 	// For images, this should pretty much always work
 	// TODO: If we were obsessed with performance, we would do this on the cache server to avoid transfering it
-	{
-		let data = n.data();
-		if data.len() > 4 {
-			let magic = &data[0..std::cmp::min(8, data.len())];
-			match magic.sniff_mime_type() {
-				Some(mime) => res.header("Content-Type", mime.to_owned()),
-				None => res.header("Content-Type", "application/octet-stream")
-			};
+	let content_type = sniff_content_type(&strm.prefix(8)?);
+
+	res.header("Accept-Ranges", "bytes");
+
+	let size = strm.size();
+
+	let ranges = match RangeRequest::from_headers(&parts.headers, size, &etag) {
+		RangeRequest::Full => {
+			res.header("Content-Length", size.to_string());
+			None
+		},
+		RangeRequest::Unsatisfiable => return Ok(unsatisfiable_response(size)),
+		RangeRequest::Partial(ranges) => Some(ranges)
+	};
+
+	if let Some(ranges) = ranges {
+		res.status(StatusCode::PARTIAL_CONTENT);
+
+		if ranges.len() == 1 {
+			let r = &ranges[0];
+			strm.restrict(r.start, r.end)?;
+			res.header("Content-Type", content_type.as_str());
+			res.header("Content-Range", r.content_range(size));
+			res.header("Content-Length", r.len().to_string());
+		}
+		else {
+			// For multiple ranges, the individual spans are read into memory as they will typically be small
+			let (ctype, body) = multipart_body(&ranges, size, &content_type, |r| {
+				strm.read_range(r.start, r.end)
+			})?;
+
+			res.header("Content-Type", ctype);
+			res.header("Content-Length", body.len().to_string());
+			return Ok(res.body(Body::from(body)).unwrap());
 		}
 	}
+	else {
+		res.status(StatusCode::OK);
+		res.header("Content-Type", content_type.as_str());
+	}
 
+	// NOTE: The checksum is verified as the data is read, so a corrupt needle will abort the response before its last chunk is sent (the client will see a truncated body rather than bad data)
+	let body = strm
+		.map(|c| hyper::Chunk::from(c))
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
 
 	Ok(
 		res
-		.status(StatusCode::OK)
-		.body(Body::from(n.data_bytes()))
+		.body(Body::wrap_stream(body))
 		.unwrap()
 	)
 }
 
+/// Briefly locks the machine to get a handle to one of its volumes
+pub fn get_volume_handle(
+	mac_handle: &MachineHandle, volume_id: VolumeId
+) -> Option<Arc<Mutex<PhysicalVolume>>> {
+	let mac = mac_handle.inst.read().unwrap();
+	mac.volumes.get(&volume_id).cloned()
+}
+
 // Deletes a single photo needle
 fn delete_photo(
 	mac_handle: MachineHandle,
 	volume_id: VolumeId, key: NeedleKey, alt_key: NeedleAltKey
 ) -> Result<Response<Body>> {
 
-	Ok(text_response(StatusCode::OK, "Woo!"))
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let mut vol = vol_handle.lock().unwrap();
+
+	if !vol.delete_needle(&NeedleKeys { key, alt_key })? {
+		return Ok(text_response(StatusCode::NOT_FOUND, "Needle not found"));
+	}
+
+	vol.flush()?;
+
+	Ok(text_response(StatusCode::OK, "Needle deleted!"))
 }
 
 // Deletes all photo needles associated with a single photo
@@ -316,8 +497,18 @@ fn delete_photo_all(
 	volume_id: VolumeId, key: NeedleKey
 ) -> Result<Response<Body>> {
 
-	Ok(text_response(StatusCode::OK, "Woo!"))
-}
+	let vol_handle = match get_volume_handle(&mac_handle, volume_id) {
+		Some(v) => v,
+		None => return Ok(text_response(StatusCode::NOT_FOUND, "Volume not found"))
+	};
+
+	let mut vol = vol_handle.lock().unwrap();
 
+	if vol.delete_photo(key)? == 0 {
+		return Ok(text_response(StatusCode::NOT_FOUND, "Photo not found"));
+	}
 
+	vol.flush()?;
 
+	Ok(text_response(StatusCode::OK, "Photo deleted!"))
+}
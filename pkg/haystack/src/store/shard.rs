@@ -0,0 +1,481 @@
+/*
+	Erasure coded shards of a sealed volume
+
+	When a volume is encoded, all of its live needles are concatenated (exactly as they are stored in the volume but without padding) into a single stream. That stream is split into 'data_shards' consecutive equally sized pieces and then 'parity_shards' more pieces are computed from them using a Reed-Solomon code. Each store holds exactly one of these pieces in a shard file of the form:
+
+	- Header (below)
+	- Shard data ('shard_size' bytes)
+	- Needle index: for every needle, its keys, flags, size and offset into the stream
+	- CRC32C of the needle index
+
+	Every shard carries the full needle index so that any machine holding a shard can figure out which data shards it needs to read to serve any needle
+*/
+
+use super::super::common::*;
+use super::super::errors::*;
+use super::needle::*;
+use super::volume::PhysicalVolume;
+use core::algorithms::ReedSolomon;
+use std::io::{Write, Read, Cursor};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::mem::size_of;
+use std::sync::Mutex;
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use crc32c::crc32c_append;
+use bytes::Bytes;
+
+
+const SHARD_MAGIC: &str = "HAYC";
+const SHARD_MAGIC_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+
+pub const SHARD_HEADER_SIZE: usize =
+	SHARD_MAGIC_SIZE +
+	size_of::<FormatVersion>() +
+	size_of::<ClusterId>() +
+	size_of::<VolumeId>() +
+	size_of::<u32>() + // < Shard index
+	size_of::<u32>() + // < Number of data shards
+	size_of::<u32>() + // < Number of parity shards
+	size_of::<u64>() + // < Shard size
+	size_of::<u64>() + // < Stream size
+	size_of::<u64>() + // < Number of needles
+	CHECKSUM_SIZE;
+
+const SHARD_INDEX_ENTRY_SIZE: usize =
+	size_of::<NeedleKey>() +
+	size_of::<NeedleAltKey>() +
+	1 + // < Flags
+	size_of::<NeedleSize>() +
+	size_of::<u64>(); // < Offset
+
+/// Size of the pieces in which the parity of the shards is computed
+const ENCODE_CHUNK_SIZE: usize = 64*1024;
+
+
+#[derive(Clone)]
+pub struct ShardHeader {
+	pub cluster_id: ClusterId,
+	pub volume_id: VolumeId,
+
+	/// Position of this shard in the code (data shards come first, then parity shards)
+	pub shard_index: usize,
+
+	pub data_shards: usize,
+	pub parity_shards: usize,
+
+	/// Number of bytes of data in every shard of this volume
+	pub shard_size: u64,
+
+	/// Number of meaningful bytes across all data shards (the rest is zero padding)
+	pub stream_size: u64,
+
+	pub num_needles: u64
+}
+
+/// A contiguous span of bytes in a single shard
+pub struct ShardPiece {
+	pub shard_index: usize,
+	pub start: u64,
+	pub end: u64
+}
+
+impl ShardHeader {
+
+	pub fn read(reader: &mut Read) -> Result<ShardHeader> {
+		let mut buf = vec![0u8; SHARD_HEADER_SIZE];
+		reader.read_exact(&mut buf)?;
+
+		if &buf[0..SHARD_MAGIC_SIZE] != SHARD_MAGIC.as_bytes() {
+			return Err("Shard magic is incorrect".into());
+		}
+
+		let mut c = Cursor::new(&buf[SHARD_MAGIC_SIZE..]);
+
+		let version = c.read_u32::<LittleEndian>()?;
+		if version != CURRENT_FORMAT_VERSION {
+			return Err("Shard unknown format version".into());
+		}
+
+		let header = ShardHeader {
+			cluster_id: c.read_u64::<LittleEndian>()?,
+			volume_id: c.read_u32::<LittleEndian>()?,
+			shard_index: c.read_u32::<LittleEndian>()? as usize,
+			data_shards: c.read_u32::<LittleEndian>()? as usize,
+			parity_shards: c.read_u32::<LittleEndian>()? as usize,
+			shard_size: c.read_u64::<LittleEndian>()?,
+			stream_size: c.read_u64::<LittleEndian>()?,
+			num_needles: c.read_u64::<LittleEndian>()?
+		};
+
+		let checksum = c.read_u32::<LittleEndian>()?;
+		if crc32c_append(0, &buf[0..(SHARD_HEADER_SIZE - CHECKSUM_SIZE)]) != checksum {
+			return Err("Incorrect checksum in shard header".into());
+		}
+
+		if header.data_shards == 0 || header.shard_index >= header.total_shards() {
+			return Err("Shard header is inconsistent".into());
+		}
+
+		Ok(header)
+	}
+
+	pub fn write(&self, writer: &mut Write) -> Result<()> {
+		let mut buf = Vec::new(); buf.reserve(SHARD_HEADER_SIZE);
+
+		buf.write_all(SHARD_MAGIC.as_bytes())?;
+		buf.write_u32::<LittleEndian>(CURRENT_FORMAT_VERSION)?;
+		buf.write_u64::<LittleEndian>(self.cluster_id)?;
+		buf.write_u32::<LittleEndian>(self.volume_id)?;
+		buf.write_u32::<LittleEndian>(self.shard_index as u32)?;
+		buf.write_u32::<LittleEndian>(self.data_shards as u32)?;
+		buf.write_u32::<LittleEndian>(self.parity_shards as u32)?;
+		buf.write_u64::<LittleEndian>(self.shard_size)?;
+		buf.write_u64::<LittleEndian>(self.stream_size)?;
+		buf.write_u64::<LittleEndian>(self.num_needles)?;
+
+		let sum = crc32c_append(0, &buf);
+		buf.write_u32::<LittleEndian>(sum)?;
+
+		assert_eq!(buf.len(), SHARD_HEADER_SIZE);
+
+		writer.write_all(&buf)?;
+		Ok(())
+	}
+
+	pub fn total_shards(&self) -> usize {
+		self.data_shards + self.parity_shards
+	}
+
+	/// Splits the range [start, end) of the stream into the pieces of the data shards that contain it
+	pub fn pieces(&self, start: u64, end: u64) -> Vec<ShardPiece> {
+		let mut out = vec![];
+
+		let mut pos = start;
+		while pos < end {
+			let shard_index = (pos / self.shard_size) as usize;
+			let shard_start = (shard_index as u64) * self.shard_size;
+			let shard_end = std::cmp::min(shard_start + self.shard_size, end);
+
+			out.push(ShardPiece {
+				shard_index,
+				start: pos - shard_start,
+				end: shard_end - shard_start
+			});
+
+			pos = shard_end;
+		}
+
+		out
+	}
+}
+
+
+/// Location of a single needle in the stream of an encoded volume
+#[derive(Clone)]
+pub struct ShardNeedleEntry {
+	pub keys: NeedleKeys,
+	pub meta: NeedleMeta,
+
+	/// Offset of the start of the needle's header in the stream
+	pub offset: u64
+}
+
+/// A single shard of an erasure coded volume stored on this machine
+/// NOTE: Once written, shard files are never modified, so all reads are positional and can happen concurrently
+pub struct VolumeShard {
+	pub header: ShardHeader,
+
+	entries: Vec<ShardNeedleEntry>,
+
+	/// Maps each needle to its position in 'entries'
+	index: HashMap<NeedleKeys, usize>,
+
+	file: File
+}
+
+impl VolumeShard {
+
+	/// Opens a shard file and verifies that it is complete
+	pub fn open(path: &Path) -> Result<VolumeShard> {
+		let mut file = File::open(path)?;
+
+		let header = ShardHeader::read(&mut file)?;
+
+		let index_offset = (SHARD_HEADER_SIZE as u64) + header.shard_size;
+		let index_size = header.num_needles * (SHARD_INDEX_ENTRY_SIZE as u64);
+
+		if file.metadata()?.len() != index_offset + index_size + (CHECKSUM_SIZE as u64) {
+			return Err("Shard file has the wrong size".into());
+		}
+
+		let mut buf = vec![0u8; (index_size as usize) + CHECKSUM_SIZE];
+		file.read_exact_at(&mut buf, index_offset)?;
+
+		let checksum = (&buf[(index_size as usize)..]).read_u32::<LittleEndian>()?;
+		if crc32c_append(0, &buf[0..(index_size as usize)]) != checksum {
+			return Err("Incorrect checksum in shard needle index".into());
+		}
+
+		let mut entries = vec![];
+		let mut index = HashMap::new();
+
+		let mut c = Cursor::new(&buf[0..(index_size as usize)]);
+		for i in 0..header.num_needles {
+			let keys = NeedleKeys {
+				key: c.read_u64::<LittleEndian>()?,
+				alt_key: c.read_u32::<LittleEndian>()?
+			};
+
+			let meta = NeedleMeta {
+				flags: c.read_u8()?,
+				size: c.read_u64::<LittleEndian>()?
+			};
+
+			let offset = c.read_u64::<LittleEndian>()?;
+
+			if offset + meta.total_size() > header.stream_size {
+				return Err("Shard needle index entry out of bounds".into());
+			}
+
+			index.insert(keys.clone(), i as usize);
+			entries.push(ShardNeedleEntry { keys, meta, offset });
+		}
+
+		Ok(VolumeShard {
+			header,
+			entries,
+			index,
+			file
+		})
+	}
+
+	/// Encodes all live needles in a volume into a full set of new shard files at the given paths (one for each shard)
+	/// NOTE: The volume should not be getting written to while this is happening. It is only locked for as long as it takes to list its needles and then to read each one of them, so reads can still be served in between
+	pub fn encode(
+		vol: &Mutex<PhysicalVolume>, data_shards: usize, parity_shards: usize, paths: &[PathBuf]
+	) -> Result<ShardHeader> {
+
+		if paths.len() != data_shards + parity_shards {
+			return Err("Need exactly one path per shard".into());
+		}
+
+		let rs = ReedSolomon::new(data_shards, parity_shards);
+
+		let (live, cluster_id, volume_id) = {
+			let vol = vol.lock().unwrap();
+			(vol.live_needles(), vol.superblock.cluster_id, vol.superblock.volume_id)
+		};
+
+		let mut entries = vec![];
+		let mut stream_size = 0;
+		for (keys, meta) in live {
+			let size = meta.total_size();
+			entries.push(ShardNeedleEntry { keys, meta, offset: stream_size });
+			stream_size += size;
+		}
+
+		let shard_size = (stream_size + (data_shards as u64) - 1) / (data_shards as u64);
+
+		let mut header = ShardHeader {
+			cluster_id,
+			volume_id,
+			shard_index: 0,
+			data_shards,
+			parity_shards,
+			shard_size,
+			stream_size,
+			num_needles: entries.len() as u64
+		};
+
+		let mut files = vec![];
+		for (i, p) in paths.iter().enumerate() {
+			let mut f = OpenOptions::new().read(true).write(true).create_new(true).open(p)?;
+
+			header.shard_index = i;
+			header.write(&mut f)?;
+
+			files.push(f);
+		}
+
+		// Writing the stream across all of the data shards
+		let mut pos = 0;
+		for e in entries.iter_mut() {
+			let n = vol.lock().unwrap().read_needle(&e.keys)?;
+			let buf = match n {
+				Some(n) => {
+					n.needle.check()?;
+					n.needle.into_bytes()
+				},
+				None => {
+					// Deleted without the index knowing about it yet
+					e.meta.flags |= FLAG_DELETED;
+					Bytes::from(vec![0u8; e.meta.total_size() as usize])
+				}
+			};
+
+			let mut written = 0;
+			while written < buf.len() {
+				let shard_index = (pos / shard_size) as usize;
+				let room = ((shard_index as u64) + 1) * shard_size - pos;
+				let n = std::cmp::min(room, (buf.len() - written) as u64) as usize;
+
+				files[shard_index].write_all(&buf[written..(written + n)])?;
+				written += n;
+				pos += n as u64;
+			}
+		}
+
+		// Zero padding the end of the stream
+		for i in 0..data_shards {
+			let end = ((i as u64) + 1) * shard_size;
+			let start = std::cmp::max(pos, (i as u64) * shard_size);
+			if start < end {
+				write_zeros(&mut files[i], end - start)?;
+			}
+		}
+
+		// Computing the parity a chunk at a time
+		let mut off = 0;
+		while off < shard_size {
+			let n = std::cmp::min(ENCODE_CHUNK_SIZE as u64, shard_size - off) as usize;
+
+			let mut bufs = vec![];
+			for i in 0..data_shards {
+				let mut buf = vec![0u8; n];
+				files[i].read_exact_at(&mut buf, (SHARD_HEADER_SIZE as u64) + off)?;
+				bufs.push(buf);
+			}
+
+			let parity = rs.encode(&bufs.iter().map(|b| &b[..]).collect::<Vec<_>>());
+			for (j, p) in parity.iter().enumerate() {
+				files[data_shards + j].write_all(p)?;
+			}
+
+			off += n as u64;
+		}
+
+		// Every shard ends with the same needle index
+		let mut index = vec![];
+		for e in entries.iter() {
+			index.write_u64::<LittleEndian>(e.keys.key)?;
+			index.write_u32::<LittleEndian>(e.keys.alt_key)?;
+			index.write_u8(e.meta.flags)?;
+			index.write_u64::<LittleEndian>(e.meta.size)?;
+			index.write_u64::<LittleEndian>(e.offset)?;
+		}
+
+		let sum = crc32c_append(0, &index);
+		index.write_u32::<LittleEndian>(sum)?;
+
+		for f in files.iter_mut() {
+			f.write_all(&index)?;
+			f.sync_all()?;
+		}
+
+		if let Some(p) = paths[0].parent() {
+			File::open(p)?.sync_all()?;
+		}
+
+		header.shard_index = 0;
+		Ok(header)
+	}
+
+	pub fn num_needles(&self) -> usize {
+		self.entries.len()
+	}
+
+	/// Total size of the shard file
+	pub fn used_space(&self) -> u64 {
+		(SHARD_HEADER_SIZE as u64) + self.header.shard_size +
+			(self.entries.len() * SHARD_INDEX_ENTRY_SIZE + CHECKSUM_SIZE) as u64
+	}
+
+	/// Finds a needle that has not been deleted along with its position in the index (which uniquely identifies it within the volume)
+	pub fn lookup(&self, keys: &NeedleKeys) -> Option<(usize, &ShardNeedleEntry)> {
+		let i = match self.index.get(keys) {
+			Some(i) => *i,
+			None => return None
+		};
+
+		let e = &self.entries[i];
+		if e.meta.deleted() {
+			return None;
+		}
+
+		Some((i, e))
+	}
+
+	/// Reads the bytes in [start, end) of this shard's data
+	pub fn read_data(&self, start: u64, end: u64) -> Result<Bytes> {
+		if start > end || end > self.header.shard_size {
+			return Err("Range out of bounds of shard".into());
+		}
+
+		let mut buf = vec![0u8; (end - start) as usize];
+		self.file.read_exact_at(&mut buf, (SHARD_HEADER_SIZE as u64) + start)?;
+		Ok(buf.into())
+	}
+
+	/// Gets a handle to the shard file along with the absolute offset of the start of the shard's data in it (for streaming it out)
+	pub fn data_file(&self) -> Result<(File, u64)> {
+		Ok((self.file.try_clone()?, SHARD_HEADER_SIZE as u64))
+	}
+
+}
+
+fn write_zeros(file: &mut File, n: u64) -> Result<()> {
+	let zeros = vec![0u8; ENCODE_CHUNK_SIZE];
+
+	let mut remaining = n;
+	while remaining > 0 {
+		let k = std::cmp::min(remaining, ENCODE_CHUNK_SIZE as u64) as usize;
+		file.write_all(&zeros[0..k])?;
+		remaining -= k as u64;
+	}
+
+	Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn shard_header_pieces() {
+		let header = ShardHeader {
+			cluster_id: 1,
+			volume_id: 2,
+			shard_index: 0,
+			data_shards: 4,
+			parity_shards: 2,
+			shard_size: 100,
+			stream_size: 390,
+			num_needles: 0
+		};
+
+		let mut buf = vec![];
+		header.write(&mut buf).unwrap();
+		assert_eq!(buf.len(), SHARD_HEADER_SIZE);
+
+		let h = ShardHeader::read(&mut &buf[..]).unwrap();
+		assert_eq!(h.stream_size, 390);
+		assert_eq!(h.total_shards(), 6);
+
+		// Spanning all four data shards
+		let pieces = header.pieces(90, 310);
+		assert_eq!(pieces.len(), 4);
+		assert_eq!((pieces[0].shard_index, pieces[0].start, pieces[0].end), (0, 90, 100));
+		assert_eq!((pieces[1].shard_index, pieces[1].start, pieces[1].end), (1, 0, 100));
+		assert_eq!((pieces[3].shard_index, pieces[3].start, pieces[3].end), (3, 0, 10));
+
+		// Corrupting the header is noticed
+		buf[10] ^= 1;
+		assert!(ShardHeader::read(&mut &buf[..]).is_err());
+	}
+
+}
@@ -20,6 +20,11 @@ pub const SUPERBLOCK_SIZE: usize =
 
 pub struct PhysicalVolumeSuperblock {
 	pub magic: Vec<u8>,
+
+	/// Version of the layout of the rest of the file after the superblock
+	/// NOTE: The layout of the superblock itself is the same in all versions, so it is up to the owner of the file to reject versions that it can't read
+	pub format_version: FormatVersion,
+
 	pub cluster_id: ClusterId,
 	pub machine_id: MachineId,
 	pub volume_id: VolumeId,
@@ -44,12 +49,7 @@ impl PhysicalVolumeSuperblock {
 		let mut magic = Vec::new(); magic.resize(SUPERBLOCK_MAGIC_SIZE, 0);
 		cursor.read_exact(&mut magic)?;
 
-		let ver = cursor.read_u32::<LittleEndian>()?;
-
-		// Because the rest of the fields all depend on using the correct version, we check that first
-		if ver != CURRENT_FORMAT_VERSION {
-			return Err("Superblock unknown format version".into());
-		}
+		let format_version = cursor.read_u32::<LittleEndian>()?;
 
 		let cluster_id = cursor.read_u64::<LittleEndian>()?;
 		let machine_id = cursor.read_u32::<LittleEndian>()?;
@@ -68,6 +68,7 @@ impl PhysicalVolumeSuperblock {
 
 		Ok(PhysicalVolumeSuperblock {
 			magic,
+			format_version,
 			cluster_id,
 			machine_id,
 			volume_id,
@@ -87,7 +88,7 @@ impl PhysicalVolumeSuperblock {
 		{
 			let mut cursor = Cursor::new(&mut buf);
 			cursor.write_all(&self.magic)?;
-			cursor.write_u32::<LittleEndian>(CURRENT_FORMAT_VERSION)?;
+			cursor.write_u32::<LittleEndian>(self.format_version)?;
 			cursor.write_u64::<LittleEndian>(self.cluster_id)?;
 			cursor.write_u32::<LittleEndian>(self.machine_id)?;
 			cursor.write_u32::<LittleEndian>(self.volume_id)?;
@@ -1,24 +1,52 @@
 
 use super::super::common::*;
 use super::super::errors::*;
-use super::api::CookieBuf;
+use super::api::{CookieBuf, VolumeCompactionProgress};
 use super::needle::*;
 use super::volume_index::*;
+use super::needle_index::*;
 use super::superblock::*;
 use std::io;
 use std::io::{Write, Read, Seek, Cursor};
-use std::collections::HashMap;
+use byteorder::WriteBytesExt;
+use std::collections::HashSet;
+use std::fs;
 use std::fs::{File, OpenOptions};
 use crc32c::crc32c_append;
 use std::path::{Path, PathBuf};
-use super::stream::Stream;
+use super::stream::{Stream, SingleStream};
 use core::block_size_remainder;
 use fs2::FileExt;
 use core::fs::allocate_soft::*;
+use std::time::{Duration, Instant};
 
 const SUPERBLOCK_MAGIC: &str = "HAYS";
 
 
+/// Describes which format versions of volumes can be opened and how the needles in each of them are laid out
+/// NOTE: Stores always use VolumeFormat::default(). Other descriptions are mainly useful for checking that volumes in older versions are still handled correctly
+#[derive(Clone, Copy)]
+pub struct VolumeFormat {
+	/// Version in which new volumes are created and to which older volumes are migrated
+	pub current: FormatVersion,
+
+	/// Oldest version that can still be read
+	pub oldest: FormatVersion,
+
+	/// Gets the layout of the needles in volumes of each version (see needle_layout_version())
+	pub layout: fn(FormatVersion) -> Option<FormatVersion>
+}
+
+impl Default for VolumeFormat {
+	fn default() -> Self {
+		VolumeFormat {
+			current: CURRENT_FORMAT_VERSION,
+			oldest: OLDEST_FORMAT_VERSION,
+			layout: needle_layout_version
+		}
+	}
+}
+
 
 /// Simple wrapper around a read needle including the offset into the file (useful for etags)
 pub struct NeedleWithOffset {
@@ -26,6 +54,26 @@ pub struct NeedleWithOffset {
 	pub needle: Needle
 }
 
+/// State of an online compaction of a physical volume
+/// All live needles are incrementally copied into a separate '.pack' volume which replaces the original volume once it has caught up to the end of the original file
+struct VolumeCompaction {
+	/// The new volume being built
+	pack: Box<PhysicalVolume>,
+
+	/// Offset in the original volume file up to which all needles have been copied or skipped
+	offset: u64
+}
+
+/// State of a single pass of integrity checking over every needle in a volume
+struct VolumeScrub {
+	/// Every needle that was in the index when the pass started (in the order that they appear in the volume)
+	/// Needles appended after the pass started will be checked in the next pass
+	needles: Vec<(BlockOffset, NeedleKeys)>,
+
+	/// Index into 'needles' of the next needle to check
+	position: usize
+}
+
 // TODO: We'd also like to be able to set an entire physical volume as write_enabled
 // - Mainly useful so that we can report it back to clients and so that next time we need to broadcast that we are out of space, we only need to mark volumes which we haven't yet marked as disabled
 
@@ -36,15 +84,31 @@ pub struct PhysicalVolume {
 	path: PathBuf,
 	file: File,
 
+	/// Versions that this volume was opened with
+	format: VolumeFormat,
+
+	/// Layout of the needles in this volume's format version
+	layout: FormatVersion,
+
 	// TODO: Make it a set of binary heaps so that we can efficiently look up all types for a single photo?
-	index: HashMap<NeedleKeys, NeedleIndexEntry>,
+	index: NeedleIndex,
 	index_file: PhysicalVolumeIndex,
 
 	/// Number of bytes that we estimate can be gained through compaction
 	compaction_pending: u64,
 
-	/// Some physical 
-	//compaction_active: Option<Arc<PhysicalVolume>>,
+	/// Set while this volume is being compacted into a new file
+	compaction: Option<VolumeCompaction>,
+
+	/// Set while the integrity of all needles in this volume is being checked
+	scrub: Option<VolumeScrub>,
+
+	/// When the last complete scrub of this volume finished
+	/// NOTE: This isn't persisted, so every volume will be scrubbed again soon after the store restarts
+	last_scrubbed: Option<Instant>,
+
+	/// Keys of all needles that failed their integrity checks during scrubbing and have not yet been replaced
+	corrupt: HashSet<NeedleKeys>,
 
 	/// The length of the file (or the offset to the very end of the last needle + padding)
 	/// Because of the potential for partial writes, we won't trust the size reported on disk after the volume is fully loaded
@@ -64,7 +128,19 @@ impl PhysicalVolume {
 	pub fn create(
 		config: ConfigRef, path: &Path, cluster_id: ClusterId, machine_id: MachineId, volume_id: VolumeId
 	) -> Result<PhysicalVolume> {
-		
+		PhysicalVolume::create_with_format(config, path, cluster_id, machine_id, volume_id, VolumeFormat::default())
+	}
+
+	/// Same as create() but with the given description of the format versions in place of the current ones
+	pub fn create_with_format(
+		config: ConfigRef, path: &Path, cluster_id: ClusterId, machine_id: MachineId, volume_id: VolumeId, format: VolumeFormat
+	) -> Result<PhysicalVolume> {
+
+		let layout = match (format.layout)(format.current) {
+			Some(l) => l,
+			None => return Err("Current format version has an unknown needle layout".into())
+		};
+
 		let mut opts = OpenOptions::new();
 		opts.write(true).create_new(true).read(true);
 
@@ -75,6 +151,7 @@ impl PhysicalVolume {
 
 		let superblock = PhysicalVolumeSuperblock {
 			magic: SUPERBLOCK_MAGIC.as_bytes().into(),
+			format_version: format.current,
 			cluster_id,
 			machine_id,
 			volume_id,
@@ -82,8 +159,7 @@ impl PhysicalVolume {
 			allocated_space: config.store.allocation_size
 		};
 
-		let idx_path = path.to_str().unwrap().to_owned() + ".idx";
-		let idx = PhysicalVolumeIndex::create(&Path::new(&idx_path), &superblock)?;
+		let idx = PhysicalVolumeIndex::create(&index_path(path), &superblock)?;
 
 		let preallocated = file.allocated_size()?;
 
@@ -92,9 +168,15 @@ impl PhysicalVolume {
 			config,
 			path: path.to_owned(),
 			file,
-			index: HashMap::new(),
+			format,
+			layout,
+			index: NeedleIndex::new(),
 			index_file: idx,
 			compaction_pending: 0,
+			compaction: None,
+			scrub: None,
+			last_scrubbed: None,
+			corrupt: HashSet::new(),
 			extent: 0,
 			preallocated
 		};
@@ -111,8 +193,24 @@ impl PhysicalVolume {
 	// Likely also to be based on the same params
 	/// Opens a volume given it's file name
 	///
+	/// If the index file for the volume is missing or can't be loaded, then it will be rebuilt from the needles in the volume
+	///
 	///  XXX: Ideally we would have some better way of doing this right?
 	pub fn open(config: ConfigRef, path: &Path) -> Result<PhysicalVolume> {
+		PhysicalVolume::open_impl(config, path, false, VolumeFormat::default())
+	}
+
+	/// Same as open() but always throws away the existing index file and rebuilds it from the needles in the volume
+	pub fn open_rebuilding_index(config: ConfigRef, path: &Path) -> Result<PhysicalVolume> {
+		PhysicalVolume::open_impl(config, path, true, VolumeFormat::default())
+	}
+
+	/// Same as open() but with the given description of the format versions in place of the current ones
+	pub fn open_with_format(config: ConfigRef, path: &Path, format: VolumeFormat) -> Result<PhysicalVolume> {
+		PhysicalVolume::open_impl(config, path, false, format)
+	}
+
+	fn open_impl(config: ConfigRef, path: &Path, rebuild_index: bool, format: VolumeFormat) -> Result<PhysicalVolume> {
 		let mut opts = OpenOptions::new();
 		opts.read(true).write(true);
 
@@ -124,22 +222,46 @@ impl PhysicalVolume {
 			return Err("Superblock magic is incorrect".into());
 		}
 
-		let idx_path_string = path.to_str().unwrap().to_owned() + ".idx";
-		let idx_path = Path::new(&idx_path_string);
-		let idx = if idx_path.exists() {
-			// TODO: In most cases of failures to read existing indexes, we can just toss it out and regenerate a new one
+		if superblock.format_version < format.oldest || superblock.format_version > format.current {
+			return Err("Superblock unknown format version".into());
+		}
 
-			let i = PhysicalVolumeIndex::open(&idx_path)?;
+		let layout = match (format.layout)(superblock.format_version) {
+			Some(l) => l,
+			None => return Err("Superblock format version has an unknown needle layout".into())
+		};
 
-			if i.superblock.cluster_id != superblock.cluster_id || i.superblock.machine_id != superblock.machine_id || i.superblock.volume_id != superblock.volume_id {
-				return Err("Opened an index file for a mismatching volume".into())
-			}
+		if superblock.format_version != format.current {
+			eprintln!("Volume {:?} is in format version {} and will be read-only until it is migrated", path, superblock.format_version);
+		}
 
-			i
+		let idx_path = index_path(path);
+		let idx = if idx_path.exists() && !rebuild_index {
+			let res = PhysicalVolumeIndex::open(&idx_path).and_then(|i| {
+				if i.superblock.cluster_id != superblock.cluster_id || i.superblock.machine_id != superblock.machine_id || i.superblock.volume_id != superblock.volume_id {
+					return Err("Opened an index file for a mismatching volume".into())
+				}
+
+				Ok(i)
+			});
+
+			match res {
+				Ok(i) => Some(i),
+				Err(e) => {
+					eprintln!("Discarding unreadable index file {:?}: {}", idx_path, e);
+					None
+				}
+			}
 		}
 		else {
-			// Read just the superblock
-			PhysicalVolumeIndex::create(&idx_path, &superblock)?
+			None
+		};
+
+		let loaded_index = idx.is_some();
+
+		let idx = match idx {
+			Some(i) => i,
+			None => reset_index(path, &superblock)?
 		};
 
 		let preallocated = file.allocated_size()?;
@@ -149,9 +271,15 @@ impl PhysicalVolume {
 			config,
 			path: path.to_owned(),
 			file,
-			index: HashMap::new(),
+			format,
+			layout,
+			index: NeedleIndex::new(),
 			index_file: idx,
 			compaction_pending: 0,
+			compaction: None,
+			scrub: None,
+			last_scrubbed: None,
+			corrupt: HashSet::new(),
 			extent: 0,
 			preallocated
 		};
@@ -159,18 +287,101 @@ impl PhysicalVolume {
 		// Initially starts right after the superblock because we haven't checked any of the needles after it yet
 		vol.extent = vol.offset_after_super_block();
 
-		vol.scan_needles()?;
+		// The index is redundant to the volume, so if anything in it doesn't add up, we can just start over with only the volume
+		if let Err(e) = vol.scan_needles() {
+			if !loaded_index {
+				return Err(e);
+			}
+
+			eprintln!("Rebuilding index of {:?} after failing to load it: {}", path, e);
+			vol.rebuild_index()?;
+		}
 
 		Ok(vol)
 	}
 
+	/// Throws away the current index and rebuilds it by scanning the headers of every needle in the volume
+	fn rebuild_index(&mut self) -> Result<()> {
+		self.index_file = reset_index(&self.path, &self.superblock)?;
+		self.index.clear();
+		self.compaction_pending = 0;
+		self.extent = self.offset_after_super_block();
+
+		self.scan_needles()
+	}
+
+
+	/// Should be called before opening the volume at the given path to clean up after any compaction that was interrupted
+	///
+	/// If the original volume files still exist, then the compaction never completed and the pack files are discarded. Otherwise the pack files are moved into place.
+	pub fn recover_compaction(path: &Path) -> Result<()> {
+		let pack_path = pack_path(path);
+		if !pack_path.exists() {
+			return Ok(());
+		}
+
+		if path.exists() {
+			eprintln!("Discarding incomplete compaction of {:?}", path);
+			remove_volume_files(&pack_path)?;
+		}
+		else {
+			eprintln!("Finishing previous compaction of {:?}", path);
+			fs::rename(&pack_path, path)?;
+
+			let pack_idx_path = index_path(&pack_path);
+			if pack_idx_path.exists() {
+				fs::rename(&pack_idx_path, &index_path(path))?;
+			}
+		}
+
+		File::open(path.parent().unwrap())?.sync_all()?;
+
+		Ok(())
+	}
 
 	pub fn can_write_soft(&self) -> bool {
-		(((self.used_space() as f64) * 0.95) as u64) < self.superblock.allocated_space
+		!self.needs_migration() && (((self.used_space() as f64) * 0.95) as u64) < self.superblock.allocated_space
 	}
 
 	pub fn can_write(&self) -> bool {
-		self.used_space() < self.superblock.allocated_space
+		!self.needs_migration() && self.used_space() < self.superblock.allocated_space
+	}
+
+	/// Whether or not this volume is in an older format version (in which case it can only be read from)
+	pub fn needs_migration(&self) -> bool {
+		self.superblock.format_version != self.format.current
+	}
+
+	/// Upgrades this volume to the current format version so that it can be written to again
+	///
+	/// If needles are laid out the same in both versions and the index file is already in the current index format, then only the superblock is rewritten. Otherwise every needle is rewritten through a compaction (which always produces a volume and index file in the current formats)
+	pub fn migrate(&mut self) -> Result<()> {
+		let old_version = self.superblock.format_version;
+		let new_version = self.format.current;
+		if old_version == new_version {
+			return Ok(());
+		}
+
+		let same_layout = Some(self.layout) == (self.format.layout)(new_version);
+		let current_index = self.index_file.superblock.format_version == CURRENT_INDEX_FORMAT_VERSION;
+
+		if same_layout && current_index {
+			self.superblock.format_version = new_version;
+			self.file.seek(io::SeekFrom::Start(0))?;
+			self.superblock.write(&mut self.file)?;
+			self.file.sync_data()?;
+		}
+		else {
+			self.begin_compaction()?;
+			while !self.step_compaction(self.config.store.compaction_step_size)? {}
+		}
+
+		println!(
+			"- Volume {} migrated from format version {} to {}",
+			self.superblock.volume_id, old_version, new_version
+		);
+
+		Ok(())
 	}
 
 
@@ -194,16 +405,26 @@ impl PhysicalVolume {
 			self.index_file.append(&keys, &entry)?;
 		}
 
+		let block_size = self.superblock.block_size;
+
 		if let Some(old_val) = self.index.get(&keys) {
 			if old_val.block_offset == entry.block_offset {
-				// This isn't really problematic, but does indicate that we are doing something wrong
-				return Err("Adding the exact same index entry twice")?;
+				// The only valid reason to see the same needle twice is a record of it being deleted
+				if !entry.meta.deleted() || old_val.meta.deleted() {
+					// This isn't really problematic, but does indicate that we are doing something wrong
+					return Err("Adding the exact same index entry twice")?;
+				}
 			}
+			else if !old_val.meta.deleted() {
+				self.compaction_pending += old_val.meta.occupied_size(block_size)
+			}
+		}
 
-			self.compaction_pending += old_val.meta.occupied_size(self.superblock.block_size)
+		if entry.meta.deleted() {
+			self.compaction_pending += entry.meta.occupied_size(block_size);
 		}
 
-		self.index.insert(keys, entry);
+		self.index.insert(keys, entry)?;
 
 		Ok(())
 	}
@@ -211,8 +432,8 @@ impl PhysicalVolume {
 	/// Scans all of the needles in the file and builds the initial index from them
 	/// 
 	/// (this should generally only be used if no separate index file is available)
-	/// 
-	/// TODO: We should also use this for checking the integrity of an existing file
+	///
+	/// NOTE: Only the headers of needles are looked at here. The integrity of the data of all needles is checked separately by scrubbing (see begin_scrub())
 	fn scan_needles(&mut self) -> Result<()> {
 
 		// Start scanning at last known good end of file
@@ -222,16 +443,15 @@ impl PhysicalVolume {
 		let max_extent = self.file.metadata().unwrap().len();
 		let index_pairs = self.index_file.read_all(max_extent)?;
 
-		if index_pairs.len() > 0 {
-			{
-				let p = &index_pairs[index_pairs.len() - 1];
-				off = p.value.end_offset(self.superblock.block_size);
+		for pair in index_pairs {
+			// NOTE: Deletion records may refer back to earlier needles, so the last entry is not necessarily the last needle
+			let end_off = pair.value.end_offset(self.superblock.block_size);
+			if end_off > off {
+				off = end_off;
 			}
 
-			for pair in index_pairs {
-				// TODO: This will end up readding it the 
-				self.add_to_index(pair.keys, pair.value, true)?;
-			}
+			// TODO: This will end up readding it the 
+			self.add_to_index(pair.keys, pair.value, true)?;
 		}
 
 
@@ -257,7 +477,7 @@ impl PhysicalVolume {
 
 			self.file.read_exact(&mut buf)?;
 
-			let n = NeedleHeader::parse(&buf)?;
+			let n = NeedleHeader::parse_layout(&buf, self.layout)?;
 
 			let entry = NeedleIndexEntry {
 				meta: n.meta.clone(),
@@ -290,12 +510,51 @@ impl PhysicalVolume {
 		Ok(())
 	}
 
+	/// Gets the keys of all needles that have not been deleted in the order that they appear in the volume
+	pub fn live_needle_keys(&self) -> Vec<NeedleKeys> {
+		self.live_needles().into_iter().map(|(k, _)| k).collect()
+	}
+
+	/// Same as live_needle_keys but also includes the last known metadata of each needle
+	pub fn live_needles(&self) -> Vec<(NeedleKeys, NeedleMeta)> {
+		let mut entries = self.index.iter()
+			.filter(|(_, e)| !e.meta.deleted())
+			.map(|(k, e)| (e.block_offset, k.clone(), e.meta.clone()))
+			.collect::<Vec<_>>();
+
+		entries.sort_by_key(|(off, _, _)| *off);
+
+		entries.into_iter().map(|(_, k, m)| (k, m)).collect()
+	}
+
+	/// Gets up to 'limit' needles (including deleted ones) in the order that their latest entries were appended to the index file, starting at the given position in the index file
+	/// Returns the needles along with the position at which the next page starts (or None if the end of the index has been reached). Listing all needles can be done a page at a time by starting at position zero and passing back in the returned position
+	/// NOTE: Entries that have since been superseded are skipped, so deleted needles are listed where their deletion was recorded
+	pub fn list_needles(&self, position: u64, limit: usize) -> Result<(Vec<(NeedleKeys, NeedleIndexEntry)>, Option<u64>)> {
+		let (pairs, next) = self.index_file.read_range(position, limit)?;
+
+		let mut out = vec![];
+		for pair in pairs.into_iter() {
+			let entry = match self.index.get(&pair.keys) {
+				Some(e) => e,
+				None => continue
+			};
+
+			if entry.block_offset == pair.value.block_offset && entry.meta.deleted() == pair.value.meta.deleted() {
+				out.push((pair.keys, entry));
+			}
+		}
+
+		let next = if next < self.index_file.used_space() { Some(next) } else { None };
+
+		Ok((out, next))
+	}
+
 	/// See what the offset of a needle is as fast as possible (mainly a cache optimization for etags received upstream from the cache)
 	pub fn peek_needle_block_offset(&self, keys: &NeedleKeys) -> Option<BlockOffset> {
 		self.index.get(keys).map(|e| e.block_offset)
 	}
 
-	// TODO: If we want to go super fast, we could implement the data as a stream and start sending it back to a user right away
 	/**
 	 * Tries to read a single needle from the volume
 	 * Will only return if it exists, has not been deleted
@@ -307,7 +566,7 @@ impl PhysicalVolume {
 		// This is basically the matter of reading from the current position in the thing
 
 		// TODO: We go need to distinguish this case as being totally different
-		let entry = match self.index.get_mut(keys) {
+		let entry = match self.index.get(keys) {
 			Some(e) => e,
 			None => return Ok(None)
 		};
@@ -319,13 +578,14 @@ impl PhysicalVolume {
 
 		self.file.seek(io::SeekFrom::Start(entry.offset(self.superblock.block_size)))?;
 
-		let needle = Needle::read_oneshot(&mut self.file, &entry.meta)?;
+		let needle = Needle::read_oneshot_layout(&mut self.file, &entry.meta, self.layout)?;
 
 		// Update index with most up-to-date flags
-		entry.meta.flags = needle.header.meta.flags;
+		self.index.set_flags(keys, needle.header.meta.flags);
 
-		// Separate index files do not persist deletes, so we will be double check the main flags 
+		// Deletions recorded in the index file may not have been flushed before the last shutdown, so we will double check the main flags
 		if needle.header.meta.deleted() {
+			self.compaction_pending += entry.meta.occupied_size(self.superblock.block_size);
 			return Ok(None);
 		}
 		
@@ -335,6 +595,40 @@ impl PhysicalVolume {
 		}))
 	}
 
+	/// Opens a needle such that its data can be streamed out without reading all of it into memory at once
+	/// Like read_needle, this will only return needles that exist and have not been deleted
+	///
+	/// NOTE: The checksum of the data is verified as it is streamed
+	pub fn open_needle(&mut self, keys: &NeedleKeys) -> Result<Option<NeedleStream>> {
+
+		let block_size = self.superblock.block_size;
+
+		let entry = match self.index.get(keys) {
+			Some(e) => e,
+			None => return Ok(None)
+		};
+
+		if entry.meta.deleted() {
+			return Ok(None);
+		}
+
+		let strm = NeedleStream::open(self.file.try_clone()?, entry.offset(block_size), entry.block_offset, self.layout)?;
+
+		if strm.header.keys != *keys || strm.header.meta.size != entry.meta.size {
+			return Err("Index entry does not match the needle in the volume".into());
+		}
+
+		// Same as in read_needle, the flags in the volume file are authoritative
+		self.index.set_flags(keys, strm.header.meta.flags);
+
+		if strm.header.meta.deleted() {
+			self.compaction_pending += entry.meta.occupied_size(block_size);
+			return Ok(None);
+		}
+
+		Ok(Some(strm))
+	}
+
 	// TODO: We will likely also want to have a create operation that gurantees that a needle does not exist
 	/// Adds a new needle to the very end of the file (overriding any previous needle for the same keys)
 	/// 
@@ -344,8 +638,16 @@ impl PhysicalVolume {
 		&mut self, keys: NeedleKeys, cookie: CookieBuf, meta: NeedleMeta, data: &mut Stream
 	) -> Result<()> {
 
+		if self.needs_migration() {
+			return Err("Volume must be migrated before it can be written to".into());
+		}
+
+		if meta.size > MAX_INDEXED_NEEDLE_SIZE {
+			return Err("Needle is too large".into());
+		}
+
 		// Typically needles will not be overwritten, but if they are, we consider needles with the same exact keys/cookie to be identical, so we will ignore attempts to update them
-		// TODO: The main exception to this will be error correction (in which case we to be able to do this)
+		// NOTE: The main exception to this is error correction (see repair_needle()) which must always write a new copy
 		if let Some(existing) = self.index.get(&keys) {
 			
 			// TODO: We can no longer do deduplication of uploads
@@ -493,31 +795,411 @@ impl PhysicalVolume {
 		index_extent
 	}
 
-	pub fn delete_needle(&mut self, keys: &NeedleKeys) -> Result<()> {
+	/// Marks a single needle as deleted
+	///
+	/// The deleted flag is written in-place into the needle's header and a record of the deletion is appended to the index file
+	/// Returns false if there was no undeleted needle with the given keys
+	pub fn delete_needle(&mut self, keys: &NeedleKeys) -> Result<bool> {
 
-		let entry = match self.index.get(keys) {
-			Some(e) => e,
-			None => return Err("Needle does not exist".into()),
+		if self.needs_migration() {
+			return Err("Volume must be migrated before it can be written to".into());
+		}
+
+		let block_size = self.superblock.block_size;
+
+		let (offset, block_offset) = match self.index.get(keys) {
+			Some(e) => {
+				if e.meta.deleted() {
+					return Ok(false);
+				}
+
+				(e.offset(block_size), e.block_offset)
+			},
+			None => return Ok(false),
 		};
 
-		if entry.meta.deleted() {
-			return Err("Needle already deleted".into());
+		// Read the header in the file to double check that it is the needle we think it is
+		self.file.seek(io::SeekFrom::Start(offset))?;
+		let header = NeedleHeader::read(&mut self.file)?;
+
+		if header.keys != *keys {
+			return Err("Index entry does not match the needle in the volume".into());
+		}
+
+		let meta = NeedleMeta {
+			flags: header.meta.flags | FLAG_DELETED,
+			size: header.meta.size
+		};
+
+		// Write back the flags in place
+		self.file.seek(io::SeekFrom::Start(offset + (NEEDLE_FLAGS_OFFSET as u64)))?;
+		self.file.write_u8(meta.flags)?;
+
+		self.add_to_index(keys.clone(), NeedleIndexEntry {
+			meta,
+			block_offset
+		}, false)?;
+
+		// If an active compaction already copied this needle, then it must also be deleted from the new volume
+		if let Some(ref mut c) = self.compaction {
+			c.pack.delete_needle(keys)?;
+		}
+
+		Ok(true)
+	}
+
+	/// Marks all needles associated with a single photo key as deleted
+	/// Returns the number of needles that were deleted
+	pub fn delete_photo(&mut self, key: NeedleKey) -> Result<usize> {
+
+		// TODO: This is a linear scan over the whole index (see the TODO on the index field)
+		let all_keys = self.index.iter()
+			.filter(|(k, e)| k.key == key && !e.meta.deleted())
+			.map(|(k, _)| k.clone())
+			.collect::<Vec<_>>();
+
+		let mut n = 0;
+		for keys in all_keys {
+			if self.delete_needle(&keys)? {
+				n += 1;
+			}
+		}
+
+		Ok(n)
+	}
+
+	/// Gets the number of bytes that we estimate can be reclaimed by compacting this volume
+	pub fn compaction_pending(&self) -> u64 {
+		self.compaction_pending
+	}
+
+	/// Whether or not enough space has been wasted in this volume that it should be compacted
+	pub fn should_compact(&self) -> bool {
+		if self.compaction.is_some() || self.compaction_pending == 0 {
+			return false;
+		}
+
+		(self.compaction_pending as f64) > (self.extent as f64) * self.config.store.compaction_threshold
+	}
+
+	pub fn is_compacting(&self) -> bool {
+		self.compaction.is_some()
+	}
+
+	pub fn compaction_progress(&self) -> Option<VolumeCompactionProgress> {
+		self.compaction.as_ref().map(|c| {
+			let start = self.offset_after_super_block();
+			VolumeCompactionProgress {
+				processed: c.offset - start,
+				total: self.extent - start,
+				packed: c.pack.used_space()
+			}
+		})
+	}
+
+	/// Starts a new compaction of this volume
+	/// The compaction must then be driven to completion by repeatedly calling step_compaction()
+	pub fn begin_compaction(&mut self) -> Result<()> {
+		if self.compaction.is_some() {
+			return Err("Volume is already being compacted".into());
+		}
+
+		let pack_path = pack_path(&self.path);
+
+		// Anything still here is left over from a previously failed compaction
+		remove_volume_files(&pack_path)?;
+
+		let pack = PhysicalVolume::create_with_format(
+			self.config.clone(), &pack_path,
+			self.superblock.cluster_id, self.superblock.machine_id, self.superblock.volume_id, self.format
+		)?;
+
+		self.compaction = Some(VolumeCompaction {
+			pack: Box::new(pack),
+			offset: self.offset_after_super_block()
+		});
+
+		Ok(())
+	}
+
+	/// Copies up to around max_bytes of live needles into the packed volume
+	/// Once all needles have been copied, the packed volume will atomically replace this volume and this will return true
+	///
+	/// NOTE: Because this is called while holding a lock on the volume, reads and appends can continue to occur in between steps. Needles appended in the meantime will be picked up by later steps
+	pub fn step_compaction(&mut self, max_bytes: u64) -> Result<bool> {
+		let mut compaction = match self.compaction.take() {
+			Some(c) => c,
+			None => return Err("Volume is not being compacted".into())
+		};
+
+		let done = match self.copy_live_needles(&mut compaction, max_bytes) {
+			Ok(done) => done,
+			Err(e) => {
+				remove_volume_files(&compaction.pack.path)?;
+				return Err(e);
+			}
+		};
+
+		if !done {
+			self.compaction = Some(compaction);
+			return Ok(false);
 		}
 
+		self.finish_compaction(compaction)?;
+
+		Ok(true)
+	}
+
+	/// Stops any active compaction and deletes the partially built packed volume
+	pub fn abort_compaction(&mut self) -> Result<()> {
+		if let Some(compaction) = self.compaction.take() {
+			remove_volume_files(&compaction.pack.path)?;
+		}
+
+		Ok(())
+	}
+
+	fn copy_live_needles(&mut self, compaction: &mut VolumeCompaction, max_bytes: u64) -> Result<bool> {
+
+		let block_size = self.superblock.block_size;
+		let mut ncopied = 0;
+
+		while compaction.offset < self.extent && ncopied < max_bytes {
+			let off = compaction.offset;
 
-		// TODO: Whenever we see a deleted needle, we can add it to our pending compaction count
+			self.file.seek(io::SeekFrom::Start(off))?;
+			let header = NeedleHeader::read_layout(&mut self.file, self.layout)?;
+
+			let entry = NeedleIndexEntry {
+				meta: header.meta.clone(),
+				block_offset: (off / block_size) as BlockOffset
+			};
+
+			// Only the most recent version of each needle is retained (and only if it hasn't been deleted)
+			let live = match self.index.get(&header.keys) {
+				Some(e) => e.block_offset == entry.block_offset && !e.meta.deleted(),
+				None => false
+			};
+
+			if live && !header.meta.deleted() {
+				self.file.seek(io::SeekFrom::Start(off))?;
+				let needle = Needle::read_oneshot_layout(&mut self.file, &entry.meta, self.layout)?;
+
+				// We don't want to carry over corrupt data into the new volume as if it were good
+				needle.check()?;
+
+				let mut strm = SingleStream::from(needle.data());
+				compaction.pack.append_needle(
+					header.keys.clone(), needle.header.cookie.clone(), needle.header.meta.clone(), &mut strm
+				)?;
+
+				ncopied += entry.meta.occupied_size(block_size);
+			}
+
+			compaction.offset = entry.end_offset(block_size);
+		}
+
+		Ok(compaction.offset >= self.extent)
+	}
 
-		//entry.offset.
+	fn finish_compaction(&mut self, compaction: VolumeCompaction) -> Result<()> {
+		let mut pack = *compaction.pack;
 
-		// read the header in the file
+		pack.flush()?;
+		pack.index_file.flush()?;
 
-		// double check it's flag isn't already set
+		// Once the original files are gone, the pack files are considered to be the authoritative copy of the volume (see recover_compaction)
+		fs::remove_file(&index_path(&self.path))?;
+		fs::remove_file(&self.path)?;
+		fs::rename(&pack.path, &self.path)?;
+		fs::rename(&index_path(&pack.path), &index_path(&self.path))?;
+		File::open(self.path.parent().unwrap())?.sync_all()?;
 
-		// write back to the file in place
+		println!(
+			"- Volume {} compacted from {} to {} bytes",
+			self.superblock.volume_id, self.used_space(), pack.used_space()
+		);
+
+		pack.path = self.path.clone();
+		*self = pack;
 
 		Ok(())
 	}
 
+	/// Whether or not it has been long enough since this volume was last checked for corruption that it should be checked again
+	pub fn should_scrub(&self, interval: u64) -> bool {
+		if self.scrub.is_some() {
+			return false;
+		}
+
+		match self.last_scrubbed {
+			Some(t) => t.elapsed() >= Duration::from_millis(interval),
+			None => true
+		}
+	}
+
+	pub fn is_scrubbing(&self) -> bool {
+		self.scrub.is_some()
+	}
+
+	/// Keys of all needles found to be corrupt by scrubbing
+	pub fn corrupt_needles(&self) -> Vec<NeedleKeys> {
+		self.corrupt.iter().cloned().collect()
+	}
+
+	/// Starts a new pass of checking the integrity of every needle in this volume
+	/// The pass must then be driven to completion by repeatedly calling step_scrub()
+	pub fn begin_scrub(&mut self) {
+		let mut needles = self.index.iter()
+			.filter(|(_, e)| !e.meta.deleted())
+			.map(|(k, e)| (e.block_offset, k.clone()))
+			.collect::<Vec<_>>();
+
+		needles.sort_by_key(|(off, _)| *off);
+
+		self.scrub = Some(VolumeScrub {
+			needles,
+			position: 0
+		});
+	}
+
+	/// Checks up to around max_bytes of needles against their checksums and index entries
+	/// Returns the number of bytes checked. Once every needle has been checked, is_scrubbing() will become false
+	///
+	/// NOTE: Like compaction, the lock on the volume only needs to be held for a single step
+	pub fn step_scrub(&mut self, max_bytes: u64) -> Result<u64> {
+		let mut scrub = match self.scrub.take() {
+			Some(s) => s,
+			None => return Err("Volume is not being scrubbed".into())
+		};
+
+		let block_size = self.superblock.block_size;
+		let mut nchecked = 0;
+
+		while scrub.position < scrub.needles.len() && nchecked < max_bytes {
+			let (block_offset, keys) = scrub.needles[scrub.position].clone();
+			scrub.position += 1;
+
+			// Needles deleted or replaced since the pass started no longer matter
+			let meta = match self.index.get(&keys) {
+				Some(ref e) if e.block_offset == block_offset && !e.meta.deleted() => e.meta.clone(),
+				_ => {
+					self.corrupt.remove(&keys);
+					continue;
+				}
+			};
+
+			nchecked += meta.occupied_size(block_size);
+
+			self.scrub_entry(keys, NeedleIndexEntry { meta, block_offset });
+		}
+
+		if scrub.position < scrub.needles.len() {
+			self.scrub = Some(scrub);
+		}
+		else {
+			self.last_scrubbed = Some(Instant::now());
+		}
+
+		Ok(nchecked)
+	}
+
+	/// Immediately checks the integrity of a single needle (outside of any scrubbing pass) and remembers the result
+	/// Returns None if there is no live needle with the given keys, otherwise whether or not it is corrupt
+	pub fn check_needle(&mut self, keys: &NeedleKeys) -> Option<bool> {
+		let entry = match self.index.get(keys) {
+			Some(ref e) if !e.meta.deleted() => e.clone(),
+			_ => return None
+		};
+
+		let corrupt = self.scrub_entry(keys.clone(), entry);
+
+		// The needle may have turned out to have already been deleted on disk
+		if !corrupt && self.index.get(keys).map(|e| e.meta.deleted()).unwrap_or(true) {
+			return None;
+		}
+
+		Some(corrupt)
+	}
+
+	/// Replaces a corrupt needle with a good copy of it (typically fetched from another replica of this volume)
+	/// The copy is appended to the end of the volume and the space used by the old copy will be reclaimed by the next compaction
+	///
+	/// The block_offset should be that of the corrupt copy at the time that the good copy was requested. If the needle has since been replaced or deleted, then nothing is written and false is returned
+	/// NOTE: This does not check that the volume has space left, as a needle that is being replaced was already accounted for
+	pub fn repair_needle(
+		&mut self, keys: &NeedleKeys, block_offset: BlockOffset, cookie: CookieBuf, data: &[u8]
+	) -> Result<bool> {
+
+		let unchanged = match self.index.get(keys) {
+			Some(e) => e.block_offset == block_offset && !e.meta.deleted(),
+			None => false
+		};
+
+		if !unchanged {
+			self.corrupt.remove(keys);
+			return Ok(false);
+		}
+
+		let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+		self.append_needle(keys.clone(), cookie, meta, &mut SingleStream::from(data))?;
+
+		self.corrupt.remove(keys);
+
+		println!(
+			"- Volume {}: needle {}/{} repaired",
+			self.superblock.volume_id, keys.key, keys.alt_key
+		);
+
+		Ok(true)
+	}
+
+	/// Verifies a single needle and records whether or not it is corrupt
+	/// Returns true if it is corrupt
+	fn scrub_entry(&mut self, keys: NeedleKeys, entry: NeedleIndexEntry) -> bool {
+		match self.verify_needle(&keys, &entry) {
+			Ok(_) => {
+				self.corrupt.remove(&keys);
+				false
+			},
+			Err(e) => {
+				eprintln!(
+					"Volume {}: needle {}/{} is corrupt: {:?}",
+					self.superblock.volume_id, keys.key, keys.alt_key, e
+				);
+
+				self.corrupt.insert(keys);
+				true
+			}
+		}
+	}
+
+	/// Checks that the needle pointed to by an index entry is the needle that the index expects and that its data matches its checksum
+	fn verify_needle(&mut self, keys: &NeedleKeys, entry: &NeedleIndexEntry) -> Result<()> {
+		let block_size = self.superblock.block_size;
+
+		if entry.offset(block_size) < self.offset_after_super_block() || entry.end_offset(block_size) > self.extent {
+			return Err("Index entry points outside of the volume".into());
+		}
+
+		self.file.seek(io::SeekFrom::Start(entry.offset(block_size)))?;
+		let needle = Needle::read_oneshot_layout(&mut self.file, &entry.meta, self.layout)?;
+
+		if needle.header.keys != *keys {
+			return Err("Index entry does not match the needle in the volume".into());
+		}
+
+		// Deletions are written in-place to the volume, so the only thing that could be wrong with a deleted needle is its data which we no longer care about
+		if needle.header.meta.deleted() {
+			self.index.set_flags(keys, needle.header.meta.flags);
+
+			self.compaction_pending += entry.meta.occupied_size(block_size);
+			return Ok(());
+		}
+
+		needle.check()
+	}
+
 	/// Flushes the volume such that any recent append_needle operations persist to disk
 	pub fn flush(&mut self) -> Result<()> {
 		// TODO: If we were really crazy about performance, we could count how many needles not yet flushed and perform a flush only if everything isn't already flushed
@@ -526,12 +1208,22 @@ impl PhysicalVolume {
 	}
 
 	pub fn close(mut self) -> Result<()> {
+		// An incomplete compaction is just started over after a restart
+		self.abort_compaction()?;
+
 		// NOTE: In general, this should always have been already handled by someone else
 		self.flush()?;
 
 		self.index_file.flush()
 	}
 
+	/// Closes the volume and permanently deletes all of its files
+	pub fn remove(self) -> Result<()> {
+		let path = self.path.clone();
+		self.close()?;
+		remove_volume_files(&path)
+	}
+
 	fn pad_to_block_size(&mut self) -> Result<u64> {
 		let pos = self.file.seek(io::SeekFrom::Current(0))?;
 		let pad = block_size_remainder(self.superblock.block_size, pos);
@@ -552,13 +1244,51 @@ impl PhysicalVolume {
 
 }
 
+/// Gets the path of the index file that goes along with the volume at the given path
+fn index_path(path: &Path) -> PathBuf {
+	PathBuf::from(path.to_str().unwrap().to_owned() + ".idx")
+}
+
+/// Gets the path to which an index file that couldn't be loaded is moved (it is kept around for debugging until the volume is deleted)
+fn bad_index_path(path: &Path) -> PathBuf {
+	PathBuf::from(path.to_str().unwrap().to_owned() + ".idx.bad")
+}
+
+/// Moves any existing index file for the volume at the given path out of the way and creates a new empty one in its place
+fn reset_index(path: &Path, superblock: &PhysicalVolumeSuperblock) -> Result<PhysicalVolumeIndex> {
+	let idx_path = index_path(path);
+	if idx_path.exists() {
+		fs::rename(&idx_path, &bad_index_path(path))?;
+	}
+
+	PhysicalVolumeIndex::create(&idx_path, superblock)
+}
+
+/// Gets the path of the volume used while compacting the volume at the given path
+fn pack_path(path: &Path) -> PathBuf {
+	PathBuf::from(path.to_str().unwrap().to_owned() + ".pack")
+}
+
+/// Deletes a volume and its index if they exist
+fn remove_volume_files(path: &Path) -> Result<()> {
+	if path.exists() {
+		fs::remove_file(path)?;
+	}
+
+	for p in vec![index_path(path), bad_index_path(path)] {
+		if p.exists() {
+			fs::remove_file(&p)?;
+		}
+	}
+
+	Ok(())
+}
+
 
 #[cfg(test)]
 mod tests {
 
 	use super::*;
-	use super::super::stream::SingleStream;
-	use std::fs;
 	use std::sync::Arc;
 
 	#[test]
@@ -616,5 +1346,440 @@ mod tests {
 		Ok(())
 	}
 
-}
+	#[test]
+	fn physical_volume_list_needles() -> Result<()> {
+
+		let p = Path::new("out/testlist");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 11)?;
+
+		let data = vec![1,2,3,4,5];
+		for i in 0..10 {
+			let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+			vol.append_needle(NeedleKeys { key: 10 - i, alt_key: 1 }, CookieBuf::random(), meta, &mut SingleStream::from(&data))?;
+		}
+
+		assert!(vol.delete_needle(&NeedleKeys { key: 9, alt_key: 1 })?);
+
+		// Paging through should give back every needle once in the order that they were appended (with the deleted one moved to the end)
+		let mut all = vec![];
+		let mut position = 0;
+		loop {
+			let (page, next) = vol.list_needles(position, 3)?;
+			assert!(page.len() <= 3);
+			all.extend(page);
+
+			position = match next {
+				Some(p) => p,
+				None => break
+			};
+		}
+
+		assert_eq!(all.iter().map(|(k, _)| k.key).collect::<Vec<_>>(), vec![10, 8, 7, 6, 5, 4, 3, 2, 1, 9]);
+		assert!(all[9].1.meta.deleted());
+		assert!(!all[0].1.meta.deleted());
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_delete() -> Result<()> {
+
+		let p = Path::new("out/testdelete");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 1, alt_key: 2 };
+		let data = vec![1,2,3,4,5];
+
+		{
+			let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 9)?;
+
+			for keys in vec![&keys1, &keys2] {
+				let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+				vol.append_needle(keys.clone(), CookieBuf::random(), meta, &mut SingleStream::from(&data))?;
+			}
+
+			assert_eq!(vol.compaction_pending(), 0);
+
+			assert!(vol.delete_needle(&keys1)?);
+			assert!(!vol.delete_needle(&keys1)?);
+			assert!(vol.read_needle(&keys1)?.is_none());
+			assert!(vol.read_needle(&keys2)?.is_some());
+			assert!(vol.compaction_pending() > 0);
+
+			vol.close()?;
+		}
+
+		// The deletion should be remembered by the index file after a restart
+		{
+			let mut vol = PhysicalVolume::open(config.clone(), &p)?;
+			assert!(vol.compaction_pending() > 0);
+			assert!(vol.read_needle(&keys1)?.is_none());
+
+			assert_eq!(vol.delete_photo(1)?, 1);
+			assert!(vol.read_needle(&keys2)?.is_none());
+		}
+
+		Ok(())
+	}
+
+	/// Pretends that there is a newer format version with the same needle layout as the current one, so that volumes written in the current version can be treated as older volumes
+	fn next_layout(format_version: FormatVersion) -> Option<FormatVersion> {
+		if format_version == CURRENT_FORMAT_VERSION + 1 {
+			Some(CURRENT_NEEDLE_LAYOUT)
+		}
+		else {
+			needle_layout_version(format_version)
+		}
+	}
+
+	fn next_format() -> VolumeFormat {
+		VolumeFormat {
+			current: CURRENT_FORMAT_VERSION + 1,
+			oldest: OLDEST_FORMAT_VERSION,
+			layout: next_layout
+		}
+	}
+
+	#[test]
+	fn physical_volume_migrate() -> Result<()> {
+
+		let p = Path::new("out/testmigrate");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 1 };
+		let keys3 = NeedleKeys { key: 3, alt_key: 1 };
+		let data = vec![1,2,3,4,5];
+		let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+
+		{
+			let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 12)?;
+
+			for keys in vec![&keys1, &keys2] {
+				vol.append_needle(keys.clone(), CookieBuf::random(), meta.clone(), &mut SingleStream::from(&data))?;
+			}
+
+			vol.close()?;
+		}
 
+		{
+			let mut vol = PhysicalVolume::open_with_format(config.clone(), &p, next_format())?;
+			assert!(vol.needs_migration());
+			assert!(!vol.can_write());
+
+			// Old volumes can still be read, but not written to
+			assert!(vol.read_needle(&keys1)?.is_some());
+			assert!(vol.append_needle(keys3.clone(), CookieBuf::random(), meta.clone(), &mut SingleStream::from(&data)).is_err());
+			assert!(vol.delete_needle(&keys2).is_err());
+			assert!(vol.read_needle(&keys2)?.is_some());
+
+			// The needles don't need to be rewritten, so only the superblock changes
+			let extent = vol.extent;
+			vol.migrate()?;
+			assert_eq!(vol.extent, extent);
+			assert_eq!(vol.superblock.format_version, CURRENT_FORMAT_VERSION + 1);
+			assert!(!vol.needs_migration());
+			assert!(vol.can_write());
+
+			vol.append_needle(keys3.clone(), CookieBuf::random(), meta.clone(), &mut SingleStream::from(&data))?;
+			assert!(vol.delete_needle(&keys2)?);
+
+			vol.close()?;
+		}
+
+		// The new version should be remembered after a restart (and is too new for the current format)
+		assert!(PhysicalVolume::open(config.clone(), &p).is_err());
+
+		{
+			let mut vol = PhysicalVolume::open_with_format(config.clone(), &p, next_format())?;
+			assert!(!vol.needs_migration());
+			assert!(vol.read_needle(&keys1)?.is_some());
+			assert!(vol.read_needle(&keys2)?.is_none());
+			assert!(vol.read_needle(&keys3)?.is_some());
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_migrate_compaction() -> Result<()> {
+
+		let p = Path::new("out/testmigratecompact");
+		remove_volume_files(&p)?;
+		remove_volume_files(&pack_path(&p))?;
+
+		let config = Arc::new(Config::default());
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 1 };
+		let keys3 = NeedleKeys { key: 3, alt_key: 1 };
+		let data = vec![1,2,3,4,5];
+		let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+
+		{
+			let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 13)?;
+
+			for keys in vec![&keys1, &keys2, &keys3] {
+				vol.append_needle(keys.clone(), CookieBuf::random(), meta.clone(), &mut SingleStream::from(&data))?;
+			}
+
+			assert!(vol.delete_needle(&keys2)?);
+			vol.close()?;
+		}
+
+		// Rewriting the index file in the original unchecksummed index format
+		{
+			let pairs = PhysicalVolumeIndex::open(&index_path(&p))?.read_all(std::u64::MAX)?;
+			let superblock = PhysicalVolumeSuperblock::read(&mut File::open(&p)?)?;
+
+			let mut idx = PhysicalVolumeIndex::create_with_version(&index_path(&p), &superblock, FORMAT_VERSION_UNCHECKED)?;
+			for pair in pairs.iter() {
+				idx.append(&pair.keys, &pair.value)?;
+			}
+
+			idx.flush()?;
+		}
+
+		{
+			let mut vol = PhysicalVolume::open_with_format(config.clone(), &p, next_format())?;
+			assert!(vol.needs_migration());
+			assert_eq!(vol.index_file.superblock.format_version, FORMAT_VERSION_UNCHECKED);
+			assert!(vol.read_needle(&keys2)?.is_none());
+			assert_eq!(vol.num_needles(), 3);
+
+			// An index file in an older format can only be upgraded by rewriting everything
+			vol.migrate()?;
+			assert_eq!(vol.superblock.format_version, CURRENT_FORMAT_VERSION + 1);
+			assert_eq!(vol.index_file.superblock.format_version, CURRENT_INDEX_FORMAT_VERSION);
+			assert!(!vol.is_compacting());
+			assert!(vol.can_write());
+
+			// The deleted needle should have been dropped along the way
+			assert_eq!(vol.num_needles(), 2);
+			assert_eq!(vol.compaction_pending(), 0);
+			assert_eq!(&vol.read_needle(&keys1)?.unwrap().needle.data()[..], &data[..]);
+			assert!(vol.read_needle(&keys2)?.is_none());
+
+			vol.close()?;
+		}
+
+		assert!(!pack_path(&p).exists());
+
+		{
+			let mut vol = PhysicalVolume::open_with_format(config.clone(), &p, next_format())?;
+			assert!(!vol.needs_migration());
+			assert_eq!(vol.num_needles(), 2);
+			assert_eq!(&vol.read_needle(&keys3)?.unwrap().needle.data()[..], &data[..]);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_rebuild_index() -> Result<()> {
+
+		let p = Path::new("out/testrebuild");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 1 };
+
+		{
+			let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 8)?;
+
+			for keys in vec![&keys1, &keys2] {
+				let data = vec![1,2,3];
+				let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+				vol.append_needle(keys.clone(), CookieBuf::random(), meta, &mut SingleStream::from(&data))?;
+			}
+
+			assert!(vol.delete_needle(&keys1)?);
+			vol.close()?;
+		}
+
+		// Garbage in place of the first entry should make the index unusable
+		{
+			use std::os::unix::fs::FileExt;
+			let f = OpenOptions::new().write(true).open(&index_path(&p))?;
+			f.write_all_at(&[0xff; 16], SUPERBLOCK_SIZE as u64)?;
+		}
+
+		let mut vol = PhysicalVolume::open(config.clone(), &p)?;
+		assert!(bad_index_path(&p).exists());
+		assert_eq!(vol.num_needles(), 2);
+		assert!(vol.read_needle(&keys1)?.is_none());
+		assert!(vol.read_needle(&keys2)?.is_some());
+		assert!(vol.compaction_pending() > 0);
+		vol.close()?;
+
+		// The rebuilt index should be loaded as is next time
+		fs::remove_file(&bad_index_path(&p))?;
+		let vol = PhysicalVolume::open(config.clone(), &p)?;
+		assert!(!bad_index_path(&p).exists());
+		assert_eq!(vol.num_needles(), 2);
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_format_version() -> Result<()> {
+
+		let p = Path::new("out/testversion");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 8)?;
+		assert!(!vol.needs_migration());
+		assert!(vol.can_write());
+		vol.close()?;
+
+		// Volumes written by a newer version of the store should never be opened
+		{
+			let mut f = OpenOptions::new().read(true).write(true).open(&p)?;
+			let mut superblock = PhysicalVolumeSuperblock::read(&mut f)?;
+			superblock.format_version = CURRENT_FORMAT_VERSION + 1;
+			f.seek(io::SeekFrom::Start(0))?;
+			superblock.write(&mut f)?;
+		}
+
+		assert!(PhysicalVolume::open(config.clone(), &p).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_compact() -> Result<()> {
+
+		let p = Path::new("out/testcompact");
+		remove_volume_files(&p)?;
+		remove_volume_files(&pack_path(&p))?;
+
+		let config = Arc::new(Config::default());
+
+		let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 8)?;
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 1 };
+		let cookie = CookieBuf::random();
+
+		let data1 = vec![1,2,3,4,5];
+		let data2 = vec![6,7,8];
+		let data3 = vec![9,10,11,12];
+
+		// Overwriting the first needle should leave behind some space to reclaim
+		for (keys, data) in vec![(&keys1, &data1), (&keys2, &data2), (&keys1, &data3)] {
+			let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+			vol.append_needle(keys.clone(), cookie.clone(), meta, &mut SingleStream::from(data))?;
+		}
+
+		assert!(vol.compaction_pending() > 0);
+		let old_space = vol.used_space();
+
+		vol.begin_compaction()?;
+		assert!(vol.is_compacting());
+
+		// Appending in the middle of a compaction should still get the needle into the final volume
+		assert_eq!(vol.step_compaction(1)?, false);
+		let keys3 = NeedleKeys { key: 3, alt_key: 1 };
+		let meta = NeedleMeta { flags: 0, size: data2.len() as NeedleSize };
+		vol.append_needle(keys3.clone(), cookie.clone(), meta, &mut SingleStream::from(&data2))?;
+
+		while !vol.step_compaction(1)? {}
+
+		assert!(!vol.is_compacting());
+		assert!(!pack_path(&p).exists());
+		assert_eq!(vol.compaction_pending(), 0);
+		assert!(vol.used_space() < old_space);
+		assert_eq!(vol.num_needles(), 3);
+
+		assert_eq!(vol.read_needle(&keys1)?.unwrap().needle.data(), &data3[..]);
+		assert_eq!(vol.read_needle(&keys2)?.unwrap().needle.data(), &data2[..]);
+		assert_eq!(vol.read_needle(&keys3)?.unwrap().needle.data(), &data2[..]);
+
+		// The compacted files should be usable after a restart
+		vol.close()?;
+		let mut vol = PhysicalVolume::open(config.clone(), &p)?;
+		assert_eq!(vol.num_needles(), 3);
+		assert_eq!(vol.read_needle(&keys1)?.unwrap().needle.data(), &data3[..]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_scrub() -> Result<()> {
+
+		let p = Path::new("out/testscrub");
+		remove_volume_files(&p)?;
+
+		let config = Arc::new(Config::default());
+
+		let mut vol = PhysicalVolume::create(config.clone(), &p, 123, 456, 8)?;
+
+		let keys1 = NeedleKeys { key: 1, alt_key: 1 };
+		let keys2 = NeedleKeys { key: 2, alt_key: 1 };
+
+		let data = vec![1,2,3,4,5];
+		for keys in vec![&keys1, &keys2] {
+			let meta = NeedleMeta { flags: 0, size: data.len() as NeedleSize };
+			vol.append_needle(keys.clone(), CookieBuf::random(), meta, &mut SingleStream::from(&data))?;
+		}
+
+		assert!(vol.should_scrub(1000));
+
+		// Flip a byte in the data of the second needle behind the volume's back
+		let offset = vol.index.get(&keys2).unwrap().offset(vol.superblock.block_size) + (NEEDLE_HEADER_SIZE as u64);
+		{
+			use std::os::unix::fs::FileExt;
+			let f = OpenOptions::new().write(true).open(&p)?;
+			f.write_all_at(&[0xff], offset)?;
+		}
+
+		vol.begin_scrub();
+		assert!(vol.is_scrubbing());
+		assert!(!vol.should_scrub(0));
+
+		while vol.is_scrubbing() {
+			vol.step_scrub(1)?;
+		}
+
+		assert_eq!(vol.corrupt_needles(), vec![keys2.clone()]);
+		assert!(!vol.should_scrub(1000));
+
+		assert_eq!(vol.check_needle(&keys1), Some(false));
+		assert_eq!(vol.check_needle(&keys2), Some(true));
+
+		// Repairing from a stale offset should do nothing
+		let block_offset = vol.peek_needle_block_offset(&keys2).unwrap();
+		assert!(!vol.repair_needle(&keys2, block_offset + 1, CookieBuf::random(), &data)?);
+
+		vol.corrupt.insert(keys2.clone());
+		let pending = vol.compaction_pending();
+		assert!(vol.repair_needle(&keys2, block_offset, CookieBuf::random(), &data)?);
+		assert!(vol.corrupt_needles().is_empty());
+		assert!(vol.compaction_pending() > pending);
+		assert_eq!(vol.read_needle(&keys2)?.unwrap().needle.data(), &data[..]);
+
+		// The next pass should also find nothing wrong with the new copy
+		vol.begin_scrub();
+		while vol.is_scrubbing() {
+			vol.step_scrub(1)?;
+		}
+
+		assert!(vol.corrupt_needles().is_empty());
+
+		Ok(())
+	}
+
+}
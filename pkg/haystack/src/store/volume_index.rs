@@ -7,9 +7,23 @@ use std::fs::{File, OpenOptions};
 use std::mem::size_of;
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 use std::io::{Read, Write, Seek, Cursor, SeekFrom};
+use std::os::unix::fs::FileExt;
+use crc32c::crc32c_append;
 
 const SUPERBLOCK_MAGIC: &str = "HAYI";
 
+/// Original format in which entries were written without any integrity checks
+pub const FORMAT_VERSION_UNCHECKED: FormatVersion = 1;
+
+/// Every entry is followed by a CRC32C of the entry so that torn writes and bit flips are detected
+const FORMAT_VERSION_CHECKSUMMED: FormatVersion = 2;
+
+/// Format used for all newly created index files
+/// Existing files keep using the format that they were created with until they are rebuilt or their volume is compacted
+pub const CURRENT_INDEX_FORMAT_VERSION: FormatVersion = FORMAT_VERSION_CHECKSUMMED;
+
+const CHECKSUM_SIZE: usize = 4;
+
 /// An index for all of the entries in a physical volume
 pub struct PhysicalVolumeIndex {
 
@@ -69,16 +83,23 @@ impl NeedleIndexPair {
 	}
 }
 
-/*
-	TODO: WE probably need to checksum this thing in some way
-*/
-
 impl PhysicalVolumeIndex {
 
 	/// Create a brand new empty index
 	pub fn create(
 		path: &Path, parent_block: &PhysicalVolumeSuperblock
 	) -> Result<PhysicalVolumeIndex> {
+		PhysicalVolumeIndex::create_with_version(path, parent_block, CURRENT_INDEX_FORMAT_VERSION)
+	}
+
+	/// Same as create() but writes entries in the given index format version rather than the current one
+	pub fn create_with_version(
+		path: &Path, parent_block: &PhysicalVolumeSuperblock, format_version: FormatVersion
+	) -> Result<PhysicalVolumeIndex> {
+
+		if format_version != FORMAT_VERSION_UNCHECKED && format_version != FORMAT_VERSION_CHECKSUMMED {
+			return Err("Unknown index format version".into());
+		}
 		
 		// NOTE: The index is redundant to the main file, so it's easiest to just truncate any existing volumes in the case of newly created volumes
 		let mut opts = OpenOptions::new();
@@ -91,6 +112,7 @@ impl PhysicalVolumeIndex {
 
 		let superblock = PhysicalVolumeSuperblock {
 			magic: SUPERBLOCK_MAGIC.as_bytes().into(),
+			format_version,
 			machine_id: parent_block.machine_id,
 			volume_id: parent_block.volume_id,
 			cluster_id: parent_block.cluster_id,
@@ -126,6 +148,10 @@ impl PhysicalVolumeIndex {
 			return Err("Superblock magic is incorrect".into());
 		}
 
+		if superblock.format_version != FORMAT_VERSION_UNCHECKED && superblock.format_version != FORMAT_VERSION_CHECKSUMMED {
+			return Err("Superblock unknown format version".into());
+		}
+
 		let idx = PhysicalVolumeIndex {
 			superblock,
 			file,
@@ -136,40 +162,89 @@ impl PhysicalVolumeIndex {
 		Ok(idx)
 	}
 
+	/// Size of a single entry in this file (including any checksum)
+	fn entry_size(&self) -> usize {
+		if self.superblock.format_version == FORMAT_VERSION_UNCHECKED {
+			PAIR_SIZE
+		}
+		else {
+			PAIR_SIZE + CHECKSUM_SIZE
+		}
+	}
+
+	/// Parses a single complete entry from the file
+	/// Returns None if the entry doesn't match its checksum
+	fn parse_entry(&self, data: &[u8]) -> Result<Option<NeedleIndexPair>> {
+		let pair = NeedleIndexPair::read(&mut Cursor::new(&data[0..PAIR_SIZE]))?;
+
+		if self.superblock.format_version == FORMAT_VERSION_CHECKSUMMED {
+			let expected_sum = Cursor::new(&data[PAIR_SIZE..]).read_u32::<LittleEndian>()?;
+
+			if crc32c_append(0, &data[0..PAIR_SIZE]) != expected_sum {
+				return Ok(None);
+			}
+		}
+
+		Ok(Some(pair))
+	}
+
 	pub fn read_all(&mut self, volume_max_extent: u64) -> Result<Vec<NeedleIndexPair>> {
 
 		let mut out: Vec<NeedleIndexPair> = vec![];
 
 		self.file.seek(SeekFrom::Start(SUPERBLOCK_SIZE as u64))?;
 
-		let mut len = self.file.metadata()?.len() - (SUPERBLOCK_SIZE as u64);
+		let file_len = self.file.metadata()?.len();
+		let len = file_len - (SUPERBLOCK_SIZE as u64);
+
+		let entry_size = self.entry_size();
 
-		let rem = len % (PAIR_SIZE as u64);
+		let rem = len % (entry_size as u64);
 		if rem != 0 {
 			eprintln!("Detected partially flushed index file");
 		}
 
 
-		let n = len / (PAIR_SIZE as u64);
+		let n = (len / (entry_size as u64)) as usize;
 
 		// XXX: Not good to leave on heap
 		let mut buf = Vec::new();
 		buf.resize(len as usize, 0);
 		self.file.read_exact(&mut buf)?;
 
-		let mut c = Cursor::new(buf);
+		let mut pairs = vec![];
+		for data in buf.chunks(entry_size).take(n) {
+			pairs.push(self.parse_entry(data)?);
+		}
+
+		// A torn write will only leave bad entries at the very end of the file, so any good entry after a bad one means that the file is actually corrupt
+		if let Some(i) = pairs.iter().position(|p| p.is_none()) {
+			if pairs[i..].iter().any(|p| p.is_some()) {
+				return Err("Corrupt entry in the middle of the index file".into());
+			}
+
+			eprintln!("Detected partially written entries at the end of the index file");
+			pairs.truncate(i);
+		}
 
 		let mut off = SUPERBLOCK_SIZE;
 
-		for _ in 0..n {
-			let pair = NeedleIndexPair::read(&mut c)?;
+		// End offset in the volume of the last needle appended (excluding deletion records)
+		let mut last_end: Option<u64> = None;
+
+		for pair in pairs.into_iter() {
+			let pair = pair.unwrap();
+
+			let end_off = pair.value.end_offset(self.superblock.block_size);
 
 			// Sanity check: offsets should be non-overlapping and contiguous
-			if let Some(last_pair) = out.last() {
+			if let Some(last_end) = last_end {
 				// NOTE: Must technically also pad it up to fit everything
 
-				let off = last_pair.value.end_offset(self.superblock.block_size);
-				if off != pair.value.offset(self.superblock.block_size) {
+				// Deletions are recorded by re-appending the entry of an earlier needle with the deleted flag set
+				let is_deletion = pair.value.meta.deleted() && end_off <= last_end;
+
+				if !is_deletion && last_end != pair.value.offset(self.superblock.block_size) {
 					return Err("Corrupt non-contiguous index file entries".into());
 				}
 			}
@@ -182,22 +257,60 @@ impl PhysicalVolumeIndex {
 			// Verify that no entry in the index file would go beyond the size of the main volume file
 			// This will generally happen if the index file is flushed before the main volume file
 			// This doesn't really matter but is just a biproduct of us not qeueing index entries in batch upload scenarios as it doesn't really matter all that much
-			let end_off = pair.value.end_offset(self.superblock.block_size);
 			if end_off > volume_max_extent {
 				eprintln!("Index file contains entries beyond the end of the main volume");
-				self.file.set_len(off as u64)?;
 				break;
 			}
 
-			off = off + PAIR_SIZE;
+			if last_end.map(|e| end_off > e).unwrap_or(true) {
+				last_end = Some(end_off);
+			}
+
+			off = off + entry_size;
 			out.push(pair);
 		}
 
+		// Anything after the last good entry will be overwritten by the next append, so it must not be read back later
+		if (off as u64) < file_len {
+			self.file.set_len(off as u64)?;
+		}
+
 		self.extent = off as u64;
 
 		Ok(out)
 	}
 
+	/// Reads up to 'limit' entries starting at the given position in the file (zero for the first entry)
+	/// Returns the entries along with the position of the entry right after the last one read
+	/// NOTE: Only valid after read_all has been called
+	pub fn read_range(&self, position: u64, limit: usize) -> Result<(Vec<NeedleIndexPair>, u64)> {
+		let entry_size = self.entry_size() as u64;
+
+		let start = std::cmp::max(position, SUPERBLOCK_SIZE as u64);
+		if (start - (SUPERBLOCK_SIZE as u64)) % entry_size != 0 {
+			return Err("Position is not at the start of an index entry".into());
+		}
+
+		if start >= self.extent {
+			return Ok((vec![], start));
+		}
+
+		let n = std::cmp::min((self.extent - start) / entry_size, limit as u64);
+
+		let mut buf = vec![0u8; (n * entry_size) as usize];
+		self.file.read_exact_at(&mut buf, start)?;
+
+		let mut out = vec![];
+		for data in buf.chunks(entry_size as usize) {
+			match self.parse_entry(data)? {
+				Some(pair) => out.push(pair),
+				None => return Err("Corrupt entry in the middle of the index file".into())
+			}
+		}
+
+		Ok((out, start + n * entry_size))
+	}
+
 	/// Gets the size of the file for all entries appended up to now
 	/// NOTE: Only valid after read_all has been called
 	pub fn used_space(&self) -> u64 {
@@ -209,14 +322,19 @@ impl PhysicalVolumeIndex {
 		self.file.seek(SeekFrom::Start(self.extent))?;
 		
 		let mut buf = Vec::new();
-		buf.reserve(PAIR_SIZE);
+		buf.reserve(self.entry_size());
 		
 		NeedleIndexPair::write(keys, value, &mut Cursor::new(&mut buf))?;
 
+		if self.superblock.format_version == FORMAT_VERSION_CHECKSUMMED {
+			let sum = crc32c_append(0, &buf);
+			buf.write_u32::<LittleEndian>(sum)?;
+		}
+
 		// TODO: Probably best to not write anything right away but instead wait until the changes we have pending will make the file some size just under the FS block size and the nwrite and flush everything
 		self.file.write_all(&buf)?;
 
-		self.extent = self.extent + (PAIR_SIZE as u64);
+		self.extent = self.extent + (self.entry_size() as u64);
 		self.pending = self.pending + 1;
 
 		if self.pending > 32 {
@@ -240,10 +358,113 @@ impl PhysicalVolumeIndex {
 	/// Gets the last entry in this index
 	/// NOTE: Should only be relied on when the index is synced with the volume it came from and is flushed to disk
 	pub fn last_entry(&mut self) -> Result<NeedleIndexPair> {
-		self.file.seek(SeekFrom::Start(self.extent - (PAIR_SIZE as u64)))?;
-		Ok(NeedleIndexPair::read(&mut self.file)?)
+		let entry_size = self.entry_size();
+
+		let mut buf = Vec::new();
+		buf.resize(entry_size, 0);
+
+		self.file.seek(SeekFrom::Start(self.extent - (entry_size as u64)))?;
+		self.file.read_exact(&mut buf)?;
+
+		match self.parse_entry(&buf)? {
+			Some(pair) => Ok(pair),
+			None => Err("Last entry in the index file does not match its checksum".into())
+		}
 	}
 
 
 }
 
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+	use std::fs;
+	use std::os::unix::fs::FileExt;
+
+	fn write_index(path: &Path, n: usize, format_version: FormatVersion) -> Result<()> {
+		let parent = PhysicalVolumeSuperblock {
+			magic: "HAYS".as_bytes().into(),
+			format_version: CURRENT_FORMAT_VERSION,
+			cluster_id: 123,
+			machine_id: 456,
+			volume_id: 8,
+			block_size: 64,
+			allocated_space: 1024*1024
+		};
+
+		let mut idx = PhysicalVolumeIndex::create_with_version(path, &parent, format_version)?;
+
+		let mut block_offset = 1;
+		for i in 0..n {
+			let entry = NeedleIndexEntry {
+				meta: NeedleMeta { flags: 0, size: 10 },
+				block_offset
+			};
+
+			idx.append(&NeedleKeys { key: i as NeedleKey, alt_key: 1 }, &entry)?;
+			block_offset = (entry.end_offset(64) / 64) as BlockOffset;
+		}
+
+		idx.flush()
+	}
+
+	#[test]
+	fn physical_volume_index_checksums() -> Result<()> {
+		let p = Path::new("out/testindex.idx");
+		let entry_size = (PAIR_SIZE + CHECKSUM_SIZE) as u64;
+
+		// A torn write at the end of the file should just drop the last entry
+		write_index(&p, 3, CURRENT_INDEX_FORMAT_VERSION)?;
+		fs::OpenOptions::new().write(true).open(&p)?
+			.write_all_at(&[0xff; 4], (SUPERBLOCK_SIZE as u64) + 2*entry_size)?;
+
+		let mut idx = PhysicalVolumeIndex::open(&p)?;
+		assert_eq!(idx.read_all(std::u64::MAX)?.len(), 2);
+		assert_eq!(idx.used_space(), (SUPERBLOCK_SIZE as u64) + 2*entry_size);
+		assert_eq!(idx.last_entry()?.keys.key, 1);
+
+		// A bit flip in the middle of the file should be an error rather than a wrong offset
+		write_index(&p, 3, CURRENT_INDEX_FORMAT_VERSION)?;
+		fs::OpenOptions::new().write(true).open(&p)?
+			.write_all_at(&[0xff], (SUPERBLOCK_SIZE as u64) + entry_size + 14)?;
+
+		let mut idx = PhysicalVolumeIndex::open(&p)?;
+		assert!(idx.read_all(std::u64::MAX).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn physical_volume_index_unchecked() -> Result<()> {
+		let p = Path::new("out/testindexv1.idx");
+		let entry_size = PAIR_SIZE as u64;
+
+		// Entries without checksums can only be torn by a partial entry at the end of the file
+		write_index(&p, 3, FORMAT_VERSION_UNCHECKED)?;
+		fs::OpenOptions::new().write(true).open(&p)?
+			.write_all_at(&[0xff; 4], (SUPERBLOCK_SIZE as u64) + 3*entry_size)?;
+
+		let mut idx = PhysicalVolumeIndex::open(&p)?;
+		let pairs = idx.read_all(std::u64::MAX)?;
+		assert_eq!(pairs.len(), 3);
+		assert_eq!(pairs[0].value.block_offset, 1);
+		assert_eq!(idx.used_space(), (SUPERBLOCK_SIZE as u64) + 3*entry_size);
+		assert_eq!(fs::metadata(&p)?.len(), idx.used_space());
+		assert_eq!(idx.last_entry()?.keys.key, 2);
+
+		let (pairs, next) = idx.read_range(0, 2)?;
+		assert_eq!(pairs.iter().map(|p| p.keys.key).collect::<Vec<_>>(), vec![0, 1]);
+		assert_eq!(next, (SUPERBLOCK_SIZE as u64) + 2*entry_size);
+
+		let (pairs, next) = idx.read_range(next, 10)?;
+		assert_eq!(pairs.iter().map(|p| p.keys.key).collect::<Vec<_>>(), vec![2]);
+		assert_eq!(next, idx.used_space());
+
+		// Positions are in units of unchecksummed entries
+		assert!(idx.read_range((SUPERBLOCK_SIZE + PAIR_SIZE + CHECKSUM_SIZE) as u64, 1).is_err());
+
+		Ok(())
+	}
+}
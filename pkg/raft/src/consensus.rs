@@ -10,6 +10,7 @@ use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 
+use bytes::Bytes;
 use rand::RngCore;
 
 
@@ -147,6 +148,14 @@ impl<T> MustPersistMetadata<T> {
 
 
 
+/// A persisted snapshot of the state machine along with the configuration as of the same point in the log
+struct LogSnapshot {
+	last_included: LogPosition,
+	config: ConfigurationSnapshot,
+	data: Bytes
+}
+
+
 pub struct ConsensusModule {
 	/// Id of the current server we are representing
 	id: ServerId,
@@ -156,6 +165,10 @@ pub struct ConsensusModule {
 	/// The currently active configuration of the cluster
 	config: ConfigurationStateMachine,
 
+	/// Latest snapshot that can be sent to followers that need entries that are no longer in our log
+	/// The log must never be compacted past the end of this snapshot
+	snapshot: Option<LogSnapshot>,
+
 	/// A reader for the current state of the log
 	/// NOTE: This also allows for enqueuing entries to eventually go into the log, but should never block
 	log: Arc<LogStorage + Send + Sync + 'static>,
@@ -219,6 +232,7 @@ impl ConsensusModule {
 			id,
 			meta,
 			config,
+			snapshot: None,
 			log,
 			state
 		}
@@ -238,6 +252,73 @@ impl ConsensusModule {
 		self.config.snapshot()
 	}
 
+	/// Should be called with the latest persisted snapshot of the state machine (containing all entries up to last_applied)
+	/// Returns whether or not the log may now be compacted up to last_applied
+	/// NOTE: This will be false if the configuration as of the snapshot can't be determined anymore, in which case a newer snapshot is needed
+	pub fn snapshot_persisted(&mut self, last_applied: LogIndex, data: Bytes) -> bool {
+		if let Some(ref s) = self.snapshot {
+			if s.last_included.index >= last_applied {
+				return true;
+			}
+		}
+
+		let term = match self.log.term(last_applied) {
+			Some(t) => t,
+			None => return false
+		};
+
+		let config = match self.config_at(last_applied) {
+			Some(c) => c,
+			None => return false
+		};
+
+		self.snapshot = Some(LogSnapshot {
+			last_included: LogPosition { index: last_applied, term },
+			config,
+			data
+		});
+
+		true
+	}
+
+	/// Discards all entries in the log up to and including the given index
+	/// NOTE: This panics unless the discarded entries are all covered by a snapshot given to snapshot_persisted()
+	pub fn discard_log_prefix(&mut self, end_index: LogIndex) {
+		let covered = self.snapshot.as_ref().map(|s| s.last_included.index >= end_index).unwrap_or(false);
+		if !covered {
+			panic!("Discarding log entries that are not in a snapshot");
+		}
+
+		self.log.discard_prefix(end_index);
+	}
+
+	/// Gets the committed configuration as of right after the entry at the given index was applied
+	/// Returns None if it has been changed since then (as the previous value is no longer known)
+	fn config_at(&self, index: LogIndex) -> Option<ConfigurationSnapshot> {
+		let latest = self.config.snapshot();
+
+		// The committed configuration is never behind the commit_index
+		if index > latest.last_applied {
+			return None;
+		}
+
+		for i in (index + 1)..(latest.last_applied + 1) {
+			let e = match self.log.entry(i) {
+				Some(e) => e,
+				None => return None
+			};
+
+			if let LogEntryData::Config(_) = e.data {
+				return None;
+			}
+		}
+
+		Some(ConfigurationSnapshot {
+			last_applied: index,
+			data: latest.data.clone()
+		})
+	}
+
 	/// Propose a new state machine command given some data packet
 	// NOTE: Will immediately produce an output right?
 	pub fn propose_command(&mut self, data: Vec<u8>, out: &mut Tick) -> ProposeResult {
@@ -352,6 +433,66 @@ impl ConsensusModule {
 		}
 	}
 
+	pub fn is_leader(&self) -> bool {
+		if let ServerState::Leader(_) = self.state { true } else { false }
+	}
+
+	/// Starts a linearizable read using the ReadIndex technique (section 6.4 of the Raft thesis)
+	/// On the leader, this returns the position that must be applied to the state machine before the read can be performed along with the time at which the read started. The read may only be performed once leader_confirmed_since() reaches that time
+	pub fn read_index(&mut self, tick: &mut Tick) -> std::result::Result<(LogPosition, Instant), ProposeError> {
+		let started = if let ServerState::Leader(ref mut s) = self.state {
+			s.read_since = Some(tick.time.clone());
+			true
+		} else { false };
+
+		if started {
+			// Everything committed by previous leaders is somewhere in our log, so waiting for our last entry to get committed covers all of it
+			// NOTE: A new leader always has a no-op pending in its term if its last entry isn't committed yet
+			let index = self.log.last_index().unwrap_or(0);
+			let term = self.log.term(index).unwrap();
+			let start = tick.time.clone();
+
+			// Heartbeat any server that hasn't been sent a request since the read started rather than waiting for the next heartbeat timeout
+			self.cycle(tick);
+
+			Ok((LogPosition { index, term }, start))
+		}
+		else if let ServerState::Follower(ref s) = self.state {
+			Err(ProposeError::NotLeader { leader_hint: s.last_leader_id.or(self.meta.voted_for) })
+		}
+		else {
+			Err(ProposeError::NotLeader { leader_hint: None })
+		}
+	}
+
+	/// On the leader, gets the latest time at which a majority of voting members (including ourselves) are known to have still recognized us as the leader
+	/// Any read started at or before this time can't have missed an entry committed by a newer leader
+	pub fn leader_confirmed_since(&self, now: Instant) -> Option<Instant> {
+		let s = match self.state {
+			ServerState::Leader(ref s) => s,
+			_ => return None
+		};
+
+		let mut times = vec![];
+		for id in self.config.value.members.iter() {
+			if *id == self.id {
+				times.push(now.clone());
+			}
+			else if let Some(t) = s.servers.get(id).and_then(|p| p.last_acked_sent) {
+				times.push(t);
+			}
+		}
+
+		let majority = self.majority_size();
+		if times.len() < majority {
+			return None;
+		}
+
+		// Newest first, so that the majority-th time has been acknowledged by at least a majority of members
+		times.sort_by(|a, b| b.cmp(a));
+		Some(times[majority - 1])
+	}
+
 	// NOTE: Because most types are private, we probably only want to expose being able to 
 
 	// TODO: Cycle should probably be left as private but triggered by some specific 
@@ -448,7 +589,8 @@ impl ConsensusModule {
 						.collect::<_>();
 
 					self.state = ServerState::Leader(ServerLeaderState {
-						servers
+						servers,
+						read_since: None
 					});
 
 					// We are starting our leadership term with at least one uncomitted entry from a pervious term. To immediately commit it, we will propose a no-op
@@ -595,6 +737,7 @@ impl ConsensusModule {
 		};
 
 		let config = &self.config.value;
+		let snapshot = &self.snapshot;
 
 		let leader_id = self.id;
 		let term = self.meta.current_term;
@@ -604,6 +747,8 @@ impl ConsensusModule {
 		let last_log_index = log.last_index().unwrap_or(0);
 		//let last_log_term = log.term(last_log_index).unwrap();
 
+		let read_since = state.read_since.clone();
+
 
 		// Given some previous index, produces a request containing all entries after that index
 		// TODO: Long term this could reuse the same request objects as we will typically be sending the same request over and over again
@@ -624,6 +769,21 @@ impl ConsensusModule {
 			}
 		};
 
+		// Produces a request to catch up a server from our latest snapshot of the state machine
+		let new_snapshot_request = || -> InstallSnapshotRequest {
+			// We never discard entries from our log before they are in a snapshot
+			let s = snapshot.as_ref().expect("Log was compacted without a snapshot");
+
+			InstallSnapshotRequest {
+				term,
+				leader_id,
+				last_included_index: s.last_included.index,
+				last_included_term: s.last_included.term,
+				config: s.config.clone(),
+				data: s.data.clone()
+			}
+		};
+
 		// Map used to duduplicate messages that will end up being exactly the same to different followers
 		let mut message_map: HashMap<u64, Message> = HashMap::new();
 
@@ -663,7 +823,10 @@ impl ConsensusModule {
 
 					let elapsed = tick.time.duration_since(*time);
 
-					if elapsed < HEARTBEAT_TIMEOUT {
+					// A pending read needs an acknowledgement of a request sent after it started, so it can't wait for the next heartbeat
+					let read_waiting = read_since.map(|t| *time < t).unwrap_or(false);
+
+					if elapsed < HEARTBEAT_TIMEOUT && !read_waiting {
 						if elapsed > since_last_heartbeat {
 							since_last_heartbeat = elapsed;
 						}
@@ -683,6 +846,16 @@ impl ConsensusModule {
 			// - We can optimistically increment the next_index as soon as we send this request
 			// - Combining with some scenario for throttling the maximum number of requests that can go through to a single server at a given time, we can send many append_entries in a row to a server before waiting for previous ones to suceed
 			let msg_key = progress.next_index - 1;
+
+			// The entries this server needs have already been discarded from our log
+			if msg_key + 1 < log.first_index().unwrap_or(0) {
+				tick.send(Message {
+					to: vec![*server_id],
+					body: MessageBody::InstallSnapshot(new_snapshot_request())
+				});
+
+				continue;
+			}
 			
 			// If we are already 
 			if message_map.contains_key(&msg_key) {
@@ -890,6 +1063,11 @@ impl ConsensusModule {
 			// TODO: Across multiple election cycles, this may no longer be available
 			let mut progress = s.servers.get_mut(&from_id).unwrap();
 
+			// Any response in our term means that the server hadn't moved on to a newer leader by the time it got our request
+			if resp.term == self.meta.current_term {
+				progress.last_acked_sent = progress.last_sent.clone();
+			}
+
 			if resp.success { // On success, we should 
 				if last_index > progress.match_index { // NOTE: THis condition should only be needed if we allow multiple concurrent requests to occur
 					progress.match_index = last_index;
@@ -929,6 +1107,8 @@ impl ConsensusModule {
 			false
 		};
 
+		self.clear_confirmed_reads(tick);
+
 		if should_noop {
 			self.propose_noop(tick).expect("Failed to propose noop as leader");
 		}
@@ -938,6 +1118,56 @@ impl ConsensusModule {
 		}
 	}
 
+	/// Handles the response to an InstallSnapshot request (last_index being the index of the last entry in the snapshot that was sent)
+	pub fn install_snapshot_callback(
+		&mut self, from_id: ServerId, last_index: u64, resp: InstallSnapshotResponse, tick: &mut Tick
+	) {
+
+		self.observe_term(resp.term, tick);
+
+		let should_cycle = if let ServerState::Leader(ref mut s) = self.state {
+			let mut progress = s.servers.get_mut(&from_id).unwrap();
+
+			// The server will now have everything up to the end of the snapshot (or it already had it committed)
+			if resp.term == self.meta.current_term {
+				progress.last_acked_sent = progress.last_sent.clone();
+
+				if last_index > progress.match_index {
+					progress.match_index = last_index;
+				}
+
+				progress.next_index = progress.match_index + 1;
+			}
+
+			progress.request_pending = false;
+
+			true
+		}
+		else {
+			false
+		};
+
+		self.clear_confirmed_reads(tick);
+
+		if should_cycle {
+			self.cycle(tick);
+		}
+	}
+
+	/// Stops forcing immediate heartbeats once the most recent read has been confirmed
+	fn clear_confirmed_reads(&mut self, tick: &mut Tick) {
+		let confirmed = match self.leader_confirmed_since(tick.time) {
+			Some(t) => t,
+			None => return
+		};
+
+		if let ServerState::Leader(ref mut s) = self.state {
+			if s.read_since.map(|t| t <= confirmed).unwrap_or(false) {
+				s.read_since = None;
+			}
+		}
+	}
+
 	/// Handles the event of received no response or an error/timeout from an append_entries request
 	pub fn append_entries_noresponse(&mut self, from_id: ServerId, tick: &mut Tick) {
 		if let ServerState::Leader(ref mut s) = self.state {
@@ -1136,9 +1366,9 @@ impl ConsensusModule {
 		}
 		
 
-		// This should never happen as the snapshot should only contain comitted entries which should never be resent
-		if req.prev_log_index < self.log.first_index().unwrap_or(1) - 1 {
-			return Err("Requested previous log entry is before the start of the log".into());
+		// Entries before the start of our log are already in our state machine (this may happen if the leader sent this before it got the response to a snapshot it sent us), so we will ask the leader to continue from the end of our log
+		if req.prev_log_index + 1 < self.log.first_index().unwrap_or(0) {
+			return Ok(response(false, Some(self.log.last_index().unwrap_or(0))).into());
 		}
 
 		match self.log.term(req.prev_log_index) {
@@ -1253,6 +1483,80 @@ impl ConsensusModule {
 		))
 	}
 
+	/// Handles a snapshot sent by the leader in place of entries that it has already discarded from its log
+	/// The given function is called to replace the contents of the state machine before our log is reset to start right after the snapshot
+	pub fn install_snapshot<F>(&mut self, req: InstallSnapshotRequest, tick: &mut Tick, restore: F) -> Result<InstallSnapshotResponse>
+		where F: FnOnce(LogIndex, Bytes) -> Result<()>
+	{
+
+		self.observe_term(req.term, tick);
+
+		// Same as for AppendEntries
+		if req.term == self.meta.current_term {
+
+			let is_candidate = match self.state { ServerState::Candidate(_) => true, _ => false };
+
+			if is_candidate {
+				self.become_follower(tick);
+			}
+		}
+
+		let response = InstallSnapshotResponse {
+			term: self.meta.current_term
+		};
+
+		if req.term < self.meta.current_term {
+			return Ok(response);
+		}
+
+		match self.state {
+			ServerState::Follower(ref mut s) => {
+				s.last_heartbeat = tick.time.clone();
+				s.last_leader_id = Some(req.leader_id);
+			},
+			_ => {
+				return Err("Received a snapshot while not being a follower in the current term".into());
+			}
+		};
+
+		// We already have everything in the snapshot
+		if req.last_included_index <= self.meta.commit_index {
+			return Ok(response);
+		}
+
+		// If the last entry in the snapshot is in our log, then so is everything before it and it will be applied normally once committed
+		if self.log.term(req.last_included_index) == Some(req.last_included_term) {
+			self.update_commited(req.last_included_index, tick);
+			return Ok(response);
+		}
+
+		// NOTE: The state machine is persisted before the log changes so that we never restart with a log that starts after the state machine
+		restore(req.last_included_index, req.data.clone())?;
+
+		let last_included = LogPosition {
+			index: req.last_included_index,
+			term: req.last_included_term
+		};
+
+		self.log.reset(last_included.clone());
+
+		self.config = ConfigurationStateMachine::from(req.config.clone());
+
+		// Our log now starts right after this snapshot, so it is also what we would send to other servers if we become the leader
+		self.snapshot = Some(LogSnapshot {
+			last_included,
+			config: req.config,
+			data: req.data
+		});
+
+		self.meta.commit_index = req.last_included_index;
+		tick.write_meta();
+		tick.write_config();
+		tick.new_entries = true;
+
+		Ok(response)
+	}
+
 	pub fn timeout_now(&mut self, req: TimeoutNow, tick: &mut Tick) -> Result<()> {
 		// TODO: Possibly avoid a pre-vote in this case to speed up leader transfer
 		self.start_election(tick);
@@ -1262,3 +1566,99 @@ impl ConsensusModule {
 
 }
 
+
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	/// Creates a single member cluster with one learner which will always need to be caught up by the member
+	fn learner_config() -> ConfigurationSnapshot {
+		let mut data = Configuration::default();
+		data.members.insert(1);
+		data.learners.insert(2);
+
+		ConfigurationSnapshot { last_applied: 0, data }
+	}
+
+	#[test]
+	fn install_snapshot_round_trip() {
+		let leader_log = Arc::new(MemoryLogStorage::new());
+		let mut leader = ConsensusModule::new(1, Metadata::default(), learner_config(), leader_log.clone());
+
+		// As the only member, this will immediately win an election
+		let mut tick = Tick::empty();
+		leader.cycle(&mut tick);
+		assert!(leader.is_leader());
+
+		for i in 0..3 {
+			leader.propose_command(vec![i], &mut tick).unwrap();
+		}
+
+		let last_index = leader_log.last_index().unwrap();
+		let term = leader.meta().current_term;
+		assert_eq!(leader.meta().commit_index, last_index);
+
+		assert!(leader.snapshot_persisted(last_index, Bytes::from("state")));
+		leader.discard_log_prefix(last_index);
+
+		// The learner has nothing in its log, so it can only be caught up from the snapshot
+		let mut tick = Tick::empty();
+		leader.append_entries_callback(2, last_index, AppendEntriesResponse {
+			term, success: false, last_log_index: Some(0)
+		}, &mut tick);
+
+		let req = tick.messages.into_iter().filter_map(|m| match m.body {
+			MessageBody::InstallSnapshot(req) => Some(req),
+			_ => None
+		}).next().unwrap();
+
+		assert_eq!(req.last_included_index, last_index);
+		assert_eq!(req.last_included_term, term);
+		assert_eq!(req.config.last_applied, last_index);
+		assert_eq!(req.data, Bytes::from("state"));
+
+		let follower_log = Arc::new(MemoryLogStorage::new());
+		let mut follower = ConsensusModule::new(2, Metadata::default(), learner_config(), follower_log.clone());
+
+		let mut restored = None;
+		let mut tick = Tick::empty();
+		let resp = follower.install_snapshot(req, &mut tick, |index, data| {
+			restored = Some((index, data));
+			Ok(())
+		}).unwrap();
+
+		assert_eq!(restored, Some((last_index, Bytes::from("state"))));
+		assert_eq!(follower_log.prev(), LogPosition { index: last_index, term });
+		assert_eq!(follower.meta().commit_index, last_index);
+		assert_eq!(follower.config_snapshot().last_applied, last_index);
+		assert!(tick.config);
+
+		let mut tick = Tick::empty();
+		leader.install_snapshot_callback(2, last_index, resp, &mut tick);
+
+		match leader.state {
+			ServerState::Leader(ref s) => {
+				let progress = &s.servers[&2];
+				assert_eq!(progress.match_index, last_index);
+				assert_eq!(progress.next_index, last_index + 1);
+			},
+			_ => panic!("No longer the leader")
+		};
+	}
+
+	#[test]
+	#[should_panic]
+	fn discard_log_prefix_without_snapshot() {
+		let log = Arc::new(MemoryLogStorage::new());
+		let mut inst = ConsensusModule::new(1, Metadata::default(), learner_config(), log.clone());
+
+		let mut tick = Tick::empty();
+		inst.cycle(&mut tick);
+		inst.propose_command(vec![1], &mut tick).unwrap();
+
+		inst.discard_log_prefix(log.last_index().unwrap());
+	}
+
+}
@@ -112,11 +112,11 @@ impl StateMachine<KeyValueReturn> for MemoryKVStateMachine {
 		})
 	}
 
-	fn snapshot<'a>(&'a self) -> Option<StateMachineSnapshot<'a>> {
+	fn snapshot(&self) -> Option<StateMachineSnapshot> {
 		None
 	}
 
-	fn restore(&self, data: Bytes) -> Result<()> {
+	fn restore(&self, last_applied: LogIndex, data: Bytes) -> Result<()> {
 		// A snapshot should not have been generatable
 		Ok(())
 	}
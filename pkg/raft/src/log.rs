@@ -21,6 +21,7 @@ pub trait LogStorage {
 	fn term(&self, index: LogIndex) -> Option<Term>;
 
 	/// Gets the index of the first entry in the log
+	/// If a prefix of the log has been discarded, this is the index right after the last discarded entry (even if no entries have been appended since then)
 	/// XXX: Should always be present (at least as 0)
 	fn first_index(&self) -> Option<LogIndex>;
 
@@ -44,6 +45,15 @@ pub trait LogStorage {
 	/// Should immediately remove all log entries starting at the given index until the end of the log
 	fn truncate_suffix(&self, start_index: LogIndex);
 
+	/// Should remove all log entries up to and including the given index (which must be in the log)
+	/// The term of the last removed entry must still be retrievable with term() so that the log can still be appended to after it
+	/// This only needs to be persisted on the next flush
+	fn discard_prefix(&self, end_index: LogIndex);
+
+	/// Should remove every entry in the log and make the log restart immediately after the given position
+	/// Used when a snapshot from the leader replaces the whole log
+	fn reset(&self, prev: LogPosition);
+
 
 	// TODO: Everything belo this point should never be used by the core consensus code
 
@@ -59,26 +69,40 @@ pub trait LogStorage {
 use std::sync::Mutex;
 
 pub struct MemoryLogStorage {
-	log: Mutex<Vec<Arc<LogEntry>>>
+	log: Mutex<MemoryLog>
 }
 
-impl MemoryLogStorage {
-	pub fn new() -> Self {
-		MemoryLogStorage {
-			log: Mutex::new(vec![])
-		}
-	}
+struct MemoryLog {
+	/// Position of the entry immediately before the first one in the log (or zeros if nothing has been discarded)
+	prev: LogPosition,
 
+	entries: Vec<Arc<LogEntry>>
+}
+
+impl MemoryLog {
 	// EIther it is a valid index, it is the index for the previous entry, or None
-	fn pos_for(index: u64, log: &Vec<Arc<LogEntry>>) -> Option<usize> {
-		if log.len() == 0 {
+	fn pos_for(&self, index: u64) -> Option<usize> {
+		if index <= self.prev.index {
 			return None;
 		}
 
-		let first_index = log[0].index;
+		Some((index - self.prev.index - 1) as usize)
+	}
+}
 
-		// TODO: This could go negative if we are not careful
-		Some((index - first_index) as usize)
+impl MemoryLogStorage {
+	pub fn new() -> Self {
+		MemoryLogStorage {
+			log: Mutex::new(MemoryLog {
+				prev: LogPosition { index: 0, term: 0 },
+				entries: vec![]
+			})
+		}
+	}
+
+	/// Gets the position of the entry immediately before the first entry in the log
+	pub fn prev(&self) -> LogPosition {
+		self.log.lock().unwrap().prev.clone()
 	}
 }
 
@@ -88,22 +112,16 @@ impl LogStorage for MemoryLogStorage {
 		
 		let log = self.log.lock().unwrap();
 
-		if index == 0 {
-			return Some(0);
+		if index == log.prev.index {
+			return Some(log.prev.term);
 		}
 
-		if log.len() == 0 {
-			return None;
-		}
-
-		// TODO: This does not properly implement the previous index case right now
-
-		let pos = match Self::pos_for(index, &log) {
+		let pos = match log.pos_for(index) {
 			Some(v) => v,
 			None => return None
 		};
 		
-		match log.get(pos) {
+		match log.entries.get(pos) {
 			Some(v) => {
 				assert_eq!(v.index, index);
 				Some(v.term)
@@ -115,18 +133,18 @@ impl LogStorage for MemoryLogStorage {
 	fn first_index(&self) -> Option<u64> {
 		let log = self.log.lock().unwrap();
 
-		match log.first() {
+		match log.entries.first() {
 			Some(v) => Some(v.index),
-			None => None
+			None => if log.prev.index > 0 { Some(log.prev.index + 1) } else { None }
 		}
 	}
 
 	fn last_index(&self) -> Option<u64> {
 		let log = self.log.lock().unwrap();
 
-		match log.last() {
+		match log.entries.last() {
 			Some(v) => Some(v.index),
-			None => None
+			None => if log.prev.index > 0 { Some(log.prev.index) } else { None }
 		}
 	}
 
@@ -136,12 +154,12 @@ impl LogStorage for MemoryLogStorage {
 		// XXX: Basically why it is better to pass around arcs 
 		// Simplest 
 
-		let pos = match Self::pos_for(index, &log) {
+		let pos = match log.pos_for(index) {
 			Some(v) => v,
 			None => return None
 		};
 		
-		match log.get(pos) {
+		match log.entries.get(pos) {
 			Some(v) => {
 				assert_eq!(v.index, index);
 				Some(v.clone())
@@ -158,18 +176,42 @@ impl LogStorage for MemoryLogStorage {
 	fn append(&self, entry: LogEntry) {
 		let mut log = self.log.lock().unwrap();
 
-		log.push(Arc::new(entry));
+		log.entries.push(Arc::new(entry));
 	}
 
 	fn truncate_suffix(&self, start_index: u64) {
-		let log = self.log.lock().unwrap();
+		let mut log = self.log.lock().unwrap();
 
-		let pos = match Self::pos_for(start_index, &log) {
+		let pos = match log.pos_for(start_index) {
 			Some(v) => v,
 			None => panic!("Truncating starting at unknown position")
 		};
 
-		self.log.lock().unwrap().truncate(pos);
+		log.entries.truncate(pos);
+	}
+
+	fn discard_prefix(&self, end_index: u64) {
+		let mut log = self.log.lock().unwrap();
+
+		let pos = match log.pos_for(end_index) {
+			Some(v) => v,
+			// Already discarded
+			None => return
+		};
+
+		let term = match log.entries.get(pos) {
+			Some(e) => e.term,
+			None => panic!("Discarding past the end of the log")
+		};
+
+		log.entries.drain(0..(pos + 1));
+		log.prev = LogPosition { index: end_index, term };
+	}
+
+	fn reset(&self, prev: LogPosition) {
+		let mut log = self.log.lock().unwrap();
+		log.entries.clear();
+		log.prev = prev;
 	}
 
 
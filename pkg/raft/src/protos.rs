@@ -248,9 +248,26 @@ pub struct RequestVoteResponse {
 	pub vote_granted: bool
 }
 
+/// Sent by the leader in place of AppendEntries to a server that needs entries which the leader has already discarded from its log
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InstallSnapshotRequest {
+	pub term: Term,
+	pub leader_id: ServerId,
+
+	/// Index and term of the last log entry included in the snapshot
+	pub last_included_index: LogIndex,
+	pub last_included_term: Term,
+
+	/// Configuration of the cluster as of the last entry included in the snapshot
+	pub config: ConfigurationSnapshot,
 
+	/// Serialized contents of the state machine
+	pub data: Bytes
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstallSnapshotResponse {
+	pub term: Term
 }
 
 
@@ -289,7 +306,8 @@ pub struct TimeoutNow {
 pub enum MessageBody {
 	PreVote(RequestVoteRequest),
 	RequestVote(RequestVoteRequest),
-	AppendEntries(AppendEntriesRequest, LogIndex) // The index is the last_index of the original request (naturally not needed if we support retaining the original request while receiving the response)
+	AppendEntries(AppendEntriesRequest, LogIndex), // The index is the last_index of the original request (naturally not needed if we support retaining the original request while receiving the response)
+	InstallSnapshot(InstallSnapshotRequest)
 }
 
 pub struct Message {
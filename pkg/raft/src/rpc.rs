@@ -101,7 +101,7 @@ pub trait ServerService {
 	
 	fn timeout_now(&self, req: TimeoutNow) -> ServiceFuture<()>;
 
-	// Also InstallSnapshot
+	fn install_snapshot(&self, req: InstallSnapshotRequest) -> ServiceFuture<InstallSnapshotResponse>;
 
 	/// This is the odd ball out internal client method
 	/// NOTE: 'AddServer' and 'RemoveServer' will be implemented by clients in terms of this method
@@ -479,6 +479,9 @@ pub fn ServerService_router<R: 'static, S: 'static>(
 		"/ConsensusService/AppendEntries" => {
 			await!(run_handler(inst.borrow(), data, S::append_entries))
 		},
+		"/ConsensusService/InstallSnapshot" => {
+			await!(run_handler(inst.borrow(), data, S::install_snapshot))
+		},
 		"/ConsensusService/Propose" => {
 			await!(run_handler(inst.borrow(), data, S::propose))
 		},
@@ -617,6 +620,12 @@ impl Client {
 		self.make_request(To::Id(to), "/ConsensusService/AppendEntries", req)
 	}
 
+	pub fn call_install_snapshot(&self, to: ServerId, req: &InstallSnapshotRequest)
+		-> impl Future<Item=InstallSnapshotResponse, Error=Error> {
+
+		self.make_request(To::Id(to), "/ConsensusService/InstallSnapshot", req)
+	}
+
 	pub fn call_propose(&self, to: ServerId, req: &ProposeRequest)
 		-> impl Future<Item=ProposeResponse, Error=Error> {
 
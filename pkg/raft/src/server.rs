@@ -28,6 +28,15 @@ use super::state_machine::StateMachine;
 /// NOTE: This value doesn't matter very much, but the important part is that every single request must have some timeout associated with it to prevent the number of pending incomplete requests from growing indefinately in the case of other servers leaving connections open for an infinite amount of time (so that we never run out of file descriptors)
 const REQUEST_TIMEOUT: u64 = 500;
 
+/// Snapshots of the state machine may be large, so they are given more time than other requests
+const SNAPSHOT_REQUEST_TIMEOUT: u64 = 10000;
+
+/// Minimum number of entries that a state machine snapshot must cover past the start of the log before the log is compacted
+const COMPACTION_MIN_ENTRIES: u64 = 100;
+
+/// Maximum amount of time a linearizable read will wait for a majority of servers to confirm that we are still the leader
+const READ_TIMEOUT: u64 = 2000;
+
 // Basically whenever we connect to another node with a fresh connection, we must be able to negogiate with each the correct pair of cluster id and server ids on both ends otherwise we are connecting to the wrong server/cluster and that would be problematic (especially when it comes to aoiding duplicate votes because of duplicate connections)
 
 /*
@@ -137,8 +146,11 @@ struct ServerShared<R> {
 	commit_index: Condvar<LogPosition, LogPosition>,
 
 	/// Last log index applied to the state machine
-	/// This should only ever be modified by the separate applier
+	/// This should only ever be modified by the separate applier (or when installing a snapshot while holding the apply_lock)
 	last_applied: Condvar<u64, u64>,
+
+	/// Held while entries are applied to the state machine so that a snapshot from the leader is never installed in the middle of that
+	apply_lock: Mutex<()>,
 }
 
 /// All the mutable state for the server that you hold a lock in order to look at
@@ -162,7 +174,10 @@ struct ServerState<R> {
 	log_changed: ChangeSender, log_receiver: Option<ChangeReceiver>,
 
 	/// Whenever an operation is proposed, this will store callbacks that will be given back the result once it is applied
-	callbacks: std::collections::LinkedList<(LogPosition, oneshot::Sender<Option<R>>)>
+	callbacks: std::collections::LinkedList<(LogPosition, oneshot::Sender<Option<R>>)>,
+
+	/// Reads waiting for our leadership to be confirmed (in order of the time at which they started)
+	reads: std::collections::LinkedList<(Instant, oneshot::Sender<()>)>
 }
 
 impl<R: Send + 'static> Server<R> {
@@ -201,7 +216,12 @@ impl<R: Send + 'static> Server<R> {
 		}
 
 
-		let inst = ConsensusModule::new(meta.id, meta.meta, config_snapshot.config, log.clone());
+		let mut inst = ConsensusModule::new(meta.id, meta.meta, config_snapshot.config, log.clone());
+
+		// Entries discarded from the log before we restarted are covered by this snapshot
+		if let Some(snapshot) = state_machine.snapshot() {
+			inst.snapshot_persisted(snapshot.last_applied, snapshot.data);
+		}
 
 		let (tx_state, rx_state) = change();
 		let (tx_log, rx_log) = change();
@@ -215,7 +235,8 @@ impl<R: Send + 'static> Server<R> {
 			state_changed: tx_state, state_receiver: Some(rx_state),
 			scheduled_cycle: None,
 			log_changed: tx_log, log_receiver: Some(rx_log),
-			callbacks: std::collections::LinkedList::new() 
+			callbacks: std::collections::LinkedList::new(),
+			reads: std::collections::LinkedList::new()
 		};
 
 		let shared = Arc::new(ServerShared {
@@ -229,7 +250,8 @@ impl<R: Send + 'static> Server<R> {
 			match_index: Condvar::new(LogPosition { index: 0, term: 0 }),
 			commit_index: Condvar::new(LogPosition { index: 0, term: 0 }),
 
-			last_applied: Condvar::new(last_applied)
+			last_applied: Condvar::new(last_applied),
+			apply_lock: Mutex::new(())
 		});
 
 
@@ -402,91 +424,100 @@ impl<R: Send + 'static> Server<R> {
 
 		loop_fn((server.shared.clone(), std::collections::LinkedList::new()), |(shared, mut callbacks)| {
 
-			let commit_index = shared.commit_index.lock().index;
-			let mut last_applied = *shared.last_applied.lock();
+			let commit_index = {
+				// NOTE: A snapshot from the leader can only be installed while this is not held
+				let _apply_guard = shared.apply_lock.lock().unwrap();
+
+				let commit_index = shared.commit_index.lock().index;
+				let mut last_applied = *shared.last_applied.lock();
 			
-			// Take ownership of all pending callbacks (as long as a callback is appended to the list before the commit_index variable is incremented, this should always see them)
-			{
-				let mut state = shared.state.lock().unwrap();
-				callbacks.append(&mut state.callbacks);
-			}
+				// Take ownership of all pending callbacks (as long as a callback is appended to the list before the commit_index variable is incremented, this should always see them)
+				{
+					let mut state = shared.state.lock().unwrap();
+					callbacks.append(&mut state.callbacks);
+				}
 
-			// TODO: Suppose we have the item in our log but it gets truncated, then in this case, callbacks will all be blocked until a new operation of some type is proposed
+				// TODO: Suppose we have the item in our log but it gets truncated, then in this case, callbacks will all be blocked until a new operation of some type is proposed
 
-			{
+				{
 
-			let state_machine = &shared.state_machine;
+				let state_machine = &shared.state_machine;
 
-			// Apply all committed entries to state machine
-			while last_applied < commit_index {
-				let entry = shared.log.entry(last_applied + 1);
-				if let Some(e) = entry {
+				// Apply all committed entries to state machine
+				while last_applied < commit_index {
+					let entry = shared.log.entry(last_applied + 1);
+					if let Some(e) = entry {
 					
-					let ret = if let LogEntryData::Command(ref data) = e.data {
-						match state_machine.apply(e.index, data) {
-							Ok(v) => Some(v),
-							Err(e) => {
-								// TODO: Ideally notify everyone that all progress has been halted
-								// If we are the leader, then we should probably demote ourselves to a healthier node
-								eprintln!("Applier failed to apply to state machine: {:?}", e);
-								return Either::A(ok(Loop::Break(())));
+						let ret = if let LogEntryData::Command(ref data) = e.data {
+							match state_machine.apply(e.index, data) {
+								Ok(v) => Some(v),
+								Err(e) => {
+									// TODO: Ideally notify everyone that all progress has been halted
+									// If we are the leader, then we should probably demote ourselves to a healthier node
+									eprintln!("Applier failed to apply to state machine: {:?}", e);
+									return Either::A(ok(Loop::Break(())));
+								}
 							}
-						}
-					} else {
-						// Other types of log entries produce no output and generally any callbacks specified shouldn't expect any output
-						None
-					};
-
-					// TODO: the main complication is that we should probably execute all of the callbacks after we have updated the last_applied index so that they are guranteed to see a consistent view of the world if they try to observe its value
-
-					// So we should probably defer all results until after that 
-
-					// Resolve/reject callbacks waiting for this change to get commited
-					// TODO: In general, we should assert that the linked list is monotonically increasing always based on proposal indexes
-					// TODO: the other thing is that callbacks can be rejected early in the case of something newer getting commited which would override it
-					while callbacks.len() > 0 {
-						let first = callbacks.front().unwrap().0.clone();
-
-						if e.term > first.term || e.index >= first.index {
-							let item = callbacks.pop_front().unwrap();
-
-							if e.term == first.term && e.index == first.index {
-								item.1.send(ret);
-								break; // NOTE: This is not really necessary as it should immediately get completed on the next run through the loop by the other break 
+						} else {
+							// Other types of log entries produce no output and generally any callbacks specified shouldn't expect any output
+							None
+						};
+
+						// TODO: the main complication is that we should probably execute all of the callbacks after we have updated the last_applied index so that they are guranteed to see a consistent view of the world if they try to observe its value
+
+						// So we should probably defer all results until after that 
+
+						// Resolve/reject callbacks waiting for this change to get commited
+						// TODO: In general, we should assert that the linked list is monotonically increasing always based on proposal indexes
+						// TODO: the other thing is that callbacks can be rejected early in the case of something newer getting commited which would override it
+						while callbacks.len() > 0 {
+							let first = callbacks.front().unwrap().0.clone();
+
+							if e.term > first.term || e.index >= first.index {
+								let item = callbacks.pop_front().unwrap();
+
+								if e.term == first.term && e.index == first.index {
+									item.1.send(ret);
+									break; // NOTE: This is not really necessary as it should immediately get completed on the next run through the loop by the other break 
+								}
+								// Otherwise, older than the current entry
+								else {
+									item.1.send(None);
+								}
 							}
-							// Otherwise, older than the current entry
+							// Otherwise possibly more recent than the current commit
 							else {
-								item.1.send(None);
+								break;
 							}
 						}
-						// Otherwise possibly more recent than the current commit
-						else {
-							break;
-						}
-					}
 
 
-					last_applied += 1;
-				}
-				else {
-					// Our log may be behind the commit_index in the consensus module, but the commit_index conditional variable should always be at most at the newest value in our log
-					// (so if we see this, then we have a bug somewhere in this file)
-					eprintln!("Need to apply an entry not in our log yet");
-					break;
+						last_applied += 1;
+					}
+					else {
+						// Our log may be behind the commit_index in the consensus module, but the commit_index conditional variable should always be at most at the newest value in our log
+						// (so if we see this, then we have a bug somewhere in this file)
+						eprintln!("Need to apply an entry not in our log yet");
+						break;
+					}
 				}
-			}
 
-			}
+				}
 
 
-			// Update last_applied
-			{
-				let mut guard = shared.last_applied.lock();
-				if last_applied > *guard {
-					*guard = last_applied;
-					guard.notify_all();
+				// Update last_applied
+				{
+					let mut guard = shared.last_applied.lock();
+					if last_applied > *guard {
+						*guard = last_applied;
+						guard.notify_all();
+					}
 				}
-			}
+
+				commit_index
+			};
+
+			ServerShared::compact_log_if_needed(&shared);
 
 			// Wait for the next time commit_index changes
 			let waiter = {
@@ -604,11 +635,36 @@ impl<R: Send + 'static> Server<R> {
 		}))
 	}
 
-	/// Blocks until the state machine can be read such that all changes that were commited before the time at which this was called have been flushed to disk
+	/// Blocks until the state machine can be read such that all changes that were commited before the time at which this was called have been applied to it
+	/// Only the leader can serve these reads (a NotLeader error is returned on other servers)
 	/// TODO: Other consistency modes: 
 	/// - For follower reads, it is usually sufficient to check for a 
-	pub fn linearizable_read(&self) -> impl Future<Item=(), Error=Error> + Send {
-		ok(())
+	pub fn linearizable_read(&self) -> impl Future<Item=(), Error=ExecuteError> + Send {
+
+		let res = ServerShared::run_tick(&self.shared, |state, tick| {
+			state.inst.read_index(tick).map(|(pos, start)| {
+				let (tx, rx) = oneshot::channel();
+				state.reads.push_back((start, tx));
+				(pos, rx)
+			})
+		});
+
+		let (pos, rx) = match res {
+			Ok(v) => v,
+			Err(e) => return Either::A(err(ExecuteError::Propose(e)))
+		};
+
+		let shared = self.shared.clone();
+
+		// If we lose our leadership, the sender is dropped without a value
+		Either::B(rx
+		.timeout(Duration::from_millis(READ_TIMEOUT))
+		.map_err(|_| ExecuteError::NoResult)
+		.and_then(move |_| {
+			ServerShared::wait_for_commit(shared.clone(), pos.clone())
+			.and_then(move |_| ServerShared::wait_for_applied(shared, pos))
+			.map_err(|_| ExecuteError::NoResult)
+		}))
 	}
 
 
@@ -707,6 +763,10 @@ impl<R: Send + 'static> ServerShared<R> {
 			}
 		}
 
+		if state.reads.len() > 0 {
+			Self::confirm_reads(state, &tick.time);
+		}
+
 		// TODO: A leader can dispatch RequestVotes in parallel to flushing its metadata so long as the metadata is flushed before it elects itself as the leader (aka befoer it processes replies to RequestVote)
 
 		Self::dispatch_messages(&shared, &state, tick.messages);
@@ -715,6 +775,68 @@ impl<R: Send + 'static> ServerShared<R> {
 		Ok(())
 	}
 
+	/// Once the state machine has persisted a snapshot covering enough of the log, this discards the log entries included in it
+	fn compact_log_if_needed(shared: &Arc<Self>) {
+		let snapshot = match shared.state_machine.snapshot() {
+			Some(s) => s,
+			None => return
+		};
+
+		let snapshot_index = snapshot.last_applied;
+
+		let res = Self::run_tick(shared, |state, _| -> Result<()> {
+			// Followers that need entries from before the start of our log will be sent this snapshot instead
+			if !state.inst.snapshot_persisted(snapshot_index, snapshot.data) {
+				return Ok(());
+			}
+
+			if snapshot_index < shared.log.first_index().unwrap_or(0) + COMPACTION_MIN_ENTRIES {
+				return Ok(());
+			}
+
+			// On restart, the configuration is rebuilt by replaying the log after the last persisted configuration snapshot and the match_index must still have a known term
+			let config_index = state.inst.config_snapshot().last_applied;
+			let match_index = shared.log.match_index().unwrap_or(0);
+			let end_index = std::cmp::min(snapshot_index, std::cmp::min(config_index, match_index));
+
+			if end_index < shared.log.first_index().unwrap_or(0) {
+				return Ok(());
+			}
+
+			// The configuration must be persisted before the entries that it was built from can go away
+			state.config_file.store(&rpc::marshal(ServerConfigurationSnapshotRef {
+				config: state.inst.config_snapshot()
+			})?)?;
+
+			state.inst.discard_log_prefix(end_index);
+			state.log_changed.notify();
+
+			Ok(())
+		});
+
+		if let Err(e) = res {
+			eprintln!("Failed to compact the log: {:?}", e);
+		}
+	}
+
+	/// Lets through all pending reads that started before our leadership was last confirmed by a majority of the cluster
+	fn confirm_reads(state: &mut ServerState<R>, now: &Instant) {
+		match state.inst.leader_confirmed_since(now.clone()) {
+			Some(confirmed) => {
+				while state.reads.front().map(|r| r.0 <= confirmed).unwrap_or(false) {
+					let (_, tx) = state.reads.pop_front().unwrap();
+					let _ = tx.send(());
+				}
+			},
+			None => {
+				// Dropping the senders fails all of the reads
+				if !state.inst.is_leader() {
+					state.reads.clear();
+				}
+			}
+		}
+	}
+
 	fn update_match_index(shared: &Arc<Self>) {
 		// Getting latest match_index
 		let cur_mi = shared.log.match_index().unwrap_or(0);
@@ -787,6 +909,7 @@ impl<R: Send + 'static> ServerShared<R> {
 
 		let mut append_entries = vec![];
 		let mut request_votes = vec![];
+		let mut install_snapshots = vec![];
 
 		// TODO: We should chain on some promise holding one side of a channel so that we can cancel this entire request later if we end up needing to 
 		let new_request_vote = |
@@ -837,6 +960,30 @@ impl<R: Send + 'static> ServerShared<R> {
 			ret
 		};
 
+		let new_install_snapshot = |
+			to_id: ServerId, req: &InstallSnapshotRequest
+		| {
+
+			let shared = shared.clone();
+			let last_index = req.last_included_index;
+
+			shared.client.call_install_snapshot(to_id, req)
+			.timeout(Duration::from_millis(SNAPSHOT_REQUEST_TIMEOUT))
+			.then(move |res| -> FutureResult<(), ()> {
+
+				Self::run_tick(&shared, |state, tick| {
+					if let Ok(resp) = res {
+						state.inst.install_snapshot_callback(to_id, last_index, resp, tick);
+					}
+					else {
+						state.inst.append_entries_noresponse(to_id, tick);
+					}
+				});
+
+				ok(())
+			})
+		};
+
 		for msg in messages {
 			for to_id in msg.to {
 				match msg.body {
@@ -846,6 +993,9 @@ impl<R: Send + 'static> ServerShared<R> {
 					MessageBody::RequestVote(ref req) => {
 						request_votes.push(new_request_vote(to_id, req));
 					},
+					MessageBody::InstallSnapshot(ref req) => {
+						install_snapshots.push(new_install_snapshot(to_id, req));
+					},
 					_ => {}
 					// TODO: Handle all cases
 				};
@@ -854,7 +1004,7 @@ impl<R: Send + 'static> ServerShared<R> {
 
 
 		// Let them all loose
-		let f = join_all(append_entries).join(join_all(request_votes))
+		let f = join_all(append_entries).join3(join_all(request_votes), join_all(install_snapshots))
 		.map(|_| ())
 		.map_err(|_| {
 			//eprintln!("{:?}", e);
@@ -1001,6 +1151,34 @@ impl<R: Send + 'static> rpc::ServerService for Server<R> {
 
 	}) }
 
+	fn install_snapshot(&self, req: InstallSnapshotRequest) -> rpc::ServiceFuture<InstallSnapshotResponse> { to_future_box!({
+
+		let shared = &self.shared;
+		let last_included_index = req.last_included_index;
+
+		// Keeps the applier from running until last_applied matches the restored state machine
+		let _apply_guard = shared.apply_lock.lock().unwrap();
+
+		let mut restored = false;
+
+		let res = ServerShared::run_tick(shared, |state, tick| {
+			state.inst.install_snapshot(req, tick, |last_applied, data| {
+				shared.state_machine.restore(last_applied, data)?;
+				restored = true;
+				Ok(())
+			})
+		})?;
+
+		if restored {
+			let mut guard = shared.last_applied.lock();
+			*guard = last_included_index;
+			guard.notify_all();
+		}
+
+		Ok(res)
+
+	}) }
+
 	// TODO: This may become a ClientService method only? (although it is still sufficiently internal that we don't want just any old client to be using this)
 	fn propose(&self, req: ProposeRequest) -> rpc::ServiceFuture<ProposeResponse> {
 
@@ -7,6 +7,15 @@ use std::sync::{Arc, Mutex};
 use std::path::Path;
 
 
+/// Format of the file storing the log
+#[derive(Serialize, Deserialize)]
+struct SimpleLogFile {
+	/// Position of the last entry discarded from the start of the log
+	prev: LogPosition,
+
+	entries: Vec<LogEntry>
+}
+
 /// A simple log implementation backed be a single file that is rewritten every time a flush is needed and otherwise stores all entries in memory 
 pub struct SimpleLog {
 	mem: MemoryLogStorage,
@@ -18,7 +27,10 @@ impl SimpleLog {
 	pub fn create(path: &Path) -> Result<SimpleLog> {
 		let b = BlobFile::builder(path)?;
 
-		let log: Vec<LogEntry> = vec![];
+		let log = SimpleLogFile {
+			prev: LogPosition { index: 0, term: 0 },
+			entries: vec![]
+		};
 		let file = b.create(&marshal(log)?)?;
 
 		Ok(SimpleLog {
@@ -31,14 +43,20 @@ impl SimpleLog {
 		let b = BlobFile::builder(path)?;
 		let (file, data) = b.open()?;
 
-		let log: Vec<LogEntry> = unmarshal(&data)?;
+		// Logs written before prefixes could be discarded are stored as a plain list of entries
+		let log: SimpleLogFile = match unmarshal::<Vec<LogEntry>>(&data) {
+			Ok(entries) => SimpleLogFile { prev: LogPosition { index: 0, term: 0 }, entries },
+			Err(_) => unmarshal(&data)?
+		};
+
 		let mem = MemoryLogStorage::new();
 
-		println!("RESTORE {:?}", log);
+		println!("RESTORE {:?}", log.entries);
 
-		let mut match_index = 0;
+		let mut match_index = log.prev.index;
+		mem.reset(log.prev);
 
-		for e in log {
+		for e in log.entries {
 			match_index = e.index;
 			mem.append(e);
 		}
@@ -65,6 +83,20 @@ impl LogStorage for SimpleLog {
 	fn entry(&self, index: u64) -> Option<Arc<LogEntry>> { self.mem.entry(index) }
 	fn append(&self, entry: LogEntry) { self.mem.append(entry); }
 	fn truncate_suffix(&self, start_index: u64) { self.mem.truncate_suffix(start_index); }
+
+	fn discard_prefix(&self, end_index: u64) {
+		// Held so that this never happens in the middle of a flush
+		let _s = self.snapshot.lock().unwrap();
+		self.mem.discard_prefix(end_index);
+	}
+
+	fn reset(&self, prev: LogPosition) {
+		let mut s = self.snapshot.lock().unwrap();
+		self.mem.reset(prev.clone());
+
+		// Everything up to the new start of the log is covered by the snapshot that replaced it
+		s.0 = prev.index;
+	}
 	
 	// TODO: May be wrong on truncations right?
 	fn match_index(&self) -> Option<u64> {
@@ -77,18 +109,19 @@ impl LogStorage for SimpleLog {
 
 		let mut s = self.snapshot.lock().unwrap();
 
+		let prev = self.mem.prev();
 		let idx = self.mem.last_index().unwrap_or(0);
-		let mut log: Vec<LogEntry> = vec![];
+		let mut entries: Vec<LogEntry> = vec![];
 
-		let mut last_idx = s.0;
+		let mut last_idx = std::cmp::max(s.0, prev.index);
 
-		for i in 1..(idx + 1) {
+		for i in (prev.index + 1)..(idx + 1) {
 			let e = self.mem.entry(i).expect("Failed to get entry from log");
 			last_idx = e.index;
-			log.push((*e).clone());
+			entries.push((*e).clone());
 		}
 
-		s.1.store(&marshal(log)?)?;
+		s.1.store(&marshal(SimpleLogFile { prev, entries })?)?;
 		s.0 = last_idx;
 
 		Ok(())
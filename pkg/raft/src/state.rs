@@ -15,6 +15,10 @@ pub struct ServerProgress {
 	/// Time at which we sent out the last request to this server
 	pub last_sent: Option<Instant>,
 
+	/// Time at which we sent the last request that this server has responded to in our current term
+	/// Used to confirm that we were still the leader at some point in time (see ConsensusModule::read_index)
+	pub last_acked_sent: Option<Instant>,
+
 	/// Whether or not we are currently waiting for a response on an active request
 	pub request_pending: bool
 }
@@ -27,6 +31,7 @@ impl ServerProgress {
 			next_index: last_log_index + 1,
 			match_index: 0,
 			last_sent: None, // This will force the leader to send initial heartbeats to all servers upon being elected
+			last_acked_sent: None,
 			request_pending: false
 		}
 	}
@@ -65,7 +70,11 @@ pub struct ServerCandidateState {
 /// (Reinitialized after election)
 #[derive(Clone, Debug)]
 pub struct ServerLeaderState {
-	pub servers: HashMap<ServerId, ServerProgress>
+	pub servers: HashMap<ServerId, ServerProgress>,
+
+	/// Start time of the most recent read that is still waiting for a majority of servers to confirm that we are the leader
+	/// While set, every server that hasn't been sent a request since this time will be sent one immediately
+	pub read_since: Option<Instant>
 }
 
 
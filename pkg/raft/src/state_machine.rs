@@ -1,7 +1,6 @@
 use bytes::Bytes;
 use super::errors::*;
 use super::protos::*;
-use std::io::Read;
 
 
 pub trait StateMachine<R> {
@@ -18,9 +17,12 @@ pub trait StateMachine<R> {
 
 	/// Should retrieve the last created snapshot if any is available
 	/// This should be a cheap operation that can quickly queried to check on the last snapshot
-	fn snapshot<'a>(&'a self) -> Option<StateMachineSnapshot<'a>>;
+	/// NOTE: The snapshot must already be persisted as log entries up to its last_applied index may be discarded once it is returned here
+	fn snapshot(&self) -> Option<StateMachineSnapshot>;
 
-	fn restore(&self, data: Bytes) -> Result<()>;
+	/// Should replace the entire contents of the state machine with the data of a snapshot (as produced by snapshot() on another server) that contains all entries up to the given index
+	/// The restored state must be persisted before this returns
+	fn restore(&self, last_applied: LogIndex, data: Bytes) -> Result<()>;
 
 	// Triggers a new snapshot to begin being created and persisted to disk
 	// The index of the last entry applied to the state machine is given as an argument to be stored alongside the snapshot
@@ -28,15 +30,12 @@ pub trait StateMachine<R> {
 	//fn perform_snapshot(&self, last_applied: u64) -> Result<oneshot::Receiver<()>>;
 }
 
-pub struct StateMachineSnapshot<'a> {
+pub struct StateMachineSnapshot {
 
 	/// Index of the last log entry in this snapshot (same value originally given to the perform_snapshot that created this snapshot )
 	pub last_applied: u64,
 
-	/// Number of bytes needed to store this snapshot
-	pub size: u64,
-
-	/// A reader for retrieving the contents of the snapshot
-	pub data: &'a Read
+	/// Serialized contents of the snapshot (as given to restore())
+	pub data: Bytes
 }
 